@@ -0,0 +1,39 @@
+use avrisp::programmer::stk500v2::bench;
+use avrisp::programmer::{MemoryOps, MemoryType};
+use avrisp::specs;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+fn framing(c: &mut Criterion) {
+    let body = [0x55; 266];
+    c.bench_function("message new", |b| {
+        b.iter(|| bench::frame(black_box(1), black_box(&body)))
+    });
+    c.bench_function("checksum", |b| b.iter(|| bench::checksum(black_box(&body))));
+}
+
+fn transfer(c: &mut Criterion) {
+    let specs = specs::atmega::ATMEGA_2560;
+    let mut isp = bench::mock(specs.clone());
+    let mut group = c.benchmark_group("atmega2560");
+
+    group.throughput(Throughput::Bytes(specs.flash.size as u64));
+    let mut flash = vec![0; specs.flash.size];
+    group.bench_function("read flash", |b| {
+        b.iter(|| isp.read(MemoryType::Flash, &mut flash).unwrap())
+    });
+    // Erased pages are skipped, so image must hold data.
+    let image = vec![0x55; specs.flash.size];
+    group.bench_function("write flash", |b| {
+        b.iter(|| isp.write(MemoryType::Flash, &image).unwrap())
+    });
+
+    group.throughput(Throughput::Bytes(specs.eeprom.size as u64));
+    let mut eeprom = vec![0; specs.eeprom.size];
+    group.bench_function("read eeprom", |b| {
+        b.iter(|| isp.read(MemoryType::Eeprom, &mut eeprom).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, framing, transfer);
+criterion_main!(benches);
@@ -1,4 +1,5 @@
 use avrisp::errors;
+use avrisp::format;
 use avrisp::programmer;
 use avrisp::programmer::*;
 use avrisp::specs;
@@ -19,7 +20,7 @@ fn main() -> Result<(), errors::ErrorKind> {
     flash(&mut isp)?;
     eeprom(&mut isp)?;
     isp.close()?;
-    return Ok(());
+    Ok(())
 }
 
 fn fuses<T: programmer::AVRFuseGet>(programmer: &mut T) -> Result<(), errors::ErrorKind> {
@@ -41,32 +42,23 @@ fn signature<T: programmer::MCUSignature>(programmer: &mut T) -> Result<(), erro
     Ok(())
 }
 
-fn eeprom<T: programmer::EEPROMRead>(programmer: &mut T) -> Result<(), errors::ErrorKind> {
+fn eeprom<T: programmer::MemoryOps>(programmer: &mut T) -> Result<(), errors::ErrorKind> {
     let mut eeprom: Vec<u8> = vec![0; SPECS.eeprom.size];
-    programmer.read(&mut eeprom)?;
-    dump(&mut eeprom, String::from("eeprom.bin"));
+    programmer.read(programmer::MemoryType::Eeprom, &mut eeprom)?;
+    format::trim(&mut eeprom, 0xff);
+    dump(&eeprom, String::from("eeprom.bin"));
     Ok(())
 }
 
-fn flash<T: programmer::FlashRead>(programmer: &mut T) -> Result<(), errors::ErrorKind> {
+fn flash<T: programmer::MemoryOps>(programmer: &mut T) -> Result<(), errors::ErrorKind> {
     let mut flash: Vec<u8> = vec![0; SPECS.flash.size];
-    programmer.read(&mut flash)?;
-    truncate(&mut flash);
-    dump(&mut flash, String::from("flash.bin"));
+    programmer.read(programmer::MemoryType::Flash, &mut flash)?;
+    format::trim(&mut flash, 0xff);
+    dump(&flash, String::from("flash.bin"));
     Ok(())
 }
 
-fn truncate(bytes: &mut Vec<u8>) {
-    let found = bytes.iter().rposition(|&x| x != 0xff);
-    let end = match found {
-        Some(0) => 0,
-        None => return,
-        Some(x) => x + 1,
-    };
-    bytes.truncate(end);
-}
-
-fn dump(bytes: &Vec<u8>, name: String) {
+fn dump(bytes: &[u8], name: String) {
     let mut file = File::create(name).unwrap();
-    file.write_all(&bytes).unwrap();
+    file.write_all(bytes).unwrap();
 }
@@ -0,0 +1,583 @@
+//! avrdude command line compatibility, e.g.
+//! `avrdude -p m32 -c stk500v2 -P /dev/ttyUSB0 -U flash:w:fw.hex:i`.
+//!
+//! Common flags are translated to avrisp options and `-U` operations are run in given order.
+use crate::backend::Backend;
+use crate::error::Error;
+use crate::hex;
+use crate::ledger::Cycles;
+use crate::memory::{self, Memory};
+use avrisp::errors::ErrorKind;
+use avrisp::format::{self, Firmware, Image};
+use avrisp::programmer::{self, Action, Confirm, Force, Session};
+use avrisp::specs::{self, Fuse};
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Flags which take a value. Value is either glued to the flag (`-pm32`) or the next argument.
+const WITH_VALUE: &str = "pcPbBCUxliY";
+
+/// Parsed avrdude command line.
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    /// Chip name, e.g. ATmega32 for `-p m32`.
+    pub part: Option<String>,
+    pub programmer: Option<Backend>,
+    /// None for `-P usb`.
+    pub port: Option<String>,
+    pub baud: Option<usize>,
+    /// `-e`
+    pub erase: bool,
+    /// Flash writes erase chip first, unless `-D` is given.
+    pub auto_erase: bool,
+    /// Written memories are read back, unless `-V` is given.
+    pub verify: bool,
+    /// `-F` continues with wrong signature.
+    pub force: bool,
+    pub operations: Vec<Operation>,
+}
+
+/// Memory of `-U` operation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+    Memory(Memory),
+    Fuse(Fuse),
+    Lock,
+    /// Memory which can only be read, e.g. `signature`. Name as in chip memory map.
+    ReadOnly(&'static str),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Read,
+    Write,
+    Verify,
+}
+
+/// File format letter of `-U` operation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileFormat {
+    /// Chosen by file extension.
+    Auto,
+    Intel,
+    Raw,
+    Elf,
+    /// Value given in place of file name, e.g. `lfuse:w:0xe4:m`.
+    Immediate,
+    /// Hex text, e.g. `0xe4`. Output only.
+    Hex,
+    /// Decimal text. Output only.
+    Decimal,
+}
+
+/// `-U memory:op:file[:format]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Operation {
+    pub region: Region,
+    pub op: Op,
+    pub file: String,
+    pub format: FileFormat,
+}
+
+impl Operation {
+    fn parse(value: &str) -> Result<Operation, Error> {
+        let invalid = || Error::Usage(format!("Invalid -U operation: {}", value));
+        let mut parts = value.splitn(3, ':');
+        let (region, op, rest) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(region), Some(op), Some(rest)) => (region, op, rest),
+            // Bare file name writes flash.
+            _ => ("flash", "w", value),
+        };
+        let region = match region {
+            "flash" => Region::Memory(Memory::Flash),
+            "eeprom" => Region::Memory(Memory::Eeprom),
+            "lfuse" | "fuse" => Region::Fuse(Fuse::Low),
+            "hfuse" => Region::Fuse(Fuse::High),
+            "efuse" => Region::Fuse(Fuse::Extended),
+            "lock" | "lockbits" => Region::Lock,
+            "signature" => Region::ReadOnly("signature"),
+            "calibration" => Region::ReadOnly("calibration"),
+            _ => return Err(Error::Usage(format!("Unsupported memory: {}", region))),
+        };
+        let op = match op {
+            "r" => Op::Read,
+            "w" => Op::Write,
+            "v" => Op::Verify,
+            _ => return Err(invalid()),
+        };
+        // File name may contain colons, e.g. C:\fw.hex. Format is single letter after the last one.
+        let (file, format) = match rest.rsplit_once(':') {
+            Some((file, format)) if format.len() == 1 => (file, format),
+            _ => (rest, "a"),
+        };
+        let format = match format {
+            "a" => FileFormat::Auto,
+            "i" => FileFormat::Intel,
+            "r" => FileFormat::Raw,
+            "e" => FileFormat::Elf,
+            "m" => FileFormat::Immediate,
+            "h" => FileFormat::Hex,
+            "d" => FileFormat::Decimal,
+            _ => return Err(Error::Usage(format!("Unsupported file format: {}", format))),
+        };
+        if file.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Operation {
+            region,
+            op,
+            file: file.to_string(),
+            format,
+        })
+    }
+
+    /// Data to write or compare with.
+    fn data(&self) -> Result<Image, Error> {
+        let path = Path::new(&self.file);
+        let firmware = match self.format {
+            FileFormat::Auto => format::load(path)?,
+            FileFormat::Intel => {
+                format::ihex::parse(&String::from_utf8(fs::read(path)?).map_err(ErrorKind::from)?)?
+            }
+            FileFormat::Elf => format::elf::parse(&fs::read(path)?)?,
+            FileFormat::Raw => Firmware {
+                flash: Image::from(fs::read(path)?),
+                eeprom: Image::new(),
+            },
+            FileFormat::Immediate => {
+                return self
+                    .file
+                    .split([',', ' '])
+                    .filter(|value| !value.is_empty())
+                    .map(parse_value)
+                    .collect::<Result<Vec<u8>, Error>>()
+                    .map(Image::from)
+            }
+            FileFormat::Hex | FileFormat::Decimal => {
+                return Err(Error::Usage(format!(
+                    "Format of {} can be used only for reading",
+                    self.file
+                )))
+            }
+        };
+        Ok(match self.region {
+            // .eep files made by avr-objcopy hold EEPROM at address 0.
+            Region::Memory(Memory::Eeprom) if !firmware.eeprom.is_empty() => firmware.eeprom,
+            _ => firmware.flash,
+        })
+    }
+
+    /// Save memory read from chip. Files hold it from address 0, like avrdude writes them.
+    fn save(&self, image: &Image) -> Result<(), Error> {
+        let bytes = image.to_bytes(0xff);
+        let text = |digits: fn(&u8) -> String| -> String {
+            let values: Vec<String> = bytes.iter().map(digits).collect();
+            values.join(",") + "\n"
+        };
+        let content = match self.format {
+            FileFormat::Intel => format::ihex::encode(image)?.into_bytes(),
+            FileFormat::Auto if has_extension(&self.file, &["hex", "ihex", "eep"]) => {
+                format::ihex::encode(image)?.into_bytes()
+            }
+            FileFormat::Auto | FileFormat::Raw => bytes,
+            FileFormat::Hex => text(|byte| format!("{:#04x}", byte)).into_bytes(),
+            FileFormat::Decimal => text(|byte| byte.to_string()).into_bytes(),
+            FileFormat::Immediate | FileFormat::Elf => {
+                return Err(Error::Usage(format!(
+                    "Format of {} can not be used for reading",
+                    self.file
+                )))
+            }
+        };
+        fs::write(PathBuf::from(&self.file), content)?;
+        Ok(())
+    }
+}
+
+impl Options {
+    /// Parse avrdude arguments, without program name.
+    pub fn parse(args: &[String]) -> Result<Options, Error> {
+        let mut options = Options {
+            part: None,
+            programmer: None,
+            port: None,
+            baud: None,
+            erase: false,
+            auto_erase: true,
+            verify: true,
+            force: false,
+            operations: Vec::new(),
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let flags = arg
+                .strip_prefix('-')
+                .filter(|flags| !flags.is_empty())
+                .ok_or_else(|| Error::Usage(format!("Unexpected argument: {}", arg)))?;
+            // Flags without value can be grouped, e.g. -vD.
+            for (i, flag) in flags.char_indices() {
+                if WITH_VALUE.contains(flag) {
+                    let glued = &flags[i + flag.len_utf8()..];
+                    let value = match glued {
+                        "" => args
+                            .next()
+                            .ok_or_else(|| Error::Usage(format!("Missing value of -{}", flag)))?,
+                        glued => glued,
+                    };
+                    options.set(flag, value)?;
+                    break;
+                }
+                match flag {
+                    'e' => options.erase = true,
+                    'D' => options.auto_erase = false,
+                    'V' => options.verify = false,
+                    'F' => options.force = true,
+                    // Verbosity, safemode and old erase cycle counter have no effect here.
+                    'v' | 'q' | 's' | 'u' | 'y' => (),
+                    _ => return Err(Error::Usage(format!("Unsupported avrdude flag: -{}", flag))),
+                }
+            }
+        }
+        Ok(options)
+    }
+
+    fn set(&mut self, flag: char, value: &str) -> Result<(), Error> {
+        match flag {
+            'p' => self.part = Some(part(value)),
+            'c' => self.programmer = Some(programmer(value)?),
+            'P' => self.port = Some(value.to_string()).filter(|port| port != "usb"),
+            'b' => {
+                let baud = value
+                    .parse()
+                    .map_err(|_| Error::Usage(format!("Invalid baud rate: {}", value)))?;
+                self.baud = Some(baud)
+            }
+            'U' => self.operations.push(Operation::parse(value)?),
+            // Bit clock, config file, extended parameters, log file and erase cycle count are not
+            // used.
+            'B' | 'C' | 'x' | 'l' | 'i' | 'Y' => {
+                eprintln!("warning: -{} {} is ignored", flag, value)
+            }
+            _ => unreachable!("-{} has no value", flag),
+        }
+        Ok(())
+    }
+
+    /// Chip is erased before the operations.
+    fn erases(&self) -> bool {
+        self.erase
+            || self.auto_erase
+                && self.operations.iter().any(|operation| {
+                    operation.op == Op::Write && operation.region == Region::Memory(Memory::Flash)
+                })
+    }
+}
+
+/// Command line for clap. Arguments of `avrdude` command go after `--`, so avrdude flags like
+/// `-p` are not taken for avrisp ones. Program installed as `avrdude` gets the command added.
+pub fn arguments(args: impl Iterator<Item = OsString>) -> Vec<OsString> {
+    let mut args: Vec<OsString> = args.collect();
+    let invoked_as_avrdude = args
+        .first()
+        .and_then(|program| Path::new(program).file_stem())
+        .is_some_and(|stem| stem == "avrdude");
+    if invoked_as_avrdude {
+        args.splice(1..1, [OsString::from("avrdude"), OsString::from("--")]);
+    } else if args.get(1).is_some_and(|command| command == "avrdude")
+        && args.get(2).is_none_or(|arg| arg != "--")
+    {
+        args.insert(2, OsString::from("--"));
+    }
+    args
+}
+
+/// Chip name of avrdude part id, e.g. m32 or t10. Full names are kept.
+fn part(id: &str) -> String {
+    let lower = id.to_lowercase();
+    let prefixes = [("m", "ATmega"), ("t", "ATtiny"), ("x", "ATxmega")];
+    for (short, family) in prefixes.iter() {
+        if let Some(rest) = lower.strip_prefix(short) {
+            if rest.starts_with(|c: char| c.is_ascii_digit()) {
+                return format!("{}{}", family, rest.to_uppercase());
+            }
+        }
+    }
+    id.to_string()
+}
+
+/// Backend of avrdude programmer id.
+fn programmer(id: &str) -> Result<Backend, Error> {
+    Ok(match id.to_lowercase().as_str() {
+        "stk500" | "stk500v2" | "wiring" => Backend::Stk500v2,
+        "stk500v1" | "arduino" | "avrisp" => Backend::Stk500v1,
+        "avr109" | "avr911" | "butterfly" => Backend::Avr109,
+        "jtag2isp" => Backend::JtagiceMkii,
+        // Parallel (high voltage) and JTAG programming, not ISP.
+        "stk500pp" | "stk500hvsp" | "jtag2" | "jtagmkii" => {
+            return Err(Error::Usage(format!(
+                "avrdude programmer {} does not use ISP. Only ISP is supported",
+                id
+            )))
+        }
+        #[cfg(feature = "usb")]
+        "avrisp2" | "avrispmkii" => Backend::AvrispMkii,
+        #[cfg(feature = "usb")]
+        "stk600" => Backend::Stk600,
+        #[cfg(feature = "usb")]
+        "atmelice" | "atmelice_isp" | "xplainedmini" | "xplainedpro" | "medbg" => Backend::Edbg,
+        #[cfg(feature = "usb")]
+        "usbtiny" => Backend::Usbtiny,
+        #[cfg(feature = "usb")]
+        "ft232h" | "ft2232h" | "ft4232h" => Backend::Mpsse,
+        #[cfg(feature = "usb")]
+        "ch341a" => Backend::Ch341a,
+        _ => {
+            return Err(Error::Usage(format!(
+                "Unsupported avrdude programmer: {}",
+                id
+            )))
+        }
+    })
+}
+
+/// Byte given with `m` format. Hex with 0x prefix or decimal.
+fn parse_value(value: &str) -> Result<u8, Error> {
+    hex::parse_address(value)
+        .ok()
+        .and_then(|value| u8::try_from(value).ok())
+        .ok_or_else(|| Error::Usage(format!("Invalid byte: {}", value)))
+}
+
+fn has_extension(file: &str, extensions: &[&str]) -> bool {
+    Path::new(file)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            extensions
+                .iter()
+                .any(|known| ext.eq_ignore_ascii_case(known))
+        })
+}
+
+/// Erase chip if needed and run operations in order. Returns cycles done, also on failure.
+pub fn run(
+    options: &Options,
+    isp: &mut dyn Session,
+    chip: &specs::Specs,
+    confirm: &mut dyn Confirm,
+) -> (Result<(), Error>, Cycles) {
+    let mut cycles = Cycles::default();
+    let result = (|| {
+        if options.erases() {
+            programmer::ask(confirm, Action::Erase)?;
+            isp.erase()?;
+            cycles.erase += 1;
+        }
+        for operation in &options.operations {
+            if operation.op == Op::Write && operation.region == Region::Memory(Memory::Eeprom) {
+                cycles.eeprom += 1;
+            }
+            execute(options, operation, isp, chip, confirm)?;
+        }
+        Ok(())
+    })();
+    (result, cycles)
+}
+
+fn execute(
+    options: &Options,
+    operation: &Operation,
+    isp: &mut dyn Session,
+    chip: &specs::Specs,
+    confirm: &mut dyn Confirm,
+) -> Result<(), Error> {
+    match (operation.op, operation.region) {
+        (Op::Read, Region::Memory(memory)) => {
+            let mut image = memory::read(isp, memory, memory.specs(chip).size)?;
+            image.trim(0xff);
+            operation.save(&image)
+        }
+        (Op::Read, Region::ReadOnly(name)) => {
+            operation.save(&Image::from(read_all(isp, chip, name)?))
+        }
+        (Op::Write, Region::ReadOnly(name)) => {
+            // Refused by the memory map.
+            let bytes = operation.data()?.to_bytes(0xff);
+            Ok(programmer::write_memory(isp, chip, name, 0, &bytes)?)
+        }
+        (Op::Verify, Region::ReadOnly(name)) => {
+            let expected = operation.data()?;
+            let found = read_all(isp, chip, name)?;
+            // Not paged, the whole region is one page.
+            Ok(expected.verify(&found, found.len().max(1))?)
+        }
+        (Op::Read, region) => operation.save(&Image::from(vec![byte(isp, region)?])),
+        (Op::Write, Region::Memory(memory)) => {
+            let image = operation.data()?;
+            memory::check_size(chip, memory, &image)?;
+            match memory {
+                Memory::Flash => {
+                    let force = if options.force {
+                        Some(Force::accept_risk())
+                    } else {
+                        None
+                    };
+                    memory::write_flash(isp, chip, &image, force)?
+                }
+                Memory::Eeprom => memory::write(isp, memory, &image)?,
+            }
+            if options.verify {
+                memory::verify(isp, chip, memory, &image)?;
+            }
+            Ok(())
+        }
+        (Op::Write, region) => {
+            let value = single(operation)?;
+            // Programmer reads the value back and compares it.
+            match region {
+                Region::Fuse(fuse) => {
+                    let mut fuses = isp.get_fuses()?;
+                    fuses.set(fuse, value);
+                    programmer::ask(confirm, Action::WriteFuses(fuses))?;
+                    programmer::write_fuses(isp, chip, &fuses, None)?;
+                }
+                _ => {
+                    programmer::ask(confirm, Action::WriteLock(value))?;
+                    isp.set_lock_byte(value)?;
+                }
+            }
+            Ok(())
+        }
+        (Op::Verify, Region::Memory(memory)) => {
+            memory::verify(isp, chip, memory, &operation.data()?)
+        }
+        (Op::Verify, region) => {
+            let expected = single(operation)?;
+            let found = byte(isp, region)?;
+            if expected != found {
+                return Err(Error::Verify(format!(
+                    "{} verification failed. Expected {:#04x} found {:#04x}",
+                    operation.file, expected, found
+                )));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Whole memory called `name` in chip memory map.
+fn read_all(isp: &mut dyn Session, chip: &specs::Specs, name: &str) -> Result<Vec<u8>, Error> {
+    let size = chip
+        .memories()
+        .iter()
+        .find(|region| region.name == name)
+        .map_or(0, |region| region.size);
+    Ok(programmer::read_memory(isp, chip, name, 0..size)?)
+}
+
+/// Fuse or lock byte value.
+fn byte(isp: &mut dyn Session, region: Region) -> Result<u8, Error> {
+    Ok(match region {
+        Region::Fuse(fuse) => isp.get_fuses()?.get(fuse),
+        _ => isp.get_lock_byte()?,
+    })
+}
+
+/// Exactly one byte of fuse or lock operation.
+fn single(operation: &Operation) -> Result<u8, Error> {
+    match operation.data()?.to_bytes(0xff)[..] {
+        [value] => Ok(value),
+        _ => Err(Error::Usage(format!(
+            "Expected single byte in {}",
+            operation.file
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn makefile_command_line() {
+        let options = Options::parse(&args(
+            "-v -pm32 -c stk500v2 -P /dev/ttyUSB0 -b 115200 -D -U flash:w:fw.hex:i -U lfuse:w:0xe4:m",
+        ))
+        .unwrap();
+        assert_eq!(options.part.as_deref(), Some("ATmega32"));
+        assert_eq!(options.programmer, Some(Backend::Stk500v2));
+        assert_eq!(options.port.as_deref(), Some("/dev/ttyUSB0"));
+        assert_eq!(options.baud, Some(115_200));
+        assert!(!options.erases());
+        assert_eq!(
+            options.operations,
+            [
+                Operation {
+                    region: Region::Memory(Memory::Flash),
+                    op: Op::Write,
+                    file: String::from("fw.hex"),
+                    format: FileFormat::Intel,
+                },
+                Operation {
+                    region: Region::Fuse(Fuse::Low),
+                    op: Op::Write,
+                    file: String::from("0xe4"),
+                    format: FileFormat::Immediate,
+                },
+            ]
+        );
+        assert!(Options::parse(&args("-t")).is_err());
+    }
+
+    #[test]
+    fn operation_file_names() {
+        let operation = Operation::parse(r"eeprom:r:C:\out.eep").unwrap();
+        assert_eq!(operation.file, r"C:\out.eep");
+        assert_eq!(operation.format, FileFormat::Auto);
+        let operation = Operation::parse("fw.hex").unwrap();
+        assert_eq!(operation.region, Region::Memory(Memory::Flash));
+        assert_eq!(operation.op, Op::Write);
+        let operation = Operation::parse("signature:r:sig:h").unwrap();
+        assert_eq!(operation.region, Region::ReadOnly("signature"));
+        assert!(Operation::parse("boot:r:sig:h").is_err());
+    }
+
+    #[test]
+    fn installed_as_avrdude() {
+        let command = |line: &str| -> Vec<OsString> {
+            arguments(line.split_whitespace().map(OsString::from))
+        };
+        assert_eq!(
+            command("/usr/bin/avrdude -p m32")[1..],
+            command("avrisp avrdude -- -p m32")[1..]
+        );
+        assert_eq!(
+            command("avrisp avrdude -p m32"),
+            command("avrisp avrdude -- -p m32")
+        );
+        assert_eq!(command("avrisp -p m32").len(), 3);
+    }
+
+    #[test]
+    fn part_ids() {
+        assert_eq!(part("m2560"), "ATmega2560");
+        assert_eq!(part("t10"), "ATtiny10");
+        assert_eq!(part("ATmega32"), "ATmega32");
+    }
+
+    #[test]
+    fn programmer_ids() {
+        let options = Options::parse(&args("-c arduino -b 115200 -Y 10 -pm328p")).unwrap();
+        assert_eq!(options.programmer, Some(Backend::Stk500v1));
+        assert_eq!(options.part.as_deref(), Some("ATmega328P"));
+        assert_eq!(programmer("jtag2isp").unwrap(), Backend::JtagiceMkii);
+        assert!(programmer("jtag2").is_err());
+        assert!(programmer("stk500pp").is_err());
+    }
+}
@@ -0,0 +1,104 @@
+use crate::error::Error;
+use avrisp::programmer::stk500v2::LinkOptions;
+use avrisp::programmer::{avr109, stk500v1, ProgrammerKind};
+use clap::ValueEnum;
+
+/// SCK of programmers without their own clock setting. Fits chips running at 1 MHz.
+#[cfg(feature = "usb")]
+const SPEED: u32 = 200_000;
+
+/// Programmer hardware selected with --programmer.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Backend {
+    Stk500v2,
+    Stk500v1,
+    Avr109,
+    JtagiceMkii,
+    #[cfg(feature = "usb")]
+    AvrispMkii,
+    #[cfg(feature = "usb")]
+    Stk600,
+    #[cfg(feature = "usb")]
+    Edbg,
+    #[cfg(feature = "usb")]
+    Usbtiny,
+    #[cfg(feature = "usb")]
+    Mpsse,
+    #[cfg(feature = "usb")]
+    Ch341a,
+}
+
+impl Backend {
+    /// Connection parameters. Serial programmers need `port`, USB ones take the first device.
+    /// `link` applies to serial STK500v2 programmers, `baud` to STK500v1 and AVR109 ones.
+    pub fn kind(
+        &self,
+        port: Option<&str>,
+        link: &LinkOptions,
+        baud: Option<usize>,
+    ) -> Result<ProgrammerKind, Error> {
+        let port = || {
+            port.map(String::from)
+                .ok_or_else(|| Error::Usage(String::from("No port given. Use --port")))
+        };
+        Ok(match self {
+            Backend::Stk500v2 => ProgrammerKind::Stk500v2 {
+                port: port()?,
+                options: link.clone(),
+            },
+            Backend::Stk500v1 => ProgrammerKind::Stk500v1 {
+                port: port()?,
+                baud: baud.unwrap_or(stk500v1::DEFAULT_BAUD),
+            },
+            Backend::Avr109 => ProgrammerKind::Avr109 {
+                port: port()?,
+                baud: baud.unwrap_or(avr109::DEFAULT_BAUD),
+            },
+            Backend::JtagiceMkii => ProgrammerKind::JtagIceMkII { port: port()? },
+            #[cfg(feature = "usb")]
+            Backend::AvrispMkii => ProgrammerKind::AvrIspMkII,
+            #[cfg(feature = "usb")]
+            Backend::Stk600 => ProgrammerKind::Stk600 { cards: None },
+            #[cfg(feature = "usb")]
+            Backend::Edbg => ProgrammerKind::Edbg,
+            #[cfg(feature = "usb")]
+            Backend::Usbtiny => ProgrammerKind::UsbTiny,
+            #[cfg(feature = "usb")]
+            Backend::Mpsse => ProgrammerKind::Mpsse { speed: SPEED },
+            #[cfg(feature = "usb")]
+            Backend::Ch341a => ProgrammerKind::Ch341a,
+        })
+    }
+
+    /// Serial programmer with settable link rate.
+    pub fn takes_baud(&self) -> bool {
+        matches!(
+            self,
+            Backend::Stk500v2 | Backend::Stk500v1 | Backend::Avr109
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serial_programmer_needs_port() {
+        let link = LinkOptions::default();
+        assert!(Backend::Stk500v1.kind(None, &link, None).is_err());
+        match Backend::Stk500v1
+            .kind(Some("/dev/ttyUSB0"), &link, None)
+            .unwrap()
+        {
+            ProgrammerKind::Stk500v1 { port, baud } => {
+                assert_eq!((port.as_str(), baud), ("/dev/ttyUSB0", 19_200))
+            }
+            kind => panic!("unexpected {:?}", kind),
+        }
+        match Backend::Stk500v1.kind(Some("COM3"), &link, Some(115_200)) {
+            Ok(ProgrammerKind::Stk500v1 { baud, .. }) => assert_eq!(baud, 115_200),
+            kind => panic!("unexpected {:?}", kind),
+        }
+    }
+}
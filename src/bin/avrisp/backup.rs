@@ -0,0 +1,151 @@
+//! Full chip backup archive (`.avrbk`).
+//!
+//! Archive is a JSON document. Memories are stored as hex strings, so archives can be inspected
+//! and compared with ordinary text tools.
+use crate::error::Error;
+use crate::hex;
+use crate::job::{Fuses, Job};
+use crate::memory::{self, Memory};
+use crate::program;
+use crate::report::Report;
+use avrisp::errors::ErrorKind;
+use avrisp::format::{Firmware, Image};
+use avrisp::programmer::{Confirm, Session};
+use avrisp::specs;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Archive format version. Increased on incompatible changes.
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Backup {
+    pub version: u32,
+    pub created: String,
+    /// Tool which created the archive, e.g. `avrisp 0.3.0`.
+    pub tool: String,
+    pub chip: String,
+    #[serde(with = "hex")]
+    pub signature: Vec<u8>,
+    pub fuses: Fuses,
+    pub lock: u8,
+    pub osccal: u8,
+    #[serde(with = "hex")]
+    pub flash: Vec<u8>,
+    #[serde(with = "hex")]
+    pub eeprom: Vec<u8>,
+}
+
+impl Backup {
+    /// Read everything from the chip.
+    pub fn read(isp: &mut dyn Session, chip: &specs::Specs) -> Result<Backup, Error> {
+        let (sig0, sig1, sig2) = isp.get_mcu_signature()?.bytes;
+        let fuses = isp.get_fuses()?;
+        let flash = memory::read(isp, Memory::Flash, chip.flash.size)?.to_bytes(0xff);
+        let eeprom = memory::read(isp, Memory::Eeprom, chip.eeprom.size)?.to_bytes(0xff);
+        Ok(Backup {
+            version: VERSION,
+            created: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            tool: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            chip: String::from(chip.name),
+            signature: vec![sig0, sig1, sig2],
+            fuses: Fuses::of_chip(&fuses, chip),
+            lock: isp.get_lock_byte()?,
+            osccal: isp.get_osccal()?,
+            flash,
+            eeprom,
+        })
+    }
+
+    /// Archived memories.
+    pub fn firmware(&self) -> Firmware {
+        Firmware {
+            flash: Image::from(self.flash.clone()),
+            eeprom: Image::from(self.eeprom.clone()),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let json = serde_json::to_string_pretty(self).expect("backup is always serializable");
+        fs::write(path, json + "\n")?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Backup, Error> {
+        let backup: Backup = serde_json::from_str(&fs::read_to_string(path)?)
+            .map_err(|err| Error::Usage(format!("{}: {}", path.display(), err)))?;
+        if backup.version != VERSION {
+            return Err(Error::Usage(format!(
+                "{}: unsupported backup version {}",
+                path.display(),
+                backup.version
+            )));
+        }
+        Ok(backup)
+    }
+
+    /// Write archive back to the chip: flash, EEPROM, fuses and lock byte, each verified.
+    ///
+    /// Lock byte goes last, as it may prevent further reading.
+    pub fn restore(
+        &self,
+        isp: &mut dyn Session,
+        chip: &specs::Specs,
+        report: &mut Report,
+        confirm: &mut dyn Confirm,
+    ) -> Result<(), Error> {
+        let found = match self.signature[..] {
+            [sig0, sig1, sig2] => specs::Signature::from([sig0, sig1, sig2]),
+            _ => return Err(Error::Usage(String::from("Invalid signature in backup"))),
+        };
+        if !found.matches(chip) {
+            return Err(ErrorKind::SignatureMismatch {
+                expected: chip.signature.clone(),
+                found,
+            }
+            .into());
+        }
+        let job = Job {
+            fuses: Some(self.fuses),
+            lock: Some(self.lock),
+            ..Job::default()
+        };
+        // Chip is erased anyway, skip writing erased tail.
+        let mut firmware = self.firmware();
+        firmware.flash.trim(0xff);
+        firmware.eeprom.trim(0xff);
+        // Archive holds whole flash, bootloader included.
+        program::run(isp, chip, &job, &firmware, report, true, confirm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_round_trip() {
+        let backup = Backup {
+            version: VERSION,
+            created: String::from("2020-01-01T00:00:00Z"),
+            tool: String::from("avrisp 0.3.0"),
+            chip: String::from("ATmega32"),
+            signature: vec![0x1e, 0x95, 0x02],
+            fuses: Fuses {
+                low: 0xe1,
+                high: 0x99,
+                extended: None,
+            },
+            lock: 0xff,
+            osccal: 0xa5,
+            flash: vec![0x0c, 0x94],
+            eeprom: vec![0xff],
+        };
+        let json = serde_json::to_string(&backup).unwrap();
+        assert!(json.contains(r#""signature":"1e9502""#));
+        assert_eq!(serde_json::from_str::<Backup>(&json).unwrap(), backup);
+    }
+}
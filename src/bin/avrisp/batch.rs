@@ -0,0 +1,56 @@
+use crate::error::Error;
+use std::io::{self, BufRead};
+use std::thread;
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Program boards one after another until end of input.
+///
+/// Each board is started by pressing Enter. With `detect` given, boards are instead programmed as
+/// soon as target answers, and next board is awaited after current one is disconnected.
+pub fn run<P, D>(mut program: P, mut detect: Option<D>) -> Result<(), Error>
+where
+    P: FnMut() -> Result<(), Error>,
+    D: FnMut() -> bool,
+{
+    let (mut passed, mut failed) = (0, 0);
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        match detect.as_mut() {
+            Some(detect) => {
+                eprintln!("Waiting for board. Press Ctrl-C to finish.");
+                wait_until(&mut *detect);
+            }
+            None => {
+                eprintln!("Connect board and press Enter. Press Ctrl-D to finish.");
+                if lines.next().transpose()?.is_none() {
+                    break;
+                }
+            }
+        }
+        match program() {
+            Ok(()) => {
+                passed += 1;
+                println!("#{} PASS", passed + failed);
+            }
+            Err(err) => {
+                failed += 1;
+                println!("#{} FAIL: {}", passed + failed, err);
+            }
+        }
+        println!("Passed: {} Failed: {}", passed, failed);
+        if let Some(detect) = detect.as_mut() {
+            eprintln!("Disconnect board.");
+            wait_until(|| !detect());
+        }
+    }
+    Ok(())
+}
+
+fn wait_until<F: FnMut() -> bool>(mut condition: F) {
+    while !condition() {
+        thread::sleep(POLL_INTERVAL);
+    }
+}
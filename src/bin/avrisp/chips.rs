@@ -0,0 +1,60 @@
+use avrisp::specs;
+
+/// Print table of built in chips. Only chips with name containing `filter` are listed. With
+/// `memories` memory map of each chip is printed instead.
+pub fn list(filter: Option<&str>, memories: bool) {
+    let filter = filter.unwrap_or_default().to_lowercase();
+    let chips = specs::CHIPS
+        .iter()
+        .filter(|chip| chip.name.to_lowercase().contains(&filter));
+    if memories {
+        for chip in chips {
+            println!("{}", chip.name);
+            for region in chip.memories() {
+                println!(
+                    "  {:<12} {:>8} bytes, page {:>4}, {}",
+                    region.name, region.size, region.page_size, region.access
+                );
+            }
+        }
+        return;
+    }
+    println!(
+        "{:<12} {:<16} {:>8} {:>5} {:>8} {:>5}",
+        "Name", "Signature", "Flash", "Page", "EEPROM", "Page"
+    );
+    for chip in chips {
+        println!(
+            "{:<12} {:<16} {:>8} {:>5} {:>8} {:>5}",
+            chip.name,
+            format!("{:#}", chip.signature),
+            chip.flash.size,
+            chip.flash.page_size,
+            chip.eeprom.size,
+            chip.eeprom.page_size,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readme_examples_use_built_in_chips() {
+        let readme = include_str!("../../../README.md");
+        let names = readme
+            .split("--chip ")
+            .skip(1)
+            .filter_map(|rest| rest.split_whitespace().next());
+        for name in names {
+            assert!(specs::find(name).is_some(), "unknown chip {}", name);
+        }
+        for list in readme.split("chips: [").skip(1) {
+            let list = list.split(']').next().unwrap();
+            for name in list.split(',') {
+                assert!(specs::find(name.trim()).is_some(), "unknown chip {}", name);
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Decoded fuse and lock byte settings, exported as JSON or YAML for configuration audits.
+use crate::error::Error;
+use avrisp::programmer::{AVRFuse, Session};
+use avrisp::specs::{self, Signature};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+/// Lock bit groups of ATmega chips.
+const LOCK_FIELDS: [(&str, u8); 3] = [("LB", 0x03), ("BLB0", 0x0c), ("BLB1", 0x30)];
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Format {
+    Json,
+    Yaml,
+}
+
+/// Fuse and lock byte settings of a chip.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Config {
+    pub chip: String,
+    /// Hex digits, e.g. 1e9502.
+    pub signature: String,
+    pub fuses: Vec<Byte>,
+    pub lock: Byte,
+}
+
+/// Raw byte and its named bit groups.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Byte {
+    pub name: String,
+    /// Hex, e.g. 0xd9.
+    pub value: String,
+    pub fields: Vec<Field>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub mask: String,
+    /// Field bits shifted to lowest position.
+    pub value: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meaning: Option<String>,
+}
+
+impl Config {
+    /// Decode fuses and lock byte according to `chip`.
+    pub fn new(chip: &specs::Specs, signature: &Signature, fuses: &AVRFuse, lock: u8) -> Config {
+        let (sig0, sig1, sig2) = signature.bytes;
+        let fuses = chip
+            .fuses()
+            .into_iter()
+            .map(|fuse| {
+                let byte = fuses.get(fuse);
+                let fields = chip
+                    .fuse_bits
+                    .iter()
+                    .filter(|bits| bits.fuse == fuse)
+                    .map(|bits| {
+                        let value = bits.value(byte);
+                        Field {
+                            name: bits.name.to_string(),
+                            mask: format!("{:#04x}", bits.mask),
+                            value,
+                            meaning: fuse_meaning(chip, bits, value),
+                        }
+                    })
+                    .collect();
+                Byte {
+                    name: fuse.to_string(),
+                    value: format!("{:#04x}", byte),
+                    fields,
+                }
+            })
+            .collect();
+        let lock = Byte {
+            name: String::from("lock"),
+            value: format!("{:#04x}", lock),
+            fields: LOCK_FIELDS
+                .iter()
+                .map(|&(name, mask)| {
+                    let value = (lock & mask) >> mask.trailing_zeros();
+                    Field {
+                        name: name.to_string(),
+                        mask: format!("{:#04x}", mask),
+                        value,
+                        meaning: Some(lock_meaning(name, value).to_string()),
+                    }
+                })
+                .collect(),
+        };
+        Config {
+            chip: chip.name.to_string(),
+            signature: format!("{:02x}{:02x}{:02x}", sig0, sig1, sig2),
+            fuses,
+            lock,
+        }
+    }
+
+    /// Serialized text, ending with a newline.
+    pub fn render(&self, format: Format) -> String {
+        match format {
+            Format::Json => {
+                serde_json::to_string_pretty(self).expect("config is always serializable") + "\n"
+            }
+            Format::Yaml => serde_yaml::to_string(self).expect("config is always serializable"),
+        }
+    }
+}
+
+/// Fuse bits are active low: 0 means programmed.
+fn fuse_meaning(chip: &specs::Specs, bits: &specs::FuseBits, value: u8) -> Option<String> {
+    match bits.name {
+        "BOOTRST" if value == 0 => Some(String::from("reset starts bootloader")),
+        "BOOTRST" => Some(String::from("reset starts application")),
+        "BOOTSZ" => chip
+            .boot_section(value)
+            .map(|section| format!("boot section {:#06x}..{:#06x}", section.start, section.end)),
+        _ if bits.mask.count_ones() == 1 => Some(String::from(if value == 0 {
+            "programmed"
+        } else {
+            "unprogrammed"
+        })),
+        _ => None,
+    }
+}
+
+fn lock_meaning(name: &str, value: u8) -> &'static str {
+    match (name, value) {
+        ("LB", 3) => "no memory lock",
+        ("LB", 2) => "further programming disabled",
+        ("LB", 0) => "programming and verification disabled",
+        ("LB", _) => "reserved",
+        (_, 3) => "no restrictions",
+        (_, 2) => "SPM can not write the section",
+        (_, 0) => "SPM can not write, LPM can not read the section",
+        _ => "LPM can not read the section",
+    }
+}
+
+/// Read fuses and lock byte and print or save them in `format`.
+pub fn export(
+    isp: &mut dyn Session,
+    chip: &specs::Specs,
+    format: Format,
+    output: Option<&Path>,
+) -> Result<(), Error> {
+    let signature = isp.get_mcu_signature()?;
+    // Decode according to detected chip if possible.
+    let chip = specs::find_by_signature(&signature).unwrap_or(chip);
+    let config = Config::new(chip, &signature, &isp.get_fuses()?, isp.get_lock_byte()?);
+    let text = config.render(format);
+    match output {
+        Some(path) => fs::write(path, text)?,
+        None => print!("{}", text),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use avrisp::specs::atmega;
+
+    #[test]
+    fn decodes_fields() {
+        let chip = &atmega::ATMEGA_32;
+        let fuses = AVRFuse::new(0xe1, 0x98, 0xff);
+        let config = Config::new(chip, &chip.signature, &fuses, 0x3c);
+        assert_eq!(config.signature, "1e9502");
+        let high = &config.fuses[1];
+        assert_eq!(high.value, "0x98");
+        let field = |name: &str| high.fields.iter().find(|field| field.name == name).unwrap();
+        assert_eq!(
+            field("BOOTRST").meaning.as_deref(),
+            Some("reset starts bootloader")
+        );
+        assert_eq!(field("SPIEN").meaning.as_deref(), Some("programmed"));
+        assert_eq!(
+            config.lock.fields[0].meaning.as_deref(),
+            Some("programming and verification disabled")
+        );
+        let yaml = config.render(Format::Yaml);
+        assert!(yaml.contains("chip: ATmega32"), "{}", yaml);
+    }
+}
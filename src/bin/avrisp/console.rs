@@ -0,0 +1,111 @@
+use crate::error::Error;
+use avrisp::errors::ErrorKind;
+use clap::Args;
+use serial::core::{PortSettings, SerialPort};
+use std::io::{self, BufRead, Read, Write};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_BAUD: usize = 9600;
+
+#[derive(Args, Clone)]
+pub struct ConsoleOptions {
+    /// Baud rate of serial console.
+    #[arg(long, global = true, default_value_t = DEFAULT_BAUD)]
+    pub monitor_baud: usize,
+    /// Prefix each received line with time since console was opened.
+    #[arg(long, global = true)]
+    pub timestamps: bool,
+}
+
+/// Options for opening serial console after programming. Also console options of `monitor`
+/// command.
+#[derive(Args, Clone)]
+pub struct MonitorArgs {
+    /// Open serial console after programming. Programmer port is used if PORT is not given.
+    /// Use as `--monitor=PORT`.
+    #[arg(
+        long,
+        global = true,
+        value_name = "PORT",
+        num_args = 0..=1,
+        require_equals = true
+    )]
+    pub monitor: Option<Option<String>>,
+    #[command(flatten)]
+    pub options: ConsoleOptions,
+}
+
+impl MonitorArgs {
+    /// Console port if monitor was requested.
+    pub fn port(&self, programmer: Option<&String>) -> Option<String> {
+        match &self.monitor {
+            Some(Some(port)) => Some(port.clone()),
+            Some(None) => programmer.cloned(),
+            None => None,
+        }
+    }
+}
+
+/// Simple serial console.
+///
+/// Received bytes are printed to stdout. Lines typed on stdin are sent to the port.
+/// End of input (Ctrl-D) closes the console.
+pub fn run(port: &str, options: &ConsoleOptions) -> Result<(), Error> {
+    let mut port = serial::open(port)?;
+    port.configure(&PortSettings {
+        baud_rate: serial::BaudRate::from_speed(options.monitor_baud),
+        char_size: serial::Bits8,
+        parity: serial::ParityNone,
+        stop_bits: serial::Stop1,
+        flow_control: serial::FlowNone,
+    })?;
+    port.set_timeout(Duration::from_millis(100))?;
+    eprintln!("Console opened. Press Ctrl-D to exit.");
+    let input = spawn_stdin_reader();
+    let start = Instant::now();
+    let mut line_start = true;
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut buffer = [0; 256];
+    loop {
+        match input.try_recv() {
+            Ok(line) => port.write_all(&line).map_err(ErrorKind::Io)?,
+            Err(mpsc::TryRecvError::Disconnected) => return Ok(()),
+            Err(mpsc::TryRecvError::Empty) => (),
+        }
+        let size = match port.read(&mut buffer) {
+            Ok(size) => size,
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => continue,
+            Err(err) => return Err(ErrorKind::Io(err).into()),
+        };
+        for &byte in &buffer[..size] {
+            if options.timestamps && line_start {
+                write!(stdout, "[{:>10.3}] ", start.elapsed().as_secs_f64())?;
+            }
+            stdout.write_all(&[byte])?;
+            line_start = byte == b'\n';
+        }
+        stdout.flush()?;
+    }
+}
+
+/// Read stdin lines in background. Channel is closed at end of input.
+fn spawn_stdin_reader() -> mpsc::Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().split(b'\n') {
+            let mut line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            line.push(b'\n');
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
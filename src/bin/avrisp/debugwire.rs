@@ -0,0 +1,98 @@
+//! Guided recovery of chips with debugWIRE enabled.
+//!
+//! With DWEN fuse programmed, reset pin is used by debugWIRE after next power cycle and ISP stops
+//! working. STK500v2 programmers can not speak debugWIRE, so the chip can be recovered only while
+//! ISP still answers, i.e. before it was power cycled. Otherwise debugWIRE capable hardware must
+//! send the disable command.
+use crate::error::Error;
+use avrisp::programmer::{self, AVRFuse, Session};
+use avrisp::specs::{self, Fuse, FuseBits};
+use std::io::{self, BufRead};
+
+const NO_ISP: &str = "Target does not answer ISP. If debugWIRE is active, disable it with \
+debugWIRE capable hardware (e.g. JTAGICE mkII, AVR Dragon, Atmel-ICE) first";
+
+/// DWEN fuse bit of `chip`.
+pub fn dwen(chip: &specs::Specs) -> Result<&'static FuseBits, Error> {
+    chip.fuse_bits
+        .iter()
+        .find(|bits| bits.name == "DWEN")
+        .ok_or_else(|| Error::Usage(format!("{} has no debugWIRE", chip.name)))
+}
+
+/// Clear DWEN fuse bit. Returns false if it was already cleared.
+pub fn disable(isp: &mut dyn Session, chip: &specs::Specs) -> Result<bool, Error> {
+    let bits = dwen(chip)?;
+    let fuses = isp.get_fuses()?;
+    // Fuse bits are active low.
+    if bits.value(fuses.get(bits.fuse)) != 0 {
+        return Ok(false);
+    }
+    let value = fuses.get(bits.fuse) | bits.mask;
+    let fuses = AVRFuse::new(
+        if bits.fuse == Fuse::Low {
+            value
+        } else {
+            fuses.get(Fuse::Low)
+        },
+        if bits.fuse == Fuse::High {
+            value
+        } else {
+            fuses.get(Fuse::High)
+        },
+        if bits.fuse == Fuse::Extended {
+            value
+        } else {
+            fuses.get(Fuse::Extended)
+        },
+    );
+    // Fails if fuse read back differs.
+    programmer::write_fuses(isp, chip, &fuses, None)?;
+    Ok(true)
+}
+
+/// Guide user through recovery. `connect` runs given action with target in programming mode.
+pub fn recover<C>(mut connect: C) -> Result<(), Error>
+where
+    C: FnMut(
+        &mut dyn FnMut(&mut dyn Session, &specs::Specs) -> Result<(), Error>,
+    ) -> Result<(), Error>,
+{
+    let mut cleared = false;
+    connect(&mut |isp, chip| {
+        cleared = disable(isp, chip)?;
+        Ok(())
+    })
+    .map_err(|err| match err {
+        Error::Usage(_) | Error::Verify(_) => err,
+        err => Error::Usage(format!("{}: {}", NO_ISP, err)),
+    })?;
+    if !cleared {
+        println!("debugWIRE is already disabled.");
+        return Ok(());
+    }
+    println!("DWEN cleared. Power-cycle the target and press Enter.");
+    io::stdin().lock().read_line(&mut String::new())?;
+    connect(&mut |isp, chip| {
+        let bits = dwen(chip)?;
+        if bits.value(isp.get_fuses()?.get(bits.fuse)) == 0 {
+            return Err(Error::Verify(String::from("DWEN is still programmed")));
+        }
+        Ok(())
+    })?;
+    println!("ISP works again.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use avrisp::specs::atmega;
+
+    #[test]
+    fn dwen_of_debugwire_chips() {
+        let bits = dwen(&atmega::ATMEGA_328P).unwrap();
+        assert_eq!((bits.fuse, bits.mask), (Fuse::High, 0x40));
+        assert!(dwen(&atmega::ATMEGA_32).is_err());
+    }
+}
@@ -0,0 +1,42 @@
+//! Offline comparison of two firmware images.
+use crate::backup::Backup;
+use crate::error::Error;
+use avrisp::format::{self, Firmware};
+use std::path::Path;
+
+/// Print address ranges which differ between images. Backup archives can be compared too. Memories of different length are compared
+/// as if the shorter one was padded with erased bytes.
+pub fn run(a: &Path, b: &Path) -> Result<(), Error> {
+    let (a, b) = (load(a)?, load(b)?);
+    let mut differ = false;
+    for (name, a, b) in [
+        ("flash", &a.flash, &b.flash),
+        ("eeprom", &a.eeprom, &b.eeprom),
+    ]
+    .iter()
+    {
+        for range in a.diff(b) {
+            differ = true;
+            println!(
+                "{:<6} {:#06x}..{:#06x} ({} bytes)",
+                name,
+                range.start,
+                range.end,
+                range.len()
+            );
+        }
+    }
+    if differ {
+        return Err(Error::Verify(String::from("Images differ")));
+    }
+    println!("Images are identical");
+    Ok(())
+}
+
+/// Load firmware file or backup archive (`.avrbk`).
+fn load(path: &Path) -> Result<Firmware, Error> {
+    if path.extension().is_some_and(|ext| ext == "avrbk") {
+        return Ok(Backup::load(path)?.firmware());
+    }
+    Ok(format::load(path)?)
+}
@@ -0,0 +1,195 @@
+//! Step by step connection check of STK500v2 programmer and its target, with hints on failure.
+use crate::error::Error;
+use avrisp::errors::ErrorKind;
+use avrisp::programmer::stk500v2::{self, IspMode, LinkOptions, STK500v2};
+use avrisp::programmer::MCUSignature;
+use avrisp::specs::{self, Signature};
+use std::convert::TryInto;
+use std::fmt::Display;
+
+/// `SckDuration` for ISP entry, about 28 kHz on STK500. Works with targets running at 128 kHz.
+const SLOW_SCK: u8 = 10;
+/// Below this AVR chips do not run, in tenths of volt.
+const MIN_VTARGET: u8 = 18;
+
+/// Run checks in order and stop at the first failing one, which error is returned.
+pub fn run(port: &str, link: &LinkOptions, chip: &specs::Specs) -> Result<(), Error> {
+    check(
+        &format!("Open port {}", port),
+        serial::open(port).map(|_| ((), "ok")).map_err(Error::from),
+        "Port does not exist or is used by another program. See `avrisp ports`",
+    )?;
+    let mut stk = check(
+        "Sign on",
+        STK500v2::open_with(port, link, chip.clone())
+            .map(|stk| (stk, "ok"))
+            .map_err(Error::from),
+        "No answer. Check that this is the programmer port, try other --baud or reset the \
+         programmer",
+    )?;
+    check(
+        "Firmware versions",
+        versions(&mut stk)
+            .map(|versions| ((), versions))
+            .map_err(Error::from),
+        "Programmer answers sign on only. Its firmware may not be STK500v2 compatible",
+    )?;
+    check_vtarget(&mut stk)?;
+    // Not every programmer can change SCK.
+    let initial_sck = stk.get_sck_duration().ok();
+    if let Some(initial) = initial_sck {
+        stk.set_sck_duration(SLOW_SCK.max(initial))?;
+    }
+    let entered: Result<IspMode, ErrorKind> = stk.try_into();
+    // Target which never answered gives up with signature read, which tells the cause.
+    if let Err(ErrorKind::SignatureMismatch { found, .. }) = &entered {
+        if let Some(hint) = signature_hint(found, chip) {
+            return Err(fail("Signature", found, &hint));
+        }
+    }
+    let mut isp = check(
+        &format!(
+            "ISP entry at {} kHz SCK",
+            stk500v2::sck_frequency(SLOW_SCK) / 1000
+        ),
+        entered.map(|isp| (isp, "ok")).map_err(Error::from),
+        "Target does not answer programming enable. Check wiring (MISO, MOSI, SCK, RESET, GND), \
+         that RESET is not disabled by fuse and that target has a clock",
+    )?;
+    let signature = isp.get_mcu_signature();
+    let mut stk = isp.leave()?;
+    if let Some(initial) = initial_sck {
+        stk.set_sck_duration(initial)?;
+    }
+    let signature = signature?;
+    let hint = match signature_hint(&signature, chip) {
+        Some(hint) => hint,
+        None => {
+            pass("Signature", &signature);
+            return Ok(());
+        }
+    };
+    let unreachable = fail("Signature", &signature, &hint);
+    match signature.bytes {
+        (0, 0, 0) | (0xff, 0xff, 0xff) => Err(unreachable),
+        _ => Err(ErrorKind::SignatureMismatch {
+            expected: chip.signature.clone(),
+            found: signature,
+        }
+        .into()),
+    }
+}
+
+fn versions(stk: &mut STK500v2) -> Result<String, ErrorKind> {
+    Ok(format!(
+        "{}, hardware {}, firmware {}",
+        stk.read_programmer_signature()?,
+        stk.get_hw_version()?,
+        stk.get_sw_version()?
+    ))
+}
+
+pub fn pass(step: &str, detail: impl Display) {
+    println!("[PASS] {}: {}", step, detail);
+}
+
+/// Print failed step with a hint.
+pub fn report_fail(step: &str, detail: impl Display, hint: &str) {
+    println!("[FAIL] {}: {}", step, detail);
+    println!("       hint: {}", hint);
+}
+
+/// Print failed step. Returned error tells that target is not reachable.
+pub fn fail(step: &str, detail: impl Display, hint: &str) -> Error {
+    report_fail(step, detail, hint);
+    Error::Check(format!("{} check failed", step))
+}
+
+/// Check that target is powered, if programmer measures it.
+pub fn check_vtarget(stk: &mut STK500v2) -> Result<(), Error> {
+    let parameters = match stk.dump_parameters() {
+        Ok(parameters) => parameters,
+        Err(err) => {
+            report_fail(
+                "Target voltage",
+                &err,
+                "Programmer does not answer parameter reads. Its firmware may not be STK500v2 \
+                 compatible",
+            );
+            return Err(err.into());
+        }
+    };
+    match parameters.vtarget {
+        Some(tenths) => {
+            let volts = format!("{:.1} V", tenths as f32 / 10.0);
+            if let Some(hint) = vtarget_hint(tenths) {
+                return Err(fail("Target voltage", &volts, &hint));
+            }
+            pass("Target voltage", &volts);
+        }
+        None => pass("Target voltage", "not measured by this programmer"),
+    }
+    Ok(())
+}
+
+/// Print outcome of a step and pass its value on.
+fn check<T, D: Display>(step: &str, result: Result<(T, D), Error>, hint: &str) -> Result<T, Error> {
+    match result {
+        Ok((value, detail)) => {
+            pass(step, detail);
+            Ok(value)
+        }
+        Err(err) => {
+            report_fail(step, &err, hint);
+            Err(err)
+        }
+    }
+}
+
+fn vtarget_hint(tenths: u8) -> Option<String> {
+    match tenths {
+        0 => Some(String::from(
+            "Vtarget is 0.0 V: target not powered or VCC pin of ISP header not connected",
+        )),
+        tenths if tenths < MIN_VTARGET => Some(format!(
+            "Vtarget is {:.1} V: target supply is too low or overloaded",
+            tenths as f32 / 10.0
+        )),
+        _ => None,
+    }
+}
+
+fn signature_hint(signature: &Signature, chip: &specs::Specs) -> Option<String> {
+    match signature.bytes {
+        (0xff, 0xff, 0xff) => Some(String::from(
+            "All ones: MISO not connected, target has no clock or SCK is still too fast",
+        )),
+        (0, 0, 0) => Some(String::from(
+            "All zeros: MISO shorted to GND or target held in reset",
+        )),
+        _ if !signature.matches(chip) => Some(format!(
+            "Target is not {}. Use --chip of the connected chip",
+            chip.name
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use avrisp::specs::atmega;
+
+    #[test]
+    fn hints() {
+        assert!(vtarget_hint(0).unwrap().contains("not powered"));
+        assert!(vtarget_hint(12).is_some());
+        assert_eq!(vtarget_hint(50), None);
+        let chip = &atmega::ATMEGA_32;
+        assert!(signature_hint(&Signature::from([0xff; 3]), chip).is_some());
+        assert!(signature_hint(&atmega::ATMEGA_2560.signature, chip)
+            .unwrap()
+            .contains("ATmega32"));
+        assert_eq!(signature_hint(&chip.signature, chip), None);
+    }
+}
@@ -0,0 +1,123 @@
+use avrisp::errors::ErrorKind;
+use std::fmt;
+use std::io;
+use std::process;
+
+/// Process exit codes.
+///
+/// Scripts rely on them, so existing values must never change.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitCode {
+    Success = 0,
+    /// Anything not covered below. E.g. output file can not be written.
+    Failure = 1,
+    /// Invalid command line arguments. Same value as used by clap.
+    Usage = 2,
+    /// Port can not be opened or programmer / target does not respond.
+    Connection = 3,
+    /// Connected chip is not the requested one.
+    SignatureMismatch = 4,
+    /// Memory content differs from the file.
+    VerifyFailed = 5,
+    /// Refused to write fuses which could make chip unusable.
+    FuseRefused = 6,
+}
+
+impl From<ExitCode> for process::ExitCode {
+    fn from(code: ExitCode) -> process::ExitCode {
+        process::ExitCode::from(code as u8)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Usage(String),
+    Open(serial::Error),
+    File(io::Error),
+    Programmer(ErrorKind),
+    /// Value read back after writing differs. E.g. fuses.
+    Verify(String),
+    /// Connected chip is none of those the job accepts.
+    UnexpectedChip(String),
+    /// Step of `doctor` or `rescue` failed. Details and hint are printed with the step.
+    Check(String),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            Error::Usage(_) => ExitCode::Usage,
+            Error::Open(_) => ExitCode::Connection,
+            Error::File(_) => ExitCode::Failure,
+            Error::Verify(_) => ExitCode::VerifyFailed,
+            Error::UnexpectedChip(_) => ExitCode::SignatureMismatch,
+            Error::Check(_) => ExitCode::Connection,
+            Error::Programmer(kind) => match kind {
+                ErrorKind::AnswerIdError
+                | ErrorKind::StatusError
+                | ErrorKind::SequenceError
+                | ErrorKind::ChecksumError
+                | ErrorKind::AnswerLength { .. }
+                | ErrorKind::MalformedFrame
+                | ErrorKind::Io(_)
+                | ErrorKind::Timeout { .. }
+                | ErrorKind::TargetVoltage { .. }
+                | ErrorKind::UnknownProgrammer => ExitCode::Connection,
+                ErrorKind::SignatureMismatch { .. } => ExitCode::SignatureMismatch,
+                ErrorKind::VerifyError { .. }
+                | ErrorKind::FuseVerifyError { .. }
+                | ErrorKind::LockVerifyError { .. }
+                | ErrorKind::NotLocked { .. } => ExitCode::VerifyFailed,
+                ErrorKind::UnsafeFuses | ErrorKind::ReservedFuseBits { .. } => {
+                    ExitCode::FuseRefused
+                }
+                ErrorKind::AddressOutOfRange { .. } | ErrorKind::InvalidRange { .. } => {
+                    ExitCode::Usage
+                }
+                ErrorKind::FromUtf8Error
+                | ErrorKind::FileFormat(_)
+                | ErrorKind::NotErased { .. }
+                | ErrorKind::Unsupported(_)
+                | ErrorKind::BootSection { .. }
+                | ErrorKind::LockNeedsErase { .. }
+                | ErrorKind::PageWriteFailed { .. }
+                | ErrorKind::Declined(_)
+                | ErrorKind::BodyTooLarge { .. } => ExitCode::Failure,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Usage(msg)
+            | Error::Verify(msg)
+            | Error::UnexpectedChip(msg)
+            | Error::Check(msg) => {
+                write!(f, "{}", msg)
+            }
+            Error::Open(err) => write!(f, "Can not open port: {}", err),
+            Error::File(err) => write!(f, "{}", err),
+            Error::Programmer(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<serial::Error> for Error {
+    fn from(err: serial::Error) -> Error {
+        Error::Open(err)
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(err: ErrorKind) -> Error {
+        Error::Programmer(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::File(err)
+    }
+}
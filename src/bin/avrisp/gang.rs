@@ -0,0 +1,45 @@
+use crate::error::Error;
+use std::thread;
+
+/// Program boards concurrently, one thread per programmer port.
+///
+/// Progress of each port is printed as it happens, followed by a summary. First error in port
+/// order is returned.
+pub fn run<T, F>(boards: Vec<(String, T)>, program: F) -> Result<(), Error>
+where
+    T: Send,
+    F: Fn(&str, T) -> Result<(), Error> + Sync,
+{
+    let program = &program;
+    let results: Vec<(String, Result<(), Error>)> = thread::scope(|scope| {
+        let handles: Vec<_> = boards
+            .into_iter()
+            .map(|(port, data)| {
+                scope.spawn(move || {
+                    eprintln!("{}: programming", port);
+                    let result = program(&port, data);
+                    match &result {
+                        Ok(()) => eprintln!("{}: PASS", port),
+                        Err(err) => eprintln!("{}: FAIL: {}", port, err),
+                    }
+                    (port, result)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("programming thread panicked"))
+            .collect()
+    });
+    let passed = results.iter().filter(|(_, result)| result.is_ok()).count();
+    println!("{:<20} Result", "Port");
+    for (port, result) in &results {
+        let status = match result {
+            Ok(()) => String::from("PASS"),
+            Err(err) => format!("FAIL: {}", err),
+        };
+        println!("{:<20} {}", port, status);
+    }
+    println!("Passed: {} Failed: {}", passed, results.len() - passed);
+    results.into_iter().try_for_each(|(_, result)| result)
+}
@@ -0,0 +1,93 @@
+//! Hex text helpers.
+//!
+//! Can be used as `#[serde(with = "hex")]` to store bytes as hex string.
+use serde::{Deserialize, Deserializer, Serializer};
+use std::ops::Range;
+
+/// Parse single hex byte, with or without `0x` prefix.
+pub fn parse_byte(value: &str) -> Result<u8, String> {
+    let digits = value.trim_start_matches("0x").trim_start_matches("0X");
+    u8::from_str_radix(digits, 16).map_err(|_| format!("Invalid hex byte: {}", value))
+}
+
+/// Parse address. Hex with `0x` prefix or decimal.
+pub fn parse_address(value: &str) -> Result<usize, String> {
+    let parsed = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(digits) => usize::from_str_radix(digits, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|_| format!("Invalid address: {}", value))
+}
+
+/// Parse `START..END` address range, end excluded.
+pub fn parse_range(value: &str) -> Result<Range<usize>, String> {
+    let (start, end) = value
+        .split_once("..")
+        .ok_or_else(|| format!("Invalid range: {}. Use START..END", value))?;
+    let range = parse_address(start)?..parse_address(end)?;
+    if range.is_empty() {
+        return Err(format!("Empty range: {}", value));
+    }
+    Ok(range)
+}
+
+/// Lowercase hex digits without separators.
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Parse hex digits. Bytes may be separated with `:`, `-` or spaces.
+pub fn decode(text: &str) -> Result<Vec<u8>, String> {
+    let digits: String = text
+        .chars()
+        .filter(|c| !matches!(c, ':' | '-' | ' '))
+        .collect();
+    if !digits.is_ascii() || !digits.len().is_multiple_of(2) {
+        return Err(format!("Invalid hex value: {}", text));
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| parse_byte(&digits[i..i + 2]))
+        .collect()
+}
+
+pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&encode(bytes))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    decode(&text).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        assert_eq!(encode(&[0x00, 0x1a, 0xff]), "001aff");
+        assert_eq!(decode("001aff").unwrap(), vec![0x00, 0x1a, 0xff]);
+        assert_eq!(decode("00:1A-ff").unwrap(), vec![0x00, 0x1a, 0xff]);
+        assert!(decode("0").is_err());
+        assert!(decode("zz").is_err());
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(parse_address("0x1FFE").unwrap(), 0x1ffe);
+        assert_eq!(parse_address("256").unwrap(), 256);
+        assert!(parse_address("1FFE").is_err());
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("0..256").unwrap(), 0..256);
+        assert_eq!(parse_range("0x10..0x20").unwrap(), 0x10..0x20);
+        assert!(parse_range("16").is_err());
+        assert!(parse_range("16..16").is_err());
+    }
+}
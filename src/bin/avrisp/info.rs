@@ -0,0 +1,65 @@
+use crate::error::Error;
+use avrisp::programmer::{ChipStatus, Session};
+use avrisp::specs;
+use clap::ValueEnum;
+
+/// Part of the report printed by `info` command.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Section {
+    Programmer,
+    Signature,
+    Fuses,
+    Lock,
+    Osccal,
+}
+
+/// Print chip and programmer report. Empty `sections` means all of them.
+pub fn report(
+    isp: &mut dyn Session,
+    chip: &specs::Specs,
+    sections: &[Section],
+) -> Result<(), Error> {
+    let selected = |section| sections.is_empty() || sections.contains(&section);
+    if selected(Section::Programmer) {
+        println!("Programmer: {}", isp.identify()?);
+    }
+    let chip_sections = [
+        Section::Signature,
+        Section::Fuses,
+        Section::Lock,
+        Section::Osccal,
+    ];
+    if !chip_sections.iter().any(|&section| selected(section)) {
+        return Ok(());
+    }
+    let status = ChipStatus::read(isp)?;
+    // Decode fuses according to detected chip if possible.
+    let chip = specs::find_by_signature(&status.signature).unwrap_or(chip);
+    if selected(Section::Signature) {
+        println!("Signature:  {}", status.signature);
+    }
+    if selected(Section::Fuses) {
+        let fuses = &status.fuses;
+        for fuse in chip.fuses() {
+            println!("Fuse {:<9} {:#04x}", format!("{}:", fuse), fuses.get(fuse));
+            for bits in chip.fuse_bits.iter().filter(|bits| bits.fuse == fuse) {
+                println!("    {:<10} {:#x}", bits.name, bits.value(fuses.get(fuse)));
+            }
+        }
+    }
+    if selected(Section::Lock) {
+        println!("Lock byte:  {:#04x}", status.lock);
+    }
+    if selected(Section::Osccal) {
+        match status.osccal {
+            Some(osccal) => println!("OSCCAL:     {:#04x}", osccal),
+            None => println!("OSCCAL:     not readable"),
+        }
+    }
+    if sections.is_empty() {
+        if let Some(present) = status.bootloader_present {
+            println!("Bootloader: {}", if present { "yes" } else { "no" });
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,179 @@
+//! Per-device data written on top of firmware, e.g. serial numbers or calibration.
+//!
+//! ```yaml
+//! inject:
+//!   - { memory: eeprom, address: 0x00, source: counter, counter: serial.txt, size: 4 }
+//!   - { memory: eeprom, address: 0x04, source: uuid }
+//!   - { memory: eeprom, address: 0x14, source: csv, file: macs.csv, column: mac, counter: macs.next }
+//! ```
+//!
+//! Counter files hold a decimal number. Counters are incremented only after successful job, so
+//! failed boards do not consume values.
+use crate::error::Error;
+use crate::hex;
+use crate::memory::Memory;
+use avrisp::format::Firmware;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "source", rename_all = "lowercase")]
+pub enum Source {
+    /// Counter value as little endian number of `size` bytes.
+    Counter {
+        counter: PathBuf,
+        #[serde(default = "default_size")]
+        size: usize,
+    },
+    /// Random UUID, 16 bytes.
+    Uuid,
+    /// Hex bytes from `column`. Row is selected by counter, first row after header is 0.
+    Csv {
+        file: PathBuf,
+        column: String,
+        counter: PathBuf,
+    },
+}
+
+fn default_size() -> usize {
+    4
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct Injection {
+    pub memory: Memory,
+    pub address: usize,
+    #[serde(flatten)]
+    pub source: Source,
+}
+
+impl Injection {
+    /// Make file paths relative to `dir`.
+    pub fn resolve(&mut self, dir: &Path) {
+        match &mut self.source {
+            Source::Counter { counter, .. } => *counter = dir.join(&counter),
+            Source::Uuid => (),
+            Source::Csv { file, counter, .. } => {
+                *file = dir.join(&file);
+                *counter = dir.join(&counter);
+            }
+        }
+    }
+
+    /// Bytes for current device.
+    pub fn data(&self) -> Result<Vec<u8>, Error> {
+        match &self.source {
+            Source::Counter { counter, size } => {
+                let value = read_counter(counter)?;
+                let bytes = value.to_le_bytes();
+                if *size > bytes.len() || bytes[*size..].iter().any(|&byte| byte != 0) {
+                    return Err(Error::Usage(format!(
+                        "Counter {} does not fit in {} bytes",
+                        value, size
+                    )));
+                }
+                Ok(bytes[..*size].to_vec())
+            }
+            Source::Uuid => Ok(uuid::Uuid::new_v4().as_bytes().to_vec()),
+            Source::Csv {
+                file,
+                column,
+                counter,
+            } => {
+                let row = read_counter(counter)?;
+                csv_value(&fs::read_to_string(file)?, column, row as usize)
+            }
+        }
+    }
+
+    /// Move to the next value after device was programmed.
+    pub fn commit(&self) -> Result<(), Error> {
+        match &self.source {
+            Source::Counter { counter, .. } | Source::Csv { counter, .. } => {
+                let value = read_counter(counter)?;
+                fs::write(counter, format!("{}\n", value + 1))?;
+            }
+            Source::Uuid => (),
+        }
+        Ok(())
+    }
+}
+
+fn read_counter(path: &Path) -> Result<u64, Error> {
+    let content = fs::read_to_string(path)?;
+    content
+        .trim()
+        .parse()
+        .map_err(|_| Error::Usage(format!("{}: invalid counter value", path.display())))
+}
+
+/// Hex bytes from given row and column.
+fn csv_value(content: &str, column: &str, row: usize) -> Result<Vec<u8>, Error> {
+    let invalid = |msg: String| Error::Usage(format!("CSV: {}", msg));
+    let mut reader = csv::Reader::from_reader(content.as_bytes());
+    let index = reader
+        .headers()
+        .map_err(|err| invalid(err.to_string()))?
+        .iter()
+        .position(|name| name == column)
+        .ok_or_else(|| invalid(format!("no column {}", column)))?;
+    let record = reader
+        .records()
+        .nth(row)
+        .ok_or_else(|| invalid(format!("no row {}. All values used", row)))?
+        .map_err(|err| invalid(err.to_string()))?;
+    let value = hex::decode(&record[index]).map_err(invalid)?;
+    if value.is_empty() {
+        return Err(invalid(String::from("empty value")));
+    }
+    Ok(value)
+}
+
+/// Write `data` at `address`, over firmware data already there.
+pub fn patch(firmware: &mut Firmware, memory: Memory, address: usize, data: &[u8]) {
+    memory.image(firmware).insert(address, data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use avrisp::format::Image;
+
+    #[test]
+    fn patch_extends_memory() {
+        let mut firmware = Firmware {
+            flash: Image::new(),
+            eeprom: Image::from(vec![1]),
+        };
+        patch(&mut firmware, Memory::Eeprom, 2, &[3, 4]);
+        assert_eq!(firmware.eeprom.to_bytes(0xff), [1, 0xff, 3, 4]);
+        assert!(firmware.flash.is_empty());
+    }
+
+    #[test]
+    fn csv_row_and_column() {
+        let csv = "serial,mac\n1,00:1a:2b:3c:4d:5e\n2,001A2B3C4D5F\n";
+        let value = csv_value(csv, "mac", 1).unwrap();
+        assert_eq!(value, vec![0x00, 0x1a, 0x2b, 0x3c, 0x4d, 0x5f]);
+        assert!(csv_value(csv, "mac", 2).is_err());
+        assert!(csv_value(csv, "ip", 0).is_err());
+    }
+
+    #[test]
+    fn deserialize_sources() {
+        let injection: Injection =
+            serde_yaml::from_str("{ memory: flash, address: 16, source: uuid }").unwrap();
+        assert_eq!(injection.source, Source::Uuid);
+        let injection: Injection =
+            serde_yaml::from_str("{ memory: eeprom, address: 0, source: counter, counter: n }")
+                .unwrap();
+        assert_eq!(
+            injection.source,
+            Source::Counter {
+                counter: PathBuf::from("n"),
+                size: 4
+            }
+        );
+    }
+}
@@ -0,0 +1,472 @@
+//! Programming jobs.
+//!
+//! Job is either given on command line (`program`, `batch`) or read from a manifest file
+//! (`run`). Manifest is YAML (`.yaml`, `.yml`) or TOML (`.toml`):
+//!
+//! ```yaml
+//! chip: atmega32                # or chips: [atmega32, atmega328p]
+//! flash: firmware.hex
+//! eeprom: eeprom.hex
+//! fuses: { low: 0xe1, high: 0x99 }
+//! lock: 0x3c
+//! verify: true
+//! preserve_eeprom: false
+//! fill: 0x00                    # program gaps between records, left erased if missing
+//! reject_overlap: true
+//! secure: false                 # confirm that locked chip can not be read back
+//! keep_going: false             # write remaining memories after a failed one
+//! osccal: { memory: eeprom, address: 0x1ff }
+//! steps: [erase, flash, eeprom, osccal, fuses, lock]
+//! ```
+//!
+//! Per-device data can be added with `inject`, see [`crate::inject`].
+//!
+//! Relative paths are resolved against manifest directory.
+use crate::error::Error;
+use crate::hex::{parse_address, parse_byte};
+use crate::inject::{self, Injection};
+use crate::memory::{self, Memory};
+use avrisp::format::{self, FillOptions, Firmware, Gaps};
+use avrisp::programmer::AVRFuse;
+use avrisp::specs::{self, Fuse};
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Job given on command line.
+#[derive(Args, Clone)]
+pub struct JobArgs {
+    /// Firmware file with flash and EEPROM content.
+    pub file: PathBuf,
+    /// Write fuses after memories. Hex bytes LOW:HIGH[:EXTENDED], e.g. e1:99.
+    #[arg(long, value_name = "FUSES", value_parser = |value: &str| value.parse::<AVRFuse>())]
+    pub fuses: Option<AVRFuse>,
+    /// Write lock byte as the last step, e.g. 0x3c.
+    #[arg(long, value_name = "BYTE", value_parser = parse_byte)]
+    pub lock: Option<u8>,
+    /// Keep EEPROM content across chip erase, also when EESAVE fuse is not programmed.
+    #[arg(long)]
+    pub preserve_eeprom: bool,
+    /// Store oscillator calibration byte at MEMORY:ADDRESS, e.g. eeprom:0x1ff.
+    #[arg(long, value_name = "LOCATION", value_parser = parse_osccal)]
+    pub osccal: Option<Osccal>,
+    /// Program gaps between records of HEX and ELF files with BYTE. Gaps are left erased
+    /// otherwise.
+    #[arg(long, value_name = "BYTE", value_parser = parse_byte)]
+    pub fill: Option<u8>,
+    /// Fail when records of HEX or ELF files overlap.
+    #[arg(long)]
+    pub reject_overlap: bool,
+    /// Release build: after lock byte is written, check that memories can no longer be read
+    /// back. Needs --lock disabling verification, e.g. 0x3c.
+    #[arg(long, requires = "lock")]
+    pub secure: bool,
+    /// Continue with remaining memories when writing or verifying one fails, to collect all
+    /// failures in the report. Fuses and lock byte are not written then.
+    #[arg(long)]
+    pub keep_going: bool,
+}
+
+impl JobArgs {
+    pub fn new(file: PathBuf) -> JobArgs {
+        JobArgs {
+            file,
+            fuses: None,
+            lock: None,
+            preserve_eeprom: false,
+            osccal: None,
+            fill: None,
+            reject_overlap: false,
+            secure: false,
+            keep_going: false,
+        }
+    }
+}
+
+/// Single operation of a job.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Step {
+    Erase,
+    Flash,
+    Eeprom,
+    Osccal,
+    Fuses,
+    Lock,
+}
+
+impl Step {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Step::Erase => "erase",
+            Step::Flash => "flash",
+            Step::Eeprom => "eeprom",
+            Step::Osccal => "osccal",
+            Step::Fuses => "fuses",
+            Step::Lock => "lock",
+        }
+    }
+
+    /// Step writes flash or EEPROM, which chip erase would clear.
+    pub fn writes_memory(&self) -> bool {
+        matches!(self, Step::Flash | Step::Eeprom | Step::Osccal)
+    }
+}
+
+/// Where firmware expects oscillator calibration byte.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Osccal {
+    pub memory: Memory,
+    pub address: usize,
+}
+
+/// Fuse values in manifest. Missing extended fuse is written as 0xff.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Fuses {
+    pub low: u8,
+    pub high: u8,
+    pub extended: Option<u8>,
+}
+
+impl Fuses {
+    /// Fuses present in `chip`.
+    pub fn of_chip(fuses: &AVRFuse, chip: &specs::Specs) -> Fuses {
+        Fuses {
+            low: fuses.get(Fuse::Low),
+            high: fuses.get(Fuse::High),
+            extended: chip
+                .fuses()
+                .contains(&Fuse::Extended)
+                .then(|| fuses.get(Fuse::Extended)),
+        }
+    }
+}
+
+impl From<Fuses> for AVRFuse {
+    fn from(fuses: Fuses) -> AVRFuse {
+        AVRFuse::new(fuses.low, fuses.high, fuses.extended.unwrap_or(0xff))
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Job {
+    /// Chip the job is meant for.
+    pub chip: Option<String>,
+    /// Chips accepted by the board. Connected one is picked by its signature.
+    #[serde(default)]
+    pub chips: Vec<String>,
+    /// Firmware with both flash and EEPROM content. E.g. ELF file.
+    pub file: Option<PathBuf>,
+    /// Flash content. Overrides flash from `file`.
+    pub flash: Option<PathBuf>,
+    /// EEPROM content. Overrides EEPROM from `file`.
+    pub eeprom: Option<PathBuf>,
+    pub fuses: Option<Fuses>,
+    pub lock: Option<u8>,
+    /// Read back and compare everything which was written.
+    #[serde(default = "verify_default")]
+    pub verify: bool,
+    /// Read EEPROM before chip erase and write it back afterwards. Not needed when EESAVE fuse
+    /// is programmed. EEPROM image of the job is written over restored content.
+    #[serde(default)]
+    pub preserve_eeprom: bool,
+    /// Copy OSCCAL byte to this location.
+    pub osccal: Option<Osccal>,
+    /// Order of operations, e.g. fuses first to switch clock source. Steps without data are
+    /// skipped.
+    #[serde(default = "Job::default_steps")]
+    pub steps: Vec<Step>,
+    /// Per-device data written on top of firmware.
+    #[serde(default)]
+    pub inject: Vec<Injection>,
+    /// Program gaps between records with this byte instead of leaving them erased.
+    pub fill: Option<u8>,
+    /// Fail when file records overlap instead of letting the last one win.
+    #[serde(default)]
+    pub reject_overlap: bool,
+    /// Confirm after locking that written memories read back garbled.
+    #[serde(default)]
+    pub secure: bool,
+    /// Write remaining memories after a failed one. Fuses and lock byte are skipped then.
+    #[serde(default)]
+    pub keep_going: bool,
+}
+
+fn verify_default() -> bool {
+    true
+}
+
+impl Job {
+    fn default_steps() -> Vec<Step> {
+        vec![
+            Step::Erase,
+            Step::Flash,
+            Step::Eeprom,
+            Step::Osccal,
+            Step::Fuses,
+            Step::Lock,
+        ]
+    }
+
+    /// Read job manifest. Format is selected by file extension.
+    pub fn load(path: &Path) -> Result<Job, Error> {
+        let content = fs::read_to_string(path)?;
+        let extension = path.extension().and_then(|ext| ext.to_str());
+        let mut job = match extension.map(str::to_ascii_lowercase).as_deref() {
+            Some("yaml") | Some("yml") => Job::from_yaml(&content),
+            Some("toml") => Job::from_toml(&content),
+            _ => Err(String::from("Unknown job file format. Use .yaml or .toml")),
+        }
+        .and_then(|job| check_steps(&job.steps).map(|_| job))
+        .map_err(|err| Error::Usage(format!("{}: {}", path.display(), err)))?;
+        job.resolve(path.parent().unwrap_or_else(|| Path::new("")));
+        Ok(job)
+    }
+
+    fn from_yaml(content: &str) -> Result<Job, String> {
+        serde_yaml::from_str(content).map_err(|err| err.to_string())
+    }
+
+    fn from_toml(content: &str) -> Result<Job, String> {
+        toml::from_str(content).map_err(|err| err.to_string())
+    }
+
+    /// Make file paths relative to `dir`.
+    fn resolve(&mut self, dir: &Path) {
+        let paths = self
+            .file
+            .iter_mut()
+            .chain(&mut self.flash)
+            .chain(&mut self.eeprom);
+        for path in paths {
+            *path = dir.join(&path);
+        }
+        for injection in &mut self.inject {
+            injection.resolve(dir);
+        }
+    }
+
+    /// Whether job can be run on chip called `name`.
+    pub fn allows(&self, name: &str) -> bool {
+        if self.chip.is_none() && self.chips.is_empty() {
+            return true;
+        }
+        self.chip
+            .iter()
+            .chain(&self.chips)
+            .any(|chip| chip.eq_ignore_ascii_case(name))
+    }
+
+    pub fn fuses(&self) -> Option<AVRFuse> {
+        self.fuses.map(AVRFuse::from)
+    }
+
+    /// Load flash and EEPROM content from job files and inject per-device data.
+    pub fn firmware(&self) -> Result<Firmware, Error> {
+        let options = FillOptions {
+            gaps: self.fill.map_or(Gaps::Skip, Gaps::Fill),
+            reject_overlap: self.reject_overlap,
+        };
+        let mut firmware = match &self.file {
+            Some(file) => format::load_with(file, &options)?,
+            None => Firmware::default(),
+        };
+        if let Some(flash) = &self.flash {
+            firmware.flash = memory::load(flash, Memory::Flash, &options)?;
+        }
+        if let Some(eeprom) = &self.eeprom {
+            firmware.eeprom = memory::load(eeprom, Memory::Eeprom, &options)?;
+        }
+        for injection in &self.inject {
+            inject::patch(
+                &mut firmware,
+                injection.memory,
+                injection.address,
+                &injection.data()?,
+            );
+        }
+        Ok(firmware)
+    }
+
+    /// Advance injection counters after device was programmed.
+    pub fn commit(&self) -> Result<(), Error> {
+        self.inject.iter().try_for_each(Injection::commit)
+    }
+}
+
+impl From<JobArgs> for Job {
+    fn from(args: JobArgs) -> Job {
+        let fuses = args.fuses.map(|fuses| Fuses {
+            low: fuses.get(Fuse::Low),
+            high: fuses.get(Fuse::High),
+            extended: Some(fuses.get(Fuse::Extended)),
+        });
+        Job {
+            file: Some(args.file),
+            fuses,
+            lock: args.lock,
+            preserve_eeprom: args.preserve_eeprom,
+            osccal: args.osccal,
+            fill: args.fill,
+            reject_overlap: args.reject_overlap,
+            secure: args.secure,
+            keep_going: args.keep_going,
+            ..Job::default()
+        }
+    }
+}
+
+impl Default for Job {
+    /// Job without any data. Everything is verified.
+    fn default() -> Job {
+        Job {
+            chip: None,
+            chips: Vec::new(),
+            file: None,
+            flash: None,
+            eeprom: None,
+            fuses: None,
+            lock: None,
+            verify: verify_default(),
+            preserve_eeprom: false,
+            osccal: None,
+            steps: Job::default_steps(),
+            inject: Vec::new(),
+            fill: None,
+            reject_overlap: false,
+            secure: false,
+            keep_going: false,
+        }
+    }
+}
+
+/// Refuse orders which undo their own work.
+fn check_steps(steps: &[Step]) -> Result<(), String> {
+    for (i, step) in steps.iter().enumerate() {
+        let before = &steps[..i];
+        if before.contains(step) {
+            return Err(format!("Step {} is given twice", step.name()));
+        }
+        if *step == Step::Erase {
+            if let Some(written) = before.iter().find(|step| step.writes_memory()) {
+                return Err(format!(
+                    "Step erase after {} clears what was written",
+                    written.name()
+                ));
+            }
+        }
+        if before.contains(&Step::Lock) {
+            // Lock bits block further programming, chip erase clears them.
+            return Err(format!(
+                "Step {} after lock. Lock must be the last step",
+                step.name()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Parse `MEMORY:ADDRESS` location of OSCCAL byte.
+fn parse_osccal(value: &str) -> Result<Osccal, String> {
+    let (memory, address) = value
+        .split_once(':')
+        .ok_or_else(|| format!("Invalid location: {}. Use MEMORY:ADDRESS", value))?;
+    Ok(Osccal {
+        memory: Memory::from_str(memory, true)?,
+        address: parse_address(address)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yaml_manifest() {
+        let job = Job::from_yaml(
+            "chip: atmega32\nflash: fw.hex\nfuses: { low: 0xe1, high: 0x99 }\nlock: 0x3c\n",
+        )
+        .unwrap();
+        assert_eq!(job.chip.as_deref(), Some("atmega32"));
+        assert_eq!(job.flash, Some(PathBuf::from("fw.hex")));
+        assert_eq!(
+            job.fuses,
+            Some(Fuses {
+                low: 0xe1,
+                high: 0x99,
+                extended: None
+            })
+        );
+        assert_eq!(job.lock, Some(0x3c));
+        assert!(job.verify);
+        assert!(!job.preserve_eeprom);
+        assert_eq!(job.steps, Job::default_steps());
+        assert_eq!(job.fill, None);
+        let job = Job::from_yaml("flash: fw.hex\nfill: 0x00\nreject_overlap: true\n").unwrap();
+        assert_eq!(job.fill, Some(0));
+        assert!(job.reject_overlap);
+        assert!(!job.secure);
+        assert!(!job.keep_going);
+    }
+
+    #[test]
+    fn toml_manifest() {
+        let job =
+            Job::from_toml("file = \"fw.elf\"\nverify = false\nsteps = [\"erase\", \"flash\"]\n")
+                .unwrap();
+        assert_eq!(job.file, Some(PathBuf::from("fw.elf")));
+        assert!(!job.verify);
+        assert_eq!(job.steps, vec![Step::Erase, Step::Flash]);
+    }
+
+    #[test]
+    fn step_order() {
+        assert_eq!(check_steps(&Job::default_steps()), Ok(()));
+        let fuses_first = [Step::Fuses, Step::Erase, Step::Flash, Step::Lock];
+        assert_eq!(check_steps(&fuses_first), Ok(()));
+        assert!(check_steps(&[Step::Flash, Step::Erase]).is_err());
+        assert!(check_steps(&[Step::Lock, Step::Fuses]).is_err());
+        assert!(check_steps(&[Step::Flash, Step::Flash]).is_err());
+    }
+
+    #[test]
+    fn unknown_field() {
+        assert!(Job::from_yaml("flsh: fw.hex\n").is_err());
+    }
+
+    #[test]
+    fn paths_are_relative_to_manifest() {
+        let mut job = Job::from_yaml("flash: fw.hex\n").unwrap();
+        job.resolve(Path::new("jobs"));
+        assert_eq!(job.flash, Some(PathBuf::from("jobs/fw.hex")));
+    }
+
+    #[test]
+    fn chip_list() {
+        let job = Job::from_yaml("chips: [atmega168pa, ATmega328P]\n").unwrap();
+        assert!(job.allows("atmega328p"));
+        assert!(!job.allows("atmega32"));
+        assert!(Job::default().allows("atmega32"));
+    }
+
+    #[test]
+    fn parses_fuses() {
+        let fuses: AVRFuse = "e1:0x99".parse().unwrap();
+        assert_eq!(fuses.get(Fuse::Low), 0xe1);
+        assert_eq!(fuses.get(Fuse::High), 0x99);
+        assert_eq!(fuses.get(Fuse::Extended), 0xff);
+        assert!("e1".parse::<AVRFuse>().is_err());
+    }
+
+    #[test]
+    fn parses_osccal_location() {
+        let osccal = parse_osccal("eeprom:0x1ff").unwrap();
+        assert_eq!(osccal.memory, Memory::Eeprom);
+        assert_eq!(osccal.address, 0x1ff);
+        assert!(parse_osccal("0x1ff").is_err());
+    }
+}
@@ -0,0 +1,113 @@
+//! Local ledger of erase / program cycles per board.
+//!
+//! Ledger is a JSON file mapping `<signature>/<board id>` to cycle counts. Test jigs reflashing
+//! the same chip thousands of times use it to notice worn out memories before they fail.
+use crate::error::Error;
+use crate::hex;
+use avrisp::specs;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// Typical AVR flash endurance, write / erase cycles.
+const FLASH_ENDURANCE: u64 = 10_000;
+/// Typical AVR EEPROM endurance, write / erase cycles.
+const EEPROM_ENDURANCE: u64 = 100_000;
+/// Warn when this percentage of endurance is used.
+const WARN_PERCENT: u64 = 90;
+
+/// Cycles done on a chip. Chip erase wears both flash and EEPROM.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq)]
+pub struct Cycles {
+    pub erase: u64,
+    pub eeprom: u64,
+}
+
+impl Cycles {
+    pub fn is_empty(&self) -> bool {
+        *self == Cycles::default()
+    }
+}
+
+#[derive(Clone)]
+pub struct Ledger {
+    path: PathBuf,
+    board: String,
+    /// Threads programming in parallel share the ledger file.
+    lock: Arc<Mutex<()>>,
+}
+
+impl Ledger {
+    pub fn new(path: PathBuf, board: String) -> Ledger {
+        Ledger {
+            path,
+            board,
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Same ledger for another board. E.g. for each port in gang mode.
+    pub fn board(&self, suffix: &str) -> Ledger {
+        Ledger {
+            board: format!("{}@{}", self.board, suffix),
+            ..self.clone()
+        }
+    }
+
+    /// Add `cycles` to the board and warn if memories are close to their endurance.
+    pub fn record(&self, chip: &specs::Specs, cycles: Cycles) -> Result<Cycles, Error> {
+        if cycles.is_empty() {
+            return Ok(cycles);
+        }
+        let _guard = self.lock.lock().expect("ledger lock poisoned");
+        let mut entries: BTreeMap<String, Cycles> = match fs::read_to_string(&self.path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|err| Error::Usage(format!("{}: {}", self.path.display(), err)))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let (sig0, sig1, sig2) = chip.signature.bytes;
+        let key = format!("{}/{}", hex::encode(&[sig0, sig1, sig2]), self.board);
+        let total = entries.entry(key).or_default();
+        total.erase += cycles.erase;
+        total.eeprom += cycles.eeprom;
+        let total = *total;
+        let json = serde_json::to_string_pretty(&entries).expect("ledger is always serializable");
+        fs::write(&self.path, json + "\n")?;
+        warn(&self.board, "Flash", total.erase, FLASH_ENDURANCE);
+        warn(
+            &self.board,
+            "EEPROM",
+            total.erase + total.eeprom,
+            EEPROM_ENDURANCE,
+        );
+        Ok(total)
+    }
+}
+
+fn warn(board: &str, memory: &str, cycles: u64, endurance: u64) {
+    if near_endurance(cycles, endurance) {
+        eprintln!(
+            "warning: {} of board {} has {} cycles. Typical endurance is {}",
+            memory, board, cycles, endurance
+        );
+    }
+}
+
+fn near_endurance(cycles: u64, endurance: u64) -> bool {
+    cycles * 100 >= endurance * WARN_PERCENT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warns_at_ninety_percent() {
+        assert!(!near_endurance(8_999, FLASH_ENDURANCE));
+        assert!(near_endurance(9_000, FLASH_ENDURANCE));
+    }
+}
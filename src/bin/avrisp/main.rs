@@ -0,0 +1,1008 @@
+mod avrdude;
+mod backend;
+mod backup;
+mod batch;
+mod chips;
+mod config;
+mod console;
+mod debugwire;
+mod diff;
+mod doctor;
+mod error;
+mod gang;
+mod hex;
+mod info;
+mod inject;
+mod job;
+mod ledger;
+mod memory;
+mod ports;
+mod program;
+mod report;
+mod rescue;
+mod size;
+mod template;
+mod watch;
+
+use avrisp::errors::ErrorKind;
+use avrisp::format;
+use avrisp::hexdump;
+use avrisp::programmer::stk500v2::{self, LinkOptions, STK500v2, SequencePolicy};
+use avrisp::programmer::{self, Action, Confirm, Event, ProgrammerKind, Session};
+use avrisp::specs;
+use backend::Backend;
+use clap::{Parser, Subcommand, ValueEnum};
+use error::{Error, ExitCode};
+use memory::Memory;
+use std::env;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Serial port of the programmer.
+    #[arg(short, long, global = true, env = "AVRISP_PORT")]
+    port: Option<String>,
+    /// Programmer hardware. USB programmers do not need --port.
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = Backend::Stk500v2,
+        env = "AVRISP_PROGRAMMER"
+    )]
+    programmer: Backend,
+    /// Target chip name. E.g. atmega32.
+    #[arg(short, long, global = true, env = "AVRISP_CHIP")]
+    chip: Option<String>,
+    /// Count erase and write cycles per board in this ledger file.
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        env = "AVRISP_LEDGER",
+        requires = "board"
+    )]
+    ledger: Option<PathBuf>,
+    /// Board id or serial number used as ledger key.
+    #[arg(long, global = true, value_name = "ID", env = "AVRISP_BOARD")]
+    board: Option<String>,
+    /// Continue when chip signature does not match --chip or flash image overwrites resident
+    /// bootloader. Writing with wrong page size corrupts the chip.
+    #[arg(long, global = true)]
+    force: bool,
+    /// Ask before chip erase and writing fuses or lock byte.
+    #[arg(long, global = true, env = "AVRISP_CONFIRM")]
+    confirm: bool,
+    /// How STK500v2 programmers wait for writes and chip erase to finish: delay, value or rdy-bsy.
+    /// Chip description decides by default.
+    #[arg(long, global = true, value_name = "METHOD", env = "AVRISP_POLL")]
+    poll: Option<specs::PollMethod>,
+    /// Set SCK from target clock given by fuses: the fastest below a quarter of it. STK500 only,
+    /// chips running from crystal or external clock keep SCK as it is.
+    #[arg(long, global = true, env = "AVRISP_AUTO_SCK")]
+    auto_sck: bool,
+    /// Print progress events (page read / written, retries, verify mismatches) as JSON lines on
+    /// stderr, for GUI and TUI frontends.
+    #[arg(long, global = true, env = "AVRISP_EVENTS")]
+    events: bool,
+    /// Sequence number check of STK500v2 answers: strict, warn or ignore. Some clones echo
+    /// wrong numbers.
+    #[arg(
+        long,
+        global = true,
+        value_name = "POLICY",
+        default_value = "strict",
+        env = "AVRISP_SEQUENCE"
+    )]
+    sequence: SequencePolicy,
+    /// Baud rates tried in order until STK500v2 programmer answers. E.g. 115200,57600.
+    /// STK500v1 and AVR109 use the first one, 19200 by default.
+    #[arg(
+        long,
+        global = true,
+        value_name = "RATES",
+        value_delimiter = ',',
+        env = "AVRISP_BAUD"
+    )]
+    baud: Vec<usize>,
+    /// Stop bits of STK500v2 serial link.
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u8).range(1..=2),
+        env = "AVRISP_STOP_BITS"
+    )]
+    stop_bits: u8,
+    /// Milliseconds to wait for STK500v2 answer. Raise for slow isolators or long lines.
+    #[arg(
+        long,
+        global = true,
+        value_name = "MS",
+        default_value_t = 1000,
+        env = "AVRISP_TIMEOUT"
+    )]
+    timeout: u64,
+    /// Milliseconds to wait for STK500v2 programmer which dropped off USB. Port is found again by
+    /// USB serial number and programming is resumed.
+    #[arg(long, global = true, value_name = "MS", env = "AVRISP_RECONNECT")]
+    reconnect: Option<u64>,
+    /// Seconds to wait for programmer to be plugged in before giving up.
+    #[arg(long, global = true, value_name = "SECONDS", env = "AVRISP_WAIT")]
+    wait: Option<u64>,
+    /// Cargo runner mode. Program given ELF file, e.g. from `cargo run`.
+    #[arg(long, value_name = "ELF")]
+    runner: Option<PathBuf>,
+    #[command(flatten)]
+    monitor: console::MonitorArgs,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(flatten)]
+    Device(DeviceCommand),
+    /// List built in chips.
+    Chips {
+        /// Show only chips which name contains this string.
+        filter: Option<String>,
+        /// Print memory map of each chip: sizes, page sizes and access.
+        #[arg(long)]
+        memories: bool,
+    },
+    /// List serial ports.
+    Ports {
+        /// Check which ports have STK500v2 programmer attached.
+        #[arg(long)]
+        probe: bool,
+    },
+    /// Find programmers on all serial ports and print chip attached to each of them.
+    ///
+    /// --chip gives ISP parameters for reading signatures, any ATmega works for that.
+    Scan {
+        /// Only USB serial adapters.
+        #[arg(long)]
+        usb: bool,
+        /// Only USB adapters with this vendor id, e.g. 0403.
+        #[arg(long, value_name = "VID", value_parser = ports::parse_vid)]
+        vid: Option<u16>,
+    },
+    /// Print flash and EEPROM usage of an image on --chip, like avr-size.
+    Size { file: PathBuf },
+    /// Compare two images offline and print differing address ranges.
+    ///
+    /// Any supported file format can be used, e.g. `diff backup.hex flash.bin`.
+    Diff { a: PathBuf, b: PathBuf },
+    /// Program file each time it changes. Same as program command.
+    Watch { file: PathBuf },
+    /// Program many boards in a row and print PASS / FAIL for each of them.
+    Batch {
+        #[command(flatten)]
+        job: job::JobArgs,
+        /// Start programming when target is detected instead of waiting for Enter.
+        #[arg(long)]
+        detect: bool,
+    },
+    /// Run programming job from YAML or TOML manifest.
+    Run {
+        /// Job manifest. Chip given in manifest must match --chip if both are given.
+        manifest: PathBuf,
+        /// Write JSON report of the job to this file.
+        #[arg(long, value_name = "FILE")]
+        report: Option<PathBuf>,
+    },
+    /// Run job manifest on several programmers at once.
+    ///
+    /// Injected counters are reserved for all boards before programming starts.
+    Gang {
+        /// Job manifest. Same as for run command.
+        manifest: PathBuf,
+        /// Serial ports of the programmers.
+        #[arg(required = true)]
+        ports: Vec<String>,
+    },
+    /// Write backup archive back to the chip and verify it.
+    ///
+    /// Chip stored in archive is used if --chip is not given.
+    Restore {
+        /// Archive created by backup command.
+        file: PathBuf,
+    },
+    /// Clear DWEN fuse of a chip with debugWIRE enabled, while ISP still answers.
+    Debugwire,
+    /// Control target reset line.
+    Reset {
+        #[arg(value_enum, default_value_t = ResetAction::Pulse)]
+        action: ResetAction,
+    },
+    /// Print every readable parameter of STK500v2 programmer.
+    Params,
+    /// Check connection step by step: port, sign on, versions, target voltage, ISP entry and
+    /// signature. Prints hints for the failing step. STK500v2 only.
+    Doctor,
+    /// Recover chip which does not answer ISP. Finds failure mode step by step, tries slow SCK
+    /// and clock from STK500 oscillator output, then writes factory fuses. STK500v2 only.
+    Rescue {
+        /// Clock output frequency in Hz.
+        #[arg(long, value_name = "HZ", default_value_t = 1_000_000)]
+        clock: u32,
+    },
+    /// Read signature at a range of SCK speeds and report the fastest reliable one. STK500v2 only.
+    Sweep {
+        /// Signature reads at each speed.
+        #[arg(long, default_value_t = 5)]
+        reads: usize,
+    },
+    /// Run avrdude command line, e.g. `avrdude -p m32 -c stk500v2 -P /dev/ttyUSB0 -U flash:w:fw.hex`.
+    ///
+    /// Same happens when avrisp is installed as (or linked to) `avrdude`.
+    Avrdude {
+        /// avrdude flags and -U operations.
+        #[arg(allow_hyphen_values = true, trailing_var_arg = true)]
+        args: Vec<String>,
+    },
+    /// Open serial console.
+    Monitor {
+        /// Console port. Programmer port is used if not given.
+        console: Option<String>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ResetAction {
+    /// Restart target.
+    Pulse,
+    /// Keep target in reset, also after avrisp exits.
+    Hold,
+    /// Let target run.
+    Release,
+}
+
+/// Commands which need connected programmer and target.
+#[derive(Subcommand)]
+enum DeviceCommand {
+    /// Read memory into a binary file. Trailing erased bytes are left out.
+    Read {
+        memory: Memory,
+        /// Output file. May hold {chip}, {signature}, {date}, {time} and {memory} fields.
+        file: PathBuf,
+        /// Value of trailing bytes to leave out.
+        #[arg(long, value_parser = hex::parse_byte, default_value = "0xff")]
+        pad: u8,
+        /// Save whole memory.
+        #[arg(long)]
+        full: bool,
+        /// Read memory this many times and keep majority value of each byte. Addresses read
+        /// differently are reported.
+        #[arg(long, value_name = "PASSES", value_parser = clap::value_parser!(u8).range(2..=5))]
+        vote: Option<u8>,
+    },
+    /// Print memory as hex dump.
+    Dump {
+        memory: Memory,
+        /// Address range START..END, end excluded. Whole memory by default.
+        #[arg(long, value_parser = hex::parse_range)]
+        range: Option<Range<usize>>,
+    },
+    /// Compare memory with a binary file.
+    Verify { memory: Memory, file: PathBuf },
+    /// Erase whole chip including EEPROM and flash.
+    Erase,
+    /// Erase chip, write flash and EEPROM from file and verify them. Optionally write fuses and
+    /// lock byte.
+    ///
+    /// ELF (.elf), Intel HEX (.hex) and raw binary (any other extension) files are supported.
+    Program {
+        #[command(flatten)]
+        job: job::JobArgs,
+        /// Write JSON report of the job to this file.
+        #[arg(long, value_name = "FILE")]
+        report: Option<PathBuf>,
+    },
+    /// Change bytes at given address, keeping the rest of memory.
+    ///
+    /// Flash can not be erased without erasing whole chip, so in flash only bits set to 1 can be
+    /// cleared. E.g. `poke flash 0x1FFE 0xAA,0x55`.
+    Poke {
+        memory: Memory,
+        /// Byte address. Hex with 0x prefix or decimal.
+        #[arg(value_parser = hex::parse_address)]
+        address: usize,
+        /// Comma separated hex bytes.
+        #[arg(value_delimiter = ',', required = true, value_parser = hex::parse_byte)]
+        bytes: Vec<u8>,
+    },
+    /// Save flash, EEPROM, fuses, lock byte, OSCCAL and signature into a single archive.
+    Backup {
+        /// Archive file, e.g. board.avrbk or {chip}_{signature}_{date}.avrbk.
+        file: PathBuf,
+    },
+    /// Print programmer and chip details.
+    Info {
+        /// Print only these parts of the report.
+        #[arg(value_enum)]
+        sections: Vec<info::Section>,
+    },
+    /// Export decoded fuses and lock byte, e.g. to compare boards with expected configuration.
+    Config {
+        #[arg(long, value_enum, default_value_t = config::Format::Json)]
+        format: config::Format,
+        /// Write to this file instead of standard output.
+        #[arg(long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+}
+
+impl DeviceCommand {
+    /// Report commands work with any connected chip.
+    fn checks_signature(&self) -> bool {
+        !matches!(
+            self,
+            DeviceCommand::Info { .. } | DeviceCommand::Config { .. }
+        )
+    }
+}
+
+fn main() -> process::ExitCode {
+    let cli = Cli::parse_from(avrdude::arguments(env::args_os()));
+    match run(cli) {
+        Ok(()) => ExitCode::Success.into(),
+        Err(err) => {
+            eprintln!("error: {}", err);
+            err.exit_code().into()
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Error> {
+    let target = Target {
+        programmer: cli.programmer,
+        port: cli.port,
+        chip: cli.chip,
+        force: cli.force,
+        confirm: cli.confirm,
+        poll: cli.poll,
+        auto_sck: cli.auto_sck,
+        events: cli.events,
+        baud: cli.baud.first().copied(),
+        link: LinkOptions {
+            sequence: cli.sequence,
+            bauds: if cli.baud.is_empty() {
+                LinkOptions::DEFAULT_BAUDS.to_vec()
+            } else {
+                cli.baud
+            },
+            stop_bits: if cli.stop_bits == 2 {
+                serial::Stop2
+            } else {
+                serial::Stop1
+            },
+            timeout: Duration::from_millis(cli.timeout),
+            reconnect: cli.reconnect.map(Duration::from_millis),
+        },
+        wait: cli.wait.map(Duration::from_secs),
+        ledger: cli
+            .ledger
+            .zip(cli.board)
+            .map(|(path, board)| ledger::Ledger::new(path, board)),
+    };
+    let monitor = cli.monitor;
+    if let Some(file) = cli.runner {
+        if cli.command.is_some() {
+            return Err(Error::Usage(String::from(
+                "--runner can not be used with a command",
+            )));
+        }
+        let command = DeviceCommand::Program {
+            job: job::JobArgs::new(file),
+            report: None,
+        };
+        return program(&target, command, &monitor);
+    }
+    let command = cli
+        .command
+        .ok_or_else(|| Error::Usage(String::from("No command given. See --help")))?;
+    match command {
+        Command::Device(command) => program(&target, command, &monitor),
+        Command::Chips { filter, memories } => {
+            chips::list(filter.as_deref(), memories);
+            Ok(())
+        }
+        Command::Ports { probe } => ports::list(probe),
+        Command::Scan { usb, vid } => {
+            let chip = match &target.chip {
+                Some(name) => find_chip(Some(name))?,
+                None => specs::CHIPS[0],
+            };
+            ports::scan(ports::Filter { usb, vid }, &target.link, chip)
+        }
+        Command::Size { file } => size::run(&file, find_chip(target.chip.as_deref())?),
+        Command::Diff { a, b } => diff::run(&a, &b),
+        Command::Watch { file } => watch::run(&file, || {
+            let command = DeviceCommand::Program {
+                job: job::JobArgs::new(file.clone()),
+                report: None,
+            };
+            device(&target, command)
+        }),
+        Command::Batch { job, detect } => {
+            let program = || {
+                let command = DeviceCommand::Program {
+                    job: job.clone(),
+                    report: None,
+                };
+                device(&target, command)
+            };
+            if detect {
+                batch::run(program, Some(|| target_present(&target)))
+            } else {
+                batch::run(program, None::<fn() -> bool>)
+            }
+        }
+        Command::Run { manifest, report } => {
+            let job = job::Job::load(&manifest)?;
+            let target = Target {
+                chip: job_chip(&target, &job)?,
+                ..target
+            };
+            with_target(&target, true, |isp, chip| {
+                run_job(&target, isp, chip, &job, report.as_deref())
+            })
+        }
+        Command::Gang { manifest, ports } => {
+            if target.confirm {
+                return Err(Error::Usage(String::from(
+                    "--confirm can not be used with gang, ports are programmed in parallel",
+                )));
+            }
+            let job = job::Job::load(&manifest)?;
+            // Concurrent boards must never get the same injected values.
+            let mut boards = Vec::new();
+            for port in ports {
+                boards.push((port, job.firmware()?));
+                job.commit()?;
+            }
+            gang::run(boards, |port, firmware| {
+                let target = Target {
+                    programmer: target.programmer,
+                    port: Some(port.to_string()),
+                    chip: target.chip.clone(),
+                    force: target.force,
+                    confirm: false,
+                    poll: target.poll,
+                    auto_sck: target.auto_sck,
+                    events: target.events,
+                    baud: target.baud,
+                    link: target.link.clone(),
+                    wait: target.wait,
+                    ledger: target.ledger.as_ref().map(|ledger| ledger.board(port)),
+                };
+                let target = Target {
+                    chip: job_chip(&target, &job)?,
+                    ..target
+                };
+                with_target(&target, true, |isp, chip| {
+                    let mut report = report::Report::new(chip);
+                    let result = program::run(
+                        isp,
+                        chip,
+                        &job,
+                        &firmware,
+                        &mut report,
+                        target.force,
+                        &mut programmer::Approve,
+                    );
+                    target.record(chip, result, report.cycles)
+                })
+            })
+        }
+        Command::Restore { file } => {
+            let backup = backup::Backup::load(&file)?;
+            let target = Target {
+                chip: target.chip.clone().or_else(|| Some(backup.chip.clone())),
+                ..target
+            };
+            with_target(&target, true, |isp, chip| {
+                let mut report = report::Report::new(chip);
+                let result = backup.restore(isp, chip, &mut report, target.policy().as_mut());
+                target.record(chip, result, report.cycles)
+            })
+        }
+        Command::Debugwire => debugwire::recover(|action| with_target(&target, true, action)),
+        Command::Reset { action } => {
+            if target.programmer != Backend::Stk500v2 {
+                return Err(Error::Usage(String::from(
+                    "Reset line can be controlled only with stk500v2 programmer",
+                )));
+            }
+            let chip = find_chip(target.chip.as_deref())?;
+            let port = target.port()?;
+            let mut stk = STK500v2::open_with(port, &target.link, chip.clone())?;
+            match action {
+                ResetAction::Pulse => stk.reset()?,
+                ResetAction::Hold => stk.hold_reset()?,
+                ResetAction::Release => stk.release_reset()?,
+            }
+            Ok(())
+        }
+        Command::Params => {
+            if target.programmer != Backend::Stk500v2 {
+                return Err(Error::Usage(String::from(
+                    "Parameters can be read only from stk500v2 programmer",
+                )));
+            }
+            // Parameters do not depend on target chip.
+            let chip = match target.chip.as_deref() {
+                Some(name) => find_chip(Some(name))?,
+                None => specs::CHIPS[0],
+            };
+            let mut stk = STK500v2::open_with(target.port()?, &target.link, chip.clone())?;
+            println!("{}", stk.dump_parameters()?);
+            Ok(())
+        }
+        Command::Doctor => {
+            if target.programmer != Backend::Stk500v2 {
+                return Err(Error::Usage(String::from(
+                    "Connection can be checked only with stk500v2 programmer",
+                )));
+            }
+            let chip = find_chip(target.chip.as_deref())?;
+            doctor::run(target.port()?, &target.link, chip)
+        }
+        Command::Rescue { clock } => {
+            if target.programmer != Backend::Stk500v2 {
+                return Err(Error::Usage(String::from(
+                    "Only stk500v2 programmer has clock output",
+                )));
+            }
+            let chip = find_chip(target.chip.as_deref())?;
+            // Walks user through the steps, so fuses are always confirmed.
+            rescue::run(target.port()?, &target.link, chip, clock, &mut prompt)
+        }
+        Command::Sweep { reads } => {
+            if target.programmer != Backend::Stk500v2 {
+                return Err(Error::Usage(String::from(
+                    "SCK can be swept only with stk500v2 programmer",
+                )));
+            }
+            let chip = find_chip(target.chip.as_deref())?;
+            let stk = STK500v2::open_with(target.port()?, &target.link, chip.clone())?;
+            let trials = stk500v2::sweep_sck(stk, &stk500v2::SWEEP_SCK, reads)?;
+            println!("SCK duration  STK500 SCK  Signature reads");
+            for trial in &trials {
+                println!(
+                    "{:>12}  {:>6} kHz  {}/{}",
+                    trial.duration,
+                    stk500v2::sck_frequency(trial.duration) / 1000,
+                    trial.matched,
+                    trial.reads
+                );
+            }
+            match trials.iter().find(|trial| trial.reliable()) {
+                Some(trial) => println!(
+                    "Fastest reliable SCK duration: {} (about {} kHz on STK500)",
+                    trial.duration,
+                    stk500v2::sck_frequency(trial.duration) / 1000
+                ),
+                None => println!("Target did not answer reliably at any SCK speed"),
+            }
+            Ok(())
+        }
+        Command::Avrdude { args } => {
+            let options = avrdude::Options::parse(&args)?;
+            let programmer = options.programmer.unwrap_or(target.programmer);
+            let mut link = target.link.clone();
+            if let Some(baud) = options.baud {
+                if !programmer.takes_baud() {
+                    return Err(Error::Usage(format!(
+                        "-b is not used by {:?} programmer",
+                        programmer
+                    )));
+                }
+                link.bauds = vec![baud];
+            }
+            let target = Target {
+                programmer,
+                baud: options.baud.or(target.baud),
+                port: options.port.clone().or(target.port),
+                chip: options.part.clone().or(target.chip),
+                force: target.force || options.force,
+                link,
+                ..target
+            };
+            with_target(&target, true, |isp, chip| {
+                let (result, cycles) = avrdude::run(&options, isp, chip, target.policy().as_mut());
+                target.record(chip, result, cycles)
+            })
+        }
+        Command::Monitor { console } => {
+            let port = console
+                .or(target.port)
+                .ok_or_else(|| Error::Usage(String::from("No console port given. Use --port")))?;
+            console::run(&port, &monitor.options)
+        }
+    }
+}
+
+/// Programmer, its port, target chip and optional cycle ledger.
+#[derive(Clone)]
+struct Target {
+    programmer: Backend,
+    port: Option<String>,
+    chip: Option<String>,
+    /// Signature mismatch and overwritten bootloader are only warnings.
+    force: bool,
+    /// Destructive steps are confirmed on terminal.
+    confirm: bool,
+    poll: Option<specs::PollMethod>,
+    /// SCK is set from target clock given by fuses.
+    auto_sck: bool,
+    /// Progress events are printed as JSON lines.
+    events: bool,
+    /// Link rate of STK500v1 and AVR109 programmers.
+    baud: Option<usize>,
+    link: LinkOptions,
+    /// How long to wait for programmer to be plugged in.
+    wait: Option<Duration>,
+    ledger: Option<ledger::Ledger>,
+}
+
+impl Target {
+    fn port(&self) -> Result<&str, Error> {
+        self.port
+            .as_deref()
+            .ok_or_else(|| Error::Usage(String::from("No port given. Use --port")))
+    }
+
+    /// Approval of destructive steps: terminal prompt with --confirm, otherwise all are approved.
+    fn policy(&self) -> Box<dyn Confirm> {
+        if self.confirm {
+            Box::new(prompt)
+        } else {
+            Box::new(programmer::Approve)
+        }
+    }
+
+    /// Add cycles done by an operation to the ledger, also when the operation failed.
+    fn record(
+        &self,
+        chip: &specs::Specs,
+        result: Result<(), Error>,
+        cycles: ledger::Cycles,
+    ) -> Result<(), Error> {
+        let recorded = match &self.ledger {
+            Some(ledger) => ledger.record(chip, cycles).map(|_| ()),
+            None => Ok(()),
+        };
+        result?;
+        recorded
+    }
+}
+
+/// Ask on terminal whether `action` may be done. Only "y" or "yes" approves it.
+fn prompt(action: &Action) -> bool {
+    eprint!("{}? [y/N] ", action);
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Run device command and open serial console after programming if requested.
+fn program(
+    target: &Target,
+    command: DeviceCommand,
+    monitor: &console::MonitorArgs,
+) -> Result<(), Error> {
+    let console = match command {
+        DeviceCommand::Program { .. } => monitor.port(target.port.as_ref()),
+        _ => None,
+    };
+    device(target, command)?;
+    if let Some(console) = console {
+        console::run(&console, &monitor.options)?;
+    }
+    Ok(())
+}
+
+fn device(target: &Target, command: DeviceCommand) -> Result<(), Error> {
+    let checks_signature = command.checks_signature();
+    with_target(target, checks_signature, |isp, chip| {
+        execute(target, isp, chip, command)
+    })
+}
+
+/// Check if requested chip answers.
+fn target_present(target: &Target) -> bool {
+    with_target(target, true, |_, _| Ok(())).is_ok()
+}
+
+/// Enter programming mode, run `action` and leave programming mode.
+fn with_target<F>(target: &Target, checks_signature: bool, action: F) -> Result<(), Error>
+where
+    F: FnOnce(&mut dyn Session, &'static specs::Specs) -> Result<(), Error>,
+{
+    let chip = find_chip(target.chip.as_deref())?;
+    let kind = target
+        .programmer
+        .kind(target.port.as_deref(), &target.link, target.baud)?;
+    let mut specs = chip.clone();
+    if let Some(poll) = target.poll {
+        specs.set_poll_method(poll);
+    }
+    let mut isp = connect(target, &kind, &specs)?;
+    if let Some(clock) = isp.slowed_clock()? {
+        eprintln!("Target answered with slower SCK: {}", clock);
+    }
+    let result = if checks_signature {
+        match programmer::check_signature(isp.as_mut(), chip, None) {
+            Err(err @ ErrorKind::SignatureMismatch { .. }) if target.force => {
+                eprintln!("warning: {}. Continuing because of --force", err);
+                Ok(())
+            }
+            result => result.map(|_| ()).map_err(Error::from),
+        }
+    } else {
+        Ok(())
+    };
+    let result = result.and_then(|_| {
+        if target.auto_sck {
+            if let Some(clock) = isp.adapt_clock()? {
+                eprintln!("SCK set from target clock: {}", clock);
+            }
+        }
+        action(isp.as_mut(), chip)
+    });
+    let mismatches = isp.counters().mismatches;
+    if mismatches > 0 {
+        eprintln!("warning: {} answers had wrong sequence number", mismatches);
+    }
+    // Always try to leave programming mode. Report the first error.
+    let closed = isp.finish();
+    result?;
+    closed?;
+    Ok(())
+}
+
+/// Connect to programmer. With --wait, programmer which is not plugged in yet is waited for.
+fn connect(
+    target: &Target,
+    kind: &ProgrammerKind,
+    specs: &specs::Specs,
+) -> Result<Box<dyn Session>, Error> {
+    let wait = match target.wait {
+        Some(wait) => wait,
+        None => return Ok(open(target, kind, specs)?),
+    };
+    if let Some(port) = &target.port {
+        avrisp::ports::wait(port, wait)?;
+        return Ok(open(target, kind, specs)?);
+    }
+    // USB programmers have no port to watch.
+    let start = Instant::now();
+    loop {
+        match open(target, kind, specs) {
+            Err(ErrorKind::Io(err))
+                if err.kind() == io::ErrorKind::NotFound && start.elapsed() < wait =>
+            {
+                thread::sleep(Duration::from_millis(100))
+            }
+            result => return Ok(result?),
+        }
+    }
+}
+
+fn open(
+    target: &Target,
+    kind: &ProgrammerKind,
+    specs: &specs::Specs,
+) -> Result<Box<dyn Session>, ErrorKind> {
+    if !target.events {
+        return kind.connect(specs);
+    }
+    kind.connect_with(
+        specs,
+        Box::new(|event: &Event| {
+            eprintln!(
+                "{}",
+                serde_json::to_string(event).expect("event is always serializable")
+            )
+        }),
+    )
+}
+
+/// Chip from job manifest. It must match chip given on command line.
+///
+/// Job listing several chips gets the connected one, found by its signature.
+fn job_chip(target: &Target, job: &job::Job) -> Result<Option<String>, Error> {
+    if job.chip.is_some() && !job.chips.is_empty() {
+        return Err(Error::Usage(String::from(
+            "Job can give either chip or chips, not both",
+        )));
+    }
+    match (&job.chip, target.chip.clone()) {
+        (_, Some(chip)) if !job.allows(&chip) => Err(Error::Usage(format!(
+            "Job is for {} but --chip is {}",
+            job.chip.clone().unwrap_or_else(|| job.chips.join(", ")),
+            chip
+        ))),
+        (None, None) if !job.chips.is_empty() => detect_chip(target, &job.chips).map(Some),
+        (job_chip, chip) => Ok(chip.or_else(|| job_chip.clone())),
+    }
+}
+
+/// Chip of `allowed` which is connected.
+fn detect_chip(target: &Target, allowed: &[String]) -> Result<String, Error> {
+    let chips = allowed
+        .iter()
+        .map(|name| find_chip(Some(name)))
+        .collect::<Result<Vec<_>, _>>()?;
+    // Programming mode is entered alike on all of them.
+    let probe = Target {
+        chip: Some(String::from(chips[0].name)),
+        ..target.clone()
+    };
+    let mut signature = None;
+    with_target(&probe, false, |isp, _| {
+        signature = Some(isp.get_mcu_signature()?);
+        Ok(())
+    })?;
+    let signature = signature.expect("signature is read");
+    chips
+        .iter()
+        .find(|chip| signature.matches(chip))
+        .map(|chip| String::from(chip.name))
+        .ok_or_else(|| {
+            Error::UnexpectedChip(format!(
+                "Connected chip {} is not one of {}",
+                signature,
+                allowed.join(", ")
+            ))
+        })
+}
+
+/// Fill in file name template with details of connected chip.
+fn output_path(
+    isp: &mut dyn Session,
+    chip: &specs::Specs,
+    file: &Path,
+    memory: Option<Memory>,
+) -> Result<PathBuf, Error> {
+    if !template::is_template(file) {
+        return Ok(file.to_path_buf());
+    }
+    let fields = template::Fields {
+        chip: chip.name,
+        signature: isp.get_mcu_signature()?,
+        memory: memory.map(|memory| memory.name()),
+        now: SystemTime::now(),
+    };
+    let path = template::expand(file, &fields)?;
+    eprintln!("Saving to {}", path.display());
+    Ok(path)
+}
+
+fn find_chip(name: Option<&str>) -> Result<&'static specs::Specs, Error> {
+    let name = name.ok_or_else(|| Error::Usage(String::from("No chip given. Use --chip")))?;
+    specs::find(name).ok_or_else(|| Error::Usage(format!("Unknown chip: {}", name)))
+}
+
+fn execute(
+    target: &Target,
+    isp: &mut dyn Session,
+    chip: &specs::Specs,
+    command: DeviceCommand,
+) -> Result<(), Error> {
+    match command {
+        DeviceCommand::Read {
+            memory,
+            file,
+            pad,
+            full,
+            vote,
+        } => {
+            let size = memory.specs(chip).size;
+            let mut image = match vote {
+                Some(passes) => memory::read_voted(isp, memory, size, passes.into())?,
+                None => memory::read(isp, memory, size)?,
+            };
+            eprintln!("{}: {}", memory.name(), format::Usage::new(&image, size));
+            if !full {
+                image.trim(pad);
+            }
+            let path = output_path(isp, chip, &file, Some(memory))?;
+            fs::write(path, image.to_bytes(0xff))?;
+        }
+        DeviceCommand::Dump { memory, range } => {
+            let specs = memory.specs(chip);
+            let range = range.unwrap_or(0..specs.size);
+            if range.end > specs.size {
+                return Err(ErrorKind::AddressOutOfRange {
+                    address: range.end - 1,
+                    size: specs.size,
+                }
+                .into());
+            }
+            // Memory is read from start in whole pages.
+            let image = memory::read(isp, memory, memory::pages(chip, memory, range.end))?;
+            print!(
+                "{}",
+                hexdump::hexdump(range.start, &image.to_bytes(0xff)[range])
+            );
+        }
+        DeviceCommand::Verify { memory, file } => {
+            memory::verify(isp, chip, memory, &format::Image::from(fs::read(file)?))?;
+        }
+        DeviceCommand::Erase => {
+            let cycles = ledger::Cycles {
+                erase: 1,
+                eeprom: 0,
+            };
+            programmer::ask(target.policy().as_mut(), Action::Erase)?;
+            target.record(chip, isp.erase().map_err(Error::from), cycles)?
+        }
+        DeviceCommand::Program { job, report, .. } => {
+            run_job(target, isp, chip, &job.into(), report.as_deref())?
+        }
+        DeviceCommand::Poke {
+            memory,
+            address,
+            bytes,
+        } => {
+            let result = memory::patch(isp, memory, address, &bytes).map_err(Error::from);
+            let cycles = ledger::Cycles {
+                erase: 0,
+                eeprom: (memory == Memory::Eeprom) as u64,
+            };
+            target.record(chip, result, cycles)?
+        }
+        DeviceCommand::Backup { file } => {
+            let path = output_path(isp, chip, &file, None)?;
+            backup::Backup::read(isp, chip)?.save(&path)?
+        }
+        DeviceCommand::Info { sections } => info::report(isp, chip, &sections)?,
+        DeviceCommand::Config { format, output } => {
+            config::export(isp, chip, format, output.as_deref())?
+        }
+    }
+    Ok(())
+}
+
+/// Run job and save its report if requested. Report is saved also when job fails.
+///
+/// Injected counters are advanced only after success.
+fn run_job(
+    target: &Target,
+    isp: &mut dyn Session,
+    chip: &specs::Specs,
+    job: &job::Job,
+    path: Option<&Path>,
+) -> Result<(), Error> {
+    let mut report = report::Report::new(chip);
+    let result = job.firmware().and_then(|firmware| {
+        program::run(
+            isp,
+            chip,
+            job,
+            &firmware,
+            &mut report,
+            target.force,
+            target.policy().as_mut(),
+        )?;
+        job.commit()
+    });
+    if let Some(path) = path {
+        report.finish(&result);
+        report.save(path)?;
+    }
+    target.record(chip, result, report.cycles)
+}
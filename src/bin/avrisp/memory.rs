@@ -0,0 +1,170 @@
+use crate::error::Error;
+use avrisp::errors::ErrorKind;
+use avrisp::format::{self, FillOptions, Firmware, Image};
+use avrisp::programmer::{self, Event, Force, MemoryType, Session, Stats};
+use avrisp::specs;
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Memory {
+    Flash,
+    Eeprom,
+}
+
+impl Memory {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Memory::Flash => "flash",
+            Memory::Eeprom => "eeprom",
+        }
+    }
+
+    pub fn specs<'a>(&self, specs: &'a specs::Specs) -> &'a specs::Memory {
+        match self {
+            Memory::Flash => &specs.flash,
+            Memory::Eeprom => &specs.eeprom,
+        }
+    }
+
+    pub fn image<'a>(&self, firmware: &'a mut Firmware) -> &'a mut Image {
+        match self {
+            Memory::Flash => &mut firmware.flash,
+            Memory::Eeprom => &mut firmware.eeprom,
+        }
+    }
+}
+
+impl From<Memory> for MemoryType {
+    fn from(memory: Memory) -> MemoryType {
+        match memory {
+            Memory::Flash => MemoryType::Flash,
+            Memory::Eeprom => MemoryType::Eeprom,
+        }
+    }
+}
+
+/// Read `size` bytes from address 0. Size must be multiple of page size.
+pub fn read(isp: &mut dyn Session, memory: Memory, size: usize) -> Result<Image, ErrorKind> {
+    let mut bytes = vec![0; size];
+    let ((), stats) = Stats::measure(isp, size, |isp| isp.read(memory.into(), &mut bytes))?;
+    eprintln!("{} read: {}", memory.name(), stats);
+    Ok(Image::from(bytes))
+}
+
+/// Read `passes` times and keep majority value of each byte. Unstable addresses are reported.
+pub fn read_voted(
+    isp: &mut dyn Session,
+    memory: Memory,
+    size: usize,
+    passes: usize,
+) -> Result<Image, ErrorKind> {
+    let mut bytes = vec![0; size];
+    let (unstable, stats) = Stats::measure(isp, size * passes, |isp| {
+        programmer::read_voted(isp, memory.into(), &mut bytes, passes)
+    })?;
+    eprintln!("{} read {} times: {}", memory.name(), passes, stats);
+    if !unstable.is_empty() {
+        let shown: Vec<String> = unstable
+            .iter()
+            .take(8)
+            .map(|address| format!("{:#06x}", address))
+            .collect();
+        let more = if unstable.len() > shown.len() {
+            ", ..."
+        } else {
+            ""
+        };
+        eprintln!(
+            "warning: {} unstable addresses: {}{}",
+            unstable.len(),
+            shown.join(", "),
+            more
+        );
+    }
+    Ok(Image::from(bytes))
+}
+
+/// Write `image` from address 0. Gaps are written erased, flash pages holding only gap are
+/// skipped by programmers.
+pub fn write(isp: &mut dyn Session, memory: Memory, image: &Image) -> Result<(), ErrorKind> {
+    let bytes = image.to_bytes(0xff);
+    let ((), stats) = Stats::measure(isp, bytes.len(), |isp| isp.write(memory.into(), &bytes))?;
+    eprintln!("{} written: {}", memory.name(), stats);
+    Ok(())
+}
+
+/// Write flash `image` from address 0. Image overwriting resident bootloader is written only
+/// with `force`, see [`programmer::write_flash`].
+pub fn write_flash(
+    isp: &mut dyn Session,
+    chip: &specs::Specs,
+    image: &Image,
+    force: Option<Force>,
+) -> Result<(), ErrorKind> {
+    let bytes = image.to_bytes(0xff);
+    let ((), stats) = Stats::measure(isp, bytes.len(), |isp| {
+        programmer::write_flash(isp, chip, &bytes, force)
+    })?;
+    eprintln!("{} written: {}", Memory::Flash.name(), stats);
+    Ok(())
+}
+
+/// Change bytes at `address` keeping the rest of their pages.
+pub fn patch(
+    isp: &mut dyn Session,
+    memory: Memory,
+    address: usize,
+    bytes: &[u8],
+) -> Result<(), ErrorKind> {
+    isp.patch(memory.into(), address, bytes)
+}
+
+/// Make sure `image` fits into memory.
+pub fn check_size(chip: &specs::Specs, memory: Memory, image: &Image) -> Result<(), Error> {
+    let size = memory.specs(chip).size;
+    if image.end() > size {
+        return Err(Error::Usage(format!(
+            "Data is larger ({} bytes) than memory ({} bytes)",
+            image.end(),
+            size
+        )));
+    }
+    Ok(())
+}
+
+/// Whole pages holding first `size` bytes.
+pub fn pages(chip: &specs::Specs, memory: Memory, size: usize) -> usize {
+    let page_size = memory.specs(chip).page_size;
+    size.div_ceil(page_size) * page_size
+}
+
+/// Read back memory and compare it with `expected`. Gaps are not compared.
+pub fn verify(
+    isp: &mut dyn Session,
+    chip: &specs::Specs,
+    memory: Memory,
+    expected: &Image,
+) -> Result<(), Error> {
+    check_size(chip, memory, expected)?;
+    let found = read(isp, memory, pages(chip, memory, expected.end()))?.to_bytes(0xff);
+    for mismatch in expected.mismatches(&found) {
+        isp.emit(Event::VerifyMismatch {
+            memory: memory.into(),
+            mismatch,
+        });
+    }
+    Ok(expected.verify(&found, memory.specs(chip).page_size)?)
+}
+
+/// Load `memory` content from firmware file. EEPROM file may hold data at 0 (e.g.
+/// `avr-objcopy -j .eeprom` output or raw binary) or at EEPROM address used in ELF files.
+pub fn load(path: &Path, memory: Memory, options: &FillOptions) -> Result<Image, Error> {
+    let firmware = format::load_with(path, options)?;
+    Ok(match memory {
+        Memory::Eeprom if !firmware.eeprom.is_empty() => firmware.eeprom,
+        _ => firmware.flash,
+    })
+}
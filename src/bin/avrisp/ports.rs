@@ -0,0 +1,133 @@
+use crate::error::Error;
+use avrisp::ports;
+use avrisp::programmer::stk500v2::{self, LinkOptions};
+use avrisp::programmer::ProgrammerKind;
+use avrisp::specs;
+
+/// Print table of serial ports. With `probe` each port is asked for STK500v2 sign on.
+pub fn list(probe: bool) -> Result<(), Error> {
+    print!("{:<24} {:<9} {:<24}", "Port", "VID:PID", "Manufacturer");
+    if probe {
+        print!(" Programmer");
+    }
+    println!();
+    for port in ports::list()? {
+        let (id, manufacturer) = match &port.usb {
+            Some(usb) => (
+                format!("{:04x}:{:04x}", usb.vid, usb.pid),
+                usb.manufacturer.clone().unwrap_or_default(),
+            ),
+            None => (String::from("-"), String::new()),
+        };
+        print!("{:<24} {:<9} {:<24}", port.name, id, manufacturer);
+        if probe {
+            match stk500v2::probe(&port.name) {
+                Ok(variant) => print!(" {}", variant),
+                Err(_) => print!(" -"),
+            }
+        }
+        println!();
+    }
+    Ok(())
+}
+
+/// Which ports are scanned.
+#[derive(Clone, Copy, Default)]
+pub struct Filter {
+    /// Only USB serial adapters.
+    pub usb: bool,
+    /// Only USB adapters with this vendor id.
+    pub vid: Option<u16>,
+}
+
+impl Filter {
+    fn accepts(&self, port: &ports::PortInfo) -> bool {
+        match (&port.usb, self.vid) {
+            (Some(usb), Some(vid)) => usb.vid == vid,
+            (None, _) => !self.usb && self.vid.is_none(),
+            (Some(_), None) => true,
+        }
+    }
+}
+
+/// Sign on to STK500v2 programmer at every port and read signature of attached chip.
+///
+/// `specs` give ISP parameters used to read the signature.
+pub fn scan(filter: Filter, link: &LinkOptions, specs: &specs::Specs) -> Result<(), Error> {
+    println!("{:<24} {:<9} {:<12} Chip", "Port", "VID:PID", "Programmer");
+    for port in ports::list()?
+        .into_iter()
+        .filter(|port| filter.accepts(port))
+    {
+        let id = match &port.usb {
+            Some(usb) => format!("{:04x}:{:04x}", usb.vid, usb.pid),
+            None => String::from("-"),
+        };
+        let (programmer, chip) = match stk500v2::probe_with(&port.name, link) {
+            Ok(variant) => (variant.to_string(), target(&port.name, link, specs)),
+            Err(_) => (String::from("-"), String::from("-")),
+        };
+        println!("{:<24} {:<9} {:<12} {}", port.name, id, programmer, chip);
+    }
+    Ok(())
+}
+
+/// Signature of chip attached to programmer at `port`, or why it could not be read.
+fn target(port: &str, link: &LinkOptions, specs: &specs::Specs) -> String {
+    let kind = ProgrammerKind::Stk500v2 {
+        port: port.to_string(),
+        options: link.clone(),
+    };
+    let mut isp = match kind.connect(specs) {
+        Ok(isp) => isp,
+        Err(err) => return format!("no target ({})", err),
+    };
+    let chip = match isp.get_mcu_signature() {
+        Ok(signature) => signature.to_string(),
+        Err(err) => format!("no target ({})", err),
+    };
+    // Scan goes on with other ports also if this one misbehaves.
+    let _ = isp.finish();
+    chip
+}
+
+/// Parse USB vendor id. Hex with or without 0x prefix.
+pub fn parse_vid(value: &str) -> Result<u16, String> {
+    let digits = value.trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Invalid vendor id: {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(vid: Option<u16>) -> ports::PortInfo {
+        ports::PortInfo {
+            name: String::from("/dev/ttyUSB0"),
+            usb: vid.map(|vid| ports::UsbInfo {
+                vid,
+                pid: 0x6001,
+                manufacturer: None,
+                product: None,
+                serial_number: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn usb_filter() {
+        let all = Filter::default();
+        assert!(all.accepts(&port(None)) && all.accepts(&port(Some(0x0403))));
+        let usb = Filter {
+            usb: true,
+            vid: None,
+        };
+        assert!(!usb.accepts(&port(None)) && usb.accepts(&port(Some(0x0403))));
+        let ftdi = Filter {
+            usb: false,
+            vid: Some(parse_vid("0x0403").unwrap()),
+        };
+        assert!(ftdi.accepts(&port(Some(0x0403))));
+        assert!(!ftdi.accepts(&port(Some(0x1a86))) && !ftdi.accepts(&port(None)));
+    }
+}
@@ -0,0 +1,451 @@
+use crate::error::Error;
+use crate::job::{Job, Step};
+use crate::memory::{self, Memory};
+use crate::report::Report;
+use avrisp::errors::ErrorKind;
+use avrisp::format::{Firmware, Image};
+use avrisp::programmer::{self, Action, Confirm, Force, Session};
+use avrisp::specs;
+
+/// Lock bits LB2 and LB1. Both programmed (0) disable reading memories over ISP.
+const LB_MASK: u8 = 0x03;
+
+/// Execute job with already loaded `firmware`, recording details in `report`.
+///
+/// With `force` flash image may overwrite resident bootloader. Erase, fuses and lock byte are
+/// written only after `confirm` approves them.
+pub fn run(
+    isp: &mut dyn Session,
+    chip: &specs::Specs,
+    job: &Job,
+    firmware: &Firmware,
+    report: &mut Report,
+    force: bool,
+    confirm: &mut dyn Confirm,
+) -> Result<(), Error> {
+    report.connected(isp)?;
+    let result = steps(isp, chip, job, firmware, report, force, confirm);
+    // Values are reported also after failure, if target still answers.
+    let read = report.final_values(isp, chip);
+    result.and(read)
+}
+
+/// Execute job steps in order. Steps without data are skipped.
+fn steps(
+    isp: &mut dyn Session,
+    chip: &specs::Specs,
+    job: &Job,
+    firmware: &Firmware,
+    report: &mut Report,
+    force: bool,
+    confirm: &mut dyn Confirm,
+) -> Result<(), Error> {
+    let fuses = job.fuses();
+    // Refuse before anything is written.
+    if job.secure {
+        check_secure(job)?;
+    }
+    if let Some(fuses) = &fuses {
+        // Checked again by programmer::write_fuses.
+        fuses.check_safe(chip)?;
+    }
+    report.images(firmware);
+    let memories = [
+        (Memory::Flash, &firmware.flash),
+        (Memory::Eeprom, &firmware.eeprom),
+    ];
+    for (memory, image) in memories.iter() {
+        memory::check_size(chip, *memory, image)?;
+    }
+    // Flash is checked again by programmer::write_flash, against fuses in chip.
+    let mut flash_force = None;
+    if job.steps.contains(&Step::Flash) && !firmware.flash.is_empty() {
+        // Fuses written by the job decide where chip starts. Refused before erase.
+        let boot_fuses = match &fuses {
+            Some(fuses) => *fuses,
+            None => isp.get_fuses()?,
+        };
+        match boot_fuses.check_boot_section(chip, &firmware.flash.to_bytes(0xff)) {
+            Err(err @ ErrorKind::BootSection { .. }) if force => {
+                eprintln!("warning: {}. Continuing because of --force", err);
+                flash_force = Some(Force::accept_risk());
+            }
+            result => result?,
+        }
+        // Chip may still have other fuses when flash is written, job ones were checked above.
+        if fuses.is_some() {
+            flash_force = Some(Force::accept_risk());
+        }
+    }
+    // With keep going the first failure is returned after remaining memories are written.
+    let mut failed = None;
+    for step in &job.steps {
+        if failed.is_some() && !step.writes_memory() {
+            report
+                .failures
+                .push(format!("{}: skipped after failure", step.name()));
+            continue;
+        }
+        match run_step(
+            isp,
+            chip,
+            job,
+            *step,
+            firmware,
+            flash_force,
+            report,
+            confirm,
+        ) {
+            Err(err) if job.keep_going && step.writes_memory() => {
+                report.failures.push(format!("{}: {}", step.name(), err));
+                failed.get_or_insert(err);
+            }
+            result => result?,
+        }
+    }
+    failed.map_or(Ok(()), Err)
+}
+
+/// Execute one job step. Flash is written over resident bootloader only with `flash_force`.
+#[allow(clippy::too_many_arguments)]
+fn run_step(
+    isp: &mut dyn Session,
+    chip: &specs::Specs,
+    job: &Job,
+    step: Step,
+    firmware: &Firmware,
+    flash_force: Option<Force>,
+    report: &mut Report,
+    confirm: &mut dyn Confirm,
+) -> Result<(), Error> {
+    match step {
+        Step::Erase => {
+            let saved = if job.preserve_eeprom {
+                eeprom_backup(isp, chip)?
+            } else {
+                None
+            };
+            programmer::ask(confirm, Action::Erase)?;
+            isp.erase()?;
+            report.cycles.erase += 1;
+            // Restored right away, so EEPROM is kept also when a later step fails.
+            if let Some(image) = saved {
+                write(isp, chip, Memory::Eeprom, &image, None, job.verify, report)?;
+            }
+        }
+        Step::Flash => write(
+            isp,
+            chip,
+            Memory::Flash,
+            &firmware.flash,
+            flash_force,
+            job.verify,
+            report,
+        )?,
+        Step::Eeprom => write(
+            isp,
+            chip,
+            Memory::Eeprom,
+            &firmware.eeprom,
+            None,
+            job.verify,
+            report,
+        )?,
+        Step::Osccal => {
+            if let Some(osccal) = job.osccal {
+                store_osccal(isp, chip, osccal.memory, osccal.address, job.verify, report)?;
+            }
+        }
+        Step::Fuses => {
+            if let Some(fuses) = &job.fuses() {
+                programmer::ask(confirm, Action::WriteFuses(*fuses))?;
+                // Programmer reads fuses back and compares them.
+                let result = programmer::write_fuses(isp, chip, fuses, None);
+                match &result {
+                    Err(ErrorKind::FuseVerifyError { .. }) => report.verified("fuses", false),
+                    Ok(_) if job.verify => report.verified("fuses", true),
+                    _ => (),
+                }
+                result?;
+            }
+        }
+        Step::Lock => {
+            if let Some(lock) = job.lock {
+                programmer::ask(confirm, Action::WriteLock(lock))?;
+                // Programmer reads lock byte back and compares it.
+                let result = isp.set_lock_byte(lock);
+                match &result {
+                    Err(ErrorKind::LockVerifyError { .. }) => report.verified("lock", false),
+                    Ok(_) if job.verify => report.verified("lock", true),
+                    _ => (),
+                }
+                result?;
+                if job.secure {
+                    confirm_locked(isp, firmware, report)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write `image`, flash over resident bootloader only with `force`.
+fn write(
+    isp: &mut dyn Session,
+    chip: &specs::Specs,
+    memory: Memory,
+    image: &Image,
+    force: Option<Force>,
+    verify: bool,
+    report: &mut Report,
+) -> Result<(), Error> {
+    if image.is_empty() {
+        return Ok(());
+    }
+    match memory {
+        Memory::Flash => memory::write_flash(isp, chip, image, force)?,
+        Memory::Eeprom => memory::write(isp, memory, image)?,
+    }
+    if memory == Memory::Eeprom {
+        report.cycles.eeprom += 1;
+    }
+    if verify {
+        let result = memory::verify(isp, chip, memory, image);
+        report.compared(memory.name(), &result);
+        result?;
+    }
+    Ok(())
+}
+
+/// Copy calibration byte to `address`, where firmware loads it from.
+fn store_osccal(
+    isp: &mut dyn Session,
+    chip: &specs::Specs,
+    memory: Memory,
+    address: usize,
+    verify: bool,
+    report: &mut Report,
+) -> Result<(), Error> {
+    let osccal = programmer::store_osccal(isp, memory.into(), address)?;
+    if memory == Memory::Eeprom {
+        report.cycles.eeprom += 1;
+    }
+    if verify {
+        // Memory is read from its start in whole pages.
+        let found = memory::read(isp, memory, memory::pages(chip, memory, address + 1))?;
+        let result = check_readback("OSCCAL", osccal, found.get(address).unwrap_or(0xff));
+        report.verified("osccal", result.is_ok());
+        result?;
+    }
+    Ok(())
+}
+
+/// Secure job must end with lock byte which disables reading back, verifying all before it.
+fn check_secure(job: &Job) -> Result<(), Error> {
+    let lock = match job.lock {
+        Some(lock) if job.steps.last() == Some(&Step::Lock) => lock,
+        _ => {
+            return Err(Error::Usage(String::from(
+                "Secure job needs lock byte written as the last step",
+            )))
+        }
+    };
+    if lock & LB_MASK != 0 {
+        return Err(Error::Usage(format!(
+            "Lock byte {:#04x} does not disable reading back. Clear lock bits LB1 and LB2, \
+             e.g. {:#04x}",
+            lock,
+            lock & !LB_MASK
+        )));
+    }
+    if !job.verify {
+        return Err(Error::Usage(String::from(
+            "Secure job verifies memories before locking. Do not disable verify",
+        )));
+    }
+    Ok(())
+}
+
+/// Check that memories written by the job no longer read back as written. Memories written
+/// erased can not be told from locked ones and are left out of the report.
+fn confirm_locked(
+    isp: &mut dyn Session,
+    firmware: &Firmware,
+    report: &mut Report,
+) -> Result<(), Error> {
+    let memories = [
+        (Memory::Flash, &firmware.flash),
+        (Memory::Eeprom, &firmware.eeprom),
+    ];
+    for (memory, image) in memories.iter().filter(|(_, image)| !image.is_empty()) {
+        let name = format!("{} locked", memory.name());
+        match programmer::confirm_locked(isp, (*memory).into(), image) {
+            Ok(true) => report.verified(&name, true),
+            Ok(false) => eprintln!(
+                "{} holds only erased bytes, locking can not be confirmed",
+                memory.name()
+            ),
+            Err(err @ ErrorKind::NotLocked { .. }) => {
+                report.verified(&name, false);
+                return Err(err.into());
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+/// EEPROM content to write back after chip erase. None if EESAVE fuse keeps it anyway.
+fn eeprom_backup(isp: &mut dyn Session, chip: &specs::Specs) -> Result<Option<Image>, Error> {
+    if let Some(bits) = chip.fuse_bits.iter().find(|bits| bits.name == "EESAVE") {
+        // Fuse bits are active low.
+        if bits.value(isp.get_fuses()?.get(bits.fuse)) == 0 {
+            return Ok(None);
+        }
+    }
+    let mut image = memory::read(isp, Memory::Eeprom, chip.eeprom.size)?;
+    // Erased tail does not need to be written.
+    image.trim(0xff);
+    Ok(Some(image))
+}
+
+fn check_readback(name: &str, expected: u8, found: u8) -> Result<(), Error> {
+    if expected != found {
+        return Err(Error::Verify(format!(
+            "{} verification failed. Expected {:#04x} found {:#04x}",
+            name, expected, found
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::job::Fuses;
+    use avrisp::command;
+    use avrisp::programmer::isp;
+    use std::convert::TryInto;
+
+    /// Target keeping EEPROM and lock byte, whose flash page writes fail.
+    struct BrokenFlash {
+        eeprom: Vec<u8>,
+        lock: u8,
+    }
+
+    impl isp::Bus for BrokenFlash {
+        fn transfer(&mut self, data: &mut [u8]) -> Result<(), ErrorKind> {
+            for instruction in data.chunks_mut(4) {
+                let address = usize::from(u16::from_be_bytes([instruction[1], instruction[2]]));
+                let cmd = (instruction[0], instruction[1]);
+                if cmd == (command::PROGRAMMING_ENABLE.0, command::PROGRAMMING_ENABLE.1) {
+                    instruction[2] = command::PROGRAMMING_ENABLE.1;
+                } else if cmd == (command::WRITE_LOCK.0, command::WRITE_LOCK.1) {
+                    self.lock = instruction[3];
+                } else if cmd.0 == command::WRITE_FLASH.0 {
+                    return Err(ErrorKind::StatusError);
+                } else if cmd.0 == command::WRITE_EEPROM.0 {
+                    self.eeprom[address] = instruction[3];
+                } else if cmd.0 == command::READ_EEPROM.0 {
+                    instruction[3] = self.eeprom[address];
+                } else {
+                    instruction[3] = 0xff;
+                }
+            }
+            Ok(())
+        }
+
+        fn reset(&mut self, _active: bool) -> Result<(), ErrorKind> {
+            Ok(())
+        }
+
+        fn name(&self) -> &'static str {
+            "broken flash"
+        }
+    }
+
+    #[test]
+    fn keep_going_writes_eeprom_after_failed_flash() {
+        let chip = &specs::atmega::ATMEGA_32;
+        let bus = BrokenFlash {
+            eeprom: vec![0xff; chip.eeprom.size],
+            lock: 0xff,
+        };
+        let mut isp: isp::IspMode<BrokenFlash> =
+            isp::Isp::new(bus, chip.clone()).try_into().unwrap();
+        let job = Job {
+            fuses: Some(Fuses {
+                low: 0xe1,
+                high: 0x99,
+                extended: None,
+            }),
+            lock: Some(0x3c),
+            keep_going: true,
+            ..Job::default()
+        };
+        let firmware = Firmware {
+            flash: Image::from(vec![1, 2, 3]),
+            eeprom: Image::from(vec![4, 5, 6]),
+        };
+        let mut report = Report::new(chip);
+        let result = run(
+            &mut isp,
+            chip,
+            &job,
+            &firmware,
+            &mut report,
+            false,
+            &mut programmer::Approve,
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Programmer(ErrorKind::StatusError))
+        ));
+        assert_eq!(report.failures.len(), 3);
+        assert!(report.failures[0].starts_with("flash: "));
+        assert_eq!(
+            report.failures[1..],
+            [
+                "fuses: skipped after failure",
+                "lock: skipped after failure"
+            ]
+        );
+        assert_eq!(report.verify.len(), 1);
+        assert_eq!(report.verify[0].memory, "eeprom");
+        assert!(report.verify[0].passed);
+        let bus = isp.programmer().bus();
+        assert_eq!(bus.eeprom[..3], [4, 5, 6]);
+        assert_eq!(bus.lock, 0xff);
+    }
+
+    #[test]
+    fn secure_job_needs_readout_lock() {
+        let job = Job {
+            lock: Some(0x3c),
+            secure: true,
+            ..Job::default()
+        };
+        assert!(check_secure(&job).is_ok());
+        let err = check_secure(&Job {
+            lock: Some(0x3e),
+            ..job.clone()
+        });
+        assert!(err.unwrap_err().to_string().contains("0x3c"));
+        assert!(check_secure(&Job {
+            lock: None,
+            ..job.clone()
+        })
+        .is_err());
+        let steps = vec![Step::Lock, Step::Flash];
+        assert!(check_secure(&Job {
+            steps,
+            ..job.clone()
+        })
+        .is_err());
+        assert!(check_secure(&Job {
+            verify: false,
+            ..job
+        })
+        .is_err());
+    }
+}
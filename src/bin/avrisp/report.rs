@@ -0,0 +1,203 @@
+//! Machine readable report of a programming job, for manufacturing traceability records.
+use crate::error::Error;
+use crate::hex;
+use crate::job::Fuses;
+use crate::ledger::Cycles;
+use avrisp::errors::{ErrorKind, Mismatch};
+use avrisp::format::Firmware;
+use avrisp::programmer::Session;
+use avrisp::specs;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+#[derive(Serialize)]
+pub struct Programmer {
+    pub variant: String,
+    pub hardware: Option<u8>,
+    pub firmware: Option<String>,
+}
+
+/// Image written to a memory.
+#[derive(Serialize)]
+pub struct Image {
+    pub memory: String,
+    pub size: usize,
+    pub sha256: String,
+}
+
+/// Result of reading back and comparing written data.
+#[derive(Serialize)]
+pub struct Verify {
+    pub memory: String,
+    pub passed: bool,
+    /// First few mismatching bytes of failed memory verify.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mismatches: Vec<Mismatch>,
+    /// Number of all mismatching bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mismatched: Option<usize>,
+}
+
+#[derive(Serialize, Default)]
+pub struct Report {
+    pub started: String,
+    pub finished: String,
+    pub passed: bool,
+    pub error: Option<String>,
+    pub chip: String,
+    pub programmer: Option<Programmer>,
+    pub signature: Option<String>,
+    pub images: Vec<Image>,
+    pub verify: Vec<Verify>,
+    /// Errors of steps passed over with keep going, and steps skipped because of them.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub failures: Vec<String>,
+    /// Fuses read after the job.
+    pub fuses: Option<Fuses>,
+    /// Lock byte read after the job.
+    pub lock: Option<u8>,
+    /// Erase and EEPROM write cycles done by the job.
+    pub cycles: Cycles,
+}
+
+fn now() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()
+}
+
+impl Report {
+    pub fn new(chip: &specs::Specs) -> Report {
+        Report {
+            started: now(),
+            chip: String::from(chip.name),
+            ..Report::default()
+        }
+    }
+
+    /// Record programmer and target details.
+    pub fn connected(&mut self, isp: &mut dyn Session) -> Result<(), Error> {
+        let identity = isp.identify()?;
+        self.programmer = Some(Programmer {
+            variant: identity.name,
+            hardware: identity.hardware,
+            firmware: identity.firmware.map(|version| version.to_string()),
+        });
+        self.signature = Some(format!("{:#}", isp.get_mcu_signature()?));
+        Ok(())
+    }
+
+    pub fn images(&mut self, firmware: &Firmware) {
+        let images = [("flash", &firmware.flash), ("eeprom", &firmware.eeprom)];
+        for (memory, image) in images.iter().filter(|(_, image)| !image.is_empty()) {
+            // Gaps are hashed as erased bytes.
+            self.images.push(Image {
+                memory: memory.to_string(),
+                size: image.end(),
+                sha256: hex::encode(&Sha256::digest(image.to_bytes(0xff))),
+            });
+        }
+    }
+
+    pub fn verified(&mut self, memory: &str, passed: bool) {
+        self.verify.push(Verify {
+            memory: String::from(memory),
+            passed,
+            mismatches: Vec::new(),
+            mismatched: None,
+        });
+    }
+
+    /// Record result of memory verify, with mismatching bytes if it failed.
+    pub fn compared(&mut self, memory: &str, result: &Result<(), Error>) {
+        self.verified(memory, result.is_ok());
+        if let Err(Error::Programmer(ErrorKind::VerifyError {
+            mismatches, count, ..
+        })) = result
+        {
+            let verify = self.verify.last_mut().expect("just pushed");
+            verify.mismatches = mismatches.clone();
+            verify.mismatched = Some(*count);
+        }
+    }
+
+    /// Record final fuses and lock byte.
+    pub fn final_values(
+        &mut self,
+        isp: &mut dyn Session,
+        chip: &specs::Specs,
+    ) -> Result<(), Error> {
+        let fuses = isp.get_fuses()?;
+        self.fuses = Some(Fuses::of_chip(&fuses, chip));
+        self.lock = Some(isp.get_lock_byte()?);
+        Ok(())
+    }
+
+    pub fn finish(&mut self, result: &Result<(), Error>) {
+        self.finished = now();
+        self.passed = result.is_ok();
+        self.error = result.as_ref().err().map(|err| err.to_string());
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let json = serde_json::to_string_pretty(self).expect("report is always serializable");
+        fs::write(path, json + "\n")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_hashes() {
+        let firmware = Firmware {
+            flash: b"abc".to_vec().into(),
+            eeprom: Default::default(),
+        };
+        let mut report = Report::default();
+        report.images(&firmware);
+        assert!(serde_json::to_value(&report)
+            .unwrap()
+            .get("failures")
+            .is_none());
+        assert_eq!(report.images.len(), 1);
+        assert_eq!(report.images[0].memory, "flash");
+        assert_eq!(
+            report.images[0].sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn verify_lists_mismatches() {
+        let mut report = Report::default();
+        report.compared("flash", &Ok(()));
+        let mismatch = Mismatch {
+            address: 0x81,
+            expected: 0x0c,
+            found: 0xff,
+        };
+        let failed = ErrorKind::VerifyError {
+            page: 1,
+            mismatches: vec![mismatch],
+            count: 3,
+        };
+        report.compared("eeprom", &Err(failed.into()));
+        let json = serde_json::to_value(&report.verify).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([
+                { "memory": "flash", "passed": true },
+                {
+                    "memory": "eeprom",
+                    "passed": false,
+                    "mismatches": [{ "address": 0x81, "expected": 0x0c, "found": 0xff }],
+                    "mismatched": 3
+                }
+            ])
+        );
+    }
+}
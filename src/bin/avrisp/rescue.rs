@@ -0,0 +1,160 @@
+//! Guided recovery of chip which does not answer ISP.
+//!
+//! Failure mode is found step by step: programmer sign on, target voltage, signature at any SCK.
+//! Chip running from a slow clock (e.g. CKDIV8 with 128 kHz oscillator) answers at slow SCK.
+//! Chip fused for external clock which is not there is clocked from STK500 oscillator output.
+//! Either way fuses can then be set back to factory values. Otherwise high voltage programming
+//! is suggested.
+use crate::debugwire;
+use crate::doctor;
+use crate::error::Error;
+use avrisp::errors::ErrorKind;
+use avrisp::programmer::stk500v2::{self, IspMode, LinkOptions, STK500v2};
+use avrisp::programmer::{self, AVRFuse, AVRFuseGet, Action, Confirm, MCUSignature};
+use avrisp::specs;
+use std::io::{self, BufRead};
+
+/// ISP entries tried at rescue SCK. Target may need some time to start on the new clock.
+const ATTEMPTS: usize = 5;
+/// Signature reads at each SCK when looking for a slow target.
+const READS: usize = 2;
+
+/// Run recovery steps in order until chip answers, then write factory fuses once `confirm`
+/// approves them. Rescue clock runs at `clock` Hz.
+pub fn run(
+    port: &str,
+    link: &LinkOptions,
+    chip: &specs::Specs,
+    clock: u32,
+    confirm: &mut dyn Confirm,
+) -> Result<(), Error> {
+    let mut stk = match STK500v2::open_with(port, link, chip.clone()) {
+        Ok(stk) => stk,
+        Err(err) => {
+            return Err(doctor::fail(
+                "Sign on",
+                err,
+                "No answer from programmer. Run `avrisp doctor` to check the connection",
+            ))
+        }
+    };
+    doctor::pass("Sign on", "ok");
+    doctor::check_vtarget(&mut stk)?;
+    let trials = stk500v2::sweep_sck(stk, &stk500v2::SWEEP_SCK, READS)?;
+    let stk = STK500v2::open_with(port, link, chip.clone())?;
+    if let Some(trial) = trials.iter().find(|trial| trial.matched > 0) {
+        let sck = stk500v2::sck_frequency(trial.duration) / 1000;
+        doctor::pass("Signature", format!("at {} kHz SCK", sck));
+        let mut isp = stk500v2::enter_slow(stk, trial.duration, ATTEMPTS)?;
+        let result = recover(&mut isp, chip, confirm);
+        // Failed recovery tells more than failed leave.
+        let left = isp.leave();
+        result?;
+        left?;
+        return Ok(());
+    }
+    doctor::report_fail(
+        "Signature",
+        "no answer at any SCK",
+        "Chip may be fused for external clock which is not there",
+    );
+    if rescue_clock(stk, chip, clock, confirm)? {
+        return Ok(());
+    }
+    let mut hint = String::from(
+        "RESET may be disabled (RSTDISBL) or SPI programming disabled (SPIEN). Such chip needs \
+         high voltage programming: HVSP for 8 and 14 pin ATtiny, parallel programming for others",
+    );
+    if debugwire::dwen(chip).is_ok() {
+        hint.push_str(
+            ". If DWEN was programmed, disable debugWIRE with debugWIRE capable hardware",
+        );
+    }
+    Err(doctor::fail("Rescue", "ISP does not answer", &hint))
+}
+
+/// Clock target from STK500 oscillator output and recover it. False if it still does not
+/// answer, programmer has no clock output or user skipped the step.
+fn rescue_clock(
+    mut stk: STK500v2,
+    chip: &specs::Specs,
+    clock: u32,
+    confirm: &mut dyn Confirm,
+) -> Result<bool, Error> {
+    let clock = match stk.set_oscillator(clock) {
+        Ok(clock) => clock,
+        Err(ErrorKind::StatusError) => {
+            println!("[SKIP] Rescue clock: programmer has no clock output");
+            return Ok(false);
+        }
+        Err(err) => return Err(err.into()),
+    };
+    println!("Clock output started at {} Hz.", clock);
+    println!(
+        "Connect it to XTAL1 (CLKI) of the target: on STK500 put OSCSEL jumper on pins 1-2 and \
+         mount XTAL1 jumper, on other boards wire STK500 OSC pin to XTAL1."
+    );
+    println!("Press Enter when connected, or type skip.");
+    match io::stdin().lock().lines().next().transpose()? {
+        Some(line) if line.trim() != "skip" => (),
+        // Skipped or end of input.
+        _ => {
+            stk.set_oscillator(0)?;
+            println!("[SKIP] Rescue clock");
+            return Ok(false);
+        }
+    }
+    // Eighth of clock leaves margin for inaccurate clock output.
+    let duration = stk500v2::sck_below(clock, 8);
+    let mut stopped = false;
+    let result = match stk500v2::enter_slow(stk, duration, ATTEMPTS) {
+        Ok(mut isp) => {
+            let result = clocked_recover(&mut isp, chip, duration, confirm);
+            // Recovered chip runs from its internal oscillator.
+            let left = isp.leave().and_then(|mut stk| stk.set_oscillator(0));
+            stopped = left.is_ok();
+            result.and_then(|answered| left.map(|_| answered).map_err(Error::from))
+        }
+        Err(err) => Err(err.into()),
+    };
+    if stopped {
+        println!("Clock output stopped. Disconnect it from the target.");
+    } else {
+        println!(
+            "Clock output may still be running. Disconnect it from the target and power cycle \
+             the programmer."
+        );
+    }
+    result
+}
+
+/// Recover target clocked by programmer. False if it does not answer.
+fn clocked_recover(
+    isp: &mut IspMode,
+    chip: &specs::Specs,
+    duration: u8,
+    confirm: &mut dyn Confirm,
+) -> Result<bool, Error> {
+    let step = format!("Rescue clock, {} Hz SCK", stk500v2::sck_frequency(duration));
+    if !isp.get_mcu_signature()?.matches(chip) {
+        doctor::report_fail(
+            &step,
+            "no answer",
+            "Check clock wiring or try another --clock",
+        );
+        return Ok(false);
+    }
+    doctor::pass(&step, "target answers");
+    recover(isp, chip, confirm)?;
+    Ok(true)
+}
+
+/// Print fuses and write factory ones.
+fn recover(isp: &mut IspMode, chip: &specs::Specs, confirm: &mut dyn Confirm) -> Result<(), Error> {
+    println!("Fuses: {}", isp.get_fuses()?);
+    let defaults = AVRFuse::defaults(chip);
+    programmer::ask(confirm, Action::WriteFuses(defaults))?;
+    programmer::write_fuses(isp, chip, &defaults, None)?;
+    doctor::pass("Factory fuses", defaults);
+    Ok(())
+}
@@ -0,0 +1,17 @@
+//! Memory usage of a firmware image, similar to `avr-size -C`.
+use crate::error::Error;
+use avrisp::format::{self, Usage};
+use avrisp::specs;
+use std::path::Path;
+
+/// Print flash and EEPROM usage of `file` on `chip`. Image larger than memory is
+/// reported with negative free space.
+pub fn run(file: &Path, chip: &specs::Specs) -> Result<(), Error> {
+    let firmware = format::load(file)?;
+    println!("Device: {}", chip.name);
+    println!("Flash:  {}", Usage::new(&firmware.flash, chip.flash.size));
+    if !firmware.eeprom.is_empty() {
+        println!("EEPROM: {}", Usage::new(&firmware.eeprom, chip.eeprom.size));
+    }
+    Ok(())
+}
@@ -0,0 +1,105 @@
+//! Output file name templates, e.g. `{chip}_{signature}_{date}_flash.hex`.
+//!
+//! Fields:
+//! * `chip` chip name
+//! * `signature` signature bytes as hex digits
+//! * `date` and `time` in UTC, as `2024-01-31` and `235959`
+//! * `memory` memory name, for commands reading single memory
+use crate::error::Error;
+use avrisp::specs::Signature;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Values of template fields.
+pub struct Fields<'a> {
+    pub chip: &'a str,
+    pub signature: Signature,
+    pub memory: Option<&'a str>,
+    pub now: SystemTime,
+}
+
+impl Fields<'_> {
+    fn value(&self, name: &str) -> Option<String> {
+        // E.g. 2024-01-31T23:59:59Z
+        let now = humantime::format_rfc3339_seconds(self.now).to_string();
+        let (sig0, sig1, sig2) = self.signature.bytes;
+        Some(match name {
+            "chip" => self.chip.to_string(),
+            "signature" => format!("{:02x}{:02x}{:02x}", sig0, sig1, sig2),
+            "date" => now[..10].to_string(),
+            "time" => now[11..19].replace(':', ""),
+            "memory" => self.memory?.to_string(),
+            _ => return None,
+        })
+    }
+}
+
+/// True if `path` has fields to fill in.
+pub fn is_template(path: &Path) -> bool {
+    path.to_string_lossy().contains('{')
+}
+
+/// Replace `{field}` in `path` with its value.
+pub fn expand(path: &Path, fields: &Fields) -> Result<PathBuf, Error> {
+    let template = path
+        .to_str()
+        .ok_or_else(|| Error::Usage(format!("Invalid file name: {}", path.display())))?;
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| Error::Usage(format!("Unclosed {{ in file name: {}", template)))?;
+        let name = &rest[start + 1..end];
+        let value = fields.value(name).ok_or_else(|| {
+            Error::Usage(format!(
+                "Unknown file name field {{{}}}. Use chip, signature, date, time or memory",
+                name
+            ))
+        })?;
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(PathBuf::from(expanded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn fields() -> Fields<'static> {
+        Fields {
+            chip: "ATmega32",
+            signature: Signature::from([0x1e, 0x95, 0x02]),
+            memory: Some("flash"),
+            // 2024-01-31T23:59:59Z
+            now: SystemTime::UNIX_EPOCH + Duration::from_secs(1_706_745_599),
+        }
+    }
+
+    #[test]
+    fn fields_are_filled_in() {
+        let path = Path::new("dumps/{chip}_{signature}_{date}_{time}_{memory}.hex");
+        assert!(is_template(path));
+        assert_eq!(
+            expand(path, &fields()).unwrap(),
+            Path::new("dumps/ATmega32_1e9502_2024-01-31_235959_flash.hex")
+        );
+        assert!(!is_template(Path::new("flash.bin")));
+    }
+
+    #[test]
+    fn unknown_field() {
+        assert!(expand(Path::new("{board}.hex"), &fields()).is_err());
+        assert!(expand(Path::new("{chip.hex"), &fields()).is_err());
+        let fields = Fields {
+            memory: None,
+            ..fields()
+        };
+        assert!(expand(Path::new("{memory}.bin"), &fields).is_err());
+    }
+}
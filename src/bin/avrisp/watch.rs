@@ -0,0 +1,40 @@
+use crate::error::Error;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Call `program` each time `file` is modified. Runs until process is terminated.
+///
+/// Errors are reported and watching continues, so a failed build does not end the session.
+pub fn run<F>(file: &Path, mut program: F) -> !
+where
+    F: FnMut() -> Result<(), Error>,
+{
+    let mut last = None;
+    loop {
+        let modified = wait_for_change(file, last);
+        // Give compiler a moment to finish writing the file.
+        thread::sleep(POLL_INTERVAL);
+        last = Some(modified);
+        match program() {
+            Ok(()) => eprintln!("{}: programmed", file.display()),
+            Err(err) => eprintln!("{}: error: {}", file.display(), err),
+        }
+    }
+}
+
+/// Block until modification time of `file` differs from `last`.
+fn wait_for_change(file: &Path, last: Option<SystemTime>) -> SystemTime {
+    loop {
+        // File may be missing for a moment while being rebuilt.
+        if let Ok(modified) = fs::metadata(file).and_then(|meta| meta.modified()) {
+            if Some(modified) != last {
+                return modified;
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
@@ -1,6 +1,30 @@
+use crate::specs::{Fuse, MemoryType, Signature};
 use std::error::Error;
 use std::fmt;
 use std::io;
+use std::time::Duration;
+
+/// Vtarget below this, in tenths of volt, means target is not powered at all.
+const NO_POWER: u8 = 5;
+
+/// Byte which reads back different from expected one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Mismatch {
+    pub address: usize,
+    pub expected: u8,
+    pub found: u8,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#06x} expected {:#04x} found {:#04x}",
+            self.address, self.expected, self.found
+        )
+    }
+}
 
 #[derive(Debug, PartialEq)]
 pub struct ChecksumError;
@@ -84,16 +108,259 @@ pub enum ErrorKind {
     SequenceError,
     ChecksumError,
     Io(io::Error),
+    /// Programmer did not answer in time.
+    Timeout {
+        /// What was in flight, e.g. `command 0x13` or `flash page at 0x1c00`.
+        operation: String,
+        elapsed: Duration,
+    },
     FromUtf8Error,
     UnknownProgrammer,
+    /// Connected chip is not the one given in specs.
+    SignatureMismatch {
+        expected: Signature,
+        found: Signature,
+    },
+    /// Memory content differs from expected one.
+    VerifyError {
+        /// Page of the first mismatch.
+        page: usize,
+        /// First few mismatches, in address order.
+        mismatches: Vec<Mismatch>,
+        /// Number of all mismatching bytes.
+        count: usize,
+    },
+    /// Fuse read back after writing differs from written one.
+    FuseVerifyError {
+        fuse: Fuse,
+        wrote: u8,
+        read: u8,
+    },
+    /// Lock byte read back after writing differs from written one.
+    LockVerifyError {
+        wrote: u8,
+        read: u8,
+    },
+    /// Requested lock byte is less restrictive than current one.
+    LockNeedsErase {
+        current: u8,
+        requested: u8,
+    },
+    /// Memory still reads back as written after lock bits were set.
+    NotLocked {
+        memory: MemoryType,
+    },
+    /// Programmer did not confirm that page write finished.
+    PageWriteFailed {
+        memory: MemoryType,
+        address: usize,
+        page: usize,
+    },
+    /// Fuse value programs bits which have no function.
+    ReservedFuseBits {
+        fuse: Fuse,
+        value: u8,
+        mask: u8,
+    },
+    /// Refused to write fuses which could make chip unusable.
+    UnsafeFuses,
+    /// User did not confirm destructive action.
+    Declined(String),
+    /// File content can not be parsed.
+    FileFormat(String),
+    /// Address is outside of memory.
+    AddressOutOfRange {
+        address: usize,
+        size: usize,
+    },
+    /// Address range ends before it starts.
+    InvalidRange {
+        start: usize,
+        end: usize,
+    },
+    /// Flash bits can only be cleared without chip erase.
+    NotErased {
+        address: usize,
+    },
+    /// Operation or chip can not be handled by programmer.
+    Unsupported(String),
+    /// Image without bootloader would overwrite resident one.
+    BootSection {
+        address: usize,
+    },
+    /// Voltage measured on target is outside its supply range, in tenths of volt.
+    TargetVoltage {
+        tenths: u8,
+        min: u8,
+        max: u8,
+    },
+    /// Programmer answer is shorter than the command requires.
+    AnswerLength {
+        expected: usize,
+        found: usize,
+    },
+    /// Frame received from programmer has invalid header or size.
+    MalformedFrame,
+    /// Command body does not fit into one message.
+    BodyTooLarge {
+        size: usize,
+        max: usize,
+    },
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::AnswerIdError => AnswerIdError.fmt(f),
+            ErrorKind::StatusError => StatusError.fmt(f),
+            ErrorKind::SequenceError => SequenceError.fmt(f),
+            ErrorKind::ChecksumError => ChecksumError.fmt(f),
+            ErrorKind::Io(err) => write!(f, "IO error: {}", err),
+            ErrorKind::Timeout { operation, elapsed } => write!(
+                f,
+                "Programmer stopped responding at {} after {:.1} s",
+                operation,
+                elapsed.as_secs_f64()
+            ),
+            ErrorKind::FromUtf8Error => write!(f, "Invalid UTF-8 string"),
+            ErrorKind::UnknownProgrammer => UnknownProgrammer.fmt(f),
+            ErrorKind::SignatureMismatch { expected, found } => write!(
+                f,
+                "Signature missmatch. Expected {} found {}",
+                expected, found
+            ),
+            ErrorKind::VerifyError {
+                page,
+                mismatches,
+                count,
+            } => {
+                let listed: Vec<String> = mismatches.iter().map(Mismatch::to_string).collect();
+                write!(
+                    f,
+                    "Verification failed in page {}: {}",
+                    page,
+                    listed.join(", ")
+                )?;
+                match count.saturating_sub(mismatches.len()) {
+                    0 => Ok(()),
+                    more => write!(f, " and {} more bytes", more),
+                }
+            }
+            ErrorKind::FuseVerifyError { fuse, wrote, read } => write!(
+                f,
+                "{} fuse verification failed. Wrote {:#04x} read {:#04x}. Some bits take effect \
+                 only after power cycle",
+                fuse, wrote, read
+            ),
+            ErrorKind::LockVerifyError { wrote, read } => write!(
+                f,
+                "Lock byte verification failed. Wrote {:#04x} read {:#04x}",
+                wrote, read
+            ),
+            ErrorKind::LockNeedsErase { current, requested } => write!(
+                f,
+                "Lock byte {:#04x} unlocks bits of current {:#04x}. Only chip erase clears lock \
+                 bits, erase the chip (flash and EEPROM too) and write it again",
+                requested, current
+            ),
+            ErrorKind::NotLocked { memory } => write!(
+                f,
+                "{} can still be read back after locking. Lock byte must clear LB1 and LB2",
+                memory
+            ),
+            ErrorKind::PageWriteFailed {
+                memory,
+                address,
+                page,
+            } => write!(
+                f,
+                "Writing {} page {} at {:#06x} failed. Target did not confirm it in time",
+                memory, page, address
+            ),
+            ErrorKind::ReservedFuseBits { fuse, value, mask } => write!(
+                f,
+                "{} fuse {:#04x} programs reserved bits. Bits outside {:#04x} must stay 1",
+                fuse, value, mask
+            ),
+            ErrorKind::UnsafeFuses => write!(f, "Refusing to write unsafe fuses"),
+            ErrorKind::Declined(action) => write!(f, "Not confirmed: {}", action),
+            ErrorKind::FileFormat(msg) => write!(f, "Invalid file: {}", msg),
+            ErrorKind::AddressOutOfRange { address, size } => write!(
+                f,
+                "Address {:#06x} is out of memory of {} bytes",
+                address, size
+            ),
+            ErrorKind::NotErased { address } => {
+                write!(
+                    f,
+                    "Flash at {:#06x} needs chip erase to be written",
+                    address
+                )
+            }
+            ErrorKind::InvalidRange { start, end } => write!(
+                f,
+                "Range {:#06x}..{:#06x} ends before it starts",
+                start, end
+            ),
+            ErrorKind::Unsupported(msg) => write!(f, "Not supported: {}", msg),
+            ErrorKind::BootSection { address } => write!(
+                f,
+                "Image overwrites bootloader at {:#06x} and is not a bootloader itself",
+                address
+            ),
+            ErrorKind::TargetVoltage { tenths, .. } if *tenths < NO_POWER => write!(
+                f,
+                "Target is not powered: Vtarget is {:.1} V. Check target supply and VCC pin of \
+                 ISP header",
+                f32::from(*tenths) / 10.0
+            ),
+            ErrorKind::TargetVoltage { tenths, min, max } => write!(
+                f,
+                "Vtarget {:.1} V is outside of chip supply range {:.1} V to {:.1} V",
+                f32::from(*tenths) / 10.0,
+                f32::from(*min) / 10.0,
+                f32::from(*max) / 10.0
+            ),
+            ErrorKind::AnswerLength { expected, found } => write!(
+                f,
+                "Answer has {} bytes, expected at least {}",
+                found, expected
+            ),
+            ErrorKind::MalformedFrame => write!(f, "Malformed frame received from programmer"),
+            ErrorKind::BodyTooLarge { size, max } => write!(
+                f,
+                "Command body of {} bytes exceeds message limit of {}",
+                size, max
+            ),
+        }
+    }
 }
 
+impl Error for ErrorKind {}
+
 impl From<io::Error> for ErrorKind {
     fn from(err: io::Error) -> ErrorKind {
         ErrorKind::Io(err)
     }
 }
 
+#[cfg(feature = "usb")]
+impl From<rusb::Error> for ErrorKind {
+    fn from(err: rusb::Error) -> ErrorKind {
+        match err {
+            rusb::Error::Timeout => ErrorKind::Io(io::Error::new(io::ErrorKind::TimedOut, err)),
+            err => ErrorKind::Io(io::Error::other(err)),
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "linux"))]
+impl From<gpio_cdev::Error> for ErrorKind {
+    fn from(err: gpio_cdev::Error) -> ErrorKind {
+        ErrorKind::Io(io::Error::other(err))
+    }
+}
+
 impl From<std::string::FromUtf8Error> for ErrorKind {
     fn from(_: std::string::FromUtf8Error) -> ErrorKind {
         ErrorKind::FromUtf8Error
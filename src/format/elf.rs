@@ -0,0 +1,117 @@
+//! ELF files produced by avr-gcc or rustc.
+use super::{FillOptions, Firmware, Image, Segment};
+use crate::errors;
+use goblin::elf::{program_header, Elf};
+use std::convert::TryFrom;
+
+const HEADER_SIZE: usize = 52;
+const PROGRAM_HEADER_SIZE: usize = 32;
+const EM_AVR: u16 = 83;
+
+/// Parse ELF file contents. Loadable segments are placed at their physical (load) address.
+pub fn parse(bytes: &[u8]) -> Result<Firmware, errors::ErrorKind> {
+    parse_with(bytes, &FillOptions::default())
+}
+
+/// Parse ELF file contents, filling gaps according to `options`.
+pub fn parse_with(bytes: &[u8], options: &FillOptions) -> Result<Firmware, errors::ErrorKind> {
+    Firmware::from_records(&segments(bytes)?, options)
+}
+
+/// Loadable segments in file order.
+fn segments(bytes: &[u8]) -> Result<Vec<Segment>, errors::ErrorKind> {
+    let elf = Elf::parse(bytes).map_err(|err| errors::ErrorKind::FileFormat(err.to_string()))?;
+    elf.program_headers
+        .iter()
+        .filter(|header| header.p_type == program_header::PT_LOAD && header.p_filesz > 0)
+        .map(|header| {
+            let data = bytes.get(header.file_range()).ok_or_else(|| {
+                errors::ErrorKind::FileFormat(String::from("Segment out of file"))
+            })?;
+            Ok(Segment {
+                address: header.p_paddr as usize,
+                data: data.to_vec(),
+            })
+        })
+        .collect()
+}
+
+/// Minimal 32 bit little endian AVR ELF file with a loadable segment per segment of `image`.
+/// There are no sections or symbols.
+pub fn encode(image: &Image) -> Result<Vec<u8>, errors::ErrorKind> {
+    let segments = image.segments();
+    let word = |value: usize| {
+        u32::try_from(value)
+            .map(u32::to_le_bytes)
+            .map_err(|_| errors::ErrorKind::FileFormat(format!("{:#x} does not fit ELF32", value)))
+    };
+    let mut elf = vec![0x7f, b'E', b'L', b'F', 1, 1, 1];
+    elf.resize(16, 0);
+    elf.extend(&2u16.to_le_bytes()); // executable
+    elf.extend(&EM_AVR.to_le_bytes());
+    elf.extend(&1u32.to_le_bytes());
+    elf.extend(&0u32.to_le_bytes()); // entry
+    elf.extend(&word(HEADER_SIZE)?); // program headers offset
+    elf.extend(&0u32.to_le_bytes()); // section headers offset
+    elf.extend(&0u32.to_le_bytes()); // flags
+    elf.extend(&(HEADER_SIZE as u16).to_le_bytes());
+    elf.extend(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    elf.extend(&(segments.len() as u16).to_le_bytes());
+    elf.extend(&40u16.to_le_bytes());
+    elf.extend(&0u16.to_le_bytes());
+    elf.extend(&0u16.to_le_bytes());
+    let mut offset = HEADER_SIZE + PROGRAM_HEADER_SIZE * segments.len();
+    for segment in segments {
+        elf.extend(&program_header::PT_LOAD.to_le_bytes());
+        elf.extend(&word(offset)?);
+        elf.extend(&word(segment.address)?); // virtual
+        elf.extend(&word(segment.address)?); // physical
+        elf.extend(&word(segment.data.len())?);
+        elf.extend(&word(segment.data.len())?);
+        elf.extend(&0u32.to_le_bytes());
+        elf.extend(&1u32.to_le_bytes());
+        offset += segment.data.len();
+    }
+    for segment in segments {
+        elf.extend(&segment.data);
+    }
+    Ok(elf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ELF with given (physical address, data) segments.
+    fn build(segments: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut image = Image::new();
+        for (address, data) in segments {
+            image.insert(*address, data);
+        }
+        encode(&image).unwrap()
+    }
+
+    #[test]
+    fn parses_flash_and_eeprom() {
+        let elf = build(&[(0, &[1, 2]), (4, &[3]), (0x81_0001, &[4])]);
+        let firmware = parse(&elf).unwrap();
+        assert_eq!(firmware.flash.to_bytes(0xff), [1, 2, 0xff, 0xff, 3]);
+        assert_eq!(firmware.eeprom.to_bytes(0xff), [0xff, 4]);
+        let saved = encode(&firmware.combined()).unwrap();
+        assert_eq!(parse(&saved).unwrap(), firmware);
+    }
+
+    #[test]
+    fn ignores_other_memories() {
+        let elf = build(&[(0x82_0000, &[0xe4])]);
+        assert_eq!(parse(&elf).unwrap(), Firmware::default());
+    }
+
+    #[test]
+    fn invalid_file() {
+        match parse(&[1, 2, 3]).unwrap_err() {
+            errors::ErrorKind::FileFormat(_) => (),
+            _ => panic!("wrong error returned"),
+        };
+    }
+}
@@ -0,0 +1,97 @@
+//! Intel HEX files. E.g. produced by `avr-objcopy -O ihex`.
+use super::{FillOptions, Firmware, Image, Segment};
+use crate::errors;
+use ihex::{create_object_file_representation, Reader, Record};
+
+/// Parse Intel HEX file contents. Gaps between records are left erased.
+pub fn parse(content: &str) -> Result<Firmware, errors::ErrorKind> {
+    parse_with(content, &FillOptions::default())
+}
+
+/// Parse Intel HEX file contents, filling gaps according to `options`.
+pub fn parse_with(content: &str, options: &FillOptions) -> Result<Firmware, errors::ErrorKind> {
+    Firmware::from_records(&records(content)?, options)
+}
+
+/// Data records in file order.
+fn records(content: &str) -> Result<Vec<Segment>, errors::ErrorKind> {
+    let mut records = Vec::new();
+    let mut base: usize = 0;
+    for record in Reader::new(content) {
+        match record.map_err(|err| errors::ErrorKind::FileFormat(err.to_string()))? {
+            Record::Data { offset, value } => records.push(Segment {
+                address: base + usize::from(offset),
+                data: value,
+            }),
+            Record::ExtendedSegmentAddress(segment) => base = usize::from(segment) << 4,
+            Record::ExtendedLinearAddress(upper) => base = usize::from(upper) << 16,
+            Record::EndOfFile => break,
+            Record::StartSegmentAddress { .. } | Record::StartLinearAddress(_) => (),
+        }
+    }
+    Ok(records)
+}
+
+/// Intel HEX text of `image`. Gaps have no records.
+pub fn encode(image: &Image) -> Result<String, errors::ErrorKind> {
+    let mut records = Vec::new();
+    let mut upper = 0;
+    for segment in image.segments() {
+        let mut address = segment.address;
+        let mut data = &segment.data[..];
+        while !data.is_empty() {
+            if address >> 16 != upper {
+                upper = address >> 16;
+                records.push(Record::ExtendedLinearAddress(upper as u16));
+            }
+            // Records are aligned to 16 bytes, so none crosses 64 KiB boundary.
+            let (chunk, rest) = data.split_at(data.len().min(16 - address % 16));
+            records.push(Record::Data {
+                offset: address as u16,
+                value: chunk.to_vec(),
+            });
+            address += chunk.len();
+            data = rest;
+        }
+    }
+    records.push(Record::EndOfFile);
+    create_object_file_representation(&records)
+        .map_err(|err| errors::ErrorKind::FileFormat(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_data_records() {
+        let hex = ":03000000010203F7\n:0100040003F8\n:00000001FF\n";
+        let firmware = parse(hex).unwrap();
+        assert_eq!(firmware.flash.to_bytes(0xff), [1, 2, 3, 0xff, 3]);
+        assert!(firmware.eeprom.is_empty());
+    }
+
+    #[test]
+    fn eeprom_in_extended_address() {
+        let hex = ":02000004008179\n:01000100AA54\n:00000001FF\n";
+        let firmware = parse(hex).unwrap();
+        assert_eq!(firmware.eeprom.to_bytes(0xff), [0xff, 0xaa]);
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let bytes: Vec<u8> = (0..0x1_0010).map(|i| i as u8).collect();
+        let image = Image::from(bytes);
+        let hex = encode(&image).unwrap();
+        assert!(hex.contains(":020000040001F9\n"));
+        assert_eq!(parse(&hex).unwrap().flash, image);
+    }
+
+    #[test]
+    fn bad_checksum() {
+        match parse(":0100000001FF\n").unwrap_err() {
+            errors::ErrorKind::FileFormat(_) => (),
+            _ => panic!("wrong error returned"),
+        };
+    }
+}
@@ -0,0 +1,314 @@
+//! Sparse memory contents.
+use crate::errors::{ErrorKind, Mismatch};
+use std::ops::Range;
+
+/// Mismatching bytes listed in verify error.
+const LISTED_MISMATCHES: usize = 4;
+
+/// Data at one address, e.g. Intel HEX data record or ELF segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub address: usize,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    pub fn range(&self) -> Range<usize> {
+        self.address..self.address + self.data.len()
+    }
+}
+
+/// Contents of a memory. Addresses without data are erased (0xFF).
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Image {
+    /// Sorted by address, neither overlapping nor adjacent.
+    segments: Vec<Segment>,
+}
+
+impl Image {
+    pub fn new() -> Image {
+        Image::default()
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Address after the last byte with data, 0 if there is none.
+    pub fn end(&self) -> usize {
+        self.segments
+            .last()
+            .map_or(0, |segment| segment.range().end)
+    }
+
+    /// Addresses and values of bytes with data, in address order.
+    pub fn bytes(&self) -> impl Iterator<Item = (usize, u8)> + '_ {
+        self.segments.iter().flat_map(|segment| {
+            let address = segment.address;
+            (address..).zip(segment.data.iter().copied())
+        })
+    }
+
+    /// Byte at `address`. None in gaps.
+    pub fn get(&self, address: usize) -> Option<u8> {
+        let segment = self
+            .segments
+            .iter()
+            .find(|segment| segment.range().contains(&address))?;
+        Some(segment.data[address - segment.address])
+    }
+
+    /// True if some byte in `range` has data.
+    pub fn overlaps(&self, range: Range<usize>) -> bool {
+        self.segments
+            .iter()
+            .any(|segment| segment.address < range.end && range.start < segment.range().end)
+    }
+
+    /// Write `data` at `address`, over data already there.
+    pub fn insert(&mut self, address: usize, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let end = address + data.len();
+        // Segments touching new data are joined with it.
+        let first = self
+            .segments
+            .partition_point(|segment| segment.range().end < address);
+        let last = self
+            .segments
+            .partition_point(|segment| segment.address <= end);
+        let joined: Vec<Segment> = self.segments.drain(first..last).collect();
+        let start = joined.first().map_or(address, |s| s.address.min(address));
+        let stop = joined.last().map_or(end, |s| s.range().end.max(end));
+        let mut merged = vec![0; stop - start];
+        let pieces = joined.iter().map(|s| (s.address, &s.data[..]));
+        for (at, bytes) in pieces.chain([(address, data)]) {
+            merged[at - start..at - start + bytes.len()].copy_from_slice(bytes);
+        }
+        self.segments.insert(
+            first,
+            Segment {
+                address: start,
+                data: merged,
+            },
+        );
+    }
+
+    /// Data within `range`, at the same addresses.
+    pub fn slice(&self, range: Range<usize>) -> Image {
+        let segments = self
+            .segments
+            .iter()
+            .filter_map(|segment| {
+                let start = segment.address.max(range.start);
+                let end = segment.range().end.min(range.end);
+                (start < end).then(|| Segment {
+                    address: start,
+                    data: segment.data[start - segment.address..end - segment.address].to_vec(),
+                })
+            })
+            .collect();
+        Image { segments }
+    }
+
+    /// Add data of `other`. Where both have data, `other` wins.
+    pub fn merge(&mut self, other: &Image) {
+        for segment in &other.segments {
+            self.insert(segment.address, &segment.data);
+        }
+    }
+
+    /// Give gaps from address 0 to the end value `byte`.
+    pub fn fill(&mut self, byte: u8) {
+        if !self.is_empty() {
+            *self = Image::from(self.to_bytes(byte));
+        }
+    }
+
+    /// Drop trailing `pad` bytes, e.g. erased tail of a memory dump.
+    pub fn trim(&mut self, pad: u8) {
+        while let Some(last) = self.segments.last_mut() {
+            super::trim(&mut last.data, pad);
+            if !last.data.is_empty() {
+                break;
+            }
+            self.segments.pop();
+        }
+    }
+
+    /// Contents from address 0 to the end, with gaps set to `fill`.
+    pub fn to_bytes(&self, fill: u8) -> Vec<u8> {
+        let mut bytes = vec![fill; self.end()];
+        for segment in &self.segments {
+            bytes[segment.range()].copy_from_slice(&segment.data);
+        }
+        bytes
+    }
+
+    /// Ranges of differing bytes. Gaps and addresses past the end compare as erased.
+    pub fn diff(&self, other: &Image) -> Vec<Range<usize>> {
+        let (a, b) = (self.to_bytes(0xff), other.to_bytes(0xff));
+        let byte = |bytes: &[u8], i: usize| bytes.get(i).copied().unwrap_or(0xff);
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+        for i in (0..a.len().max(b.len())).filter(|&i| byte(&a, i) != byte(&b, i)) {
+            match ranges.last_mut() {
+                Some(last) if last.end == i => last.end = i + 1,
+                _ => ranges.push(i..i + 1),
+            }
+        }
+        ranges
+    }
+
+    /// First address with data which `found`, memory content from address 0, does not match.
+    pub fn mismatch(&self, found: &[u8]) -> Option<usize> {
+        self.bytes()
+            .find(|&(address, byte)| found.get(address) != Some(&byte))
+            .map(|(address, _)| address)
+    }
+
+    /// Bytes with data which `found`, memory content from address 0, does not match. Bytes
+    /// past the end of `found` are read as erased.
+    pub fn mismatches<'a>(&'a self, found: &'a [u8]) -> impl Iterator<Item = Mismatch> + 'a {
+        self.bytes()
+            .filter(move |&(address, byte)| found.get(address) != Some(&byte))
+            .map(move |(address, expected)| Mismatch {
+                address,
+                expected,
+                found: found.get(address).copied().unwrap_or(0xff),
+            })
+    }
+
+    /// Compare with `found`. Error names page of the first mismatch and lists first few of them.
+    pub fn verify(&self, found: &[u8], page_size: usize) -> Result<(), ErrorKind> {
+        let mut mismatches: Vec<Mismatch> = self.mismatches(found).collect();
+        let first = match mismatches.first() {
+            Some(first) => first.address,
+            None => return Ok(()),
+        };
+        let count = mismatches.len();
+        mismatches.truncate(LISTED_MISMATCHES);
+        Err(ErrorKind::VerifyError {
+            page: first / page_size,
+            mismatches,
+            count,
+        })
+    }
+
+    /// CRC-32 (as in zip and PNG) of contents from address 0 to the end, gaps erased.
+    pub fn crc32(&self) -> u32 {
+        !self.to_bytes(0xff).iter().fold(!0, |crc: u32, &byte| {
+            (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+                (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+            })
+        })
+    }
+}
+
+impl From<Vec<u8>> for Image {
+    /// Contiguous data starting at address 0.
+    fn from(data: Vec<u8>) -> Image {
+        let mut image = Image::new();
+        if !data.is_empty() {
+            image.segments.push(Segment { address: 0, data });
+        }
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(segments: &[(usize, &[u8])]) -> Image {
+        let mut image = Image::new();
+        for (address, data) in segments {
+            image.insert(*address, data);
+        }
+        image
+    }
+
+    #[test]
+    fn insert_joins_segments() {
+        let mut image = image(&[(4, &[1, 2]), (0, &[3])]);
+        assert_eq!(image.segments().len(), 2);
+        image.insert(1, &[4, 5, 6, 7]);
+        assert_eq!(image, Image::from(vec![3, 4, 5, 6, 7, 2]));
+        image.insert(8, &[9]);
+        assert_eq!(image.to_bytes(0), [3, 4, 5, 6, 7, 2, 0, 0, 9]);
+        assert_eq!(image.get(5), Some(2));
+        assert_eq!(image.get(6), None);
+        assert!(image.overlaps(5..7));
+        assert!(!image.overlaps(6..8));
+    }
+
+    #[test]
+    fn slice_and_merge() {
+        let mut image = image(&[(0, &[1, 2, 3]), (6, &[4, 5])]);
+        let slice = image.slice(2..7);
+        assert_eq!(slice, self::image(&[(2, &[3]), (6, &[4])]));
+        image.merge(&self::image(&[(3, &[6, 7, 8])]));
+        assert_eq!(image.to_bytes(0xff), [1, 2, 3, 6, 7, 8, 4, 5]);
+    }
+
+    #[test]
+    fn fill_and_trim() {
+        let mut image = image(&[(1, &[1]), (3, &[0xff, 0xff])]);
+        image.trim(0xff);
+        assert_eq!(image, self::image(&[(1, &[1])]));
+        image.fill(0);
+        assert_eq!(image, Image::from(vec![0, 1]));
+    }
+
+    #[test]
+    fn diff_merges_adjacent_bytes() {
+        let a = Image::from(vec![1, 2, 3, 4, 5]);
+        assert_eq!(a.diff(&Image::from(vec![1, 0, 0, 4, 0])), vec![1..3, 4..5]);
+    }
+
+    #[test]
+    fn diff_shorter_is_erased() {
+        let a = Image::from(vec![1]);
+        assert_eq!(Image::from(vec![1, 0xff, 0xff]).diff(&a), vec![]);
+        assert_eq!(Image::from(vec![1, 0xff, 2]).diff(&a), vec![2..3]);
+    }
+
+    #[test]
+    fn mismatch_skips_gaps() {
+        let image = image(&[(1, &[1])]);
+        assert_eq!(image.mismatch(&[0, 1]), None);
+        assert_eq!(image.mismatch(&[0, 2]), Some(1));
+        assert_eq!(image.mismatch(&[0]), Some(1));
+    }
+
+    #[test]
+    fn verify_lists_first_mismatches() {
+        let image = Image::from(vec![0; 8]);
+        assert!(image.verify(&[0; 8], 4).is_ok());
+        let err = image.verify(&[0, 0, 0, 0, 0, 1, 2, 3], 4).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Verification failed in page 1: 0x0005 expected 0x00 found 0x01, \
+             0x0006 expected 0x00 found 0x02, 0x0007 expected 0x00 found 0x03"
+        );
+        let err = image.verify(&[1; 6], 2).unwrap_err();
+        assert!(
+            err.to_string().ends_with("found 0x01 and 4 more bytes"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn crc32() {
+        assert_eq!(Image::from(b"123456789".to_vec()).crc32(), 0xcbf4_3926);
+        assert_eq!(
+            image(&[(1, &[0])]).crc32(),
+            Image::from(vec![0xff, 0]).crc32()
+        );
+    }
+}
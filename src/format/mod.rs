@@ -0,0 +1,255 @@
+//! Loading and saving memory contents in files used by toolchains.
+//!
+//! Memories are placed in separate address ranges by avr-gcc linker scripts:
+//! * flash at 0
+//! * EEPROM at 0x810000
+//!
+//! Same addresses are used in ELF and Intel HEX files.
+pub mod elf;
+pub mod ihex;
+mod image;
+
+pub use image::{Image, Segment};
+
+use crate::errors;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+const EEPROM_START: u64 = 0x81_0000;
+const EEPROM_END: u64 = 0x82_0000;
+/// Anything above belongs to other memories (SRAM, fuses, lock, signature).
+const FLASH_END: u64 = 0x80_0000;
+
+/// Memory contents of a chip. Both start at address 0.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Firmware {
+    pub flash: Image,
+    pub eeprom: Image,
+}
+
+impl Firmware {
+    /// Place file records at their avr-gcc addresses. Data for memories other than flash and
+    /// EEPROM is ignored.
+    pub fn from_records(
+        records: &[Segment],
+        options: &FillOptions,
+    ) -> Result<Firmware, errors::ErrorKind> {
+        let mut firmware = Firmware::default();
+        for record in records {
+            let address = record.address as u64;
+            let (image, offset) = if address < FLASH_END {
+                (&mut firmware.flash, 0)
+            } else if (EEPROM_START..EEPROM_END).contains(&address) {
+                (&mut firmware.eeprom, EEPROM_START as usize)
+            } else {
+                continue;
+            };
+            let address = record.address - offset;
+            if options.reject_overlap && image.overlaps(address..address + record.data.len()) {
+                return Err(errors::ErrorKind::FileFormat(format!(
+                    "Records overlap at {:#06x}",
+                    record.address
+                )));
+            }
+            image.insert(address, &record.data);
+        }
+        if let Gaps::Fill(byte) = options.gaps {
+            firmware.flash.fill(byte);
+            firmware.eeprom.fill(byte);
+        }
+        Ok(firmware)
+    }
+
+    /// Both memories at their avr-gcc addresses, as stored in ELF and Intel HEX files.
+    pub fn combined(&self) -> Image {
+        let mut image = self.flash.clone();
+        for segment in self.eeprom.segments() {
+            image.insert(EEPROM_START as usize + segment.address, &segment.data);
+        }
+        image
+    }
+}
+
+/// What to do with addresses between records of a file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Gaps {
+    /// Leave gaps erased (0xFF). Flash pages which are only gap are not written.
+    Skip,
+    /// Program gaps with given byte.
+    Fill(u8),
+}
+
+/// How sparse files are turned into memory contents.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FillOptions {
+    pub gaps: Gaps,
+    /// Fail when records overlap instead of letting the last one win.
+    pub reject_overlap: bool,
+}
+
+impl Default for FillOptions {
+    fn default() -> FillOptions {
+        FillOptions {
+            gaps: Gaps::Skip,
+            reject_overlap: false,
+        }
+    }
+}
+
+/// How much of a memory an image takes, like `avr-size -C` reports it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Usage {
+    /// Bytes which are not erased (0xFF).
+    pub used: usize,
+    /// Memory size.
+    pub size: usize,
+    /// Address of the last byte which is not erased.
+    pub highest: Option<usize>,
+}
+
+impl Usage {
+    pub fn new(image: &Image, size: usize) -> Usage {
+        let used = || image.bytes().filter(|&(_, byte)| byte != 0xff);
+        Usage {
+            used: used().count(),
+            size,
+            highest: used().last().map(|(address, _)| address),
+        }
+    }
+
+    /// Bytes left, negative when image does not fit.
+    pub fn free(&self) -> isize {
+        self.size as isize - self.highest.map_or(0, |address| address as isize + 1)
+    }
+
+    /// Used part of memory in percent.
+    pub fn percent(&self) -> f64 {
+        self.used as f64 * 100.0 / self.size as f64
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes ({:.1}% Full), {} free",
+            self.used,
+            self.percent(),
+            self.free()
+        )?;
+        if let Some(highest) = self.highest {
+            write!(f, ", highest address {:#06x}", highest)?;
+        }
+        Ok(())
+    }
+}
+
+/// Load file. Format is chosen by extension:
+/// * `elf` ELF file
+/// * `hex` or `ihex` Intel HEX
+/// * anything else raw binary flash content
+pub fn load(path: &Path) -> Result<Firmware, errors::ErrorKind> {
+    load_with(path, &FillOptions::default())
+}
+
+/// Load file like [`load`], filling gaps according to `options`. Raw binary files have no gaps.
+pub fn load_with(path: &Path, options: &FillOptions) -> Result<Firmware, errors::ErrorKind> {
+    let bytes = fs::read(path)?;
+    match extension(path).as_str() {
+        "elf" => elf::parse_with(&bytes, options),
+        "hex" | "ihex" => ihex::parse_with(&String::from_utf8(bytes)?, options),
+        _ => Ok(Firmware {
+            flash: Image::from(bytes),
+            eeprom: Image::new(),
+        }),
+    }
+}
+
+/// Save file. Format is chosen by extension like in [`load`]. ELF and Intel HEX files hold both
+/// memories. Raw binary holds flash, or EEPROM if there is no flash, with gaps erased.
+pub fn save(path: &Path, firmware: &Firmware) -> Result<(), errors::ErrorKind> {
+    let bytes = match extension(path).as_str() {
+        "elf" => elf::encode(&firmware.combined())?,
+        "hex" | "ihex" => ihex::encode(&firmware.combined())?.into_bytes(),
+        _ if firmware.flash.is_empty() => firmware.eeprom.to_bytes(0xff),
+        _ => firmware.flash.to_bytes(0xff),
+    };
+    fs::write(path, bytes)?;
+    Ok(())
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+/// Drop trailing `pad` bytes, e.g. erased (0xFF) tail of a memory dump.
+pub fn trim(bytes: &mut Vec<u8>, pad: u8) {
+    let end = bytes
+        .iter()
+        .rposition(|&byte| byte != pad)
+        .map_or(0, |i| i + 1);
+    bytes.truncate(end);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usage_of_image() {
+        let usage = Usage::new(&Image::from(vec![0x0c, 0x94, 0xff, 0x12, 0xff]), 8);
+        assert_eq!(usage.used, 3);
+        assert_eq!(usage.highest, Some(3));
+        assert_eq!(usage.free(), 4);
+        assert_eq!(
+            usage.to_string(),
+            "3 bytes (37.5% Full), 4 free, highest address 0x0003"
+        );
+    }
+
+    #[test]
+    fn trims_padding() {
+        let mut bytes = vec![1, 0xff, 2, 0xff, 0xff];
+        trim(&mut bytes, 0xff);
+        assert_eq!(bytes, vec![1, 0xff, 2]);
+        let mut bytes = vec![0, 0];
+        trim(&mut bytes, 0);
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn fill_options() {
+        let segment = |address, data: &[u8]| Segment {
+            address,
+            data: data.to_vec(),
+        };
+        let records = [
+            segment(4, &[1, 2]),
+            segment(1, &[3, 4]),
+            segment(0x81_0001, &[5]),
+        ];
+        let skip = FillOptions::default();
+        let firmware = Firmware::from_records(&records, &skip).unwrap();
+        assert_eq!(firmware.flash.to_bytes(0xff), [0xff, 3, 4, 0xff, 1, 2]);
+        assert_eq!(firmware.flash.segments().len(), 2);
+        assert_eq!(firmware.combined().get(0x81_0001), Some(5));
+        let fill = FillOptions {
+            gaps: Gaps::Fill(0),
+            reject_overlap: true,
+        };
+        let firmware = Firmware::from_records(&records, &fill).unwrap();
+        assert_eq!(firmware.flash, Image::from(vec![0, 3, 4, 0, 1, 2]));
+        assert_eq!(firmware.eeprom, Image::from(vec![0, 5]));
+        let overlapping = [records[0].clone(), segment(5, &[9])];
+        let firmware = Firmware::from_records(&overlapping, &skip).unwrap();
+        assert_eq!(
+            firmware.flash.to_bytes(0xff),
+            [0xff, 0xff, 0xff, 0xff, 1, 9]
+        );
+        assert!(Firmware::from_records(&overlapping, &fill).is_err());
+    }
+}
@@ -0,0 +1,63 @@
+//! Text representation of binary data.
+
+/// Comma separated bytes, e.g. `0x01, 0xff`.
+pub fn to_hex(slice: &[u8]) -> String {
+    let mut hexes: Vec<String> = Vec::with_capacity(slice.len());
+    for i in slice {
+        hexes.push(format!("{:#04x}", i));
+    }
+    hexes.join(", ")
+}
+
+/// Canonical hex dump, like `hexdump -C`. Each line has offset, 16 bytes and their ASCII form.
+///
+/// `offset` is address of the first byte.
+pub fn hexdump(offset: usize, bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        out.push_str(&format!("{:08x} ", offset + line * 16));
+        for i in 0..16 {
+            if i % 8 == 0 {
+                out.push(' ');
+            }
+            match chunk.get(i) {
+                Some(byte) => out.push_str(&format!("{:02x} ", byte)),
+                None => out.push_str("   "),
+            }
+        }
+        let ascii: String = chunk
+            .iter()
+            .map(|&byte| match byte {
+                0x20..=0x7e => byte as char,
+                _ => '.',
+            })
+            .collect();
+        out.push_str(&format!(" |{}|\n", ascii));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_hex_joins_bytes() {
+        assert_eq!(to_hex(&[0x01, 0xff]), "0x01, 0xff");
+    }
+
+    #[test]
+    fn full_and_partial_line() {
+        let bytes: Vec<u8> = (0x41..0x53).collect();
+        assert_eq!(
+            hexdump(0x100, &bytes),
+            "00000100  41 42 43 44 45 46 47 48  49 4a 4b 4c 4d 4e 4f 50  |ABCDEFGHIJKLMNOP|\n\
+             00000110  51 52                                             |QR|\n"
+        );
+    }
+
+    #[test]
+    fn non_printable_as_dots() {
+        assert!(hexdump(0, &[0x00, 0x7f, b'a']).ends_with(" |..a|\n"));
+    }
+}
@@ -1,4 +1,10 @@
+#[macro_use]
+mod trace;
+
 pub mod command;
 pub mod errors;
+pub mod format;
+pub mod hexdump;
+pub mod ports;
 pub mod programmer;
 pub mod specs;
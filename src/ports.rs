@@ -0,0 +1,92 @@
+//! Serial port discovery.
+use crate::errors;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// USB device details of a serial port.
+#[derive(Debug, Clone)]
+pub struct UsbInfo {
+    pub vid: u16,
+    pub pid: u16,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+}
+
+/// Serial port present in the system.
+#[derive(Debug, Clone)]
+pub struct PortInfo {
+    /// Name used to open the port. E.g. `/dev/ttyUSB0` or `COM3`.
+    pub name: String,
+    /// Set only for USB serial adapters.
+    pub usb: Option<UsbInfo>,
+}
+
+impl From<serialport::SerialPortInfo> for PortInfo {
+    fn from(info: serialport::SerialPortInfo) -> PortInfo {
+        let usb = match info.port_type {
+            serialport::SerialPortType::UsbPort(usb) => Some(UsbInfo {
+                vid: usb.vid,
+                pid: usb.pid,
+                manufacturer: usb.manufacturer,
+                product: usb.product,
+                serial_number: usb.serial_number,
+            }),
+            _ => None,
+        };
+        PortInfo {
+            name: info.port_name,
+            usb,
+        }
+    }
+}
+
+/// List serial ports present in the system.
+pub fn list() -> Result<Vec<PortInfo>, errors::ErrorKind> {
+    let ports = serialport::available_ports().map_err(io::Error::from)?;
+    Ok(ports.into_iter().map(PortInfo::from).collect())
+}
+
+/// Block until port `name` appears, e.g. once programmer is plugged in. Fails after `timeout`.
+pub fn wait(name: &str, timeout: Duration) -> Result<(), errors::ErrorKind> {
+    let start = Instant::now();
+    loop {
+        if present(name) {
+            return Ok(());
+        }
+        if start.elapsed() >= timeout {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "port {} did not appear within {:.0} s",
+                    name,
+                    timeout.as_secs_f64()
+                ),
+            )
+            .into());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Device nodes are checked directly, enumeration misses some of them (e.g. `/dev/rfcomm0`).
+fn present(name: &str) -> bool {
+    Path::new(name).exists() || list().is_ok_and(|ports| ports.iter().any(|port| port.name == name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_gives_up() {
+        assert!(wait("Cargo.toml", Duration::ZERO).is_ok());
+        let err = wait("/dev/avrisp-missing", Duration::ZERO).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "IO error: port /dev/avrisp-missing did not appear within 0 s"
+        );
+    }
+}
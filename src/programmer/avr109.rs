@@ -0,0 +1,395 @@
+//! AVR109 / AVR911 serial bootloader protocol. Used by Butterfly and Caterina (Leonardo, Micro).
+//!
+//! Commands are single ASCII letters followed by arguments. Most commands are acknowledged with
+//! carriage return. Flash is addressed in words, EEPROM in bytes. Bootloader can not write fuses.
+use crate::errors;
+use crate::programmer::{self, MemoryType};
+use crate::specs;
+use serial::core::{Error, PortSettings, SerialPort};
+use std::convert::TryInto;
+use std::io::prelude::*;
+use std::thread;
+use std::time::Duration;
+
+#[allow(dead_code)]
+mod command {
+    pub const SOFTWARE_ID: u8 = b'S';
+    pub const SOFTWARE_VERSION: u8 = b'V';
+    pub const BLOCK_SUPPORT: u8 = b'b';
+    pub const DEVICE_CODES: u8 = b't';
+    pub const SELECT_DEVICE: u8 = b'T';
+    pub const ENTER_PROGMODE: u8 = b'P';
+    pub const LEAVE_PROGMODE: u8 = b'L';
+    pub const EXIT: u8 = b'E';
+    pub const ADDRESS: u8 = b'A';
+    pub const EXTENDED_ADDRESS: u8 = b'H';
+    pub const CHIP_ERASE: u8 = b'e';
+    pub const WRITE_BLOCK: u8 = b'B';
+    pub const READ_BLOCK: u8 = b'g';
+    pub const READ_LOCK: u8 = b'r';
+    pub const WRITE_LOCK: u8 = b'l';
+    pub const READ_LOW_FUSE: u8 = b'F';
+    pub const READ_HIGH_FUSE: u8 = b'N';
+    pub const READ_EXTENDED_FUSE: u8 = b'Q';
+    pub const READ_SIGNATURE: u8 = b's';
+}
+
+const ACK: u8 = b'\r';
+/// Memory type byte of block commands.
+const FLASH: u8 = b'F';
+const EEPROM: u8 = b'E';
+
+/// Butterfly default. Ignored by USB bootloaders.
+pub const DEFAULT_BAUD: usize = 19_200;
+
+const SERIAL_SETTINGS: PortSettings = PortSettings {
+    baud_rate: serial::Baud19200,
+    char_size: serial::Bits8,
+    parity: serial::ParityNone,
+    stop_bits: serial::Stop1,
+    flow_control: serial::FlowNone,
+};
+
+/// Start bootloader of a board with native USB (Leonardo, Micro) by opening its port at 1200 baud.
+///
+/// Bootloader usually shows up under a different port name after a moment.
+pub fn reset_into_bootloader(port: &str) -> Result<(), errors::ErrorKind> {
+    let mut port = serial::open(port).map_err(std::io::Error::from)?;
+    port.reconfigure(&|settings| settings.set_baud_rate(serial::Baud1200))
+        .map_err(std::io::Error::from)?;
+    drop(port);
+    thread::sleep(Duration::from_millis(500));
+    Ok(())
+}
+
+/// Bytes of address command for byte `address` of `memory`.
+fn address_command(memory: u8, address: usize, flash_size: usize) -> Vec<u8> {
+    let address = if memory == FLASH {
+        address / 2
+    } else {
+        address
+    };
+    if flash_size > 0x20000 {
+        let bytes = (address as u32).to_be_bytes();
+        vec![command::EXTENDED_ADDRESS, bytes[1], bytes[2], bytes[3]]
+    } else {
+        let bytes = (address as u16).to_be_bytes();
+        vec![command::ADDRESS, bytes[0], bytes[1]]
+    }
+}
+
+/// AVR109 bootloader running on target itself.
+pub struct AVR109 {
+    port: serial::SystemPort,
+    specs: specs::Specs,
+    counters: programmer::Counters,
+}
+
+impl AVR109 {
+    pub fn open(port: &str, specs: specs::Specs) -> Result<AVR109, Error> {
+        AVR109::open_with(port, DEFAULT_BAUD, specs)
+    }
+
+    /// Open bootloader talking at `baud`.
+    pub fn open_with(port: &str, baud: usize, specs: specs::Specs) -> Result<AVR109, Error> {
+        let mut port = serial::open(port)?;
+        port.configure(&PortSettings {
+            baud_rate: serial::BaudRate::from_speed(baud),
+            ..SERIAL_SETTINGS
+        })?;
+        port.set_timeout(Duration::from_secs(1))?;
+        Ok(AVR109 {
+            port,
+            specs,
+            counters: programmer::Counters::default(),
+        })
+    }
+
+    /// Send command and read `size` bytes of answer.
+    fn command(&mut self, body: &[u8], size: usize) -> Result<Vec<u8>, errors::ErrorKind> {
+        self.counters.frames += 1;
+        self.port.write_all(body)?;
+        self.port.flush()?;
+        let mut answer = vec![0; size];
+        self.port.read_exact(&mut answer)?;
+        Ok(answer)
+    }
+
+    /// Send command which is acknowledged with carriage return.
+    fn command_ack(&mut self, body: &[u8]) -> Result<(), errors::ErrorKind> {
+        if self.command(body, 1)?[0] != ACK {
+            return Err(errors::ErrorKind::StatusError);
+        }
+        Ok(())
+    }
+
+    /// Read bootloader identifier, e.g. `CATERIN` or `AVRBOOT`.
+    pub fn get_software_id(&mut self) -> Result<String, errors::ErrorKind> {
+        Ok(String::from_utf8(
+            self.command(&[command::SOFTWARE_ID], 7)?,
+        )?)
+    }
+
+    /// Read bootloader version.
+    pub fn get_sw_version(&mut self) -> Result<programmer::SwVersion, errors::ErrorKind> {
+        let digits = self.command(&[command::SOFTWARE_VERSION], 2)?;
+        Ok(programmer::SwVersion {
+            major: digits[0].wrapping_sub(b'0'),
+            minor: digits[1].wrapping_sub(b'0'),
+        })
+    }
+
+    /// Maximum size of block read or written with single command.
+    fn block_size(&mut self) -> Result<usize, errors::ErrorKind> {
+        let answer = self.command(&[command::BLOCK_SUPPORT], 3)?;
+        if answer[0] != b'Y' {
+            return Err(errors::ErrorKind::UnknownProgrammer);
+        }
+        Ok(u16::from_be_bytes([answer[1], answer[2]]) as usize)
+    }
+
+    /// First device code supported by bootloader. List is terminated with zero.
+    fn device_code(&mut self) -> Result<u8, errors::ErrorKind> {
+        let code = self.command(&[command::DEVICE_CODES], 1)?[0];
+        let mut next = code;
+        while next != 0 {
+            next = self.command(&[], 1)?[0];
+        }
+        Ok(code)
+    }
+}
+
+impl TryInto<ProgMode> for AVR109 {
+    type Error = errors::ErrorKind;
+    fn try_into(mut self) -> Result<ProgMode, Self::Error> {
+        let block_size = self.block_size()?;
+        let code = self.device_code()?;
+        self.command_ack(&[command::SELECT_DEVICE, code])?;
+        self.command_ack(&[command::ENTER_PROGMODE])?;
+        Ok(ProgMode {
+            prog: self,
+            block_size,
+        })
+    }
+}
+
+pub struct ProgMode {
+    prog: AVR109,
+    block_size: usize,
+}
+
+impl ProgMode {
+    /// Access bootloader.
+    pub fn programmer(&mut self) -> &mut AVR109 {
+        &mut self.prog
+    }
+
+    fn load_address(&mut self, memory: u8, address: usize) -> Result<(), errors::ErrorKind> {
+        let body = address_command(memory, address, self.prog.specs.flash.size);
+        self.prog.command_ack(&body)
+    }
+
+    /// Read memory starting at `address`. Bootloader increments address on its own.
+    fn read_block(
+        &mut self,
+        memory: u8,
+        address: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), errors::ErrorKind> {
+        self.load_address(memory, address)?;
+        for chunk in buffer.chunks_mut(self.block_size) {
+            let size = (chunk.len() as u16).to_be_bytes();
+            let data = self.prog.command(
+                &[command::READ_BLOCK, size[0], size[1], memory],
+                chunk.len(),
+            )?;
+            chunk.copy_from_slice(&data);
+        }
+        Ok(())
+    }
+
+    /// Write memory in chunks of `chunk_size` starting at aligned `address`.
+    fn write_block(
+        &mut self,
+        memory: u8,
+        chunk_size: usize,
+        address: usize,
+        bytes: &[u8],
+    ) -> Result<(), errors::ErrorKind> {
+        // Bootloader increments address on its own, unless a page is skipped.
+        let mut load = true;
+        for (i, chunk) in bytes.chunks(chunk_size).enumerate() {
+            let mut data = chunk.to_vec();
+            // Only whole flash pages can be written. Fill the rest with erased value.
+            if memory == FLASH {
+                data.resize(chunk_size, 0xff);
+                // Flash is erased already.
+                if programmer::is_erased(&data) {
+                    load = true;
+                    continue;
+                }
+            }
+            if load {
+                self.load_address(memory, address + i * chunk_size)?;
+                load = false;
+            }
+            let size = (data.len() as u16).to_be_bytes();
+            let mut body = vec![command::WRITE_BLOCK, size[0], size[1], memory];
+            body.extend_from_slice(&data);
+            self.prog.command_ack(&body)?;
+        }
+        Ok(())
+    }
+
+    fn write_flash_pages(&mut self, address: usize, bytes: &[u8]) -> Result<(), errors::ErrorKind> {
+        // Bootloader writes one page per block.
+        let size = self.prog.specs.flash.page_size;
+        self.write_block(FLASH, size, address, bytes)
+    }
+}
+
+impl programmer::MemoryOps for ProgMode {
+    fn layout(&self, memory: MemoryType) -> Result<specs::Memory, errors::ErrorKind> {
+        programmer::layout(&self.prog.specs, memory).cloned()
+    }
+
+    fn read_at(
+        &mut self,
+        memory: MemoryType,
+        address: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), errors::ErrorKind> {
+        match memory {
+            MemoryType::Flash => self.read_block(FLASH, address, buffer),
+            MemoryType::Eeprom => self.read_block(EEPROM, address, buffer),
+            _ => Err(programmer::unsupported(memory)),
+        }
+    }
+
+    fn write_at(
+        &mut self,
+        memory: MemoryType,
+        address: usize,
+        bytes: &[u8],
+    ) -> Result<(), errors::ErrorKind> {
+        match memory {
+            MemoryType::Flash => self.write_flash_pages(address, bytes),
+            // Bootloader writes EEPROM byte by byte, blocks need not be aligned.
+            MemoryType::Eeprom => {
+                let size = self.block_size;
+                self.write_block(EEPROM, size, address, bytes)
+            }
+            _ => Err(programmer::unsupported(memory)),
+        }
+    }
+}
+
+impl programmer::Erase for ProgMode {
+    /// Erase application section. Bootloader section stays intact.
+    fn erase(&mut self) -> Result<(), errors::ErrorKind> {
+        self.prog.command_ack(&[command::CHIP_ERASE])
+    }
+}
+
+impl programmer::Programmer for ProgMode {
+    /// Leave bootloader and start application.
+    fn close(mut self) -> Result<(), errors::ErrorKind> {
+        self.prog.command_ack(&[command::LEAVE_PROGMODE])?;
+        self.prog.command_ack(&[command::EXIT])
+    }
+}
+
+impl programmer::AVRLockByteGet for ProgMode {
+    fn get_lock_byte(&mut self) -> Result<u8, errors::ErrorKind> {
+        Ok(self.prog.command(&[command::READ_LOCK], 1)?[0])
+    }
+}
+
+impl programmer::AVRLockByteSet for ProgMode {
+    fn set_lock_byte(&mut self, byte: u8) -> Result<u8, errors::ErrorKind> {
+        programmer::write_lock(self, programmer::LOCK_BITS, byte, |mode, byte| {
+            mode.prog.command_ack(&[command::WRITE_LOCK, byte])
+        })
+    }
+}
+
+impl programmer::AVRFuseGet for ProgMode {
+    fn get_fuses(&mut self) -> Result<programmer::AVRFuse, errors::ErrorKind> {
+        Ok(programmer::AVRFuse {
+            low: self.prog.command(&[command::READ_LOW_FUSE], 1)?[0],
+            high: self.prog.command(&[command::READ_HIGH_FUSE], 1)?[0],
+            extended: self.prog.command(&[command::READ_EXTENDED_FUSE], 1)?[0],
+        })
+    }
+}
+
+impl programmer::AVRFuseSet for ProgMode {
+    /// Bootloaders can not write fuses.
+    fn set_fuses(
+        &mut self,
+        _fuses: &programmer::AVRFuse,
+    ) -> Result<programmer::AVRFuse, errors::ErrorKind> {
+        Err(errors::ErrorKind::Unsupported(String::from(
+            "writing fuses from bootloader",
+        )))
+    }
+}
+
+impl programmer::AVROsccalGet for ProgMode {
+    fn get_osccal(&mut self) -> Result<u8, errors::ErrorKind> {
+        Err(errors::ErrorKind::Unsupported(String::from(
+            "reading OSCCAL from bootloader",
+        )))
+    }
+}
+
+impl programmer::MCUSignature for ProgMode {
+    fn get_mcu_signature(&mut self) -> Result<specs::Signature, errors::ErrorKind> {
+        // Sent starting with last byte.
+        let bytes = self.prog.command(&[command::READ_SIGNATURE], 3)?;
+        Ok(specs::Signature::from((bytes[2], bytes[1], bytes[0])))
+    }
+}
+
+impl programmer::Session for ProgMode {
+    fn identify(&mut self) -> Result<programmer::Identity, errors::ErrorKind> {
+        Ok(programmer::Identity {
+            name: self.prog.get_software_id()?,
+            hardware: None,
+            firmware: Some(self.prog.get_sw_version()?),
+        })
+    }
+
+    fn counters(&self) -> programmer::Counters {
+        self.prog.counters
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), errors::ErrorKind> {
+        programmer::Programmer::close(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flash_address_is_in_words() {
+        assert_eq!(
+            address_command(FLASH, 0x0100, 0x8000),
+            vec![b'A', 0x00, 0x80]
+        );
+        assert_eq!(
+            address_command(EEPROM, 0x0100, 0x8000),
+            vec![b'A', 0x01, 0x00]
+        );
+    }
+
+    #[test]
+    fn large_flash_uses_extended_address() {
+        assert_eq!(
+            address_command(FLASH, 0x3_0000, 0x4_0000),
+            vec![b'H', 0x01, 0x80, 0x00]
+        );
+    }
+}
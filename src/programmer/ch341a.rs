@@ -0,0 +1,109 @@
+//! CH341A USB dongles ("black clip programmer") in SPI mode.
+//!
+//! Chip shifts bytes LSB first, so every byte is bit reversed both ways. Chip select CS0 is not
+//! touched by SPI stream, it is set through UIO stream instead. That makes it usable as target
+//! reset, held low for whole programming session. SCK is fixed by the chip at about 1.5 MHz, so
+//! target must run from at least 8 MHz clock. ISP instructions are sent by [`isp`](super::isp).
+use super::isp;
+use crate::errors;
+use rusb::{DeviceHandle, GlobalContext};
+use std::time::Duration;
+
+pub const VID: u16 = 0x1a86;
+pub const PID: u16 = 0x5512;
+
+mod command {
+    pub const SPI_STREAM: u8 = 0xa8;
+    pub const UIO_STREAM: u8 = 0xab;
+}
+
+mod uio {
+    pub const OUT: u8 = 0x80;
+    pub const DIR: u8 = 0x40;
+    pub const END: u8 = 0x20;
+}
+
+const ENDPOINT_IN: u8 = 0x82;
+const ENDPOINT_OUT: u8 = 0x02;
+/// Bulk packet, including command byte.
+const PACKET_SIZE: usize = 32;
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// D0..D5 are outputs. D0 is CS0 (reset), D3 SCK and D5 MOSI.
+const OUTPUTS: u8 = 0x3f;
+/// CS0 low, SCK low, other outputs high.
+const RESET_ACTIVE: u8 = 0x36;
+const RESET_RELEASED: u8 = 0x37;
+
+/// SPI stream packet carrying `data`. Bits are reversed for LSB first shifting.
+fn spi_packet(data: &[u8]) -> Vec<u8> {
+    let mut packet = vec![command::SPI_STREAM];
+    packet.extend(data.iter().map(|byte| byte.reverse_bits()));
+    packet
+}
+
+pub struct CH341A {
+    handle: DeviceHandle<GlobalContext>,
+}
+
+impl CH341A {
+    /// Open first CH341A found on USB.
+    pub fn open() -> Result<CH341A, errors::ErrorKind> {
+        let handle = rusb::open_device_with_vid_pid(VID, PID)
+            .ok_or(errors::ErrorKind::Io(std::io::ErrorKind::NotFound.into()))?;
+        // Not supported on all platforms. Claiming fails later if needed.
+        let _ = handle.set_auto_detach_kernel_driver(true);
+        handle.claim_interface(0)?;
+        let ch341a = CH341A { handle };
+        // Start with target running.
+        ch341a.set_pins(RESET_RELEASED)?;
+        Ok(ch341a)
+    }
+
+    fn set_pins(&self, value: u8) -> Result<(), errors::ErrorKind> {
+        let packet = [
+            command::UIO_STREAM,
+            uio::OUT | value,
+            uio::DIR | OUTPUTS,
+            uio::END,
+        ];
+        self.handle.write_bulk(ENDPOINT_OUT, &packet, TIMEOUT)?;
+        Ok(())
+    }
+}
+
+impl isp::Bus for CH341A {
+    fn transfer(&mut self, data: &mut [u8]) -> Result<(), errors::ErrorKind> {
+        for chunk in data.chunks_mut(PACKET_SIZE - 1) {
+            self.handle
+                .write_bulk(ENDPOINT_OUT, &spi_packet(chunk), TIMEOUT)?;
+            let mut answer = [0; PACKET_SIZE];
+            let size = self.handle.read_bulk(ENDPOINT_IN, &mut answer, TIMEOUT)?;
+            if size != chunk.len() {
+                return Err(errors::ErrorKind::StatusError);
+            }
+            for (byte, received) in chunk.iter_mut().zip(answer.iter()) {
+                *byte = received.reverse_bits();
+            }
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self, active: bool) -> Result<(), errors::ErrorKind> {
+        self.set_pins(if active { RESET_ACTIVE } else { RESET_RELEASED })
+    }
+
+    fn name(&self) -> &'static str {
+        "CH341A"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spi_packet_reverses_bits() {
+        assert_eq!(spi_packet(&[0xac, 0x53]), vec![0xa8, 0x35, 0xca]);
+    }
+}
@@ -0,0 +1,296 @@
+//! Atmel-ICE, Power Debugger and EDBG / mEDBG on evaluation kits, used as ISP programmer.
+//!
+//! Commands are JTAGICE3 packets carried in CMSIS-DAP vendor HID reports. Packet is split into
+//! fragments sent with `AVR_CMD` report. Answer is polled with `AVR_RSP` report. STK500v2
+//! commands are sent in AVR ISP scope, so [`IspMode`] of STK500v2 backend does all the work.
+//!
+//! [`IspMode`]: super::stk500v2::IspMode
+use super::stk500v2::{STK500v2, Transport};
+use crate::errors;
+use crate::programmer;
+use crate::specs;
+use rusb::{DeviceHandle, GlobalContext};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const VID: u16 = 0x03eb;
+/// Atmel-ICE, Power Debugger, EDBG, mEDBG and JTAGICE3 with CMSIS-DAP firmware.
+pub const PIDS: [u16; 5] = [0x2141, 0x2144, 0x2111, 0x2145, 0x2140];
+
+/// CMSIS-DAP vendor commands.
+mod report {
+    pub const AVR_CMD: u8 = 0x80;
+    pub const AVR_RSP: u8 = 0x81;
+    /// Size of report header: command, fragment, two bytes of size.
+    pub const HEADER_SIZE: usize = 4;
+    /// Fragment number and total share one byte, four bits each.
+    pub const MAX_FRAGMENTS: usize = 15;
+}
+
+mod scope {
+    pub const GENERAL: u8 = 0x01;
+    pub const AVR_ISP: u8 = 0x11;
+}
+
+mod command {
+    pub const SIGN_ON: u8 = 0x10;
+    pub const SIGN_OFF: u8 = 0x11;
+}
+
+const RSP_OK: u8 = 0x80;
+const TOKEN: u8 = 0x0e;
+const TIMEOUT: Duration = Duration::from_secs(1);
+/// Longest command, i.e. chip erase, must fit.
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Split `packet` into `AVR_CMD` reports of `report_size` bytes.
+fn fragments(packet: &[u8], report_size: usize) -> Result<Vec<Vec<u8>>, errors::ErrorKind> {
+    let chunk_size = report_size - report::HEADER_SIZE;
+    let chunks: Vec<&[u8]> = packet.chunks(chunk_size).collect();
+    if chunks.len() > report::MAX_FRAGMENTS {
+        return Err(errors::ErrorKind::BodyTooLarge {
+            size: packet.len(),
+            max: chunk_size * report::MAX_FRAGMENTS,
+        });
+    }
+    let total = chunks.len() as u8;
+    Ok(chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut data = vec![report::AVR_CMD, ((i as u8 + 1) << 4) | total];
+            data.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+            data.extend_from_slice(chunk);
+            data.resize(report_size, 0);
+            data
+        })
+        .collect())
+}
+
+/// JTAGICE3 packet: token, reserved byte, sequence number (little endian), scope and body.
+fn packet(seq: u16, scope: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![TOKEN, 0];
+    packet.extend_from_slice(&seq.to_le_bytes());
+    packet.push(scope);
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// HID interface of the debugger.
+struct Hid {
+    handle: DeviceHandle<GlobalContext>,
+    endpoint_in: u8,
+    endpoint_out: u8,
+    report_size: usize,
+}
+
+impl Hid {
+    fn open() -> Result<Hid, errors::ErrorKind> {
+        for device in rusb::devices()?.iter() {
+            let descriptor = device.device_descriptor()?;
+            if descriptor.vendor_id() != VID || !PIDS.contains(&descriptor.product_id()) {
+                continue;
+            }
+            let config = device.active_config_descriptor()?;
+            for interface in config.interfaces() {
+                for setting in interface.descriptors() {
+                    // HID class.
+                    if setting.class_code() != 3 {
+                        continue;
+                    }
+                    let mut endpoint_in = None;
+                    let mut endpoint_out = None;
+                    let mut report_size = 0;
+                    for endpoint in setting.endpoint_descriptors() {
+                        report_size = endpoint.max_packet_size() as usize;
+                        match endpoint.direction() {
+                            rusb::Direction::In => endpoint_in = Some(endpoint.address()),
+                            rusb::Direction::Out => endpoint_out = Some(endpoint.address()),
+                        }
+                    }
+                    if let (Some(endpoint_in), Some(endpoint_out)) = (endpoint_in, endpoint_out) {
+                        let handle = device.open()?;
+                        // Not supported on all platforms. Claiming fails later if needed.
+                        let _ = handle.set_auto_detach_kernel_driver(true);
+                        handle.claim_interface(setting.interface_number())?;
+                        return Ok(Hid {
+                            handle,
+                            endpoint_in,
+                            endpoint_out,
+                            report_size,
+                        });
+                    }
+                }
+            }
+        }
+        Err(errors::ErrorKind::Io(std::io::ErrorKind::NotFound.into()))
+    }
+
+    /// Send report and read answer report.
+    fn exchange(&self, report: &[u8]) -> Result<Vec<u8>, errors::ErrorKind> {
+        self.handle
+            .write_interrupt(self.endpoint_out, report, TIMEOUT)?;
+        let mut answer = vec![0; self.report_size];
+        self.handle
+            .read_interrupt(self.endpoint_in, &mut answer, TIMEOUT)?;
+        if answer[0] != report[0] {
+            return Err(errors::ErrorKind::AnswerIdError);
+        }
+        Ok(answer)
+    }
+}
+
+struct Link {
+    hid: Hid,
+    sequence: u16,
+    /// Last answer body, returned by [`Transport::transfer`].
+    answer: Vec<u8>,
+}
+
+impl Link {
+    fn new(hid: Hid) -> Result<Link, errors::ErrorKind> {
+        let mut link = Link {
+            hid,
+            sequence: 0,
+            answer: Vec::new(),
+        };
+        link.sign_on()?;
+        Ok(link)
+    }
+
+    fn send(&mut self, packet: &[u8]) -> Result<(), errors::ErrorKind> {
+        for fragment in fragments(packet, self.hid.report_size)? {
+            // Second byte is set on success.
+            if self.hid.exchange(&fragment)?[1] != 0x01 {
+                return Err(errors::ErrorKind::StatusError);
+            }
+        }
+        Ok(())
+    }
+
+    /// Poll until all fragments of answer are received.
+    fn receive(&mut self) -> Result<Vec<u8>, errors::ErrorKind> {
+        let mut request = vec![report::AVR_RSP];
+        request.resize(self.hid.report_size, 0);
+        let mut packet = Vec::new();
+        let deadline = Instant::now() + ANSWER_TIMEOUT;
+        loop {
+            let answer = self.hid.exchange(&request)?;
+            // No answer yet.
+            if answer[1] == 0 {
+                if Instant::now() > deadline {
+                    return Err(errors::ErrorKind::Io(std::io::ErrorKind::TimedOut.into()));
+                }
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            let (number, total) = (answer[1] >> 4, answer[1] & 0x0f);
+            if number == 0 || total == 0 || number > total {
+                return Err(errors::ErrorKind::MalformedFrame);
+            }
+            let size = u16::from_be_bytes([answer[2], answer[3]]) as usize;
+            let fragment = answer
+                .get(report::HEADER_SIZE..report::HEADER_SIZE + size)
+                .ok_or(errors::ErrorKind::MalformedFrame)?;
+            packet.extend_from_slice(fragment);
+            // Last fragment has its number equal to total.
+            if number == total {
+                return Ok(packet);
+            }
+        }
+    }
+
+    /// Send command in given scope. Returns answer body following scope byte.
+    fn command(&mut self, scope: u8, body: &[u8]) -> Result<Vec<u8>, errors::ErrorKind> {
+        let seq = self.sequence;
+        self.sequence = self.sequence.wrapping_add(1);
+        self.send(&packet(seq, scope, body))?;
+        let answer = self.receive()?;
+        // Token, sequence number and scope.
+        if answer.len() < 4 || answer[0] != TOKEN {
+            return Err(errors::ErrorKind::AnswerIdError);
+        }
+        if u16::from_le_bytes([answer[1], answer[2]]) != seq {
+            return Err(errors::ErrorKind::SequenceError);
+        }
+        if answer[3] != scope {
+            return Err(errors::ErrorKind::AnswerIdError);
+        }
+        Ok(answer[4..].to_vec())
+    }
+}
+
+impl Transport for Link {
+    fn transfer(&mut self, body: &[u8]) -> Result<&[u8], errors::ErrorKind> {
+        self.answer = self.command(scope::AVR_ISP, body)?;
+        Ok(&self.answer)
+    }
+
+    fn sign_on(&mut self) -> Result<programmer::Variant, errors::ErrorKind> {
+        if self
+            .command(scope::GENERAL, &[command::SIGN_ON, 0])?
+            .first()
+            != Some(&RSP_OK)
+        {
+            return Err(errors::ErrorKind::StatusError);
+        }
+        Ok(programmer::Variant::EDBG)
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        // Nothing to do on failure.
+        let _ = self.command(scope::GENERAL, &[command::SIGN_OFF, 0, 0]);
+    }
+}
+
+/// Open first EDBG based debugger found on USB.
+///
+/// Returned programmer is used as any other STK500v2 programmer.
+pub fn open(specs: specs::Specs) -> Result<STK500v2, errors::ErrorKind> {
+    let link = Link::new(Hid::open()?)?;
+    Ok(STK500v2::new(Box::new(link), specs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_fragment() {
+        let reports = fragments(&[1, 2, 3], 64).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(&reports[0][..7], &[0x80, 0x11, 0x00, 0x03, 1, 2, 3]);
+        assert_eq!(reports[0].len(), 64);
+    }
+
+    #[test]
+    fn packet_split_into_fragments() {
+        let reports = fragments(&[0xaa; 100], 64).unwrap();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(&reports[0][..4], &[0x80, 0x12, 0x00, 60]);
+        assert_eq!(&reports[1][..4], &[0x80, 0x22, 0x00, 40]);
+    }
+
+    #[test]
+    fn at_most_fifteen_fragments() {
+        assert_eq!(fragments(&[0xaa; 15 * 60], 64).unwrap().len(), 15);
+        assert!(matches!(
+            fragments(&[0xaa; 15 * 60 + 1], 64),
+            Err(errors::ErrorKind::BodyTooLarge {
+                size: 901,
+                max: 900
+            })
+        ));
+    }
+
+    #[test]
+    fn packet_header() {
+        assert_eq!(
+            packet(0x0201, scope::GENERAL, &[command::SIGN_ON, 0]),
+            vec![0x0e, 0x00, 0x01, 0x02, 0x01, 0x10, 0x00]
+        );
+    }
+}
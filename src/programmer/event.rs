@@ -0,0 +1,71 @@
+//! Typed progress of a programming session, so GUI and TUI frontends can render status without
+//! parsing log output.
+//!
+//! Listener is given to [`ProgrammerKind::connect_with`](super::ProgrammerKind::connect_with).
+//! STK500v2 based programmers report every event. Other backends report `Connected` and
+//! `IspEntered` once connected, `PageRead` and `PageWritten` once memory operation is done and
+//! `Done` and `VerifyMismatch` emitted through [`Session::emit`](super::Session::emit), but no
+//! retries.
+use super::Stats;
+use crate::errors::Mismatch;
+use crate::specs::MemoryType;
+use std::sync::mpsc::Sender;
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "event", rename_all = "snake_case"))]
+pub enum Event {
+    /// Programmer answered sign on.
+    Connected,
+    /// Target answered programming enable.
+    IspEntered { chip: &'static str },
+    /// Command repeated, e.g. programming enable at slower SCK. Counted in
+    /// [`Counters::retries`](super::Counters::retries).
+    Retry { attempt: usize },
+    /// Page starting at `address` was read.
+    PageRead { memory: MemoryType, address: usize },
+    /// Page starting at `address` was written.
+    PageWritten { memory: MemoryType, address: usize },
+    /// Byte read back differs from the written one.
+    VerifyMismatch {
+        memory: MemoryType,
+        mismatch: Mismatch,
+    },
+    /// Memory operation finished.
+    Done { stats: Stats },
+}
+
+/// Receiver of session events.
+pub trait Listener {
+    fn event(&mut self, event: &Event);
+}
+
+impl<F: FnMut(&Event)> Listener for F {
+    fn event(&mut self, event: &Event) {
+        self(event)
+    }
+}
+
+/// Events sent to another thread, e.g. GUI event loop.
+impl Listener for Sender<Event> {
+    fn event(&mut self, event: &Event) {
+        // Receiver gone means nobody listens any more.
+        let _ = self.send(event.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn channel_listener() {
+        let (sender, receiver) = mpsc::channel();
+        let mut listener: Box<dyn Listener + Send> = Box::new(sender);
+        listener.event(&Event::Retry { attempt: 1 });
+        assert_eq!(receiver.try_recv(), Ok(Event::Retry { attempt: 1 }));
+        drop(receiver);
+        listener.event(&Event::Connected);
+    }
+}
@@ -0,0 +1,448 @@
+//! ISP instructions sent directly to the target.
+//!
+//! For programmers with no firmware of their own, e.g. Linux SPI device or bare GPIO pins. Such
+//! programmer only implements [`Bus`]: full duplex byte transfer and reset line. Everything else
+//! is done here with 4 byte instructions from [`command`](crate::command).
+use crate::command as isp_command;
+use crate::errors;
+use crate::programmer::{self, MemoryType};
+use crate::specs;
+use std::convert::TryInto;
+use std::thread;
+use std::time::Duration;
+
+/// Size of ISP instruction.
+const INSTRUCTION_SIZE: usize = 4;
+/// Instructions sent with single transfer when reading memory.
+const READ_CHUNK: usize = 64;
+/// Time for target to start after reset.
+const RESET_DELAY: Duration = Duration::from_millis(20);
+/// Programming enable is retried with reset pulse in between, as datasheets advise.
+const ENABLE_ATTEMPTS: usize = 4;
+/// Fuse and lock writes are not polled. Maximum write time from datasheets.
+const WRITE_DELAY: Duration = Duration::from_millis(5);
+
+/// Connection to target: SPI lines and reset.
+pub trait Bus {
+    /// Shift `data` out and replace it with bytes shifted in. SPI mode 0, MSB first.
+    fn transfer(&mut self, data: &mut [u8]) -> Result<(), errors::ErrorKind>;
+
+    /// Hold target in reset if `active`. Reset pin of AVR is active low.
+    fn reset(&mut self, active: bool) -> Result<(), errors::ErrorKind>;
+
+    /// Name shown to user, e.g. in reports.
+    fn name(&self) -> &'static str;
+}
+
+fn to_bytes(cmd: isp_command::IspCommand) -> [u8; INSTRUCTION_SIZE] {
+    [cmd.0, cmd.1, cmd.2, cmd.3]
+}
+
+/// Instruction with 16 bit address in second and third byte.
+fn addressed(cmd: isp_command::IspCommand, address: usize, data: u8) -> [u8; INSTRUCTION_SIZE] {
+    [cmd.0, (address >> 8) as u8, address as u8, data]
+}
+
+/// Flash instruction for byte `address`. Low and high bytes of word have separate instructions.
+fn flash_instruction(
+    low: isp_command::IspCommand,
+    high: isp_command::IspCommand,
+    address: usize,
+    data: u8,
+) -> [u8; INSTRUCTION_SIZE] {
+    let cmd = if address % 2 == 1 { high } else { low };
+    addressed(cmd, address / 2, data)
+}
+
+pub struct Isp<B: Bus> {
+    bus: B,
+    specs: specs::Specs,
+    counters: programmer::Counters,
+}
+
+impl<B: Bus> Isp<B> {
+    pub fn new(bus: B, specs: specs::Specs) -> Isp<B> {
+        Isp {
+            bus,
+            specs,
+            counters: programmer::Counters::default(),
+        }
+    }
+
+    /// Access connection to target.
+    pub fn bus(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    /// Send instruction. Returns bytes shifted out by target.
+    fn instruction(
+        &mut self,
+        bytes: [u8; INSTRUCTION_SIZE],
+    ) -> Result<[u8; INSTRUCTION_SIZE], errors::ErrorKind> {
+        let mut data = bytes;
+        self.transfer(&mut data)?;
+        Ok(data)
+    }
+
+    /// Shift `data` through target in one bus transfer.
+    fn transfer(&mut self, data: &mut [u8]) -> Result<(), errors::ErrorKind> {
+        self.counters.frames += 1;
+        self.bus.transfer(data)
+    }
+
+    /// Hold reset and enable programming. Target echoes second byte of programming enable
+    /// instruction when in sync.
+    fn enter_isp_mode(&mut self) -> Result<(), errors::ErrorKind> {
+        for _ in 0..ENABLE_ATTEMPTS {
+            self.bus.reset(false)?;
+            self.bus.reset(true)?;
+            thread::sleep(RESET_DELAY);
+            let answer = self.instruction(to_bytes(isp_command::PROGRAMMING_ENABLE))?;
+            if answer[2] == isp_command::PROGRAMMING_ENABLE.1 {
+                return Ok(());
+            }
+            self.counters.retries += 1;
+        }
+        Err(errors::ErrorKind::StatusError)
+    }
+}
+
+impl<B: Bus> TryInto<IspMode<B>> for Isp<B> {
+    type Error = errors::ErrorKind;
+    fn try_into(mut self) -> Result<IspMode<B>, Self::Error> {
+        self.enter_isp_mode()?;
+        Ok(IspMode { prog: self })
+    }
+}
+
+pub struct IspMode<B: Bus> {
+    prog: Isp<B>,
+}
+
+impl<B: Bus> IspMode<B> {
+    /// Access programmer.
+    pub fn programmer(&mut self) -> &mut Isp<B> {
+        &mut self.prog
+    }
+
+    /// Select 64K word segment of flash. Needed only above 128K.
+    fn load_extended_address(&mut self, address: usize) -> Result<(), errors::ErrorKind> {
+        if self.prog.specs.flash.size > 0x20000 {
+            let (cmd, _, _, last) = isp_command::LOAD_EXTENDED_ADDRESS;
+            self.prog
+                .instruction([cmd, 0, (address >> 17) as u8, last])?;
+        }
+        Ok(())
+    }
+
+    /// Send read instructions in chunks and collect last byte of each answer.
+    fn read_with<F>(
+        &mut self,
+        address: usize,
+        buffer: &mut [u8],
+        instruction: F,
+    ) -> Result<(), errors::ErrorKind>
+    where
+        F: Fn(usize) -> [u8; INSTRUCTION_SIZE],
+    {
+        for (i, chunk) in buffer.chunks_mut(READ_CHUNK).enumerate() {
+            let start = address + i * READ_CHUNK;
+            let mut data: Vec<u8> = (start..start + chunk.len())
+                .flat_map(&instruction)
+                .collect();
+            self.prog.transfer(&mut data)?;
+            for (byte, answer) in chunk.iter_mut().zip(data.chunks(INSTRUCTION_SIZE)) {
+                *byte = answer[INSTRUCTION_SIZE - 1];
+            }
+        }
+        Ok(())
+    }
+
+    fn read_flash_pages(
+        &mut self,
+        address: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), errors::ErrorKind> {
+        let mut offset = 0;
+        while offset < buffer.len() {
+            let start = address + offset;
+            // Extended address covers 128K of flash.
+            let end = buffer.len().min((start / 0x20000 + 1) * 0x20000 - address);
+            self.load_extended_address(start)?;
+            self.read_with(start, &mut buffer[offset..end], |addr| {
+                flash_instruction(
+                    isp_command::READ_FLASH_LOW,
+                    isp_command::READ_FLASH_HIGH,
+                    addr & 0x1ffff,
+                    0,
+                )
+            })?;
+            offset = end;
+        }
+        Ok(())
+    }
+
+    fn read_eeprom_pages(
+        &mut self,
+        address: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), errors::ErrorKind> {
+        self.read_with(address, buffer, |addr| {
+            addressed(isp_command::READ_EEPROM, addr, 0)
+        })
+    }
+
+    /// Load whole pages starting at page aligned byte `address` and write each of them.
+    fn write_flash_pages(&mut self, address: usize, bytes: &[u8]) -> Result<(), errors::ErrorKind> {
+        let flash = self.prog.specs.flash.clone();
+        for (page, chunk) in bytes.chunks(flash.page_size).enumerate() {
+            // Only whole pages can be written. Fill the rest with erased value.
+            let mut data = chunk.to_vec();
+            data.resize(flash.page_size, 0xff);
+            // Flash is erased already.
+            if programmer::is_erased(&data) {
+                continue;
+            }
+            let start = address + page * flash.page_size;
+            let mut load: Vec<u8> = data
+                .iter()
+                .enumerate()
+                .flat_map(|(i, byte)| {
+                    flash_instruction(
+                        isp_command::LOAD_FLASH_PAGE_LOW,
+                        isp_command::LOAD_FLASH_PAGE_HIGH,
+                        i,
+                        *byte,
+                    )
+                })
+                .collect();
+            self.prog.transfer(&mut load)?;
+            self.load_extended_address(start)?;
+            self.prog.instruction(addressed(
+                isp_command::WRITE_FLASH,
+                (start & 0x1ffff) / 2,
+                0,
+            ))?;
+            thread::sleep(Duration::from_millis(flash.delay as u64));
+        }
+        Ok(())
+    }
+
+    /// Write EEPROM byte by byte. Works on all chips, whatever their EEPROM page size is.
+    fn write_eeprom_pages(
+        &mut self,
+        address: usize,
+        bytes: &[u8],
+    ) -> Result<(), errors::ErrorKind> {
+        let delay = Duration::from_millis(self.prog.specs.eeprom.delay as u64);
+        for (i, byte) in bytes.iter().enumerate() {
+            self.prog
+                .instruction(addressed(isp_command::WRITE_EEPROM, address + i, *byte))?;
+            thread::sleep(delay);
+        }
+        Ok(())
+    }
+
+    /// Calibration byte at `address`. Chips with several oscillators or frequencies have one
+    /// byte for each.
+    fn read_osccal(&mut self, address: usize) -> Result<u8, errors::ErrorKind> {
+        Ok(self
+            .prog
+            .instruction(addressed(isp_command::READ_OSCCAL, address, 0))?[3])
+    }
+
+    fn read_byte(&mut self, cmd: isp_command::IspCommand) -> Result<u8, errors::ErrorKind> {
+        Ok(self.prog.instruction(to_bytes(cmd))?[3])
+    }
+
+    fn write_byte(
+        &mut self,
+        cmd: isp_command::IspCommand,
+        byte: u8,
+    ) -> Result<(), errors::ErrorKind> {
+        self.prog.instruction([cmd.0, cmd.1, cmd.2, byte])?;
+        thread::sleep(WRITE_DELAY);
+        Ok(())
+    }
+}
+
+impl<B: Bus> programmer::MemoryOps for IspMode<B> {
+    fn layout(&self, memory: MemoryType) -> Result<specs::Memory, errors::ErrorKind> {
+        programmer::layout(&self.prog.specs, memory).cloned()
+    }
+
+    fn read_at(
+        &mut self,
+        memory: MemoryType,
+        address: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), errors::ErrorKind> {
+        match memory {
+            MemoryType::Flash => self.read_flash_pages(address, buffer),
+            MemoryType::Eeprom => self.read_eeprom_pages(address, buffer),
+            MemoryType::Calibration => {
+                for (offset, byte) in buffer.iter_mut().enumerate() {
+                    *byte = self.read_osccal(address + offset)?;
+                }
+                Ok(())
+            }
+            _ => Err(programmer::unsupported(memory)),
+        }
+    }
+
+    fn write_at(
+        &mut self,
+        memory: MemoryType,
+        address: usize,
+        bytes: &[u8],
+    ) -> Result<(), errors::ErrorKind> {
+        match memory {
+            MemoryType::Flash => self.write_flash_pages(address, bytes),
+            MemoryType::Eeprom => self.write_eeprom_pages(address, bytes),
+            _ => Err(programmer::unsupported(memory)),
+        }
+    }
+}
+
+impl<B: Bus> programmer::Erase for IspMode<B> {
+    fn erase(&mut self) -> Result<(), errors::ErrorKind> {
+        self.prog.instruction(to_bytes(isp_command::CHIP_ERASE))?;
+        thread::sleep(Duration::from_millis(self.prog.specs.erase_delay.into()));
+        // Chip erase ends programming mode on some devices.
+        self.prog.enter_isp_mode()
+    }
+}
+
+impl<B: Bus> programmer::Programmer for IspMode<B> {
+    /// Release reset, so target starts its program.
+    fn close(mut self) -> Result<(), errors::ErrorKind> {
+        self.prog.bus.reset(false)
+    }
+}
+
+impl<B: Bus> programmer::AVRLockByteGet for IspMode<B> {
+    fn get_lock_byte(&mut self) -> Result<u8, errors::ErrorKind> {
+        self.read_byte(isp_command::READ_LOCK)
+    }
+}
+
+impl<B: Bus> programmer::AVRLockByteSet for IspMode<B> {
+    fn set_lock_byte(&mut self, byte: u8) -> Result<u8, errors::ErrorKind> {
+        programmer::write_lock(self, programmer::LOCK_BITS, byte, |isp, byte| {
+            isp.write_byte(isp_command::WRITE_LOCK, byte)
+        })
+    }
+}
+
+impl<B: Bus> programmer::AVROsccalGet for IspMode<B> {
+    fn get_osccal(&mut self) -> Result<u8, errors::ErrorKind> {
+        self.read_osccal(0)
+    }
+}
+
+impl<B: Bus> programmer::AVRFuseGet for IspMode<B> {
+    fn get_fuses(&mut self) -> Result<programmer::AVRFuse, errors::ErrorKind> {
+        Ok(programmer::AVRFuse {
+            low: self.read_byte(isp_command::READ_LOW_FUSE)?,
+            high: self.read_byte(isp_command::READ_HIGH_FUSE)?,
+            extended: self.read_byte(isp_command::READ_EXTENDED_FUSE)?,
+        })
+    }
+}
+
+impl<B: Bus> programmer::AVRFuseSet for IspMode<B> {
+    fn set_fuses(
+        &mut self,
+        fuses: &programmer::AVRFuse,
+    ) -> Result<programmer::AVRFuse, errors::ErrorKind> {
+        for fuse in self.prog.specs.fuses() {
+            let cmd = match fuse {
+                specs::Fuse::Low => isp_command::WRITE_LOW_FUSE,
+                specs::Fuse::High => isp_command::WRITE_HIGH_FUSE,
+                specs::Fuse::Extended => isp_command::WRITE_EXTENDED_FUSE,
+            };
+            self.write_byte(cmd, fuses.get(fuse))?;
+        }
+        let read = programmer::AVRFuseGet::get_fuses(self)?;
+        programmer::check_fuses(self.prog.specs.fuse_bits, fuses, read)
+    }
+}
+
+impl<B: Bus> programmer::MCUSignature for IspMode<B> {
+    fn get_mcu_signature(&mut self) -> Result<specs::Signature, errors::ErrorKind> {
+        let mut signature = [0; 3];
+        self.read_with(0, &mut signature, |addr| {
+            let (a, b, _, d) = isp_command::READ_SIGNATURE;
+            [a, b, addr as u8, d]
+        })?;
+        Ok(specs::Signature::from(signature))
+    }
+}
+
+impl<B: Bus> programmer::Session for IspMode<B> {
+    fn identify(&mut self) -> Result<programmer::Identity, errors::ErrorKind> {
+        Ok(programmer::Identity {
+            name: String::from(self.prog.bus.name()),
+            hardware: None,
+            firmware: None,
+        })
+    }
+
+    fn counters(&self) -> programmer::Counters {
+        self.prog.counters
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), errors::ErrorKind> {
+        programmer::Programmer::close(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flash_instruction_selects_byte_of_word() {
+        let (low, high) = (isp_command::READ_FLASH_LOW, isp_command::READ_FLASH_HIGH);
+        assert_eq!(
+            flash_instruction(low, high, 0x0202, 0),
+            [0x20, 0x01, 0x01, 0]
+        );
+        assert_eq!(
+            flash_instruction(low, high, 0x0203, 0),
+            [0x28, 0x01, 0x01, 0]
+        );
+    }
+
+    /// Target answering calibration byte instruction with 0x80 plus address.
+    struct Oscillators;
+
+    impl Bus for Oscillators {
+        fn transfer(&mut self, data: &mut [u8]) -> Result<(), errors::ErrorKind> {
+            if data[0] == isp_command::PROGRAMMING_ENABLE.0 {
+                data[2] = isp_command::PROGRAMMING_ENABLE.1;
+            } else if data[0] == isp_command::READ_OSCCAL.0 {
+                data[3] = 0x80 + data[2];
+            }
+            Ok(())
+        }
+
+        fn reset(&mut self, _active: bool) -> Result<(), errors::ErrorKind> {
+            Ok(())
+        }
+
+        fn name(&self) -> &'static str {
+            "oscillators"
+        }
+    }
+
+    #[test]
+    fn reads_calibration_bytes() {
+        let mut isp: IspMode<Oscillators> = Isp::new(Oscillators, specs::atmega::ATMEGA_32)
+            .try_into()
+            .unwrap();
+        let mut bytes = [0; 4];
+        programmer::MemoryOps::read(&mut isp, MemoryType::Calibration, &mut bytes).unwrap();
+        assert_eq!(bytes, [0x80, 0x81, 0x82, 0x83]);
+    }
+}
@@ -0,0 +1,331 @@
+//! JTAGICE mkII used as ISP programmer.
+//!
+//! In SPI emulator mode, STK500v2 commands are wrapped in `ISP_PACKET` command, so [`IspMode`]
+//! of STK500v2 backend does all the work. Only framing differs:
+//!
+//! 1. Message start
+//! 1. Sequence number. Two bytes, little endian.
+//! 1. Body length. Four bytes, little endian.
+//! 1. Token
+//! 1. Body
+//! 1. CRC16 (CCITT, reflected) of all previous bytes. Two bytes, little endian.
+//!
+//! [`IspMode`]: super::stk500v2::IspMode
+use super::stk500v2::{STK500v2, Transport};
+use crate::errors;
+use crate::programmer;
+use crate::specs;
+use serial::core::{PortSettings, SerialPort};
+use std::io::prelude::*;
+use std::time::Duration;
+
+#[allow(dead_code)]
+mod command {
+    pub const SIGN_OFF: u8 = 0x00;
+    pub const GET_SIGN_ON: u8 = 0x01;
+    pub const SET_PARAMETER: u8 = 0x02;
+    pub const ISP_PACKET: u8 = 0x2f;
+}
+
+mod response {
+    pub const OK: u8 = 0x80;
+    pub const SIGN_ON: u8 = 0x86;
+    pub const SPI_DATA: u8 = 0x88;
+    /// Codes from this one up are failures.
+    pub const FAILED: u8 = 0xa0;
+}
+
+const PARAM_EMULATOR_MODE: u8 = 0x03;
+const EMULATOR_MODE_SPI: u8 = 0x03;
+
+const MESSAGE_START: u8 = 0x1b;
+const TOKEN: u8 = 0x0e;
+const HEADER_SIZE: usize = 8;
+const CRC_SIZE: usize = 2;
+/// Largest body accepted from programmer. ISP answers are much shorter.
+const MAX_BODY_SIZE: usize = 1024;
+/// Sequence number of event messages sent by programmer on its own.
+const EVENT_SEQUENCE: u16 = 0xffff;
+/// Programmer may miss first message after port is opened.
+const SIGN_ON_ATTEMPTS: usize = 3;
+
+pub const VID: u16 = 0x03eb;
+pub const PID: u16 = 0x2103;
+
+const SERIAL_SETTINGS: PortSettings = PortSettings {
+    // Default after power up.
+    baud_rate: serial::Baud19200,
+    char_size: serial::Bits8,
+    parity: serial::ParityNone,
+    stop_bits: serial::Stop1,
+    flow_control: serial::FlowNone,
+};
+
+/// CRC16 used in frames. CCITT polynomial, reflected, initial value 0xFFFF.
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0xffff;
+    for byte in bytes {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x8408
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+fn frame(seq: u16, body: &[u8]) -> Vec<u8> {
+    let mut frame = vec![MESSAGE_START];
+    frame.extend_from_slice(&seq.to_le_bytes());
+    frame.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    frame.push(TOKEN);
+    frame.extend_from_slice(body);
+    let crc = crc16(&frame);
+    frame.extend_from_slice(&crc.to_le_bytes());
+    frame
+}
+
+/// Body size given in frame header.
+fn body_size(header: &[u8]) -> Result<usize, errors::ErrorKind> {
+    if header[0] != MESSAGE_START || header[7] != TOKEN {
+        return Err(errors::ErrorKind::AnswerIdError);
+    }
+    let size = u32::from_le_bytes([header[3], header[4], header[5], header[6]]) as usize;
+    if size > MAX_BODY_SIZE {
+        return Err(errors::ErrorKind::MalformedFrame);
+    }
+    Ok(size)
+}
+
+/// Split received frame into sequence number and body.
+fn unframe(frame: &[u8]) -> Result<(u16, &[u8]), errors::ErrorKind> {
+    let end = frame
+        .len()
+        .checked_sub(CRC_SIZE)
+        .filter(|&end| end >= HEADER_SIZE)
+        .ok_or(errors::ErrorKind::MalformedFrame)?;
+    if crc16(&frame[..end]) != u16::from_le_bytes([frame[end], frame[end + 1]]) {
+        return Err(errors::ErrorKind::ChecksumError);
+    }
+    Ok((
+        u16::from_le_bytes([frame[1], frame[2]]),
+        &frame[HEADER_SIZE..end],
+    ))
+}
+
+enum Channel {
+    Serial(serial::SystemPort),
+    #[cfg(feature = "usb")]
+    Usb {
+        handle: rusb::DeviceHandle<rusb::GlobalContext>,
+        /// Received bytes not consumed yet. USB packets do not follow frame boundaries.
+        pending: Vec<u8>,
+    },
+}
+
+#[cfg(feature = "usb")]
+mod endpoint {
+    pub const OUT: u8 = 0x02;
+    pub const IN: u8 = 0x82;
+    pub const PACKET_SIZE: usize = 64;
+}
+
+impl Channel {
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), errors::ErrorKind> {
+        match self {
+            Channel::Serial(port) => {
+                port.write_all(bytes)?;
+                port.flush()?;
+            }
+            #[cfg(feature = "usb")]
+            Channel::Usb { handle, .. } => {
+                handle.write_bulk(endpoint::OUT, bytes, Duration::from_secs(1))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_exact(&mut self, buffer: &mut [u8]) -> Result<(), errors::ErrorKind> {
+        match self {
+            Channel::Serial(port) => port.read_exact(buffer)?,
+            #[cfg(feature = "usb")]
+            Channel::Usb { handle, pending } => {
+                while pending.len() < buffer.len() {
+                    let mut packet = [0; endpoint::PACKET_SIZE];
+                    let size =
+                        handle.read_bulk(endpoint::IN, &mut packet, Duration::from_secs(1))?;
+                    pending.extend_from_slice(&packet[..size]);
+                }
+                buffer.copy_from_slice(&pending[..buffer.len()]);
+                pending.drain(..buffer.len());
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Link {
+    channel: Channel,
+    sequence: u16,
+    /// Last answer body, returned by [`Transport::transfer`].
+    answer: Vec<u8>,
+}
+
+impl Link {
+    fn new(channel: Channel) -> Result<Link, errors::ErrorKind> {
+        let mut link = Link {
+            channel,
+            sequence: 0,
+            answer: Vec::new(),
+        };
+        let mut result = Ok(programmer::Variant::JTAGICE_MKII);
+        for _ in 0..SIGN_ON_ATTEMPTS {
+            result = link.sign_on();
+            if result.is_ok() {
+                break;
+            }
+        }
+        result?;
+        let answer = link.command(&[
+            command::SET_PARAMETER,
+            PARAM_EMULATOR_MODE,
+            EMULATOR_MODE_SPI,
+        ])?;
+        if answer[0] != response::OK {
+            return Err(errors::ErrorKind::AnswerIdError);
+        }
+        Ok(link)
+    }
+
+    fn read_frame(&mut self) -> Result<Vec<u8>, errors::ErrorKind> {
+        let mut frame = vec![0; HEADER_SIZE];
+        self.channel.read_exact(&mut frame)?;
+        let size = body_size(&frame)?;
+        frame.resize(HEADER_SIZE + size + CRC_SIZE, 0);
+        self.channel.read_exact(&mut frame[HEADER_SIZE..])?;
+        Ok(frame)
+    }
+
+    /// Send command body and return answer body. Failure responses are errors.
+    fn command(&mut self, body: &[u8]) -> Result<Vec<u8>, errors::ErrorKind> {
+        let seq = self.sequence;
+        self.sequence = self.sequence.wrapping_add(1);
+        if self.sequence == EVENT_SEQUENCE {
+            self.sequence = 0;
+        }
+        self.channel.write_all(&frame(seq, body))?;
+        loop {
+            let frame = self.read_frame()?;
+            let (answer_seq, answer) = unframe(&frame)?;
+            if answer_seq == EVENT_SEQUENCE {
+                continue;
+            }
+            if answer_seq != seq {
+                return Err(errors::ErrorKind::SequenceError);
+            }
+            match answer.first() {
+                None => return Err(errors::ErrorKind::MalformedFrame),
+                Some(&status) if status >= response::FAILED => {
+                    return Err(errors::ErrorKind::StatusError)
+                }
+                Some(_) => (),
+            }
+            return Ok(answer.to_vec());
+        }
+    }
+}
+
+impl Transport for Link {
+    fn transfer(&mut self, body: &[u8]) -> Result<&[u8], errors::ErrorKind> {
+        let mut packet = vec![command::ISP_PACKET];
+        packet.extend_from_slice(&(body.len() as u16).to_le_bytes());
+        packet.extend_from_slice(body);
+        self.answer = self.command(&packet)?;
+        if self.answer[0] != response::SPI_DATA {
+            return Err(errors::ErrorKind::AnswerIdError);
+        }
+        Ok(&self.answer[1..])
+    }
+
+    fn sign_on(&mut self) -> Result<programmer::Variant, errors::ErrorKind> {
+        if self.command(&[command::GET_SIGN_ON])?[0] != response::SIGN_ON {
+            return Err(errors::ErrorKind::UnknownProgrammer);
+        }
+        Ok(programmer::Variant::JTAGICE_MKII)
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        // Programmer keeps emulator mode otherwise. Nothing to do on failure.
+        let _ = self.command(&[command::SIGN_OFF]);
+    }
+}
+
+/// Open JTAGICE mkII connected to serial port and switch it to ISP mode.
+///
+/// Returned programmer is used as any other STK500v2 programmer.
+pub fn open(port: &str, specs: specs::Specs) -> Result<STK500v2, errors::ErrorKind> {
+    let mut port = serial::open(port).map_err(std::io::Error::from)?;
+    port.configure(&SERIAL_SETTINGS)
+        .map_err(std::io::Error::from)?;
+    port.set_timeout(Duration::from_secs(1))
+        .map_err(std::io::Error::from)?;
+    let link = Link::new(Channel::Serial(port))?;
+    Ok(STK500v2::new(Box::new(link), specs))
+}
+
+/// Open first JTAGICE mkII found on USB and switch it to ISP mode.
+#[cfg(feature = "usb")]
+pub fn open_usb(specs: specs::Specs) -> Result<STK500v2, errors::ErrorKind> {
+    let handle = rusb::open_device_with_vid_pid(VID, PID)
+        .ok_or(errors::ErrorKind::Io(std::io::ErrorKind::NotFound.into()))?;
+    handle.claim_interface(0)?;
+    let link = Link::new(Channel::Usb {
+        handle,
+        pending: Vec::new(),
+    })?;
+    Ok(STK500v2::new(Box::new(link), specs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x6f91);
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let sent = frame(0x0102, &[command::GET_SIGN_ON]);
+        assert_eq!(&sent[..HEADER_SIZE], &[0x1b, 0x02, 0x01, 1, 0, 0, 0, 0x0e]);
+        assert_eq!(body_size(&sent).unwrap(), 1);
+        assert_eq!(unframe(&sent).unwrap(), (0x0102, &[0x01][..]));
+    }
+
+    #[test]
+    fn frame_bad_crc() {
+        let mut sent = frame(0, &[command::GET_SIGN_ON]);
+        sent[HEADER_SIZE] = 0x02;
+        match unframe(&sent).unwrap_err() {
+            errors::ErrorKind::ChecksumError => (),
+            _ => panic!("wrong error returned"),
+        };
+    }
+
+    #[test]
+    fn short_frame_is_malformed() {
+        let sent = frame(0, &[command::GET_SIGN_ON]);
+        for size in [0, 1, HEADER_SIZE + 1] {
+            assert!(matches!(
+                unframe(&sent[..size]),
+                Err(errors::ErrorKind::MalformedFrame)
+            ));
+        }
+    }
+}
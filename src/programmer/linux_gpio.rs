@@ -0,0 +1,89 @@
+//! Target connected to bare Linux GPIO lines, for boards without usable SPI controller.
+//!
+//! SPI is bit-banged through GPIO character device (`/dev/gpiochipN`). Slow, but needs nothing
+//! but four wires. ISP instructions are sent by [`isp`](super::isp).
+use super::isp;
+use crate::errors;
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+use std::thread;
+use std::time::Duration;
+
+const CONSUMER: &str = "avrisp";
+
+/// Line offsets of GPIO chip.
+#[derive(Debug, Clone)]
+pub struct Pins {
+    pub mosi: u32,
+    pub miso: u32,
+    pub sck: u32,
+    pub reset: u32,
+}
+
+pub struct LinuxGpio {
+    mosi: LineHandle,
+    miso: LineHandle,
+    sck: LineHandle,
+    reset: LineHandle,
+    /// Half period of SCK.
+    delay: Duration,
+}
+
+impl LinuxGpio {
+    /// Claim `pins` of GPIO `chip` (e.g. `/dev/gpiochip0`). SCK is held for `delay` in each
+    /// state. It has to be longer than two target clock cycles.
+    pub fn open(chip: &str, pins: &Pins, delay: Duration) -> Result<LinuxGpio, errors::ErrorKind> {
+        let mut chip = Chip::new(chip)?;
+        let mut output = |line, value| {
+            chip.get_line(line)?
+                .request(LineRequestFlags::OUTPUT, value, CONSUMER)
+        };
+        let mosi = output(pins.mosi, 0)?;
+        let sck = output(pins.sck, 0)?;
+        // Start with target running.
+        let reset = output(pins.reset, 1)?;
+        let miso = chip
+            .get_line(pins.miso)?
+            .request(LineRequestFlags::INPUT, 0, CONSUMER)?;
+        Ok(LinuxGpio {
+            mosi,
+            miso,
+            sck,
+            reset,
+            delay,
+        })
+    }
+
+    /// Shift byte out, MSB first. Target samples MOSI and we sample MISO on rising SCK edge.
+    fn shift(&mut self, byte: u8) -> Result<u8, errors::ErrorKind> {
+        let mut received = 0;
+        for bit in (0..8).rev() {
+            self.mosi.set_value((byte >> bit) & 1)?;
+            thread::sleep(self.delay);
+            self.sck.set_value(1)?;
+            received |= self.miso.get_value()? << bit;
+            thread::sleep(self.delay);
+            self.sck.set_value(0)?;
+        }
+        Ok(received)
+    }
+}
+
+impl isp::Bus for LinuxGpio {
+    fn transfer(&mut self, data: &mut [u8]) -> Result<(), errors::ErrorKind> {
+        for byte in data.iter_mut() {
+            *byte = self.shift(*byte)?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self, active: bool) -> Result<(), errors::ErrorKind> {
+        // SCK must be low while reset is applied.
+        self.sck.set_value(0)?;
+        self.reset.set_value(if active { 0 } else { 1 })?;
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "Linux GPIO"
+    }
+}
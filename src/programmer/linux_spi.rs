@@ -0,0 +1,62 @@
+//! Target connected directly to Linux SPI device (`/dev/spidevX.Y`), e.g. Raspberry Pi header.
+//!
+//! Reset is driven by GPIO line, as chip select of SPI controller toggles between transfers.
+//! No programmer hardware is needed, ISP instructions are sent by [`isp`](super::isp).
+use super::isp;
+use crate::errors;
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+use spidev::{SpiModeFlags, Spidev, SpidevOptions, SpidevTransfer};
+
+/// SCK frequency. Has to be below quarter of target clock, so this one fits chips running at
+/// 1 MHz from factory.
+pub const DEFAULT_SPEED: u32 = 200_000;
+const CONSUMER: &str = "avrisp";
+
+pub struct LinuxSpi {
+    spi: Spidev,
+    reset: LineHandle,
+}
+
+impl LinuxSpi {
+    /// Open SPI device and claim `reset_line` of GPIO `chip` (e.g. `/dev/gpiochip0`). SCK runs
+    /// at `speed` Hz.
+    pub fn open(
+        spi: &str,
+        chip: &str,
+        reset_line: u32,
+        speed: u32,
+    ) -> Result<LinuxSpi, errors::ErrorKind> {
+        let mut spi = Spidev::open(spi)?;
+        let options = SpidevOptions::new()
+            .bits_per_word(8)
+            .max_speed_hz(speed)
+            .mode(SpiModeFlags::SPI_MODE_0)
+            .build();
+        spi.configure(&options)?;
+        // Start with target running.
+        let reset = Chip::new(chip)?.get_line(reset_line)?.request(
+            LineRequestFlags::OUTPUT,
+            1,
+            CONSUMER,
+        )?;
+        Ok(LinuxSpi { spi, reset })
+    }
+}
+
+impl isp::Bus for LinuxSpi {
+    fn transfer(&mut self, data: &mut [u8]) -> Result<(), errors::ErrorKind> {
+        let sent = data.to_vec();
+        let mut transfer = SpidevTransfer::read_write(&sent, data);
+        self.spi.transfer(&mut transfer)?;
+        Ok(())
+    }
+
+    fn reset(&mut self, active: bool) -> Result<(), errors::ErrorKind> {
+        self.reset.set_value(if active { 0 } else { 1 })?;
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "Linux SPI"
+    }
+}
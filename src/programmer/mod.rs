@@ -1,30 +1,225 @@
+pub mod avr109;
+#[cfg(feature = "usb")]
+pub mod ch341a;
+#[cfg(feature = "usb")]
+pub mod edbg;
+pub mod event;
+pub mod isp;
+pub mod jtagice_mkii;
+#[cfg(all(target_os = "linux", feature = "linux"))]
+pub mod linux_gpio;
+#[cfg(all(target_os = "linux", feature = "linux"))]
+pub mod linux_spi;
+#[cfg(feature = "usb")]
+pub mod mpsse;
+pub mod session;
+pub mod stk500v1;
 #[allow(dead_code)]
 pub mod stk500v2;
+#[cfg(feature = "usb")]
+pub mod stk600;
+pub mod tpi;
+pub mod updi;
+#[cfg(feature = "usb")]
+pub mod usbtiny;
 use crate::errors;
+use crate::format::{Firmware, Image};
 use crate::specs;
-use std::convert::TryFrom;
+pub use crate::specs::MemoryType;
+pub use event::{Event, Listener};
+pub use session::{
+    read_memory, write_memory, ChipStatus, Counters, Identity, ProgrammerKind, Session, Stats,
+};
 use std::fmt;
+use std::str::FromStr;
 
+/// Low, high and extended fuse bytes. Chips without extended fuse keep it at 0xff.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AVRFuse {
     low: u8,
     high: u8,
     extended: u8,
 }
 
+impl AVRFuse {
+    pub fn new(low: u8, high: u8, extended: u8) -> AVRFuse {
+        AVRFuse {
+            low,
+            high,
+            extended,
+        }
+    }
+
+    /// Factory fuse values of chip.
+    pub fn defaults(specs: &specs::Specs) -> AVRFuse {
+        let (low, high, extended) = specs.fuse_defaults;
+        AVRFuse::new(low, high, extended)
+    }
+
+    /// Make sure fuses do not disable ISP programming and leave reserved bits unprogrammed.
+    ///
+    /// Unsafe are: disabled serial programming (`SPIEN`), reset pin used as IO (`RSTDISBL`) and
+    /// enabled debugWIRE (`DWEN`). Fuse bits are active low.
+    pub fn check_safe(&self, specs: &specs::Specs) -> Result<(), errors::ErrorKind> {
+        for fuse in specs.fuses() {
+            let mask = specs.fuse_mask(fuse);
+            let value = self.get(fuse);
+            if value | mask != 0xff {
+                return Err(errors::ErrorKind::ReservedFuseBits { fuse, value, mask });
+            }
+        }
+        for bits in specs.fuse_bits {
+            let value = bits.value(self.get(bits.fuse));
+            let unsafe_value = match bits.name {
+                "SPIEN" => value != 0,
+                "RSTDISBL" | "DWEN" => value == 0,
+                _ => false,
+            };
+            if unsafe_value {
+                return Err(errors::ErrorKind::UnsafeFuses);
+            }
+        }
+        Ok(())
+    }
+
+    /// Boot section, if `BOOTRST` makes chip start from a resident bootloader.
+    pub fn boot_section(&self, specs: &specs::Specs) -> Option<std::ops::Range<usize>> {
+        let bootrst = specs.fuse_field("BOOTRST")?;
+        // Fuse bits are active low.
+        if bootrst.value(self.get(bootrst.fuse)) != 0 {
+            return None;
+        }
+        let bootsz = specs.fuse_field("BOOTSZ")?;
+        specs.boot_section(bootsz.value(self.get(bootsz.fuse)))
+    }
+
+    /// Make sure flash `image` does not replace resident bootloader by accident.
+    ///
+    /// Image is taken as a bootloader when all its data is inside boot section. Image with data
+    /// both below and inside of it is refused.
+    pub fn check_boot_section(
+        &self,
+        specs: &specs::Specs,
+        image: &[u8],
+    ) -> Result<(), errors::ErrorKind> {
+        let boot = match self.boot_section(specs) {
+            Some(boot) => boot,
+            None => return Ok(()),
+        };
+        let used = |bytes: &[u8]| bytes.iter().position(|&byte| byte != 0xff);
+        let split = boot.start.min(image.len());
+        match (used(&image[..split]), used(&image[split..])) {
+            (Some(_), Some(offset)) => Err(errors::ErrorKind::BootSection {
+                address: split + offset,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Target clock in Hz selected by `CKSEL` bits and divided by `CKDIV8`. None when chip runs
+    /// from crystal or external clock, which frequency is not known.
+    pub fn clock(&self, specs: &specs::Specs) -> Option<u32> {
+        let cksel = self.low & 0x0f;
+        let (_, clock) = specs.rc_clocks.iter().find(|(value, _)| *value == cksel)?;
+        // Fuse bits are active low.
+        let divided = specs
+            .fuse_bits
+            .iter()
+            .any(|bits| bits.name == "CKDIV8" && bits.value(self.get(bits.fuse)) == 0);
+        Some(if divided { clock / 8 } else { *clock })
+    }
+
+    /// Return value of given fuse byte.
+    pub fn get(&self, fuse: specs::Fuse) -> u8 {
+        match fuse {
+            specs::Fuse::Low => self.low,
+            specs::Fuse::High => self.high,
+            specs::Fuse::Extended => self.extended,
+        }
+    }
+
+    /// Change value of given fuse byte.
+    pub fn set(&mut self, fuse: specs::Fuse, value: u8) {
+        match fuse {
+            specs::Fuse::Low => self.low = value,
+            specs::Fuse::High => self.high = value,
+            specs::Fuse::Extended => self.extended = value,
+        }
+    }
+
+    pub fn low(&self) -> u8 {
+        self.low
+    }
+
+    pub fn high(&self) -> u8 {
+        self.high
+    }
+
+    pub fn extended(&self) -> u8 {
+        self.extended
+    }
+}
+
+/// Parse `LOW:HIGH[:EXTENDED]` hex bytes, e.g. `E4:D9:FF` or `0xe1:0x99`. Extended fuse defaults to
+/// 0xff.
+impl FromStr for AVRFuse {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<AVRFuse, String> {
+        let byte = |byte: &str| {
+            let digits = byte.trim_start_matches("0x").trim_start_matches("0X");
+            u8::from_str_radix(digits, 16).map_err(|_| format!("Invalid hex byte: {}", byte))
+        };
+        let bytes = value
+            .split(':')
+            .map(byte)
+            .collect::<Result<Vec<u8>, String>>()?;
+        match bytes[..] {
+            [low, high] => Ok(AVRFuse::new(low, high, 0xff)),
+            [low, high, extended] => Ok(AVRFuse::new(low, high, extended)),
+            _ => Err(String::from("Expected LOW:HIGH[:EXTENDED] fuse bytes")),
+        }
+    }
+}
+
 impl fmt::Display for AVRFuse {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        return write!(
+        write!(
             f,
             "low: {:#04X} high: {:#04X} extended: {:#04X}",
             self.low, self.high, self.extended,
-        );
+        )
+    }
+}
+
+/// Programmer firmware version.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SwVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl fmt::Display for SwVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor,)
     }
 }
 
 #[allow(non_camel_case_types)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Variant {
     STK500_V2,
     AVRISP_2,
+    /// STK500v1 protocol. Also reported by ArduinoISP sketch.
+    AVRISP,
+    JTAGICE_MKII,
+    /// Atmel-ICE, Power Debugger or EDBG on evaluation kit.
+    EDBG,
+    AVRISP_MK2,
+    STK600,
+    /// Name reported by programmer not known to this crate.
+    Unknown(String),
 }
 
 impl fmt::Display for Variant {
@@ -32,17 +227,59 @@ impl fmt::Display for Variant {
         match &self {
             Variant::STK500_V2 => write!(f, "STK 500 v2"),
             Variant::AVRISP_2 => write!(f, "AVR ISP 2"),
+            Variant::AVRISP => write!(f, "AVR ISP"),
+            Variant::JTAGICE_MKII => write!(f, "JTAGICE mkII"),
+            Variant::EDBG => write!(f, "EDBG"),
+            Variant::AVRISP_MK2 => write!(f, "AVR ISP mkII"),
+            Variant::STK600 => write!(f, "STK600"),
+            Variant::Unknown(name) => write!(f, "{} (unknown)", name),
         }
     }
 }
 
-impl TryFrom<String> for Variant {
-    type Error = errors::UnknownProgrammer;
-    fn try_from(string: String) -> Result<Self, Self::Error> {
-        match string.as_ref() {
-            "STK500_2" => Ok(Variant::STK500_V2),
-            "AVRISP_2" => Ok(Variant::AVRISP_2),
-            _ => Err(errors::UnknownProgrammer {}),
+/// What programmer hardware can do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capabilities {
+    pub tpi: bool,
+    pub pdi: bool,
+    /// High voltage parallel programming.
+    pub pp: bool,
+    /// Target voltage can be set by programmer.
+    pub voltage: bool,
+}
+
+impl Variant {
+    /// Capabilities of programmer hardware. Unknown programmers report no optional ones.
+    pub fn capabilities(&self) -> Capabilities {
+        let (tpi, pdi, pp, voltage) = match self {
+            Variant::STK500_V2 => (false, false, true, true),
+            Variant::AVRISP_2 | Variant::AVRISP => (false, false, false, false),
+            Variant::JTAGICE_MKII => (false, true, false, false),
+            Variant::EDBG | Variant::AVRISP_MK2 => (true, true, false, false),
+            Variant::STK600 => (true, true, true, true),
+            Variant::Unknown(_) => (false, false, false, false),
+        };
+        Capabilities {
+            tpi,
+            pdi,
+            pp,
+            voltage,
+        }
+    }
+}
+
+impl From<&[u8]> for Variant {
+    /// Name as sent on sign on. NUL and space padding is trimmed.
+    fn from(name: &[u8]) -> Self {
+        let name = String::from_utf8_lossy(name);
+        match name.trim_matches(|c: char| c == '\0' || c.is_whitespace()) {
+            "STK500_2" => Variant::STK500_V2,
+            "AVRISP_2" => Variant::AVRISP_2,
+            "AVR ISP" | "AVRISP" => Variant::AVRISP,
+            "AVRISP_MK2" | "AVRISP mkII" | "AVRISPmkII" => Variant::AVRISP_MK2,
+            "JTAGICE mkII" | "JTAGICEmkII" => Variant::JTAGICE_MKII,
+            "STK600" | "STK600_2" => Variant::STK600,
+            name => Variant::Unknown(String::from(name)),
         }
     }
 }
@@ -62,6 +299,10 @@ pub trait AVRFuseGet {
 }
 
 pub trait AVRFuseSet {
+    /// Write fuses present in chip. Returns fuses read back after writing.
+    ///
+    /// Fails with `FuseVerifyError` if a used bit was not written. Values are written as given,
+    /// [`write_fuses`] refuses the ones which can lock the chip up.
     fn set_fuses(&mut self, fuses: &AVRFuse) -> Result<AVRFuse, errors::ErrorKind>;
 }
 
@@ -70,17 +311,883 @@ pub trait AVRLockByteGet {
 }
 
 pub trait AVRLockByteSet {
+    /// Write lock byte. Returns lock byte read back after writing.
+    ///
+    /// Lock bits can only be programmed. Byte which would clear some of them is refused with
+    /// `LockNeedsErase`, see [`erase_and_lock`].
     fn set_lock_byte(&mut self, byte: u8) -> Result<u8, errors::ErrorKind>;
 }
 
+pub trait AVROsccalGet {
+    /// Read oscillator calibration byte.
+    fn get_osccal(&mut self) -> Result<u8, errors::ErrorKind>;
+}
+
 pub trait MCUSignature {
     fn get_mcu_signature(&mut self) -> Result<specs::Signature, errors::ErrorKind>;
 }
 
-pub trait EEPROMRead {
-    fn read(&mut self, bytes: &mut [u8]) -> Result<(), errors::ErrorKind>;
+/// Access to chip memories.
+///
+/// Backends give layout and page access of each memory, reading and writing is built on top
+/// of them. Memories not present in chip or not reachable by programmer give `Unsupported`
+/// error.
+pub trait MemoryOps {
+    /// Address space of `memory`, taken from chip specification.
+    fn layout(&self, memory: MemoryType) -> Result<specs::Memory, errors::ErrorKind>;
+
+    /// Read `buffer.len()` bytes starting at page aligned `address`.
+    fn read_at(
+        &mut self,
+        memory: MemoryType,
+        address: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), errors::ErrorKind>;
+
+    /// Write `bytes` starting at `address`, page aligned for flash. Last flash page is padded
+    /// with 0xFF and pages holding only 0xFF may be skipped. EEPROM is written as given, in
+    /// parts of pages too.
+    fn write_at(
+        &mut self,
+        memory: MemoryType,
+        address: usize,
+        bytes: &[u8],
+    ) -> Result<(), errors::ErrorKind>;
+
+    /// Write whole pages changed by [`patch`](Self::patch). `current` is their content before.
+    ///
+    /// On ISP, flash pages can not be erased on their own, so only bits set to 1 can be cleared.
+    /// Programmers able to erase single pages override this and write any value.
+    fn patch_at(
+        &mut self,
+        memory: MemoryType,
+        address: usize,
+        current: &[u8],
+        bytes: &[u8],
+    ) -> Result<(), errors::ErrorKind> {
+        if memory == MemoryType::Flash {
+            check_erased(address, current, bytes)?;
+        }
+        self.write_at(memory, address, bytes)
+    }
+
+    /// Read `bytes.len()` bytes starting at address 0.
+    fn read(&mut self, memory: MemoryType, bytes: &mut [u8]) -> Result<(), errors::ErrorKind> {
+        self.read_at(memory, 0, bytes)
+    }
+
+    /// Write bytes starting at address 0. Last flash page is padded with 0xFF.
+    ///
+    /// Flash must be erased before writing. Resident bootloader is overwritten without asking,
+    /// [`write_flash`] refuses such image. EEPROM bytes holding their value already are skipped.
+    fn write(&mut self, memory: MemoryType, bytes: &[u8]) -> Result<(), errors::ErrorKind> {
+        if memory != MemoryType::Eeprom {
+            return self.write_at(memory, 0, bytes);
+        }
+        let page_size = self.layout(memory)?.page_size;
+        write_changed(
+            self,
+            page_size,
+            0,
+            bytes,
+            |prog, address, buffer| prog.read_at(memory, address, buffer),
+            |prog, address, data| prog.write_at(memory, address, data),
+        )
+    }
+
+    /// Read-modify-write pages containing `address..address + bytes.len()`.
+    ///
+    /// Returns `NotErased` error before writing anything if flash can not take `bytes`, see
+    /// [`patch_at`](Self::patch_at).
+    fn patch(
+        &mut self,
+        memory: MemoryType,
+        address: usize,
+        bytes: &[u8],
+    ) -> Result<(), errors::ErrorKind> {
+        let layout = self.layout(memory)?;
+        let range = page_range(&layout, address, bytes.len())?;
+        let mut current = vec![0; range.len()];
+        self.read_at(memory, range.start, &mut current)?;
+        let offset = address - range.start;
+        let mut data = current.clone();
+        data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        if memory != MemoryType::Eeprom {
+            return self.patch_at(memory, range.start, &current, &data);
+        }
+        write_changed(
+            self,
+            layout.page_size,
+            range.start,
+            &data,
+            |_, _, buffer| {
+                buffer.copy_from_slice(&current);
+                Ok(())
+            },
+            |prog, address, data| prog.write_at(memory, address, data),
+        )
+    }
+}
+
+/// Copy oscillator calibration byte to `address` of `memory`, where firmware loads it from at
+/// startup. Returns the byte.
+pub fn store_osccal<P>(
+    prog: &mut P,
+    memory: MemoryType,
+    address: usize,
+) -> Result<u8, errors::ErrorKind>
+where
+    P: AVROsccalGet + MemoryOps + ?Sized,
+{
+    let osccal = prog.get_osccal()?;
+    prog.patch(memory, address, &[osccal])?;
+    Ok(osccal)
+}
+
+/// Lock bits of ISP programmed chips. Unused bits read as 1.
+pub(crate) const LOCK_BITS: u8 = 0x3f;
+
+/// Write lock byte with `write`, refusing to clear programmed bits and checking bits in `mask`
+/// read back afterwards.
+pub(crate) fn write_lock<P, F>(
+    prog: &mut P,
+    mask: u8,
+    byte: u8,
+    write: F,
+) -> Result<u8, errors::ErrorKind>
+where
+    P: AVRLockByteGet + ?Sized,
+    F: FnOnce(&mut P, u8) -> Result<(), errors::ErrorKind>,
+{
+    let current = prog.get_lock_byte()?;
+    if byte & !current & mask != 0 {
+        return Err(errors::ErrorKind::LockNeedsErase {
+            current,
+            requested: byte,
+        });
+    }
+    write(prog, byte)?;
+    let read = prog.get_lock_byte()?;
+    if (read ^ byte) & mask != 0 {
+        return Err(errors::ErrorKind::LockVerifyError { wrote: byte, read });
+    }
+    Ok(read)
+}
+
+/// Permission for an operation which can brick or wipe the chip.
+///
+/// Guarded operations take `Option<Force>` and refuse to continue without it. There is no
+/// default, the token has to be created on purpose.
+#[derive(Debug, Clone, Copy)]
+pub struct Force(());
+
+impl Force {
+    /// Accept that the operation may leave the chip unusable or destroy its content.
+    pub fn accept_risk() -> Force {
+        Force(())
+    }
+}
+
+/// Write fuses. Values refused by [`AVRFuse::check_safe`] are written only with `force`.
+pub fn write_fuses<P>(
+    prog: &mut P,
+    specs: &specs::Specs,
+    fuses: &AVRFuse,
+    force: Option<Force>,
+) -> Result<AVRFuse, errors::ErrorKind>
+where
+    P: AVRFuseSet + ?Sized,
+{
+    if force.is_none() {
+        fuses.check_safe(specs)?;
+    }
+    prog.set_fuses(fuses)
+}
+
+/// Write flash `image`. Image overwriting resident bootloader (see
+/// [`AVRFuse::check_boot_section`]) is written only with `force`.
+pub fn write_flash<P>(
+    prog: &mut P,
+    specs: &specs::Specs,
+    image: &[u8],
+    force: Option<Force>,
+) -> Result<(), errors::ErrorKind>
+where
+    P: MemoryOps + AVRFuseGet + ?Sized,
+{
+    if force.is_none() {
+        prog.get_fuses()?.check_boot_section(specs, image)?;
+    }
+    prog.write(MemoryType::Flash, image)
+}
+
+/// Make sure connected chip is the one described by `specs`. With `force` any chip is accepted.
+/// Returns signature read.
+pub fn check_signature<P>(
+    prog: &mut P,
+    specs: &specs::Specs,
+    force: Option<Force>,
+) -> Result<specs::Signature, errors::ErrorKind>
+where
+    P: MCUSignature + ?Sized,
+{
+    let found = prog.get_mcu_signature()?;
+    if force.is_none() && !found.matches(specs) {
+        return Err(errors::ErrorKind::SignatureMismatch {
+            expected: specs.signature.clone(),
+            found,
+        });
+    }
+    Ok(found)
+}
+
+/// Step which wipes the chip or can lock it up. Shown to user before it is done.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Chip erase, flash and EEPROM content is lost.
+    Erase,
+    WriteFuses(AVRFuse),
+    WriteLock(u8),
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Erase => write!(f, "Erase flash and EEPROM"),
+            Action::WriteFuses(fuses) => write!(f, "Write fuses {}", fuses),
+            Action::WriteLock(lock) => write!(f, "Write lock byte {:#04x}", lock),
+        }
+    }
+}
+
+/// Asked before destructive [`Action`]. A y/N prompt, a dialog or a fixed policy.
+pub trait Confirm {
+    /// True if `action` may be done.
+    fn confirm(&mut self, action: &Action) -> bool;
+}
+
+impl<F: FnMut(&Action) -> bool> Confirm for F {
+    fn confirm(&mut self, action: &Action) -> bool {
+        self(action)
+    }
+}
+
+/// Policy approving every action, for unattended runs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Approve;
+
+impl Confirm for Approve {
+    fn confirm(&mut self, _: &Action) -> bool {
+        true
+    }
+}
+
+/// Ask `confirm` about `action`. Fails with [`errors::ErrorKind::Declined`] if refused.
+pub fn ask(confirm: &mut dyn Confirm, action: Action) -> Result<(), errors::ErrorKind> {
+    if confirm.confirm(&action) {
+        Ok(())
+    } else {
+        Err(errors::ErrorKind::Declined(action.to_string()))
+    }
+}
+
+/// Write factory fuse values of chip described by `specs`.
+pub fn reset_fuses_to_default<P>(
+    prog: &mut P,
+    specs: &specs::Specs,
+) -> Result<AVRFuse, errors::ErrorKind>
+where
+    P: AVRFuseSet + ?Sized,
+{
+    prog.set_fuses(&AVRFuse::defaults(specs))
+}
+
+/// Chip erase, which clears lock bits along with flash and EEPROM, followed by writing `byte`.
+pub fn erase_and_lock<P>(prog: &mut P, byte: u8) -> Result<u8, errors::ErrorKind>
+where
+    P: Erase + AVRLockByteSet + ?Sized,
+{
+    prog.erase()?;
+    prog.set_lock_byte(byte)
+}
+
+/// Check that `memory`, written with `image` before lock bits were set, no longer reads back as
+/// written. Fails with `NotLocked` if it does. Returns false when nothing can be told: image
+/// holding only erased bytes reads back the same from a locked chip.
+pub fn confirm_locked<P>(
+    prog: &mut P,
+    memory: MemoryType,
+    image: &Image,
+) -> Result<bool, errors::ErrorKind>
+where
+    P: MemoryOps + ?Sized,
+{
+    if image.bytes().all(|(_, byte)| byte == 0xff) {
+        return Ok(false);
+    }
+    let found = read_image_pages(prog, memory, image)?;
+    match image.mismatch(&found) {
+        Some(_) => Ok(true),
+        None => Err(errors::ErrorKind::NotLocked { memory }),
+    }
+}
+
+/// Program release build: write and verify flash and EEPROM of `firmware`, write lock `byte`
+/// and confirm with [`confirm_locked`] that memories can no longer be read back. Flash must be
+/// erased and `byte` must disable verification, e.g. 0x3c. Returns lock byte read back.
+pub fn program_and_lock<P>(
+    prog: &mut P,
+    specs: &specs::Specs,
+    firmware: &Firmware,
+    byte: u8,
+    force: Option<Force>,
+) -> Result<u8, errors::ErrorKind>
+where
+    P: MemoryOps + AVRFuseGet + AVRLockByteSet + ?Sized,
+{
+    let memories = [
+        (MemoryType::Flash, &firmware.flash),
+        (MemoryType::Eeprom, &firmware.eeprom),
+    ];
+    let written = memories.iter().filter(|(_, image)| !image.is_empty());
+    for &(memory, image) in written.clone() {
+        let bytes = image.to_bytes(0xff);
+        match memory {
+            MemoryType::Flash => write_flash(prog, specs, &bytes, force)?,
+            _ => prog.write(memory, &bytes)?,
+        }
+        let found = read_image_pages(prog, memory, image)?;
+        image.verify(&found, prog.layout(memory)?.page_size)?;
+    }
+    let lock = prog.set_lock_byte(byte)?;
+    for &(memory, image) in written {
+        confirm_locked(prog, memory, image)?;
+    }
+    Ok(lock)
+}
+
+/// Read whole pages of `memory` holding `image`, from address 0.
+fn read_image_pages<P>(
+    prog: &mut P,
+    memory: MemoryType,
+    image: &Image,
+) -> Result<Vec<u8>, errors::ErrorKind>
+where
+    P: MemoryOps + ?Sized,
+{
+    let page_size = prog.layout(memory)?.page_size;
+    let mut found = vec![0; image.end().div_ceil(page_size) * page_size];
+    prog.read(memory, &mut found)?;
+    Ok(found)
+}
+
+/// Read memory `passes` times and keep for each byte the value read most often, for trustworthy
+/// dumps over noisy links. Returns addresses at which reads disagreed.
+///
+/// With two passes a disagreement has no majority, first read value is kept then.
+pub fn read_voted<P>(
+    prog: &mut P,
+    memory: MemoryType,
+    bytes: &mut [u8],
+    passes: usize,
+) -> Result<Vec<usize>, errors::ErrorKind>
+where
+    P: MemoryOps + ?Sized,
+{
+    let mut reads = Vec::with_capacity(passes);
+    for _ in 0..passes {
+        let mut read = vec![0; bytes.len()];
+        prog.read(memory, &mut read)?;
+        reads.push(read);
+    }
+    Ok(vote(&reads, bytes))
+}
+
+/// Byte-wise majority of `reads` into `bytes`. Ties go to the earliest read value.
+fn vote(reads: &[Vec<u8>], bytes: &mut [u8]) -> Vec<usize> {
+    let mut unstable = Vec::new();
+    for (address, byte) in bytes.iter_mut().enumerate() {
+        let values: Vec<u8> = reads.iter().map(|read| read[address]).collect();
+        let count = |value: u8| values.iter().filter(|&&other| other == value).count();
+        let mut best = values[0];
+        for &value in &values[1..] {
+            if count(value) > count(best) {
+                best = value;
+            }
+        }
+        if count(best) != values.len() {
+            unstable.push(address);
+        }
+        *byte = best;
+    }
+    unstable
+}
+
+/// Compare bits listed in `fuse_bits` of fuses `read` back after writing with `wrote` ones.
+pub(crate) fn check_fuses(
+    fuse_bits: &[specs::FuseBits],
+    wrote: &AVRFuse,
+    read: AVRFuse,
+) -> Result<AVRFuse, errors::ErrorKind> {
+    for fuse in [specs::Fuse::Low, specs::Fuse::High, specs::Fuse::Extended] {
+        let mask = fuse_bits
+            .iter()
+            .filter(|bits| bits.fuse == fuse)
+            .fold(0, |mask, bits| mask | bits.mask);
+        if (wrote.get(fuse) ^ read.get(fuse)) & mask != 0 {
+            return Err(errors::ErrorKind::FuseVerifyError {
+                fuse,
+                wrote: wrote.get(fuse),
+                read: read.get(fuse),
+            });
+        }
+    }
+    Ok(read)
+}
+
+/// Error for memory programmer can not access.
+pub(crate) fn unsupported(memory: MemoryType) -> errors::ErrorKind {
+    errors::ErrorKind::Unsupported(format!("{} memory", memory))
+}
+
+/// Layout of `memory` in chip or `Unsupported` error.
+pub(crate) fn layout(
+    specs: &specs::Specs,
+    memory: MemoryType,
+) -> Result<&specs::Memory, errors::ErrorKind> {
+    specs.memory(memory).ok_or_else(|| unsupported(memory))
+}
+
+/// Page holds only erased value. Such flash pages need not be written after chip erase.
+pub(crate) fn is_erased(page: &[u8]) -> bool {
+    page.iter().all(|&byte| byte == 0xff)
+}
+
+/// Runs of `bytes` differing from `current` content. EEPROM wears with each write, so bytes
+/// holding their value already are skipped.
+pub(crate) fn changed_runs(current: &[u8], bytes: &[u8]) -> Vec<std::ops::Range<usize>> {
+    let mut runs: Vec<std::ops::Range<usize>> = Vec::new();
+    let changed = bytes
+        .iter()
+        .zip(current)
+        .enumerate()
+        .filter(|(_, (new, old))| new != old);
+    for (i, _) in changed {
+        match runs.last_mut() {
+            Some(run) if run.end == i => run.end = i + 1,
+            _ => runs.push(i..i + 1),
+        }
+    }
+    runs
+}
+
+/// Write EEPROM `bytes` at `address` with `write`, skipping bytes which hold their value already.
+/// Current content is taken with `read`. Writes do not cross `page_size` boundaries.
+pub(crate) fn write_changed<P, R, W>(
+    prog: &mut P,
+    page_size: usize,
+    address: usize,
+    bytes: &[u8],
+    read: R,
+    mut write: W,
+) -> Result<(), errors::ErrorKind>
+where
+    P: ?Sized,
+    R: FnOnce(&mut P, usize, &mut [u8]) -> Result<(), errors::ErrorKind>,
+    W: FnMut(&mut P, usize, &[u8]) -> Result<(), errors::ErrorKind>,
+{
+    let mut current = vec![0; bytes.len()];
+    read(prog, address, &mut current)?;
+    for run in changed_runs(&current, bytes) {
+        let mut start = run.start;
+        while start < run.end {
+            let page_end = ((address + start) / page_size + 1) * page_size - address;
+            let end = run.end.min(page_end);
+            write(prog, address + start, &bytes[start..end])?;
+            start = end;
+        }
+    }
+    Ok(())
+}
+
+/// Make sure `bytes` can be written over `current` flash content at `address` without erasing.
+pub(crate) fn check_erased(
+    address: usize,
+    current: &[u8],
+    bytes: &[u8],
+) -> Result<(), errors::ErrorKind> {
+    match current
+        .iter()
+        .zip(bytes)
+        .position(|(old, new)| old & new != *new)
+    {
+        Some(i) => Err(errors::ErrorKind::NotErased {
+            address: address + i,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Page aligned range containing `address..address + size` or error if it is out of memory.
+pub(crate) fn page_range(
+    memory: &specs::Memory,
+    address: usize,
+    size: usize,
+) -> Result<std::ops::Range<usize>, errors::ErrorKind> {
+    let end = address + size;
+    if end > memory.size {
+        return Err(errors::ErrorKind::AddressOutOfRange {
+            address: end - 1,
+            size: memory.size,
+        });
+    }
+    let start = address - address % memory.page_size;
+    Ok(start..end.div_ceil(memory.page_size) * memory.page_size)
 }
 
-pub trait FlashRead {
-    fn read(&mut self, bytes: &mut [u8]) -> Result<(), errors::ErrorKind>;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::specs::atmega;
+
+    #[test]
+    fn variant_name_is_trimmed() {
+        assert_eq!(Variant::from(&b"AVRISP_2\0\0"[..]), Variant::AVRISP_2);
+        assert_eq!(
+            Variant::from(&b"ACME_ISP "[..]),
+            Variant::Unknown(String::from("ACME_ISP"))
+        );
+    }
+
+    #[test]
+    fn clock_from_fuses() {
+        let defaults = AVRFuse::defaults(&atmega::ATMEGA_32);
+        assert_eq!(defaults.clock(&atmega::ATMEGA_32), Some(1_000_000));
+        // Internal 8 MHz divided by CKDIV8.
+        let defaults = AVRFuse::defaults(&atmega::ATMEGA_2560);
+        assert_eq!(defaults.clock(&atmega::ATMEGA_2560), Some(1_000_000));
+        let fuses = AVRFuse::new(0xe2, 0x99, 0xff);
+        assert_eq!(fuses.clock(&atmega::ATMEGA_2560), Some(8_000_000));
+        // External crystal.
+        let fuses = AVRFuse::new(0xff, 0x99, 0xff);
+        assert_eq!(fuses.clock(&atmega::ATMEGA_32), None);
+    }
+
+    #[test]
+    fn mk2_style_names() {
+        assert_eq!(Variant::from(&b"AVRISP mkII"[..]), Variant::AVRISP_MK2);
+        assert!(Variant::from(&b"JTAGICEmkII"[..]).capabilities().pdi);
+        assert!(!Variant::AVRISP.capabilities().tpi);
+    }
+
+    #[test]
+    fn unknown_programmer_has_no_optional_capabilities() {
+        let capabilities = Variant::from(&b"CLONE"[..]).capabilities();
+        assert!(
+            !capabilities.tpi && !capabilities.pdi && !capabilities.pp && !capabilities.voltage
+        );
+    }
+
+    /// Lock byte of a chip. Unused bits read as 1.
+    struct Lock(u8);
+
+    impl AVRLockByteGet for Lock {
+        fn get_lock_byte(&mut self) -> Result<u8, errors::ErrorKind> {
+            Ok(self.0 | 0xc0)
+        }
+    }
+
+    #[test]
+    fn lock_bits_are_only_programmed() {
+        let mut chip = Lock(0xff);
+        let write = |chip: &mut Lock, byte| {
+            chip.0 &= byte;
+            Ok(())
+        };
+        assert_eq!(write_lock(&mut chip, LOCK_BITS, 0x3c, write).unwrap(), 0xfc);
+        assert!(matches!(
+            write_lock(&mut chip, LOCK_BITS, 0x3f, write),
+            Err(errors::ErrorKind::LockNeedsErase {
+                current: 0xfc,
+                requested: 0x3f
+            })
+        ));
+        assert!(matches!(
+            write_lock(&mut chip, LOCK_BITS, 0x30, |_, _| Ok(())),
+            Err(errors::ErrorKind::LockVerifyError { .. })
+        ));
+    }
+
+    /// Fuses of a chip, written as given.
+    struct Fuses(Option<AVRFuse>);
+
+    impl AVRFuseSet for Fuses {
+        fn set_fuses(&mut self, fuses: &AVRFuse) -> Result<AVRFuse, errors::ErrorKind> {
+            self.0 = Some(*fuses);
+            Ok(*fuses)
+        }
+    }
+
+    #[test]
+    fn dangerous_fuses_need_force() {
+        // SPIEN unprogrammed.
+        let fuses = AVRFuse::new(0xe1, 0xb9, 0xff);
+        let mut chip = Fuses(None);
+        assert!(write_fuses(&mut chip, &atmega::ATMEGA_32, &fuses, None).is_err());
+        assert_eq!(chip.0, None);
+        let force = Some(Force::accept_risk());
+        assert!(write_fuses(&mut chip, &atmega::ATMEGA_32, &fuses, force).is_ok());
+        assert_eq!(chip.0, Some(fuses));
+    }
+
+    /// Chip reading back as erased once lock bits disable verification.
+    struct Lockable {
+        flash: Vec<u8>,
+        lock: u8,
+    }
+
+    impl MemoryOps for Lockable {
+        fn layout(&self, memory: MemoryType) -> Result<specs::Memory, errors::ErrorKind> {
+            layout(&atmega::ATMEGA_32, memory).cloned()
+        }
+
+        fn read_at(
+            &mut self,
+            _memory: MemoryType,
+            address: usize,
+            buffer: &mut [u8],
+        ) -> Result<(), errors::ErrorKind> {
+            // LB1 and LB2 both programmed disable verification.
+            match self.lock & 0x03 {
+                0 => buffer.iter_mut().for_each(|byte| *byte = 0xff),
+                _ => buffer.copy_from_slice(&self.flash[address..address + buffer.len()]),
+            }
+            Ok(())
+        }
+
+        fn write_at(
+            &mut self,
+            _memory: MemoryType,
+            address: usize,
+            bytes: &[u8],
+        ) -> Result<(), errors::ErrorKind> {
+            self.flash[address..address + bytes.len()].copy_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    impl AVRFuseGet for Lockable {
+        fn get_fuses(&mut self) -> Result<AVRFuse, errors::ErrorKind> {
+            Ok(AVRFuse::defaults(&atmega::ATMEGA_32))
+        }
+    }
+
+    impl AVRLockByteSet for Lockable {
+        fn set_lock_byte(&mut self, byte: u8) -> Result<u8, errors::ErrorKind> {
+            self.lock &= byte;
+            Ok(self.lock)
+        }
+    }
+
+    #[test]
+    fn locked_chip_does_not_read_back() {
+        let mut chip = Lockable {
+            flash: vec![0xff; atmega::ATMEGA_32.flash.size],
+            lock: 0xff,
+        };
+        let firmware = Firmware {
+            flash: Image::from(vec![1, 2, 3]),
+            eeprom: Image::new(),
+        };
+        let specs = &atmega::ATMEGA_32;
+        assert!(matches!(
+            program_and_lock(&mut chip, specs, &firmware, 0xfe, None),
+            Err(errors::ErrorKind::NotLocked {
+                memory: MemoryType::Flash
+            })
+        ));
+        assert_eq!(chip.flash[..3], [1, 2, 3]);
+        assert_eq!(
+            program_and_lock(&mut chip, specs, &firmware, 0xfc, None).unwrap(),
+            0xfc
+        );
+        // Erased image reads back the same from locked chip.
+        let erased = Image::from(vec![0xff; 4]);
+        assert!(!confirm_locked(&mut chip, MemoryType::Flash, &erased).unwrap());
+        assert!(confirm_locked(&mut chip, MemoryType::Flash, &firmware.flash).unwrap());
+    }
+
+    #[test]
+    fn declined_action() {
+        let mut asked = Vec::new();
+        let mut deny = |action: &Action| {
+            asked.push(action.clone());
+            false
+        };
+        match ask(&mut deny, Action::WriteLock(0x3c)) {
+            Err(errors::ErrorKind::Declined(msg)) => assert_eq!(msg, "Write lock byte 0x3c"),
+            result => panic!("unexpected {:?}", result),
+        }
+        assert_eq!(asked, [Action::WriteLock(0x3c)]);
+        assert!(ask(&mut Approve, Action::Erase).is_ok());
+    }
+
+    #[test]
+    fn fuse_readback_mismatch() {
+        let wrote = AVRFuse::new(0xe1, 0x99, 0xff);
+        // Extended fuse is not present in ATmega32.
+        let read = AVRFuse::new(0xe1, 0x99, 0x00);
+        assert!(check_fuses(atmega::ATMEGA_32.fuse_bits, &wrote, read).is_ok());
+        let read = AVRFuse::new(0xe1, 0xd9, 0xff);
+        match check_fuses(atmega::ATMEGA_32.fuse_bits, &wrote, read) {
+            Err(errors::ErrorKind::FuseVerifyError { fuse, wrote, read }) => {
+                assert_eq!((fuse, wrote, read), (specs::Fuse::High, 0x99, 0xd9))
+            }
+            result => panic!("unexpected {:?}", result.map(|fuses| fuses.to_string())),
+        }
+    }
+
+    #[test]
+    fn majority_vote() {
+        let reads = vec![vec![1, 2, 3, 4], vec![1, 2, 7, 5], vec![1, 9, 7, 6]];
+        let mut bytes = [0; 4];
+        assert_eq!(vote(&reads, &mut bytes), vec![1, 2, 3]);
+        // All three differ at address 3, first read wins.
+        assert_eq!(bytes, [1, 2, 7, 4]);
+    }
+
+    #[test]
+    fn changed_runs_skip_equal_bytes() {
+        assert_eq!(
+            changed_runs(&[1, 2, 3, 4, 5], &[1, 0, 0, 4, 0]),
+            vec![1..3, 4..5]
+        );
+        assert!(changed_runs(&[0xff; 4], &[0xff; 4]).is_empty());
+    }
+
+    #[test]
+    fn write_changed_splits_at_pages() {
+        let mut memory = vec![0u8; 12];
+        let mut writes = Vec::new();
+        let mut bytes = vec![0u8; 10];
+        bytes[1] = 1;
+        bytes[3..7].copy_from_slice(&[2, 2, 2, 2]);
+        write_changed(
+            &mut memory,
+            4,
+            2,
+            &bytes,
+            |memory, address, buffer| {
+                buffer.copy_from_slice(&memory[address..address + buffer.len()]);
+                Ok(())
+            },
+            |memory, address, data| {
+                writes.push(address..address + data.len());
+                memory[address..address + data.len()].copy_from_slice(data);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(writes, vec![3..4, 5..8, 8..9]);
+        assert_eq!(&memory[2..], &bytes[..]);
+    }
+
+    #[test]
+    fn page_range_is_aligned() {
+        let flash = &atmega::ATMEGA_32.flash;
+        assert_eq!(page_range(flash, 0x7e, 4).unwrap(), 0x00..0x100);
+        assert_eq!(page_range(flash, 0x80, 1).unwrap(), 0x80..0x100);
+    }
+
+    #[test]
+    fn page_range_out_of_memory() {
+        let flash = &atmega::ATMEGA_32.flash;
+        match page_range(flash, flash.size - 1, 2).unwrap_err() {
+            errors::ErrorKind::AddressOutOfRange { address, .. } => {
+                assert_eq!(address, flash.size)
+            }
+            _ => panic!("wrong error returned"),
+        };
+    }
+
+    #[test]
+    fn default_fuses_are_safe() {
+        let fuses = AVRFuse::new(0xe1, 0x99, 0xff);
+        assert!(fuses.check_safe(&atmega::ATMEGA_32).is_ok());
+    }
+
+    #[test]
+    fn parses_fuses() {
+        let mut fuses: AVRFuse = "E4:D9:FF".parse().unwrap();
+        assert_eq!(fuses, AVRFuse::new(0xe4, 0xd9, 0xff));
+        fuses.set(specs::Fuse::High, 0x99);
+        assert_eq!(fuses.high(), 0x99);
+        assert!("e4:d9:ff:00".parse::<AVRFuse>().is_err());
+        assert!("e4:xx".parse::<AVRFuse>().is_err());
+    }
+
+    #[test]
+    fn factory_fuses_are_safe() {
+        for chip in specs::CHIPS {
+            assert!(AVRFuse::defaults(chip).check_safe(chip).is_ok());
+        }
+    }
+
+    #[test]
+    fn reserved_bits_are_refused() {
+        let fuses = AVRFuse::new(0x62, 0x99, 0x05);
+        match fuses.check_safe(&atmega::ATMEGA_2560) {
+            Err(errors::ErrorKind::ReservedFuseBits { fuse, mask, .. }) => {
+                assert_eq!((fuse, mask), (specs::Fuse::Extended, 0x07))
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
+    #[test]
+    fn disabled_spi_is_unsafe() {
+        let fuses = AVRFuse::new(0xe1, 0xb9, 0xff);
+        match fuses.check_safe(&atmega::ATMEGA_32).unwrap_err() {
+            errors::ErrorKind::UnsafeFuses => (),
+            _ => panic!("wrong error returned"),
+        };
+    }
+
+    #[test]
+    fn app_over_bootloader_is_refused() {
+        // BOOTRST programmed, 4K boot section.
+        let fuses = AVRFuse::new(0xe1, 0x98, 0xff);
+        let mut image = vec![0xff; 0x8000];
+        image[0x7000] = 0x0c;
+        assert!(fuses.check_boot_section(&atmega::ATMEGA_32, &image).is_ok());
+        image[0] = 0x0c;
+        match fuses.check_boot_section(&atmega::ATMEGA_32, &image) {
+            Err(errors::ErrorKind::BootSection { address }) => assert_eq!(address, 0x7000),
+            _ => panic!("wrong result returned"),
+        };
+        let no_bootloader = AVRFuse::new(0xe1, 0x99, 0xff);
+        assert!(no_bootloader
+            .check_boot_section(&atmega::ATMEGA_32, &image)
+            .is_ok());
+    }
+
+    #[test]
+    fn boot_section_from_extended_fuse() {
+        let mut chip = atmega::ATMEGA_2560.clone();
+        chip.fuse_bits = &[
+            specs::FuseBits {
+                fuse: specs::Fuse::Extended,
+                name: "BOOTSZ",
+                mask: 0x06,
+            },
+            specs::FuseBits {
+                fuse: specs::Fuse::Extended,
+                name: "BOOTRST",
+                mask: 0x01,
+            },
+        ];
+        // High fuse would select the smallest section.
+        let fuses = AVRFuse::new(0xff, 0xff, 0xf8);
+        assert_eq!(fuses.boot_section(&chip), Some(0x3e000..0x40000));
+    }
 }
@@ -0,0 +1,171 @@
+//! FT232H / FT2232H / FT4232H breakout boards in MPSSE mode.
+//!
+//! MPSSE engine generates SPI on low byte pins: ADBUS0 is SCK, ADBUS1 MOSI, ADBUS2 MISO and
+//! ADBUS3 drives target reset. Chip is driven with plain USB transfers, no FTDI library is needed.
+//! ISP instructions are sent by [`isp`](super::isp).
+use super::isp;
+use crate::errors;
+use rusb::{DeviceHandle, GlobalContext};
+use std::time::{Duration, Instant};
+
+pub const VID: u16 = 0x0403;
+/// FT2232H, FT4232H and FT232H. First interface of multi channel chips is used.
+pub const PIDS: [u16; 3] = [0x6010, 0x6011, 0x6014];
+
+mod request {
+    pub const RESET: u8 = 0x00;
+    pub const SET_LATENCY_TIMER: u8 = 0x09;
+    pub const SET_BITMODE: u8 = 0x0b;
+}
+
+mod bitmode {
+    pub const RESET: u16 = 0x00;
+    pub const MPSSE: u16 = 0x02;
+}
+
+mod opcode {
+    /// Clock bytes out on falling edge and in on rising edge, MSB first. SPI mode 0.
+    pub const TRANSFER: u8 = 0x31;
+    pub const SET_LOW_BYTE: u8 = 0x80;
+    pub const LOOPBACK_OFF: u8 = 0x85;
+    pub const SET_DIVISOR: u8 = 0x86;
+    pub const SEND_IMMEDIATE: u8 = 0x87;
+    pub const DIVIDE_BY_5_OFF: u8 = 0x8a;
+    pub const THREE_PHASE_OFF: u8 = 0x8d;
+    pub const ADAPTIVE_OFF: u8 = 0x97;
+}
+
+const INTERFACE: u16 = 1;
+const ENDPOINT_IN: u8 = 0x81;
+const ENDPOINT_OUT: u8 = 0x02;
+/// High speed packet. Each one read starts with two modem status bytes.
+const PACKET_SIZE: usize = 512;
+const STATUS_SIZE: usize = 2;
+/// MPSSE clock with divide by 5 disabled.
+const BASE_CLOCK: u32 = 60_000_000;
+/// Longest single MPSSE transfer command.
+const MAX_TRANSFER: usize = 0x10000;
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// ADBUS pins.
+const SCK: u8 = 1 << 0;
+const MOSI: u8 = 1 << 1;
+const RESET: u8 = 1 << 3;
+const OUTPUTS: u8 = SCK | MOSI | RESET;
+
+/// Clock divisor giving SCK of at most `speed` Hz.
+fn divisor(speed: u32) -> u16 {
+    let divisor = (BASE_CLOCK / 2).div_ceil(speed.max(1)).saturating_sub(1);
+    divisor.min(u16::MAX as u32) as u16
+}
+
+pub struct Mpsse {
+    handle: DeviceHandle<GlobalContext>,
+}
+
+impl Mpsse {
+    /// Open first FTDI chip found on USB and set SCK to at most `speed` Hz.
+    pub fn open(speed: u32) -> Result<Mpsse, errors::ErrorKind> {
+        let handle = PIDS
+            .iter()
+            .find_map(|pid| rusb::open_device_with_vid_pid(VID, *pid))
+            .ok_or(errors::ErrorKind::Io(std::io::ErrorKind::NotFound.into()))?;
+        // Not supported on all platforms. Claiming fails later if needed.
+        let _ = handle.set_auto_detach_kernel_driver(true);
+        handle.claim_interface(0)?;
+        let mpsse = Mpsse { handle };
+        mpsse.control(request::RESET, 0)?;
+        mpsse.control(request::SET_LATENCY_TIMER, 2)?;
+        mpsse.control(request::SET_BITMODE, bitmode::RESET)?;
+        mpsse.control(request::SET_BITMODE, bitmode::MPSSE << 8)?;
+        let divisor = divisor(speed).to_le_bytes();
+        mpsse.write(&[
+            opcode::DIVIDE_BY_5_OFF,
+            opcode::ADAPTIVE_OFF,
+            opcode::THREE_PHASE_OFF,
+            opcode::LOOPBACK_OFF,
+            opcode::SET_DIVISOR,
+            divisor[0],
+            divisor[1],
+        ])?;
+        // Start with target running.
+        mpsse.set_pins(RESET)?;
+        Ok(mpsse)
+    }
+
+    fn control(&self, request: u8, value: u16) -> Result<(), errors::ErrorKind> {
+        let request_type = rusb::request_type(
+            rusb::Direction::Out,
+            rusb::RequestType::Vendor,
+            rusb::Recipient::Device,
+        );
+        self.handle
+            .write_control(request_type, request, value, INTERFACE, &[], TIMEOUT)?;
+        Ok(())
+    }
+
+    fn write(&self, bytes: &[u8]) -> Result<(), errors::ErrorKind> {
+        self.handle.write_bulk(ENDPOINT_OUT, bytes, TIMEOUT)?;
+        Ok(())
+    }
+
+    /// Read `buffer.len()` bytes, skipping status bytes of each packet.
+    fn read(&self, buffer: &mut [u8]) -> Result<(), errors::ErrorKind> {
+        let deadline = Instant::now() + TIMEOUT;
+        let mut received = 0;
+        while received < buffer.len() {
+            let mut packet = [0; PACKET_SIZE];
+            let size = self.handle.read_bulk(ENDPOINT_IN, &mut packet, TIMEOUT)?;
+            if size > STATUS_SIZE {
+                let data = &packet[STATUS_SIZE..size];
+                let count = data.len().min(buffer.len() - received);
+                buffer[received..received + count].copy_from_slice(&data[..count]);
+                received += count;
+            } else if Instant::now() > deadline {
+                return Err(errors::ErrorKind::Io(std::io::ErrorKind::TimedOut.into()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Set output pins of low byte.
+    fn set_pins(&self, value: u8) -> Result<(), errors::ErrorKind> {
+        self.write(&[opcode::SET_LOW_BYTE, value, OUTPUTS])
+    }
+}
+
+impl isp::Bus for Mpsse {
+    fn transfer(&mut self, data: &mut [u8]) -> Result<(), errors::ErrorKind> {
+        for chunk in data.chunks_mut(MAX_TRANSFER) {
+            let mut command = vec![opcode::TRANSFER];
+            command.extend_from_slice(&((chunk.len() - 1) as u16).to_le_bytes());
+            command.extend_from_slice(chunk);
+            command.push(opcode::SEND_IMMEDIATE);
+            self.write(&command)?;
+            self.read(chunk)?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self, active: bool) -> Result<(), errors::ErrorKind> {
+        // SCK stays low.
+        self.set_pins(if active { 0 } else { RESET })
+    }
+
+    fn name(&self) -> &'static str {
+        "FTDI MPSSE"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn divisor_rounds_speed_down() {
+        assert_eq!(divisor(30_000_000), 0);
+        assert_eq!(divisor(200_000), 149);
+        assert_eq!(divisor(190_000), 157);
+        assert_eq!(divisor(1), u16::MAX);
+    }
+}
@@ -0,0 +1,810 @@
+//! Programmer selected at runtime.
+//!
+//! Each backend has its own programmer and programming mode types. [`ProgrammerKind::connect`]
+//! hides them behind [`Session`] trait object, so the backend can come from user input.
+#[cfg(any(feature = "usb", all(target_os = "linux", feature = "linux")))]
+use super::isp;
+use super::{
+    avr109, jtagice_mkii, page_range, stk500v1, stk500v2, write_fuses, AVRFuse, AVRFuseGet,
+    AVRFuseSet, AVRLockByteGet, AVRLockByteSet, AVROsccalGet, Erase, Event, Listener, MCUSignature,
+    MemoryOps, MemoryType, SwVersion,
+};
+#[cfg(feature = "usb")]
+use super::{ch341a, edbg, mpsse, stk600, usbtiny};
+#[cfg(all(target_os = "linux", feature = "linux"))]
+use super::{linux_gpio, linux_spi};
+use crate::errors;
+use crate::specs;
+use std::convert::TryInto;
+use std::fmt;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+/// Programmer details, as far as its firmware reports them.
+pub struct Identity {
+    pub name: String,
+    pub hardware: Option<u8>,
+    pub firmware: Option<SwVersion>,
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(hardware) = self.hardware {
+            write!(f, " hardware {}", hardware)?;
+        }
+        if let Some(firmware) = &self.firmware {
+            write!(f, " firmware {}", firmware)?;
+        }
+        Ok(())
+    }
+}
+
+/// Chip state read in one session.
+#[derive(Debug, Clone)]
+pub struct ChipStatus {
+    pub signature: specs::Signature,
+    /// Name of chip with this signature, if known.
+    pub chip_name: Option<&'static str>,
+    pub fuses: AVRFuse,
+    pub lock: u8,
+    /// None if programmer can not read it, e.g. bootloaders.
+    pub osccal: Option<u8>,
+    /// Whether `BOOTRST` makes chip start from boot section. None if chip is unknown or has no
+    /// boot section.
+    pub bootloader_present: Option<bool>,
+}
+
+impl ChipStatus {
+    /// Read signature, fuses, lock byte and OSCCAL.
+    pub fn read(session: &mut dyn Session) -> Result<ChipStatus, errors::ErrorKind> {
+        let signature = session.get_mcu_signature()?;
+        let chip = specs::find_by_signature(&signature);
+        let fuses = session.get_fuses()?;
+        let lock = session.get_lock_byte()?;
+        let osccal = match session.get_osccal() {
+            Ok(osccal) => Some(osccal),
+            Err(errors::ErrorKind::Unsupported(_)) => None,
+            Err(err) => return Err(err),
+        };
+        let bootloader_present = chip
+            .filter(|chip| chip.fuse_field("BOOTRST").is_some())
+            .map(|chip| fuses.boot_section(chip).is_some());
+        Ok(ChipStatus {
+            signature,
+            chip_name: chip.map(|chip| chip.name),
+            fuses,
+            lock,
+            osccal,
+            bootloader_present,
+        })
+    }
+}
+
+impl fmt::Display for ChipStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Signature:  {}", self.signature)?;
+        let fuses = match specs::find_by_signature(&self.signature) {
+            Some(chip) => chip.fuses(),
+            None => vec![specs::Fuse::Low, specs::Fuse::High, specs::Fuse::Extended],
+        };
+        let fuses: Vec<String> = fuses
+            .into_iter()
+            .map(|fuse| format!("{} {:#04x}", fuse, self.fuses.get(fuse)))
+            .collect();
+        writeln!(f, "Fuses:      {}", fuses.join(", "))?;
+        writeln!(f, "Lock byte:  {:#04x}", self.lock)?;
+        if let Some(osccal) = self.osccal {
+            writeln!(f, "OSCCAL:     {:#04x}", osccal)?;
+        }
+        match self.bootloader_present {
+            Some(true) => writeln!(f, "Bootloader: yes"),
+            Some(false) => writeln!(f, "Bootloader: no"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Read `range` of memory called `name` in [`specs::Specs::memories`], e.g.
+/// `read_memory(session, chip, "eeprom", 0..64)`.
+pub fn read_memory(
+    session: &mut dyn Session,
+    chip: &specs::Specs,
+    name: &str,
+    range: Range<usize>,
+) -> Result<Vec<u8>, errors::ErrorKind> {
+    let region = find_region(chip, name, range.clone())?;
+    let bytes = match region.memory_type {
+        Some(memory) => {
+            // Memories are read in whole pages.
+            let pages = page_range(&session.layout(memory)?, range.start, range.len())?;
+            let mut bytes = vec![0; pages.len()];
+            session.read_at(memory, pages.start, &mut bytes)?;
+            return Ok(bytes[range.start - pages.start..range.end - pages.start].to_vec());
+        }
+        None => match region.name {
+            "fuses" => {
+                let fuses = session.get_fuses()?;
+                chip.fuses()
+                    .into_iter()
+                    .map(|fuse| fuses.get(fuse))
+                    .collect()
+            }
+            "lock" => vec![session.get_lock_byte()?],
+            "signature" => {
+                let (sig0, sig1, sig2) = session.get_mcu_signature()?.bytes;
+                vec![sig0, sig1, sig2]
+            }
+            "calibration" => vec![session.get_osccal()?],
+            name => return Err(errors::ErrorKind::Unsupported(format!("memory {}", name))),
+        },
+    };
+    Ok(bytes[range].to_vec())
+}
+
+/// Write `bytes` at `address` of memory called `name` in [`specs::Specs::memories`], keeping
+/// the rest of it.
+///
+/// Flash is patched, so only bits set to 1 can be cleared. Unsafe fuses are refused, see
+/// [`write_fuses`].
+pub fn write_memory(
+    session: &mut dyn Session,
+    chip: &specs::Specs,
+    name: &str,
+    address: usize,
+    bytes: &[u8],
+) -> Result<(), errors::ErrorKind> {
+    let region = find_region(chip, name, address..address + bytes.len())?;
+    if bytes.is_empty() {
+        return Ok(());
+    }
+    if region.access == specs::Access::ReadOnly {
+        return Err(errors::ErrorKind::Unsupported(format!(
+            "writing {}, it is read-only",
+            name
+        )));
+    }
+    match (region.memory_type, region.name) {
+        (Some(memory), _) => session.patch(memory, address, bytes),
+        (None, "fuses") => {
+            let mut fuses = session.get_fuses()?;
+            for (fuse, &byte) in chip.fuses().into_iter().skip(address).zip(bytes) {
+                fuses.set(fuse, byte);
+            }
+            write_fuses(session, chip, &fuses, None).map(|_| ())
+        }
+        (None, _) => session.set_lock_byte(bytes[0]).map(|_| ()),
+    }
+}
+
+/// Region of memory map called `name` holding `range`.
+fn find_region(
+    chip: &specs::Specs,
+    name: &str,
+    range: Range<usize>,
+) -> Result<specs::Region, errors::ErrorKind> {
+    let memories = chip.memories();
+    let region = memories
+        .iter()
+        .find(|region| region.name.eq_ignore_ascii_case(name))
+        .ok_or_else(|| {
+            let names: Vec<&str> = memories.iter().map(|region| region.name).collect();
+            errors::ErrorKind::Unsupported(format!("memory {}. Use {}", name, names.join(", ")))
+        })?;
+    if range.start > range.end {
+        return Err(errors::ErrorKind::InvalidRange {
+            start: range.start,
+            end: range.end,
+        });
+    }
+    if range.end > region.size {
+        return Err(errors::ErrorKind::AddressOutOfRange {
+            address: range.end - 1,
+            size: region.size,
+        });
+    }
+    Ok(region.clone())
+}
+
+/// Frames exchanged with programmer since it was opened.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Counters {
+    pub frames: usize,
+    /// Commands repeated, e.g. sync attempts or programming enable with slower clock.
+    pub retries: usize,
+    /// Answers with unexpected sequence number, accepted by tolerant link.
+    pub mismatches: usize,
+}
+
+/// Transfer statistics of one operation.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Stats {
+    pub bytes: usize,
+    pub frames: usize,
+    pub retries: usize,
+    pub elapsed: Duration,
+}
+
+impl Stats {
+    /// Run `op`, which moves `bytes` of memory, and count what it took. Session listener gets
+    /// `Done` with the result.
+    pub fn measure<T, F>(
+        session: &mut dyn Session,
+        bytes: usize,
+        op: F,
+    ) -> Result<(T, Stats), errors::ErrorKind>
+    where
+        F: FnOnce(&mut dyn Session) -> Result<T, errors::ErrorKind>,
+    {
+        let before = session.counters();
+        let start = Instant::now();
+        let result = op(session)?;
+        let after = session.counters();
+        let stats = Stats {
+            bytes,
+            frames: after.frames - before.frames,
+            retries: after.retries - before.retries,
+            elapsed: start.elapsed(),
+        };
+        session.emit(Event::Done {
+            stats: stats.clone(),
+        });
+        Ok((result, stats))
+    }
+
+    /// Effective transfer rate in kB/s.
+    pub fn rate(&self) -> f64 {
+        self.bytes as f64 / 1000.0 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes in {:.2} s ({:.1} kB/s), {} frames, {} retries",
+            self.bytes,
+            self.elapsed.as_secs_f64(),
+            self.rate(),
+            self.frames,
+            self.retries
+        )
+    }
+}
+
+/// Programmer in programming mode, usable as trait object.
+pub trait Session:
+    MemoryOps
+    + Erase
+    + AVRFuseGet
+    + AVRFuseSet
+    + AVRLockByteGet
+    + AVRLockByteSet
+    + AVROsccalGet
+    + MCUSignature
+{
+    /// Programmer name and versions.
+    fn identify(&mut self) -> Result<Identity, errors::ErrorKind>;
+
+    /// Description of programming clock, if it had to be slowed down for target to answer.
+    fn slowed_clock(&mut self) -> Result<Option<String>, errors::ErrorKind> {
+        Ok(None)
+    }
+
+    /// Set programming clock to the fastest safe one for target clock given by fuses.
+    /// Description of clock set, None if it was left as it was.
+    fn adapt_clock(&mut self) -> Result<Option<String>, errors::ErrorKind> {
+        Ok(None)
+    }
+
+    /// Frames and retries so far.
+    fn counters(&self) -> Counters;
+
+    /// Report progress to `listener`. Sessions made by [`ProgrammerKind::connect`] without
+    /// events of their own ignore it, use [`ProgrammerKind::connect_with`] for them.
+    fn set_listener(&mut self, _listener: Box<dyn Listener + Send>) {}
+
+    /// Pass `event` to listener, e.g. verify mismatch found by caller.
+    fn emit(&mut self, _event: Event) {}
+
+    /// Leave programming mode and release programmer.
+    fn finish(self: Box<Self>) -> Result<(), errors::ErrorKind>;
+}
+
+/// Backend and its connection parameters.
+#[derive(Debug, Clone)]
+pub enum ProgrammerKind {
+    /// STK500, AVRISP mkII or other STK500v2 programmer on serial port.
+    Stk500v2 {
+        port: String,
+        options: stk500v2::LinkOptions,
+    },
+    /// ArduinoISP sketch or other STK500v1 programmer.
+    Stk500v1 { port: String, baud: usize },
+    /// AVR109 bootloader.
+    Avr109 { port: String, baud: usize },
+    /// JTAGICE mkII on serial port.
+    JtagIceMkII { port: String },
+    #[cfg(feature = "usb")]
+    AvrIspMkII,
+    /// STK600, refusing other cards than `cards` if given.
+    #[cfg(feature = "usb")]
+    Stk600 { cards: Option<stk600::Cards> },
+    /// Atmel-ICE, Power Debugger or EDBG on evaluation kit.
+    #[cfg(feature = "usb")]
+    Edbg,
+    #[cfg(feature = "usb")]
+    UsbTiny,
+    /// FTDI chip in MPSSE mode. SCK runs at most at `speed` Hz.
+    #[cfg(feature = "usb")]
+    Mpsse { speed: u32 },
+    #[cfg(feature = "usb")]
+    Ch341a,
+    #[cfg(all(target_os = "linux", feature = "linux"))]
+    LinuxSpi {
+        spi: String,
+        chip: String,
+        reset_line: u32,
+        speed: u32,
+    },
+    #[cfg(all(target_os = "linux", feature = "linux"))]
+    LinuxGpio {
+        chip: String,
+        pins: linux_gpio::Pins,
+        delay: Duration,
+    },
+}
+
+impl ProgrammerKind {
+    /// Open programmer and enter programming mode of chip described by `specs`.
+    pub fn connect(&self, specs: &specs::Specs) -> Result<Box<dyn Session>, errors::ErrorKind> {
+        self.open(specs, None)
+    }
+
+    /// Like [`connect`](Self::connect), reporting progress to `listener`. STK500v2 based
+    /// programmers report also pages and retries as they happen.
+    pub fn connect_with(
+        &self,
+        specs: &specs::Specs,
+        listener: Box<dyn Listener + Send>,
+    ) -> Result<Box<dyn Session>, errors::ErrorKind> {
+        self.open(specs, Some(listener))
+    }
+
+    fn open(
+        &self,
+        specs: &specs::Specs,
+        listener: Option<Box<dyn Listener + Send>>,
+    ) -> Result<Box<dyn Session>, errors::ErrorKind> {
+        let chip = specs.name;
+        let specs = specs.clone();
+        let session = match self {
+            ProgrammerKind::Stk500v2 { port, options } => {
+                let prog = stk500v2::STK500v2::open_with(port, options, specs)?;
+                return stk500v2_session(prog, listener);
+            }
+            ProgrammerKind::Stk500v1 { port, baud } => {
+                let prog = stk500v1::STK500v1::open_with(port, *baud, specs)?;
+                session::<stk500v1::IspMode, _>(prog)
+            }
+            ProgrammerKind::Avr109 { port, baud } => {
+                let prog =
+                    avr109::AVR109::open_with(port, *baud, specs).map_err(std::io::Error::from)?;
+                session::<avr109::ProgMode, _>(prog)
+            }
+            ProgrammerKind::JtagIceMkII { port } => {
+                return stk500v2_session(jtagice_mkii::open(port, specs)?, listener)
+            }
+            #[cfg(feature = "usb")]
+            ProgrammerKind::AvrIspMkII => {
+                return stk500v2_session(stk500v2::STK500v2::open_usb(specs)?, listener)
+            }
+            #[cfg(feature = "usb")]
+            ProgrammerKind::Stk600 { cards } => {
+                let mut stk = stk600::STK600::open(specs)?;
+                if let Some(cards) = cards {
+                    stk = stk.expect_cards(cards.clone());
+                }
+                if let Some(listener) = listener {
+                    stk.programmer().set_listener(listener);
+                }
+                return session::<stk500v2::IspMode, _>(stk);
+            }
+            #[cfg(feature = "usb")]
+            ProgrammerKind::Edbg => return stk500v2_session(edbg::open(specs)?, listener),
+            #[cfg(feature = "usb")]
+            ProgrammerKind::UsbTiny => {
+                session::<usbtiny::IspMode, _>(usbtiny::USBtiny::open(specs)?)
+            }
+            #[cfg(feature = "usb")]
+            ProgrammerKind::Mpsse { speed } => isp_session(mpsse::Mpsse::open(*speed)?, specs),
+            #[cfg(feature = "usb")]
+            ProgrammerKind::Ch341a => isp_session(ch341a::CH341A::open()?, specs),
+            #[cfg(all(target_os = "linux", feature = "linux"))]
+            ProgrammerKind::LinuxSpi {
+                spi,
+                chip,
+                reset_line,
+                speed,
+            } => isp_session(
+                linux_spi::LinuxSpi::open(spi, chip, *reset_line, *speed)?,
+                specs,
+            ),
+            #[cfg(all(target_os = "linux", feature = "linux"))]
+            ProgrammerKind::LinuxGpio { chip, pins, delay } => {
+                isp_session(linux_gpio::LinuxGpio::open(chip, pins, *delay)?, specs)
+            }
+        }?;
+        Ok(match listener {
+            Some(listener) => observed(session, chip, listener),
+            None => session,
+        })
+    }
+}
+
+/// Session of a backend without events of its own. Sign on and programming mode entry are
+/// reported once connected, `emit` is passed to `listener`.
+fn observed(
+    session: Box<dyn Session>,
+    chip: &'static str,
+    listener: Box<dyn Listener + Send>,
+) -> Box<dyn Session> {
+    let mut observed = Observed { session, listener };
+    observed.emit(Event::Connected);
+    observed.emit(Event::IspEntered { chip });
+    Box::new(observed)
+}
+
+struct Observed {
+    session: Box<dyn Session>,
+    listener: Box<dyn Listener + Send>,
+}
+
+impl Observed {
+    /// Report page sized parts of `size` bytes done at `address`. Backend gives no progress of
+    /// its own, so events follow once whole operation is done.
+    fn pages<F>(
+        &mut self,
+        memory: MemoryType,
+        address: usize,
+        size: usize,
+        event: F,
+    ) -> Result<(), errors::ErrorKind>
+    where
+        F: Fn(MemoryType, usize) -> Event,
+    {
+        let page_size = self.session.layout(memory)?.page_size;
+        for page in (address..address + size).step_by(page_size) {
+            self.emit(event(memory, page));
+        }
+        Ok(())
+    }
+}
+
+impl MemoryOps for Observed {
+    fn layout(&self, memory: MemoryType) -> Result<specs::Memory, errors::ErrorKind> {
+        self.session.layout(memory)
+    }
+
+    fn read_at(
+        &mut self,
+        memory: MemoryType,
+        address: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), errors::ErrorKind> {
+        self.session.read_at(memory, address, buffer)?;
+        self.pages(memory, address, buffer.len(), |memory, address| {
+            Event::PageRead { memory, address }
+        })
+    }
+
+    fn write_at(
+        &mut self,
+        memory: MemoryType,
+        address: usize,
+        bytes: &[u8],
+    ) -> Result<(), errors::ErrorKind> {
+        self.session.write_at(memory, address, bytes)?;
+        self.pages(memory, address, bytes.len(), |memory, address| {
+            Event::PageWritten { memory, address }
+        })
+    }
+
+    fn patch_at(
+        &mut self,
+        memory: MemoryType,
+        address: usize,
+        current: &[u8],
+        bytes: &[u8],
+    ) -> Result<(), errors::ErrorKind> {
+        self.session.patch_at(memory, address, current, bytes)?;
+        self.pages(memory, address, bytes.len(), |memory, address| {
+            Event::PageWritten { memory, address }
+        })
+    }
+}
+
+impl Erase for Observed {
+    fn erase(&mut self) -> Result<(), errors::ErrorKind> {
+        self.session.erase()
+    }
+}
+
+impl AVRFuseGet for Observed {
+    fn get_fuses(&mut self) -> Result<AVRFuse, errors::ErrorKind> {
+        self.session.get_fuses()
+    }
+}
+
+impl AVRFuseSet for Observed {
+    fn set_fuses(&mut self, fuses: &AVRFuse) -> Result<AVRFuse, errors::ErrorKind> {
+        self.session.set_fuses(fuses)
+    }
+}
+
+impl AVRLockByteGet for Observed {
+    fn get_lock_byte(&mut self) -> Result<u8, errors::ErrorKind> {
+        self.session.get_lock_byte()
+    }
+}
+
+impl AVRLockByteSet for Observed {
+    fn set_lock_byte(&mut self, byte: u8) -> Result<u8, errors::ErrorKind> {
+        self.session.set_lock_byte(byte)
+    }
+}
+
+impl AVROsccalGet for Observed {
+    fn get_osccal(&mut self) -> Result<u8, errors::ErrorKind> {
+        self.session.get_osccal()
+    }
+}
+
+impl MCUSignature for Observed {
+    fn get_mcu_signature(&mut self) -> Result<specs::Signature, errors::ErrorKind> {
+        self.session.get_mcu_signature()
+    }
+}
+
+impl Session for Observed {
+    fn identify(&mut self) -> Result<Identity, errors::ErrorKind> {
+        self.session.identify()
+    }
+
+    fn slowed_clock(&mut self) -> Result<Option<String>, errors::ErrorKind> {
+        self.session.slowed_clock()
+    }
+
+    fn adapt_clock(&mut self) -> Result<Option<String>, errors::ErrorKind> {
+        self.session.adapt_clock()
+    }
+
+    fn counters(&self) -> Counters {
+        self.session.counters()
+    }
+
+    fn set_listener(&mut self, listener: Box<dyn Listener + Send>) {
+        self.listener = listener;
+    }
+
+    fn emit(&mut self, event: Event) {
+        self.listener.event(&event);
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), errors::ErrorKind> {
+        self.session.finish()
+    }
+}
+
+/// Listener is set before programming mode is entered, so it sees the entry.
+fn stk500v2_session(
+    mut prog: stk500v2::STK500v2,
+    listener: Option<Box<dyn Listener + Send>>,
+) -> Result<Box<dyn Session>, errors::ErrorKind> {
+    if let Some(listener) = listener {
+        prog.set_listener(listener);
+    }
+    session::<stk500v2::IspMode, _>(prog)
+}
+
+fn session<S, P>(prog: P) -> Result<Box<dyn Session>, errors::ErrorKind>
+where
+    S: Session + 'static,
+    P: TryInto<S, Error = errors::ErrorKind>,
+{
+    Ok(Box::new(prog.try_into()?))
+}
+
+#[cfg(any(feature = "usb", all(target_os = "linux", feature = "linux")))]
+fn isp_session<B: isp::Bus + 'static>(
+    bus: B,
+    specs: specs::Specs,
+) -> Result<Box<dyn Session>, errors::ErrorKind> {
+    session::<isp::IspMode<B>, _>(isp::Isp::new(bus, specs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn region_by_name() {
+        let chip = &specs::atmega::ATMEGA_32;
+        assert_eq!(find_region(chip, "EEPROM", 0..64).unwrap().page_size, 4);
+        assert_eq!(find_region(chip, "fuses", 1..2).unwrap().memory_type, None);
+        match find_region(chip, "usersig", 0..1) {
+            Err(errors::ErrorKind::Unsupported(message)) => {
+                assert!(message.contains("flash, eeprom"), "{}", message)
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+        match find_region(chip, "signature", 2..4) {
+            Err(errors::ErrorKind::AddressOutOfRange { address, size }) => {
+                assert_eq!((address, size), (3, 3))
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+        #[allow(clippy::reversed_empty_ranges)]
+        let reversed = 8..4;
+        match find_region(chip, "eeprom", reversed) {
+            Err(errors::ErrorKind::InvalidRange { start, end }) => assert_eq!((start, end), (8, 4)),
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
+    #[test]
+    fn chip_status_summary() {
+        let status = ChipStatus {
+            signature: specs::atmega::ATMEGA_32.signature,
+            chip_name: Some("ATmega32"),
+            fuses: AVRFuse::new(0xe1, 0x98, 0xff),
+            lock: 0x3f,
+            osccal: None,
+            bootloader_present: Some(true),
+        };
+        assert_eq!(
+            status.to_string(),
+            "Signature:  1E 95 02 (ATmega32)\n\
+             Fuses:      low 0xe1, high 0x98\n\
+             Lock byte:  0x3f\n\
+             Bootloader: yes\n"
+        );
+    }
+
+    /// Target which is always in sync and reads as zeros.
+    struct Loopback;
+
+    impl crate::programmer::isp::Bus for Loopback {
+        fn transfer(&mut self, data: &mut [u8]) -> Result<(), errors::ErrorKind> {
+            if data[0] == crate::command::PROGRAMMING_ENABLE.0 {
+                data[2] = crate::command::PROGRAMMING_ENABLE.1;
+            } else {
+                data.iter_mut().for_each(|byte| *byte = 0);
+            }
+            Ok(())
+        }
+
+        fn reset(&mut self, _active: bool) -> Result<(), errors::ErrorKind> {
+            Ok(())
+        }
+
+        fn name(&self) -> &'static str {
+            "loopback"
+        }
+    }
+
+    #[test]
+    fn observed_session_reports_done() {
+        use crate::programmer::isp;
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let chip = specs::atmega::ATMEGA_32;
+        let isp: isp::IspMode<Loopback> = isp::Isp::new(Loopback, chip.clone()).try_into().unwrap();
+        let mut session = observed(Box::new(isp), chip.name, Box::new(sender));
+        let mut bytes = [0xff; 4];
+        Stats::measure(session.as_mut(), bytes.len(), |session| {
+            session.read(MemoryType::Eeprom, &mut bytes)
+        })
+        .unwrap();
+        let events: Vec<Event> = receiver.try_iter().collect();
+        assert_eq!(
+            events[..2],
+            [Event::Connected, Event::IspEntered { chip: "ATmega32" }]
+        );
+        match &events[2..] {
+            [Event::PageRead { address: 0, .. }, Event::Done { stats }] => {
+                assert_eq!(stats.bytes, 4)
+            }
+            events => panic!("unexpected {:?}", events),
+        }
+    }
+
+    #[test]
+    fn observed_session_reports_pages() {
+        use crate::programmer::isp;
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let chip = specs::atmega::ATMEGA_32;
+        let isp: isp::IspMode<Loopback> = isp::Isp::new(Loopback, chip.clone()).try_into().unwrap();
+        let mut session = observed(Box::new(isp), chip.name, Box::new(sender));
+        let mut bytes = [0; 256];
+        session.read(MemoryType::Flash, &mut bytes).unwrap();
+        session.write_at(MemoryType::Eeprom, 4, &[1; 8]).unwrap();
+        let events: Vec<Event> = receiver.try_iter().skip(2).collect();
+        assert_eq!(
+            events,
+            [
+                Event::PageRead {
+                    memory: MemoryType::Flash,
+                    address: 0
+                },
+                Event::PageRead {
+                    memory: MemoryType::Flash,
+                    address: 128
+                },
+                Event::PageWritten {
+                    memory: MemoryType::Eeprom,
+                    address: 4
+                },
+                Event::PageWritten {
+                    memory: MemoryType::Eeprom,
+                    address: 8
+                },
+            ]
+        );
+    }
+
+    /// Target which is always in sync, counting flash reads. Flash reads as its low address byte.
+    struct FlashReads(std::sync::Arc<std::sync::Mutex<usize>>);
+
+    impl crate::programmer::isp::Bus for FlashReads {
+        fn transfer(&mut self, data: &mut [u8]) -> Result<(), errors::ErrorKind> {
+            use crate::command::{PROGRAMMING_ENABLE, READ_FLASH_HIGH, READ_FLASH_LOW};
+            for instruction in data.chunks_mut(4) {
+                if instruction[0] == PROGRAMMING_ENABLE.0 {
+                    instruction[2] = PROGRAMMING_ENABLE.1;
+                } else if instruction[0] == READ_FLASH_LOW.0 || instruction[0] == READ_FLASH_HIGH.0
+                {
+                    *self.0.lock().unwrap() += 1;
+                    instruction[3] =
+                        instruction[2] << 1 | (instruction[0] == READ_FLASH_HIGH.0) as u8;
+                }
+            }
+            Ok(())
+        }
+
+        fn reset(&mut self, _active: bool) -> Result<(), errors::ErrorKind> {
+            Ok(())
+        }
+
+        fn name(&self) -> &'static str {
+            "flash reads"
+        }
+    }
+
+    #[test]
+    fn read_memory_reads_only_range_pages() {
+        use crate::programmer::isp;
+        let reads = std::sync::Arc::default();
+        let chip = specs::atmega::ATMEGA_2560;
+        let bus = FlashReads(std::sync::Arc::clone(&reads));
+        let mut isp: isp::IspMode<FlashReads> =
+            isp::Isp::new(bus, chip.clone()).try_into().unwrap();
+        let bytes = read_memory(&mut isp, &chip, "flash", 0x3ff10..0x3ff14).unwrap();
+        assert_eq!(bytes, [0x10, 0x11, 0x12, 0x13]);
+        assert_eq!(*reads.lock().unwrap(), chip.flash.page_size);
+    }
+
+    #[test]
+    fn stats_summary() {
+        let stats = Stats {
+            bytes: 4096,
+            frames: 17,
+            retries: 0,
+            elapsed: Duration::from_millis(500),
+        };
+        assert_eq!(
+            stats.to_string(),
+            "4096 bytes in 0.50 s (8.2 kB/s), 17 frames, 0 retries"
+        );
+    }
+}
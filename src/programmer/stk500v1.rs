@@ -0,0 +1,487 @@
+//! STK500v1 protocol as spoken by ArduinoISP sketch.
+//!
+//! Commands are plain bytes terminated with `CRC_EOP`. Each answer starts with `INSYNC` and ends
+//! with `OK`. Memory is addressed in words, also EEPROM.
+use crate::command as isp_command;
+use crate::errors;
+use crate::programmer::{self, MemoryType};
+use crate::specs;
+use serial::core::{Error, PortSettings, SerialPort};
+use std::convert::TryInto;
+use std::io::prelude::*;
+use std::thread;
+use std::time::Duration;
+
+#[allow(dead_code)]
+mod command {
+    pub const GET_SYNC: u8 = 0x30;
+    pub const GET_SIGN_ON: u8 = 0x31;
+    pub const GET_PARAMETER: u8 = 0x41;
+    pub const SET_DEVICE: u8 = 0x42;
+    pub const SET_DEVICE_EXT: u8 = 0x45;
+    pub const ENTER_PROGMODE: u8 = 0x50;
+    pub const LEAVE_PROGMODE: u8 = 0x51;
+    pub const LOAD_ADDRESS: u8 = 0x55;
+    pub const UNIVERSAL: u8 = 0x56;
+    pub const PROG_PAGE: u8 = 0x64;
+    pub const READ_PAGE: u8 = 0x74;
+    pub const READ_SIGN: u8 = 0x75;
+}
+
+mod param {
+    pub const HW_VER: u8 = 0x80;
+    pub const SW_MAJOR: u8 = 0x81;
+    pub const SW_MINOR: u8 = 0x82;
+}
+
+const CRC_EOP: u8 = 0x20;
+const INSYNC: u8 = 0x14;
+const OK: u8 = 0x10;
+/// Memory type byte of page commands.
+const FLASH: u8 = b'F';
+const EEPROM: u8 = b'E';
+/// Fuse and lock writes are not polled by firmware. Maximum write time from datasheets.
+const WRITE_DELAY: Duration = Duration::from_millis(5);
+/// Arduino resets on port open. Bootloader ignores our baud rate, so keep trying until it
+/// starts the sketch.
+const SYNC_ATTEMPTS: usize = 5;
+
+/// ArduinoISP default.
+pub const DEFAULT_BAUD: usize = 19_200;
+
+const SERIAL_SETTINGS: PortSettings = PortSettings {
+    baud_rate: serial::Baud19200,
+    char_size: serial::Bits8,
+    parity: serial::ParityNone,
+    stop_bits: serial::Stop1,
+    flow_control: serial::FlowNone,
+};
+
+/// Communication with programmer. Independent of target chip.
+struct Link {
+    port: serial::SystemPort,
+    counters: programmer::Counters,
+}
+
+impl Link {
+    fn open(port: &str, baud: usize) -> Result<Link, Error> {
+        let mut port = serial::open(port)?;
+        port.configure(&PortSettings {
+            baud_rate: serial::BaudRate::from_speed(baud),
+            ..SERIAL_SETTINGS
+        })?;
+        port.set_timeout(Duration::from_secs(1))?;
+        Ok(Link {
+            port,
+            counters: programmer::Counters::default(),
+        })
+    }
+
+    /// Send command and return `size` bytes of answer found between `INSYNC` and `OK`.
+    fn command(&mut self, body: &[u8], size: usize) -> Result<Vec<u8>, errors::ErrorKind> {
+        self.counters.frames += 1;
+        self.port.write_all(body)?;
+        self.port.write_all(&[CRC_EOP])?;
+        self.port.flush()?;
+        let mut answer = vec![0; size + 2];
+        self.port.read_exact(&mut answer)?;
+        if answer[0] != INSYNC {
+            return Err(errors::ErrorKind::AnswerIdError);
+        }
+        if answer[size + 1] != OK {
+            return Err(errors::ErrorKind::StatusError);
+        }
+        answer.pop();
+        answer.remove(0);
+        Ok(answer)
+    }
+
+    fn sync(&mut self) -> Result<(), errors::ErrorKind> {
+        let mut result = Ok(());
+        for _ in 0..SYNC_ATTEMPTS {
+            result = self.command(&[command::GET_SYNC], 0).map(drop);
+            if result.is_ok() {
+                break;
+            }
+            self.counters.retries += 1;
+            // Drop whatever bootloader or half synced firmware sent.
+            let mut buffer = [0; 64];
+            while matches!(self.port.read(&mut buffer), Ok(size) if size > 0) {}
+        }
+        result
+    }
+
+    fn sign_on(&mut self) -> Result<programmer::Variant, errors::ErrorKind> {
+        let answer = self.command(&[command::GET_SIGN_ON], 7)?;
+        Ok(programmer::Variant::from(&answer[..]))
+    }
+}
+
+/// Check if there is a STK500v1 compatible programmer (e.g. ArduinoISP) at given port.
+pub fn probe(port: &str) -> Result<programmer::Variant, errors::ErrorKind> {
+    let mut link = Link::open(port, DEFAULT_BAUD).map_err(std::io::Error::from)?;
+    link.sync()?;
+    link.sign_on()
+}
+
+/// STK500v1 programmer, e.g. Arduino board running ArduinoISP sketch.
+pub struct STK500v1 {
+    link: Link,
+    specs: specs::Specs,
+}
+
+impl STK500v1 {
+    pub fn open(port: &str, specs: specs::Specs) -> Result<STK500v1, errors::ErrorKind> {
+        STK500v1::open_with(port, DEFAULT_BAUD, specs)
+    }
+
+    /// Open programmer talking at `baud`, e.g. ArduinoISP sketch built for 115200.
+    pub fn open_with(
+        port: &str,
+        baud: usize,
+        specs: specs::Specs,
+    ) -> Result<STK500v1, errors::ErrorKind> {
+        let mut link = Link::open(port, baud).map_err(std::io::Error::from)?;
+        link.sync()?;
+        Ok(STK500v1 { link, specs })
+    }
+
+    fn get_param(&mut self, param: u8) -> Result<u8, errors::ErrorKind> {
+        Ok(self.link.command(&[command::GET_PARAMETER, param], 1)?[0])
+    }
+
+    pub fn read_programmer_signature(&mut self) -> Result<programmer::Variant, errors::ErrorKind> {
+        self.link.sign_on()
+    }
+
+    /// Read firmware version.
+    pub fn get_sw_version(&mut self) -> Result<programmer::SwVersion, errors::ErrorKind> {
+        Ok(programmer::SwVersion {
+            major: self.get_param(param::SW_MAJOR)?,
+            minor: self.get_param(param::SW_MINOR)?,
+        })
+    }
+
+    /// Read hardware version.
+    pub fn get_hw_version(&mut self) -> Result<u8, errors::ErrorKind> {
+        self.get_param(param::HW_VER)
+    }
+}
+
+/// Body of `SET_DEVICE` command.
+///
+/// Device code is not known from specs. ArduinoISP uses only page and memory sizes.
+fn set_device(specs: &specs::Specs) -> Vec<u8> {
+    let mut body = vec![
+        command::SET_DEVICE,
+        // Device code, revision, programming type, parallel mode, polling, self timed.
+        0,
+        0,
+        0,
+        1,
+        1,
+        1,
+        // Lock bytes and fuse bytes.
+        1,
+        specs.fuses().len() as u8,
+        // Flash and EEPROM poll values.
+        0xff,
+        0xff,
+        0xff,
+        0xff,
+    ];
+    body.extend_from_slice(&(specs.flash.page_size as u16).to_be_bytes());
+    body.extend_from_slice(&(specs.eeprom.size as u16).to_be_bytes());
+    body.extend_from_slice(&(specs.flash.size as u32).to_be_bytes());
+    body
+}
+
+impl TryInto<IspMode> for STK500v1 {
+    type Error = errors::ErrorKind;
+    fn try_into(mut self) -> Result<IspMode, Self::Error> {
+        self.link.command(&set_device(&self.specs), 0)?;
+        self.link.command(
+            &[
+                command::SET_DEVICE_EXT,
+                // Number of following bytes plus one.
+                5,
+                self.specs.eeprom.page_size as u8,
+                // PAGEL and BS2 pins, used only in parallel mode.
+                0xd7,
+                0xa0,
+                // Reset disable.
+                0,
+            ],
+            0,
+        )?;
+        self.link.command(&[command::ENTER_PROGMODE], 0)?;
+        Ok(IspMode { prog: self })
+    }
+}
+
+pub struct IspMode {
+    prog: STK500v1,
+}
+
+impl IspMode {
+    /// Access programmer.
+    pub fn programmer(&mut self) -> &mut STK500v1 {
+        &mut self.prog
+    }
+
+    /// Send 4 byte ISP instruction to target. Returns last byte shifted out by target.
+    fn universal(&mut self, cmd: isp_command::IspCommand) -> Result<u8, errors::ErrorKind> {
+        Ok(self
+            .prog
+            .link
+            .command(&[command::UNIVERSAL, cmd.0, cmd.1, cmd.2, cmd.3], 1)?[0])
+    }
+
+    /// Calibration byte at `address`. Chips with several oscillators or frequencies have one
+    /// byte for each.
+    fn read_osccal(&mut self, address: usize) -> Result<u8, errors::ErrorKind> {
+        let (cmd, high, _, low) = isp_command::READ_OSCCAL;
+        self.universal((cmd, high, address as u8, low))
+    }
+
+    /// Load byte `address`. Firmware expects word address for both flash and EEPROM.
+    fn load_address(&mut self, address: usize) -> Result<(), errors::ErrorKind> {
+        let word = address / 2;
+        // Flash above 128K needs extended address byte set in target.
+        if self.prog.specs.flash.size > 0x20000 {
+            let (cmd, _, _, last) = isp_command::LOAD_EXTENDED_ADDRESS;
+            self.universal((cmd, 0, (word >> 16) as u8, last))?;
+        }
+        let bytes = (word as u16).to_le_bytes();
+        self.prog
+            .link
+            .command(&[command::LOAD_ADDRESS, bytes[0], bytes[1]], 0)?;
+        Ok(())
+    }
+
+    /// Read memory in chunks of `page_size` starting at page aligned `address`.
+    fn read_pages(
+        &mut self,
+        memory: u8,
+        page_size: usize,
+        address: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), errors::ErrorKind> {
+        for (page, chunk) in buffer.chunks_mut(page_size).enumerate() {
+            self.load_address(address + page * page_size)?;
+            let size = (chunk.len() as u16).to_be_bytes();
+            let data = self
+                .prog
+                .link
+                .command(&[command::READ_PAGE, size[0], size[1], memory], chunk.len())?;
+            chunk.copy_from_slice(&data);
+        }
+        Ok(())
+    }
+
+    /// Write memory in chunks of `page_size` starting at page aligned `address`.
+    fn write_pages(
+        &mut self,
+        memory: u8,
+        page_size: usize,
+        address: usize,
+        bytes: &[u8],
+    ) -> Result<(), errors::ErrorKind> {
+        for (page, chunk) in bytes.chunks(page_size).enumerate() {
+            let mut data = chunk.to_vec();
+            // Only whole flash pages can be written. Fill the rest with erased value.
+            if memory == FLASH {
+                data.resize(page_size, 0xff);
+                // Flash is erased already.
+                if programmer::is_erased(&data) {
+                    continue;
+                }
+            }
+            self.load_address(address + page * page_size)?;
+            let size = (data.len() as u16).to_be_bytes();
+            let mut body = vec![command::PROG_PAGE, size[0], size[1], memory];
+            body.extend_from_slice(&data);
+            self.prog.link.command(&body, 0)?;
+        }
+        Ok(())
+    }
+
+    fn read_flash_pages(
+        &mut self,
+        address: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), errors::ErrorKind> {
+        let size = self.prog.specs.flash.page_size;
+        self.read_pages(FLASH, size, address, buffer)
+    }
+
+    fn read_eeprom_pages(
+        &mut self,
+        address: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), errors::ErrorKind> {
+        let size = self.prog.specs.eeprom.page_size;
+        self.read_pages(EEPROM, size, address, buffer)
+    }
+
+    fn write_flash_pages(&mut self, address: usize, bytes: &[u8]) -> Result<(), errors::ErrorKind> {
+        let size = self.prog.specs.flash.page_size;
+        self.write_pages(FLASH, size, address, bytes)
+    }
+
+    fn write_eeprom_pages(
+        &mut self,
+        address: usize,
+        bytes: &[u8],
+    ) -> Result<(), errors::ErrorKind> {
+        let size = self.prog.specs.eeprom.page_size;
+        self.write_pages(EEPROM, size, address, bytes)
+    }
+}
+
+impl programmer::MemoryOps for IspMode {
+    fn layout(&self, memory: MemoryType) -> Result<specs::Memory, errors::ErrorKind> {
+        programmer::layout(&self.prog.specs, memory).cloned()
+    }
+
+    fn read_at(
+        &mut self,
+        memory: MemoryType,
+        address: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), errors::ErrorKind> {
+        match memory {
+            MemoryType::Flash => self.read_flash_pages(address, buffer),
+            MemoryType::Eeprom => self.read_eeprom_pages(address, buffer),
+            MemoryType::Calibration => {
+                for (offset, byte) in buffer.iter_mut().enumerate() {
+                    *byte = self.read_osccal(address + offset)?;
+                }
+                Ok(())
+            }
+            _ => Err(programmer::unsupported(memory)),
+        }
+    }
+
+    fn write_at(
+        &mut self,
+        memory: MemoryType,
+        address: usize,
+        bytes: &[u8],
+    ) -> Result<(), errors::ErrorKind> {
+        match memory {
+            MemoryType::Flash => self.write_flash_pages(address, bytes),
+            MemoryType::Eeprom => self.write_eeprom_pages(address, bytes),
+            _ => Err(programmer::unsupported(memory)),
+        }
+    }
+}
+
+impl programmer::Erase for IspMode {
+    fn erase(&mut self) -> Result<(), errors::ErrorKind> {
+        self.universal(isp_command::CHIP_ERASE)?;
+        thread::sleep(Duration::from_millis(self.prog.specs.erase_delay.into()));
+        Ok(())
+    }
+}
+
+impl programmer::Programmer for IspMode {
+    fn close(mut self) -> Result<(), errors::ErrorKind> {
+        self.prog.link.command(&[command::LEAVE_PROGMODE], 0)?;
+        Ok(())
+    }
+}
+
+impl programmer::AVRLockByteGet for IspMode {
+    fn get_lock_byte(&mut self) -> Result<u8, errors::ErrorKind> {
+        self.universal(isp_command::READ_LOCK)
+    }
+}
+
+impl programmer::AVRLockByteSet for IspMode {
+    fn set_lock_byte(&mut self, byte: u8) -> Result<u8, errors::ErrorKind> {
+        programmer::write_lock(self, programmer::LOCK_BITS, byte, |isp, byte| {
+            let (a, b, c, _) = isp_command::WRITE_LOCK;
+            isp.universal((a, b, c, byte))?;
+            thread::sleep(WRITE_DELAY);
+            Ok(())
+        })
+    }
+}
+
+impl programmer::AVROsccalGet for IspMode {
+    fn get_osccal(&mut self) -> Result<u8, errors::ErrorKind> {
+        self.read_osccal(0)
+    }
+}
+
+impl programmer::AVRFuseGet for IspMode {
+    fn get_fuses(&mut self) -> Result<programmer::AVRFuse, errors::ErrorKind> {
+        Ok(programmer::AVRFuse {
+            low: self.universal(isp_command::READ_LOW_FUSE)?,
+            high: self.universal(isp_command::READ_HIGH_FUSE)?,
+            extended: self.universal(isp_command::READ_EXTENDED_FUSE)?,
+        })
+    }
+}
+
+impl programmer::AVRFuseSet for IspMode {
+    fn set_fuses(
+        &mut self,
+        fuses: &programmer::AVRFuse,
+    ) -> Result<programmer::AVRFuse, errors::ErrorKind> {
+        for fuse in self.prog.specs.fuses() {
+            let (a, b, c, _) = match fuse {
+                specs::Fuse::Low => isp_command::WRITE_LOW_FUSE,
+                specs::Fuse::High => isp_command::WRITE_HIGH_FUSE,
+                specs::Fuse::Extended => isp_command::WRITE_EXTENDED_FUSE,
+            };
+            self.universal((a, b, c, fuses.get(fuse)))?;
+            thread::sleep(WRITE_DELAY);
+        }
+        let read = programmer::AVRFuseGet::get_fuses(self)?;
+        programmer::check_fuses(self.prog.specs.fuse_bits, fuses, read)
+    }
+}
+
+impl programmer::MCUSignature for IspMode {
+    fn get_mcu_signature(&mut self) -> Result<specs::Signature, errors::ErrorKind> {
+        let bytes = self.prog.link.command(&[command::READ_SIGN], 3)?;
+        Ok(specs::Signature::from((bytes[0], bytes[1], bytes[2])))
+    }
+}
+
+impl programmer::Session for IspMode {
+    fn identify(&mut self) -> Result<programmer::Identity, errors::ErrorKind> {
+        Ok(programmer::Identity {
+            name: self.prog.read_programmer_signature()?.to_string(),
+            hardware: Some(self.prog.get_hw_version()?),
+            firmware: Some(self.prog.get_sw_version()?),
+        })
+    }
+
+    fn counters(&self) -> programmer::Counters {
+        self.prog.link.counters
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), errors::ErrorKind> {
+        programmer::Programmer::close(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::specs::atmega;
+
+    #[test]
+    fn set_device_has_memory_sizes() {
+        let body = set_device(&atmega::ATMEGA_32);
+        assert_eq!(body.len(), 21);
+        assert_eq!(body[8], 2);
+        assert_eq!(
+            &body[13..],
+            &[0x00, 0x80, 0x04, 0x00, 0x00, 0x00, 0x80, 0x00]
+        );
+    }
+}
@@ -1,130 +1,466 @@
 use crate::command as isp_command;
 use crate::errors;
-use crate::programmer;
+use crate::programmer::{self, Event, Listener, MemoryType};
 use crate::specs;
-use serial::core::{Error, PortSettings, SerialPort};
-use std::convert::{TryFrom, TryInto};
+use serial::core::{PortSettings, SerialPort};
+use std::convert::TryInto;
 use std::fmt;
 use std::io::prelude::*;
+use std::io::BufReader;
+use std::str::FromStr;
 use std::string::String;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-#[allow(dead_code)]
-mod command {
+use protocol::{check_body_size, command, Message, SequenceGenerator};
+pub(crate) use protocol::{param, Status};
 
-    pub enum Normal {
-        SignOn = 0x01,
-        SetParameter = 0x02,
-        GetParameter = 0x03,
-        SetDeviceParameters = 0x04,
-        OSCcal = 0x05,
-        LoadAddress = 0x06,
-        FirmwareUpgrade = 0x07,
-        SpiMulti = 0x1D,
-        SetControlStack = 0x2D,
-        EnterIspMode = 0x10,
-        LeaveIspMode = 0x11,
+/// STK500v2 framing, commands and parameters, shared by serial, USB and JTAGICE mkII links.
+///
+/// Usable without a programmer, e.g. by protocol analyzers or firmware test rigs.
+pub mod protocol {
+    use crate::errors;
+    use crate::hexdump::to_hex;
+    use std::convert::TryFrom;
+    use std::fmt;
+    use std::io::Read;
+
+    #[allow(dead_code)]
+    pub mod command {
+
+        pub enum Normal {
+            SignOn = 0x01,
+            SetParameter = 0x02,
+            GetParameter = 0x03,
+            SetDeviceParameters = 0x04,
+            OSCcal = 0x05,
+            LoadAddress = 0x06,
+            FirmwareUpgrade = 0x07,
+            SpiMulti = 0x1D,
+            SetControlStack = 0x2D,
+            EnterIspMode = 0x10,
+            LeaveIspMode = 0x11,
+            Xprog = 0x50,
+            XprogSetMode = 0x51,
+        }
+
+        impl From<Normal> for u8 {
+            fn from(value: Normal) -> u8 {
+                value as u8
+            }
+        }
+
+        pub enum Isp {
+            ChipErase = 0x12,
+            ProgramFlash = 0x13,
+            ReadFlash = 0x14,
+            ProgramEeprom = 0x15,
+            ReadEeprom = 0x16,
+            ProgramFuse = 0x17,
+            ReadFuse = 0x18,
+            ProgramLock = 0x19,
+            ReadLock = 0x1A,
+            ReadSignature = 0x1B,
+            ReadOsccal = 0x1C,
+        }
+
+        impl From<Isp> for u8 {
+            fn from(value: Isp) -> u8 {
+                value as u8
+            }
+        }
     }
 
-    impl Into<u8> for Normal {
-        fn into(self) -> u8 {
-            self as u8
+    #[allow(dead_code)]
+    pub mod param {
+        pub trait Readable {}
+
+        pub trait Writable {}
+
+        pub enum RO {
+            BuildNumberLow = 0x80,
+            BuildNumberHigh = 0x81,
+            HwVer = 0x90,
+            SwMajor = 0x91,
+            SwMinor = 0x92,
+            TopcardDetect = 0x9A, // This parameter only applies to STK500, not the AVRISP
+            Status = 0x9C,
+            Data = 0x9D,
+            // STK600 only.
+            SocketCardId = 0xD0,
+            RoutingCardId = 0xD1,
+            ExpansionCardId = 0xD2,
+            SwMajorSlave1 = 0xD3,
+            SwMinorSlave1 = 0xD4,
+            SwMajorSlave2 = 0xD5,
+            SwMinorSlave2 = 0xD6,
+            BoardIdStatus = 0xD7,
+        }
+
+        impl Readable for RO {}
+
+        impl From<RO> for u8 {
+            fn from(value: RO) -> u8 {
+                value as u8
+            }
+        }
+
+        pub enum RW {
+            Vtarget = 0x94,
+            Vadjust = 0x95,
+            OScPscale = 0x96,
+            OscCmatch = 0x97,
+            SckDuration = 0x98,
+            ControllerInit = 0x9F,
+            ResetPolarity = 0x9E,
+        }
+
+        impl Readable for RW {}
+
+        impl Writable for RW {}
+
+        impl From<RW> for u8 {
+            fn from(value: RW) -> u8 {
+                value as u8
+            }
+        }
+
+        /// 16 bit parameters of STK600, sent high byte first.
+        pub enum RW2 {
+            SckDuration = 0xC0,
+            ClockConf = 0xC1,
+            Aref0 = 0xC2,
+            Aref1 = 0xC3,
+        }
+
+        impl From<RW2> for u8 {
+            fn from(value: RW2) -> u8 {
+                value as u8
+            }
         }
     }
 
-    pub enum Isp {
-        ChipErase = 0x12,
-        ProgramFlash = 0x13,
-        ReadFlash = 0x14,
-        ProgramEeprom = 0x15,
-        ReadEeprom = 0x16,
-        ProgramFuse = 0x17,
-        ReadFuse = 0x18,
-        ProgramLock = 0x19,
-        ReadLock = 0x1A,
-        ReadSignature = 0x1B,
-        ReadOsccal = 0x1C,
+    #[allow(dead_code)]
+    pub enum Status {
+        CmdOk = 0x00,
+        CmdTimeout = 0x80,
+        RdyBsyTout = 0x81,
+        SetParamMissing = 0x82,
+        CmdFailed = 0xC0,
+        UnknownCmd = 0xC9,
+        CheckSumError = 0xC1,
+        AnswerChecksumError = 0xB0,
     }
 
-    impl Into<u8> for Isp {
-        fn into(self) -> u8 {
-            self as u8
+    impl From<Status> for u8 {
+        fn from(value: Status) -> u8 {
+            value as u8
         }
     }
-}
 
-#[allow(dead_code)]
-pub mod param {
-    pub trait Readable {}
+    /// Communication binary message with programmer.
+    ///
+    /// Structure:
+    /// 1. Message start
+    /// 1. Sequence number. See [`SequenceGenerator`]
+    /// 1. Body length. Two bytes (maximum 275) in big endian order.
+    /// 1. Token
+    /// 1. Body
+    /// 1. Calculated checksum
+    ///
+    /// Once constructed, buffer always holds a complete frame: start byte and token are in place, body
+    /// is at most [`Message::MAX_BODY_SIZE`] bytes and checksum matches. Failed
+    /// [`Message::read_from`] is the only exception, the message must not be used then.
+    #[derive(Debug)]
+    pub struct Message {
+        buffer: MessageBuffer,
+    }
 
-    pub trait Writable {}
+    /// Buffer large enough for the longest message.
+    pub type MessageBuffer = [u8; Message::MAX_SIZE];
 
-    pub enum RO {
-        BuildNumberLow = 0x80,
-        BuildNumberHigh = 0x81,
-        HwVer = 0x90,
-        SwMajor = 0x91,
-        SwMinor = 0x92,
-        TopcardDetect = 0x9A, // This parameter only applies to STK500, not the AVRISP
-        Status = 0x9C,
-        Data = 0x9D,
+    /// Make sure command body of `size` bytes fits into one message.
+    pub(crate) fn check_body_size(size: usize) -> Result<(), errors::ErrorKind> {
+        if size > Message::MAX_BODY_SIZE {
+            return Err(errors::ErrorKind::BodyTooLarge {
+                size,
+                max: Message::MAX_BODY_SIZE,
+            });
+        }
+        Ok(())
     }
 
-    impl Readable for RO {}
+    impl Message {
+        pub const MESSAGE_START: u8 = 0x1B;
+        const MESSAGE_START_POSITION: usize = 0;
+        pub const TOKEN: u8 = 0x0E;
+        const TOKEN_POSITION: usize = 4;
+        pub(crate) const HEADER_SIZE: usize = 5;
+        pub(crate) const CHECKSUM_SIZE: usize = 1;
+        pub(crate) const BODY_START_POSITION: usize = 5;
+        const LEN_BYTE_0_POSITION: usize = 2;
+        const LEN_BYTE_1_POSITION: usize = 3;
+        const SEQ_POSITION: usize = 1;
+        /// Longest body firmware accepts.
+        pub const MAX_BODY_SIZE: usize = 275;
+        pub const MAX_SIZE: usize = Self::MAX_BODY_SIZE + Self::CHECKSUM_SIZE + Self::HEADER_SIZE;
+
+        pub fn new(seq: u8, body: &[u8]) -> Result<Self, errors::ErrorKind> {
+            let mut msg = Message::empty();
+            msg.set(seq, body)?;
+            Ok(msg)
+        }
 
-    impl From<RO> for u8 {
-        fn from(value: RO) -> u8 {
-            value as u8
+        /// Zeroed buffer, to be filled with [`Message::set`] or by reading a message. Not a valid
+        /// frame, so not public. Other crates start from [`Message::new`].
+        pub(crate) fn empty() -> Self {
+            Message {
+                buffer: [0; Self::MAX_SIZE],
+            }
+        }
+
+        /// Frame `body` in place. Buffer is reused, so no message is allocated.
+        pub fn set(&mut self, seq: u8, body: &[u8]) -> Result<(), errors::ErrorKind> {
+            check_body_size(body.len())?;
+            let buffer = &mut self.buffer;
+            buffer[Self::MESSAGE_START_POSITION] = Self::MESSAGE_START;
+            buffer[Self::TOKEN_POSITION] = Self::TOKEN;
+            buffer[Self::SEQ_POSITION] = seq;
+            let body_size = body.len();
+            let end_index = Self::BODY_START_POSITION + body_size;
+            buffer[Self::LEN_BYTE_0_POSITION..=Self::LEN_BYTE_1_POSITION]
+                .copy_from_slice(&(body_size as u16).to_be_bytes());
+            buffer[Self::BODY_START_POSITION..end_index].copy_from_slice(body);
+            buffer[end_index] = Self::calc_checksum(&buffer[..end_index]);
+            Ok(())
+        }
+
+        /// Read one message from `reader`. Header is checked before body size is trusted.
+        pub fn read_from<R: Read>(&mut self, reader: &mut R) -> Result<(), errors::ErrorKind> {
+            reader.read_exact(&mut self.buffer[..Self::HEADER_SIZE])?;
+            self.check_header()?;
+            let end = self.get_end_index() + Self::CHECKSUM_SIZE;
+            reader.read_exact(&mut self.buffer[Self::BODY_START_POSITION..end])?;
+            self.check()
+        }
+
+        /// Check header and checksum of received message.
+        pub fn check(&self) -> Result<(), errors::ErrorKind> {
+            self.check_header()?;
+            let end_index = self.get_end_index();
+            if self.buffer[end_index] != Self::calc_checksum(&self.buffer[..end_index]) {
+                return Err(errors::ErrorKind::ChecksumError);
+            }
+            Ok(())
+        }
+
+        /// Check start byte, token and body size. Body can be read once they are valid.
+        pub(crate) fn check_header(&self) -> Result<(), errors::ErrorKind> {
+            if self.buffer[Self::MESSAGE_START_POSITION] != Self::MESSAGE_START
+                || self.buffer[Self::TOKEN_POSITION] != Self::TOKEN
+                || self.get_body_size() as usize > Self::MAX_BODY_SIZE
+            {
+                return Err(errors::ErrorKind::MalformedFrame);
+            }
+            Ok(())
+        }
+
+        /// Return sequence number.
+        pub fn get_sequence(&self) -> u8 {
+            self.buffer[Self::SEQ_POSITION]
+        }
+
+        pub fn get_body_size(&self) -> u16 {
+            u16::from_be_bytes([
+                self.buffer[Self::LEN_BYTE_0_POSITION],
+                self.buffer[Self::LEN_BYTE_1_POSITION],
+            ])
+        }
+
+        /// Return ending index at where message ends. That is index including.
+        pub(crate) fn get_end_index(&self) -> usize {
+            Self::BODY_START_POSITION + self.get_body_size() as usize
+        }
+
+        pub fn body_slice(&self) -> &[u8] {
+            &self.buffer[Self::BODY_START_POSITION..self.get_end_index()]
+        }
+
+        /// Return whole message as slice.
+        pub fn as_slice(&self) -> &[u8] {
+            &self.buffer[..=self.get_end_index()]
+        }
+
+        /// Calculate checksum (XOR of all bytes)
+        pub fn calc_checksum(bytes: &[u8]) -> u8 {
+            bytes.iter().fold(0, |result, byte| result ^ byte)
         }
     }
 
-    pub enum RW {
-        Vtarget = 0x94,
-        Vadjust = 0x95,
-        OScPscale = 0x96,
-        OscCmatch = 0x97,
-        SckDuration = 0x98,
-        ControllerInit = 0x9F,
-        ResetPolarity = 0x9E,
+    impl TryFrom<MessageBuffer> for Message {
+        type Error = errors::ErrorKind;
+
+        fn try_from(buffer: MessageBuffer) -> Result<Self, Self::Error> {
+            let msg = Message { buffer };
+            msg.check()?;
+            Ok(msg)
+        }
     }
 
-    impl Readable for RW {}
+    impl fmt::Display for Message {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(
+                f,
+                "sequence_number={} body_length={} body=[{}]",
+                self.get_sequence(),
+                self.body_slice().len(),
+                to_hex(self.body_slice()),
+            )
+        }
+    }
 
-    impl Writable for RW {}
+    /// Incremented by one for each message sent.
+    /// Wraps to zero after 0xFF is reached. Answer carries sequence number of its command.
+    #[derive(Debug, Default)]
+    pub struct SequenceGenerator {
+        count: u8,
+    }
 
-    impl From<RW> for u8 {
-        fn from(value: RW) -> u8 {
-            value as u8
+    impl SequenceGenerator {
+        pub fn new() -> SequenceGenerator {
+            SequenceGenerator { count: 0 }
+        }
+    }
+
+    impl Iterator for SequenceGenerator {
+        type Item = u8;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            let ret = Some(self.count);
+            self.count = self.count.wrapping_add(1);
+            ret
         }
     }
 }
 
-#[allow(dead_code)]
-pub enum Status {
-    CmdOk = 0x00,
-    CmdTimeout = 0x80,
-    RdyBsyTout = 0x81,
-    SetParamMissing = 0x82,
-    CmdFailed = 0xC0,
-    UnknownCmd = 0xC9,
-    CheckSumError = 0xC1,
-    AnswerChecksumError = 0xB0,
+/// Bit 0 of memory mode byte. Set for page mode, cleared for word mode.
+const PAGE_MODE: u8 = 0x01;
+/// Bit 7 of memory mode byte. Write page after loading data.
+const WRITE_PAGE: u8 = 0x80;
+/// `SckDuration` values tried when target does not answer, down to below 32 kHz SCK.
+const SLOWER_SCK: [u8; 4] = [1, 2, 3, 10];
+/// `SckDuration` values tried by [`sweep_sck`], fastest first.
+pub const SWEEP_SCK: [u8; 8] = [0, 1, 2, 3, 5, 10, 20, 50];
+/// Crystal of STK500. SCK frequency is derived from it.
+const STK500_XTAL: u32 = 7_372_800;
+/// Allowed error of Vtarget measurement, in tenths of volt.
+const VTARGET_MARGIN: u8 = 2;
+/// Prescalers of STK500 clock generator, selected by `OscPscale` 1 to 7. 0 stops it.
+const OSC_PRESCALERS: [u32; 7] = [1, 8, 32, 64, 128, 256, 1024];
+/// Most bytes read with one command. Size field of read commands is larger, but answer must fit
+/// into message buffer of firmware.
+const MAX_READ_BLOCK: usize = 256;
+/// Bytes read with one command. Whole pages, as many as fit into [`MAX_READ_BLOCK`]. Larger
+/// pages are read in parts.
+fn read_block(page_size: usize) -> usize {
+    match MAX_READ_BLOCK / page_size {
+        0 => MAX_READ_BLOCK,
+        pages => pages * page_size,
+    }
+}
+
+/// SCK frequency in Hz set by `SckDuration` parameter on STK500. Other programmers use their own
+/// tables, but slower SCK always has larger duration.
+pub fn sck_frequency(duration: u8) -> u32 {
+    match duration {
+        0 => 1_843_200,
+        1 => 460_800,
+        2 => 115_200,
+        3 => 57_600,
+        _ => STK500_XTAL / (24 * duration as u32 + 20),
+    }
+}
+
+/// Fastest `SckDuration` with SCK below target `clock` divided by `divisor`. ISP requires less
+/// than a quarter, larger divisor leaves margin for inaccurate clocks.
+pub fn sck_below(clock: u32, divisor: u32) -> u8 {
+    (0..=u8::MAX)
+        .find(|&duration| sck_frequency(duration) < clock / divisor)
+        .unwrap_or(u8::MAX)
 }
 
-impl Into<u8> for Status {
-    fn into(self) -> u8 {
-        self as u8
+/// `OscPscale` and `OscCmatch` giving STK500 clock output closest to `frequency` Hz, not below
+/// it. None if out of range.
+pub fn oscillator_setting(frequency: u32) -> Option<(u8, u8)> {
+    if frequency == 0 || frequency > STK500_XTAL / 2 {
+        return None;
+    }
+    OSC_PRESCALERS
+        .iter()
+        .zip(1..)
+        .find_map(|(prescaler, pscale)| {
+            let cmatch = STK500_XTAL / (2 * prescaler) / frequency;
+            (cmatch <= 256).then(|| (pscale, (cmatch - 1) as u8))
+        })
+}
+
+/// STK500 clock output in Hz for `OscPscale` and `OscCmatch`. 0 when stopped.
+pub fn oscillator_frequency(pscale: u8, cmatch: u8) -> u32 {
+    match pscale {
+        1..=7 => {
+            let prescaler = OSC_PRESCALERS[usize::from(pscale - 1)];
+            STK500_XTAL / (2 * prescaler * (u32::from(cmatch) + 1))
+        }
+        _ => 0,
     }
 }
 
-pub struct SwVersion {
-    pub major: u8,
-    pub minor: u8,
+pub use programmer::SwVersion;
+
+/// Readable programmer parameters. Those refused by programmer are `None`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parameters {
+    pub hardware: u8,
+    pub firmware: SwVersion,
+    pub build: Option<u16>,
+    /// Target voltage in tenths of volt.
+    pub vtarget: Option<u8>,
+    /// AREF voltage in tenths of volt.
+    pub vadjust: Option<u8>,
+    /// Clock generator prescaler and compare match.
+    pub oscillator: Option<(u8, u8)>,
+    pub sck_duration: u8,
+    /// Expansion card on STK500.
+    pub topcard: Option<u8>,
+    pub status: Option<u8>,
+    pub reset_polarity: Option<u8>,
 }
 
-impl fmt::Display for SwVersion {
+impl fmt::Display for Parameters {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}.{}", self.major, self.minor,)
+        fn optional<T: fmt::Display>(value: Option<T>) -> String {
+            value.map_or_else(|| String::from("n/a"), |value| value.to_string())
+        }
+        let volts =
+            |tenths: Option<u8>| optional(tenths.map(|t| format!("{:.1} V", t as f32 / 10.0)));
+        let hex = |value: Option<u8>| optional(value.map(|value| format!("{:#04x}", value)));
+        writeln!(f, "hardware version: {}", self.hardware)?;
+        writeln!(f, "firmware version: {}", self.firmware)?;
+        writeln!(f, "build number: {}", optional(self.build))?;
+        writeln!(f, "target voltage: {}", volts(self.vtarget))?;
+        writeln!(f, "adjustable voltage: {}", volts(self.vadjust))?;
+        let oscillator = self
+            .oscillator
+            .map(|(pscale, cmatch)| format!("prescaler {}, compare match {}", pscale, cmatch));
+        writeln!(f, "oscillator: {}", optional(oscillator))?;
+        writeln!(
+            f,
+            "SCK duration: {} (about {} kHz on STK500)",
+            self.sck_duration,
+            sck_frequency(self.sck_duration) / 1000
+        )?;
+        writeln!(f, "top card: {}", hex(self.topcard))?;
+        writeln!(f, "status: {}", hex(self.status))?;
+        write!(f, "reset polarity: {}", optional(self.reset_polarity))
     }
 }
 
@@ -137,256 +473,756 @@ pub enum TopCard {
     STK520 = 0xDD,
 }
 
-/// Communication binary message with programmer.
-///
-/// Structure:
-/// 1. Message start
-/// 1. Sequence number. See [`SequenceGenerator`]
-/// 1. Body length. Two bytes (maximum 275) in big endian order.
-/// 1. Token
-/// 1. Body
-/// 1. Calculated checksum
-#[derive(Debug)]
-struct Message {
-    buffer: MessageBuffer,
+const SERIAL_SETTINGS: PortSettings = PortSettings {
+    baud_rate: serial::Baud115200,
+    char_size: serial::Bits8,
+    parity: serial::ParityNone,
+    stop_bits: serial::Stop1,
+    // Must be set to none.
+    // Otherwise programmer may hang at random command.
+    flow_control: serial::FlowNone,
+};
+
+/// What to do when answer carries other sequence number than its command.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SequencePolicy {
+    /// Fail the command.
+    #[default]
+    Strict,
+    /// Accept the answer and count it in [`programmer::Counters::mismatches`].
+    Warn,
+    /// Accept the answer. Some clones echo a constant number.
+    Ignore,
+}
+
+impl SequencePolicy {
+    /// Check sequence number of answer to command `sent`.
+    fn check(self, sent: u8, found: u8) -> Result<(), errors::ErrorKind> {
+        if sent != found && self == SequencePolicy::Strict {
+            return Err(errors::ErrorKind::SequenceError {});
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SequencePolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<SequencePolicy, String> {
+        match value {
+            "strict" => Ok(SequencePolicy::Strict),
+            "warn" => Ok(SequencePolicy::Warn),
+            "ignore" => Ok(SequencePolicy::Ignore),
+            _ => Err(format!(
+                "Unknown sequence policy {}. Use strict, warn or ignore",
+                value
+            )),
+        }
+    }
+}
+
+/// Settings of serial link to programmer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkOptions {
+    pub sequence: SequencePolicy,
+    /// Baud rates tried in order until programmer answers sign on.
+    pub bauds: Vec<usize>,
+    pub stop_bits: serial::StopBits,
+    /// How long to wait for answer. Slow optical isolators or long RS-485 links need more.
+    pub timeout: Duration,
+    /// How long to wait for programmer which dropped off USB to come back. Disabled if None.
+    /// Answer timeout alone does not reopen the port.
+    pub reconnect: Option<Duration>,
+}
+
+impl LinkOptions {
+    /// AVRISP clones ship with differing defaults.
+    pub const DEFAULT_BAUDS: [usize; 4] = [115_200, 57_600, 38_400, 19_200];
 }
 
-type MessageBuffer = [u8; Message::MAX_SIZE];
+impl Default for LinkOptions {
+    fn default() -> LinkOptions {
+        LinkOptions {
+            sequence: SequencePolicy::default(),
+            bauds: LinkOptions::DEFAULT_BAUDS.to_vec(),
+            stop_bits: SERIAL_SETTINGS.stop_bits,
+            timeout: Duration::from_secs(1),
+            reconnect: None,
+        }
+    }
+}
+
+/// Framed communication with programmer. Independent of target chip.
+struct Link {
+    /// Buffered, so a whole answer is usually taken with a single read.
+    port: BufReader<serial::SystemPort>,
+    sequencer: SequenceGenerator,
+    /// Last sent or received message. Reused for every frame.
+    message: Message,
+    sequence: SequencePolicy,
+    /// Answers accepted with wrong sequence number.
+    mismatches: usize,
+    /// Set if link reopens port after programmer vanished.
+    reconnect: Option<Reconnect>,
+}
+
+/// Where to find programmer again and how to bring it back to the state it was left in.
+struct Reconnect {
+    port: String,
+    /// USB serial number of adapter. Port name can change after re-enumeration.
+    serial_number: Option<String>,
+    options: LinkOptions,
+    resume: Resume,
+}
+
+impl Reconnect {
+    /// Port name of adapter, as currently enumerated.
+    fn port(&self) -> String {
+        let serial_number = match &self.serial_number {
+            Some(serial_number) => serial_number,
+            None => return self.port.clone(),
+        };
+        crate::ports::list()
+            .unwrap_or_default()
+            .into_iter()
+            .find(|port| {
+                port.usb
+                    .as_ref()
+                    .is_some_and(|usb| usb.serial_number.as_ref() == Some(serial_number))
+            })
+            .map_or_else(|| self.port.clone(), |port| port.name)
+    }
+}
+
+/// Programmer state set by commands: parameters, programming mode and address.
+#[derive(Debug, Default, PartialEq)]
+struct Resume {
+    /// `SetParameter` commands, last value of each parameter. STK600 ones hold two bytes.
+    params: Vec<Vec<u8>>,
+    /// `EnterIspMode` command while programming mode is active.
+    isp: Option<Vec<u8>>,
+    /// Address firmware uses next. Incremented by reads and writes like firmware does.
+    address: Option<u32>,
+}
+
+impl Resume {
+    /// Note state changed by successful command `body`. Bodies too short for their command are
+    /// not tracked.
+    fn track(&mut self, body: &[u8]) {
+        let size = |high, low| u32::from(u16::from_be_bytes([high, low]));
+        match *body {
+            [cmd, param, _, ..] if cmd == command::Normal::SetParameter.into() => {
+                self.params.retain(|set| set[1] != param);
+                self.params.push(body.to_vec());
+            }
+            [cmd, ..] if cmd == command::Normal::EnterIspMode.into() => {
+                self.isp = Some(body.to_vec())
+            }
+            [cmd, ..] if cmd == command::Normal::LeaveIspMode.into() => self.isp = None,
+            [cmd, a, b, c, d, ..] if cmd == command::Normal::LoadAddress.into() => {
+                self.address = Some(u32::from_be_bytes([a, b, c, d]));
+            }
+            // Flash is addressed in words.
+            [cmd, high, low, ..]
+                if cmd == command::Isp::ReadFlash.into()
+                    || cmd == command::Isp::ProgramFlash.into() =>
+            {
+                self.address = self.address.map(|address| address + size(high, low) / 2);
+            }
+            [cmd, high, low, ..]
+                if cmd == command::Isp::ReadEeprom.into()
+                    || cmd == command::Isp::ProgramEeprom.into() =>
+            {
+                self.address = self.address.map(|address| address + size(high, low));
+            }
+            _ => (),
+        }
+    }
+
+    /// Commands bringing freshly connected programmer to tracked state.
+    fn commands(&self) -> Vec<Vec<u8>> {
+        let mut commands = self.params.clone();
+        commands.extend(self.isp.clone());
+        if let Some(address) = self.address {
+            let mut load = vec![command::Normal::LoadAddress.into()];
+            load.extend_from_slice(&address.to_be_bytes());
+            commands.push(load);
+        }
+        commands
+    }
+}
 
-impl Message {
-    const MESSAGE_START: u8 = 0x1B;
-    const MESSAGE_START_POSITION: usize = 0;
-    const TOKEN: u8 = 0x0E;
-    const TOKEN_PSITION: usize = 4;
-    const HEADER_SIZE: usize = 5;
-    const CHECKSUM_SIZE: usize = 1;
-    const BODY_START_POSITION: usize = 5;
-    const LEN_BYTE_0_POSITION: usize = 2;
-    const LEN_BYTE_1_POSITION: usize = 3;
-    const SEQ_PSITION: usize = 1;
-    const MAX_BODY_SIZE: usize = 275;
-    const MAX_SIZE: usize = Self::MAX_BODY_SIZE + Self::CHECKSUM_SIZE + Self::HEADER_SIZE;
+impl Link {
+    /// Open `port` at first baud rate of `options` at which programmer answers sign on.
+    fn open(port: &str, options: &LinkOptions) -> Result<Link, errors::ErrorKind> {
+        let mut link = Link::connect(port, options)?;
+        if options.reconnect.is_some() {
+            let serial_number = crate::ports::list()
+                .unwrap_or_default()
+                .into_iter()
+                .find(|info| info.name == port)
+                .and_then(|info| info.usb)
+                .and_then(|usb| usb.serial_number);
+            link.reconnect = Some(Reconnect {
+                port: String::from(port),
+                serial_number,
+                options: options.clone(),
+                resume: Resume::default(),
+            });
+        }
+        Ok(link)
+    }
 
-    fn new(seq: u8, body: Vec<u8>) -> Self {
-        let mut buffer: MessageBuffer = [0; Self::MAX_SIZE];
-        buffer[Self::MESSAGE_START_POSITION] = Self::MESSAGE_START;
-        buffer[Self::TOKEN_PSITION] = Self::TOKEN;
-        buffer[Self::SEQ_PSITION] = seq;
-        let body_size = body.len();
-        let end_index = Self::BODY_START_POSITION + body_size;
-        buffer[Self::LEN_BYTE_0_POSITION..=Self::LEN_BYTE_1_POSITION]
-            .swap_with_slice(&mut (body_size as u16).to_be_bytes());
-        buffer[Self::BODY_START_POSITION..end_index].copy_from_slice(&body.as_slice());
-        buffer[end_index] = Self::calc_checksum(&buffer[..end_index]);
-        Self { buffer }
+    fn connect(port: &str, options: &LinkOptions) -> Result<Link, errors::ErrorKind> {
+        span!("connect", port);
+        let mut port = serial::open(port).map_err(std::io::Error::from)?;
+        let settings = PortSettings {
+            stop_bits: options.stop_bits,
+            ..SERIAL_SETTINGS
+        };
+        port.configure(&settings).map_err(std::io::Error::from)?;
+        port.set_timeout(options.timeout)
+            .map_err(std::io::Error::from)?;
+        let mut link = Link {
+            port: BufReader::with_capacity(Message::MAX_SIZE, port),
+            sequencer: SequenceGenerator::new(),
+            message: Message::empty(),
+            sequence: options.sequence,
+            mismatches: 0,
+            reconnect: None,
+        };
+        let mut result = Err(errors::ErrorKind::Unsupported(String::from(
+            "no baud rate given",
+        )));
+        for &baud in &options.bauds {
+            span!("sign_on", baud);
+            link.set_baud(baud)?;
+            result = link.sign_on();
+            if result.is_ok() {
+                break;
+            }
+        }
+        result?;
+        Ok(link)
     }
 
-    /// Return sequence number.
-    fn get_sequence(&self) -> u8 {
-        self.buffer[Self::SEQ_PSITION]
+    fn set_baud(&mut self, baud: usize) -> Result<(), errors::ErrorKind> {
+        self.port
+            .get_mut()
+            .reconfigure(&|settings| settings.set_baud_rate(serial::BaudRate::from_speed(baud)))
+            .map_err(std::io::Error::from)?;
+        // Drop anything received at previous rate.
+        let buffered = self.port.buffer().len();
+        self.port.consume(buffered);
+        Ok(())
     }
 
-    fn get_body_size(&self) -> u16 {
-        u16::from_be_bytes([
-            self.buffer[Self::LEN_BYTE_0_POSITION],
-            self.buffer[Self::LEN_BYTE_1_POSITION],
-        ])
+    /// Apply sequence policy to received answer of command `seq`.
+    fn check_sequence(&mut self, seq: u8) -> Result<(), errors::ErrorKind> {
+        let found = self.message.get_sequence();
+        if seq != found && self.sequence == SequencePolicy::Warn {
+            self.mismatches += 1;
+        }
+        self.sequence.check(seq, found)
     }
 
-    /// Return ending index at where message ends. That is index including.
-    fn get_end_index(&self) -> usize {
-        Self::BODY_START_POSITION + self.get_body_size() as usize
+    fn write_message(&mut self, seq: u8, body: &[u8]) -> Result<(), errors::ErrorKind> {
+        self.message.set(seq, body)?;
+        let port = self.port.get_mut();
+        port.write_all(self.message.as_slice())?;
+        port.flush()?;
+        Ok(())
     }
 
-    fn body_slice(&self) -> &[u8] {
-        &self.buffer[Self::BODY_START_POSITION..self.get_end_index()]
+    fn read_message(&mut self) -> Result<(), errors::ErrorKind> {
+        self.message.read_from(&mut self.port)
     }
 
-    /// Return whole message as slice.
-    fn as_slice(&self) -> &[u8] {
-        &self.buffer[..=self.get_end_index()]
+    /// Send `body` and receive its answer into `message`.
+    fn exchange(&mut self, body: &[u8]) -> Result<(), errors::ErrorKind> {
+        // This will always succeed
+        let seq = self.sequencer.next().unwrap();
+        self.write_message(seq, body)?;
+        self.read_message()?;
+        self.check_sequence(seq)
     }
 
-    /// Calculate checksum (XOR of all bytes)
-    fn calc_checksum(bytes: &[u8]) -> u8 {
-        let mut result = bytes[0];
-        for byte in bytes.iter().skip(1) {
-            result ^= byte;
+    /// Reopen port once programmer is back and restore its state. Gives up after
+    /// [`LinkOptions::reconnect`].
+    fn reopen(&mut self) -> Result<(), errors::ErrorKind> {
+        let reconnect = self.reconnect.take().expect("reconnect is enabled");
+        let deadline = Instant::now() + reconnect.options.reconnect.unwrap_or_default();
+        loop {
+            span!("reconnect", port = reconnect.port.as_str());
+            match Link::connect(&reconnect.port(), &reconnect.options) {
+                Ok(link) => {
+                    *self = Link {
+                        mismatches: self.mismatches + link.mismatches,
+                        ..link
+                    };
+                    break;
+                }
+                Err(err) if Instant::now() >= deadline => {
+                    self.reconnect = Some(reconnect);
+                    return Err(err);
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(100)),
+            }
+        }
+        let commands = reconnect.resume.commands();
+        self.reconnect = Some(reconnect);
+        for body in commands {
+            self.exchange(&body)?;
+            if self.message.body_slice().get(1) != Some(&Status::CmdOk.into()) {
+                return Err(errors::ErrorKind::StatusError);
+            }
         }
-        return result;
+        Ok(())
     }
 }
 
-impl TryFrom<MessageBuffer> for Message {
-    type Error = errors::ErrorKind;
+/// Port failed, e.g. USB serial adapter was unplugged. Programmer which only does not answer
+/// in time is still connected, so reconnecting does not help.
+fn link_lost(err: &errors::ErrorKind) -> bool {
+    match err {
+        errors::ErrorKind::Io(err) => !matches!(
+            err.kind(),
+            std::io::ErrorKind::TimedOut
+                | std::io::ErrorKind::WouldBlock
+                | std::io::ErrorKind::Interrupted
+        ),
+        _ => false,
+    }
+}
 
-    fn try_from(buffer: MessageBuffer) -> Result<Self, Self::Error> {
-        let body_size = u16::from_be_bytes([
-            buffer[Self::LEN_BYTE_0_POSITION],
-            buffer[Self::LEN_BYTE_1_POSITION],
-        ]) as u16;
-        let end_index = Self::BODY_START_POSITION + body_size as usize;
-        let crc: u8 = buffer[end_index];
-        if crc != Self::calc_checksum(&buffer[..end_index]) {
-            return Err(errors::ErrorKind::ChecksumError);
-        } else {
-            Ok(Message { buffer })
+impl Transport for Link {
+    fn transfer(&mut self, body: &[u8]) -> Result<&[u8], errors::ErrorKind> {
+        match self.exchange(body) {
+            // Programmer vanished, e.g. USB re-enumeration. Repeat command once it is back.
+            Err(err) if link_lost(&err) && self.reconnect.is_some() => {
+                self.reopen()?;
+                self.exchange(body)?;
+            }
+            result => result?,
+        }
+        let answer = self.message.body_slice();
+        if let Some(reconnect) = &mut self.reconnect {
+            if answer.get(1) == Some(&Status::CmdOk.into()) {
+                reconnect.resume.track(body);
+            }
         }
+        Ok(self.message.body_slice())
+    }
+
+    fn sign_on(&mut self) -> Result<programmer::Variant, errors::ErrorKind> {
+        sign_on(self)
+    }
+
+    fn mismatches(&self) -> usize {
+        self.mismatches
     }
 }
 
-fn to_hex(slice: &[u8]) -> String {
-    let mut hexes: Vec<String> = Vec::with_capacity(slice.len());
-    for i in slice {
-        hexes.push(format!("{:#04x}", i));
+/// USB programmers (AVRISP mkII, STK600) send command bodies in bulk transfers, without framing.
+#[cfg(feature = "usb")]
+pub(crate) struct UsbLink {
+    handle: rusb::DeviceHandle<rusb::GlobalContext>,
+    endpoint_in: u8,
+    endpoint_out: u8,
+    /// Last answer. Reused for every command.
+    answer: Vec<u8>,
+}
+
+#[cfg(feature = "usb")]
+impl UsbLink {
+    const PACKET_SIZE: usize = 64;
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Open first device with given USB ids.
+    pub(crate) fn open(
+        vid: u16,
+        pid: u16,
+        endpoint_in: u8,
+        endpoint_out: u8,
+    ) -> Result<UsbLink, errors::ErrorKind> {
+        span!("connect", vid, pid);
+        let handle = rusb::open_device_with_vid_pid(vid, pid)
+            .ok_or(errors::ErrorKind::Io(std::io::ErrorKind::NotFound.into()))?;
+        handle.claim_interface(0)?;
+        Ok(UsbLink {
+            handle,
+            endpoint_in,
+            endpoint_out,
+            answer: Vec::new(),
+        })
     }
-    return hexes.join(", ");
 }
 
-impl fmt::Display for Message {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "sequence_number={} body_length={} body=[{}]",
-            self.get_sequence(),
-            self.body_slice().len(),
-            to_hex(self.body_slice()),
-        )
+#[cfg(feature = "usb")]
+impl Transport for UsbLink {
+    fn transfer(&mut self, body: &[u8]) -> Result<&[u8], errors::ErrorKind> {
+        self.handle
+            .write_bulk(self.endpoint_out, body, Self::TIMEOUT)?;
+        // Transfer ends with short packet. Send empty one if needed.
+        if body.len().is_multiple_of(Self::PACKET_SIZE) {
+            self.handle
+                .write_bulk(self.endpoint_out, &[], Self::TIMEOUT)?;
+        }
+        self.answer.clear();
+        loop {
+            let mut packet = [0; Self::PACKET_SIZE];
+            let size = self
+                .handle
+                .read_bulk(self.endpoint_in, &mut packet, Self::TIMEOUT)?;
+            self.answer.extend_from_slice(&packet[..size]);
+            if size < Self::PACKET_SIZE {
+                return Ok(&self.answer);
+            }
+        }
+    }
+
+    fn sign_on(&mut self) -> Result<programmer::Variant, errors::ErrorKind> {
+        sign_on(self)
+    }
+}
+
+/// Carries STK500v2 command bodies to programmer. Framing depends on programmer.
+pub(crate) trait Transport: Send {
+    /// Send command body and return answer body.
+    fn transfer(&mut self, body: &[u8]) -> Result<&[u8], errors::ErrorKind>;
+
+    /// Programmer variant reported on sign on.
+    fn sign_on(&mut self) -> Result<programmer::Variant, errors::ErrorKind>;
+
+    /// Answers accepted with wrong sequence number. Only serial framing has them.
+    fn mismatches(&self) -> usize {
+        0
     }
 }
 
-/// Incremented by one for each message sent.
-/// Wraps to zero after 0xFF is reached.
-struct SequenceGenerator {
-    count: u8,
+/// Read programmer variant with STK500v2 sign on command.
+fn sign_on(transport: &mut dyn Transport) -> Result<programmer::Variant, errors::ErrorKind> {
+    signature(command(transport, &[command::Normal::SignOn.into()])?)
+}
+
+/// Programmer variant from sign on answer.
+fn signature(answer: &[u8]) -> Result<programmer::Variant, errors::ErrorKind> {
+    // Name is preceded by its length. Clones may send less than they announce.
+    let size = answer_data(answer, 1)?[0] as usize;
+    let name = &answer[3..];
+    Ok(programmer::Variant::from(&name[..size.min(name.len())]))
 }
 
-impl SequenceGenerator {
-    fn new() -> SequenceGenerator {
-        SequenceGenerator { count: 0 }
+/// Send command and check that answer belongs to it and reports success.
+fn command<'a>(
+    transport: &'a mut dyn Transport,
+    body: &[u8],
+) -> Result<&'a [u8], errors::ErrorKind> {
+    let answer = answer(transport, body)?;
+    if answer[1] != Status::CmdOk.into() {
+        return Err(errors::ErrorKind::StatusError {});
+    }
+    Ok(answer)
+}
+
+/// Send `body` and return answer to it, holding command id and status. Status is not checked.
+fn answer<'a>(
+    transport: &'a mut dyn Transport,
+    body: &[u8],
+) -> Result<&'a [u8], errors::ErrorKind> {
+    let cmd = body[0];
+    span!("command", id = cmd);
+    let start = Instant::now();
+    let answer = transport.transfer(body).map_err(|err| match err {
+        errors::ErrorKind::Io(err) if err.kind() == std::io::ErrorKind::TimedOut => {
+            errors::ErrorKind::Timeout {
+                operation: format!("command {:#04x}", cmd),
+                elapsed: start.elapsed(),
+            }
+        }
+        err => err,
+    })?;
+    // Command id and status.
+    if answer.len() < 2 {
+        return Err(errors::ErrorKind::AnswerLength {
+            expected: 2,
+            found: answer.len(),
+        });
     }
+    if cmd != answer[0] {
+        return Err(errors::ErrorKind::AnswerIdError {});
+    }
+    Ok(answer)
 }
 
-impl Iterator for SequenceGenerator {
-    type Item = u8;
+/// Check `status` of ProgramFlash or ProgramEeprom. Poll timeouts mean target did not finish
+/// writing the page in time, other failures are passed on as `StatusError`.
+fn check_page_written(
+    status: u8,
+    memory: MemoryType,
+    address: usize,
+    page_size: usize,
+) -> Result<(), errors::ErrorKind> {
+    match status {
+        status if status == Status::CmdOk.into() => Ok(()),
+        status if status == Status::RdyBsyTout.into() || status == Status::CmdTimeout.into() => {
+            Err(errors::ErrorKind::PageWriteFailed {
+                memory,
+                address,
+                page: address / page_size,
+            })
+        }
+        _ => Err(errors::ErrorKind::StatusError),
+    }
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let ret = Some(self.count);
-        self.count = self.count.wrapping_add(1);
-        ret
+/// Name page which was in flight when programmer stopped answering.
+fn page_error(err: errors::ErrorKind, memory: MemoryType, address: usize) -> errors::ErrorKind {
+    match err {
+        errors::ErrorKind::Timeout { elapsed, .. } => errors::ErrorKind::Timeout {
+            operation: format!("{} page at {:#06x}", memory, address),
+            elapsed,
+        },
+        err => err,
     }
 }
 
+/// `size` data bytes following command id and status in `answer`.
+fn answer_data(answer: &[u8], size: usize) -> Result<&[u8], errors::ErrorKind> {
+    answer
+        .get(2..2 + size)
+        .ok_or(errors::ErrorKind::AnswerLength {
+            expected: 2 + size,
+            found: answer.len(),
+        })
+}
+
+/// Check if there is a STK500v2 compatible programmer at given port.
+///
+/// Returns programmer variant as reported by sign on command.
+pub fn probe(port: &str) -> Result<programmer::Variant, errors::ErrorKind> {
+    probe_with(port, &LinkOptions::default())
+}
+
+/// Same as [`probe`] with non default link settings.
+pub fn probe_with(
+    port: &str,
+    options: &LinkOptions,
+) -> Result<programmer::Variant, errors::ErrorKind> {
+    let mut link = Link::open(port, options)?;
+    link.sign_on()
+}
+
 pub struct STK500v2 {
-    port: serial::SystemPort,
-    sequencer: SequenceGenerator,
+    link: Box<dyn Transport>,
     specs: specs::Specs,
+    counters: programmer::Counters,
+    listener: Option<Box<dyn Listener + Send>>,
 }
 
-const SERIAL_SETTINGS: PortSettings = PortSettings {
-    baud_rate: serial::Baud115200,
-    char_size: serial::Bits8,
-    parity: serial::ParityNone,
-    stop_bits: serial::Stop1,
-    // Must be set to none.
-    // Otherwise programmer may hang at random command.
-    flow_control: serial::FlowNone,
-};
-
 impl STK500v2 {
-    pub fn open(port: &String, specs: specs::Specs) -> Result<STK500v2, Error> {
-        let mut port = serial::open(&port)?;
-        port.configure(&SERIAL_SETTINGS)?;
-        port.set_timeout(Duration::from_secs(1))?;
-        Ok(STK500v2 {
-            port,
-            sequencer: SequenceGenerator::new(),
-            specs,
-        })
+    pub fn open(port: &str, specs: specs::Specs) -> Result<STK500v2, errors::ErrorKind> {
+        STK500v2::open_with(port, &LinkOptions::default(), specs)
     }
 
-    fn write_message(&mut self, msg: Message) -> Result<(), errors::ErrorKind> {
-        self.port.write_all(msg.as_slice())?;
-        self.port.flush()?;
-        return Ok(());
+    /// Open serial programmer with non default link settings.
+    pub fn open_with(
+        port: &str,
+        options: &LinkOptions,
+        specs: specs::Specs,
+    ) -> Result<STK500v2, errors::ErrorKind> {
+        Ok(STK500v2::new(Box::new(Link::open(port, options)?), specs))
     }
 
-    fn read_message(&mut self) -> Result<Message, errors::ErrorKind> {
-        let mut buffer: MessageBuffer = [0; Message::MAX_SIZE];
-        self.port.read_exact(&mut buffer[..Message::HEADER_SIZE])?;
-        let body_size = u16::from_be_bytes([
-            buffer[Message::LEN_BYTE_0_POSITION],
-            buffer[Message::LEN_BYTE_1_POSITION],
-        ]) as usize;
-        let end = Message::HEADER_SIZE + body_size + Message::CHECKSUM_SIZE;
-        self.port
-            .read_exact(&mut buffer[Message::BODY_START_POSITION..end])?;
-        let msg = Message::try_from(buffer)?;
-        return Ok(msg);
+    /// Open first AVRISP mkII found on USB.
+    #[cfg(feature = "usb")]
+    pub fn open_usb(specs: specs::Specs) -> Result<STK500v2, errors::ErrorKind> {
+        let link = UsbLink::open(0x03eb, 0x2104, 0x82, 0x02)?;
+        Ok(STK500v2::new(Box::new(link), specs))
     }
 
-    fn command(&mut self, body: Vec<u8>) -> Result<Message, errors::ErrorKind> {
-        // This will always succeed
-        let seq = self.sequencer.next().unwrap();
-        let cmd = body[0];
-        let sent_msg = Message::new(seq, body);
-        self.write_message(sent_msg)?;
-        let read_msg = self.read_message()?;
-
-        if seq != read_msg.get_sequence() {
-            return Err(errors::ErrorKind::SequenceError {});
+    /// Programmer speaking STK500v2 commands over other framing.
+    pub(crate) fn new(link: Box<dyn Transport>, specs: specs::Specs) -> STK500v2 {
+        STK500v2 {
+            link,
+            specs,
+            counters: programmer::Counters::default(),
+            listener: None,
         }
-        if cmd != read_msg.body_slice()[0] {
-            return Err(errors::ErrorKind::AnswerIdError {});
+    }
+
+    /// Report progress to `listener`, starting with `Connected`.
+    pub fn set_listener(&mut self, listener: Box<dyn Listener + Send>) {
+        self.listener = Some(listener);
+        self.emit(Event::Connected);
+    }
+
+    fn emit(&mut self, event: Event) {
+        if let Some(listener) = &mut self.listener {
+            listener.event(&event);
         }
-        if read_msg.body_slice()[1] != Status::CmdOk.into() {
-            return Err(errors::ErrorKind::StatusError {});
+    }
+
+    /// Report pages of `size` bytes read at `address`.
+    fn pages_read(&mut self, memory: MemoryType, page_size: usize, address: usize, size: usize) {
+        // Blocks may hold part of a page. Event is sent for page starting in block.
+        let first = address.div_ceil(page_size) * page_size;
+        for page in (first..address + size).step_by(page_size) {
+            self.emit(Event::PageRead {
+                memory,
+                address: page,
+            });
         }
-        Ok(read_msg)
+    }
+
+    fn command(&mut self, body: &[u8]) -> Result<&[u8], errors::ErrorKind> {
+        self.counters.frames += 1;
+        command(self.link.as_mut(), body)
+    }
+
+    /// Send command and return its answer without checking status.
+    fn answer(&mut self, body: &[u8]) -> Result<&[u8], errors::ErrorKind> {
+        self.counters.frames += 1;
+        answer(self.link.as_mut(), body)
+    }
+
+    /// Send command which answers with `size` data bytes.
+    fn read_command(&mut self, body: &[u8], size: usize) -> Result<&[u8], errors::ErrorKind> {
+        answer_data(self.command(body)?, size)
     }
 
     fn set_param<T>(&mut self, param: T, value: u8) -> Result<(), errors::ErrorKind>
     where
         T: param::Writable + Into<u8>,
     {
-        let bytes = vec![command::Normal::SetParameter.into(), param.into(), value];
-        let msg = self.command(bytes)?;
-        if msg.body_slice()[0] != command::Normal::SetParameter.into() {
-            return Err(errors::ErrorKind::AnswerIdError {});
-        }
-        if msg.body_slice()[1] != Status::CmdOk.into() {
-            return Err(errors::ErrorKind::StatusError {});
-        }
+        let bytes = [command::Normal::SetParameter.into(), param.into(), value];
+        self.command(&bytes)?;
         Ok(())
     }
 
-    fn get_param<T>(&mut self, param: T) -> Result<u8, errors::ErrorKind>
+    pub(crate) fn get_param<T>(&mut self, param: T) -> Result<u8, errors::ErrorKind>
     where
         T: param::Readable + Into<u8>,
     {
-        let bytes: Vec<u8> = vec![command::Normal::GetParameter.into(), param.into()];
-        let msg = self.command(bytes)?;
-        if msg.body_slice()[0] != command::Normal::GetParameter.into() {
-            return Err(errors::ErrorKind::AnswerIdError {});
-        }
-        if msg.body_slice()[1] != Status::CmdOk.into() {
-            return Err(errors::ErrorKind::StatusError {});
-        }
-        // return parameter
-        Ok(msg.body_slice()[2])
+        let bytes = [command::Normal::GetParameter.into(), param.into()];
+        Ok(self.read_command(&bytes, 1)?[0])
+    }
+
+    pub(crate) fn set_param2(
+        &mut self,
+        param: param::RW2,
+        value: u16,
+    ) -> Result<(), errors::ErrorKind> {
+        let value = value.to_be_bytes();
+        let bytes = [
+            command::Normal::SetParameter.into(),
+            param.into(),
+            value[0],
+            value[1],
+        ];
+        self.command(&bytes)?;
+        Ok(())
+    }
+
+    pub(crate) fn get_param2(&mut self, param: param::RW2) -> Result<u16, errors::ErrorKind> {
+        let bytes = [command::Normal::GetParameter.into(), param.into()];
+        let value = self.read_command(&bytes, 2)?;
+        Ok(u16::from_be_bytes([value[0], value[1]]))
     }
 
     pub fn read_programmer_signature(&mut self) -> Result<programmer::Variant, errors::ErrorKind> {
-        let msg = self.command(vec![command::Normal::SignOn.into()])?;
-        let variant = String::from_utf8(msg.body_slice()[3..].to_vec())?;
-        Ok(programmer::Variant::try_from(variant)?)
+        self.link.sign_on()
+    }
+
+    /// Read firmware version.
+    pub fn get_sw_version(&mut self) -> Result<SwVersion, errors::ErrorKind> {
+        Ok(SwVersion {
+            major: self.get_param(param::RO::SwMajor)?,
+            minor: self.get_param(param::RO::SwMinor)?,
+        })
+    }
+
+    /// Parameter which not every programmer has. `None` if it is refused.
+    fn optional_param<T>(&mut self, param: T) -> Result<Option<u8>, errors::ErrorKind>
+    where
+        T: param::Readable + Into<u8>,
+    {
+        match self.get_param(param) {
+            Ok(value) => Ok(Some(value)),
+            Err(errors::ErrorKind::StatusError) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Read every readable parameter. For diagnosing misbehaving programmer.
+    pub fn dump_parameters(&mut self) -> Result<Parameters, errors::ErrorKind> {
+        let build = match (
+            self.optional_param(param::RO::BuildNumberLow)?,
+            self.optional_param(param::RO::BuildNumberHigh)?,
+        ) {
+            (Some(low), Some(high)) => Some(u16::from_le_bytes([low, high])),
+            _ => None,
+        };
+        let oscillator = match (
+            self.optional_param(param::RW::OScPscale)?,
+            self.optional_param(param::RW::OscCmatch)?,
+        ) {
+            (Some(pscale), Some(cmatch)) => Some((pscale, cmatch)),
+            _ => None,
+        };
+        Ok(Parameters {
+            hardware: self.get_hw_version()?,
+            firmware: self.get_sw_version()?,
+            build,
+            vtarget: self.optional_param(param::RW::Vtarget)?,
+            vadjust: self.optional_param(param::RW::Vadjust)?,
+            oscillator,
+            sck_duration: self.get_sck_duration()?,
+            topcard: self.optional_param(param::RO::TopcardDetect)?,
+            status: self.optional_param(param::RO::Status)?,
+            reset_polarity: self.optional_param(param::RW::ResetPolarity)?,
+        })
+    }
+
+    /// Read hardware version.
+    pub fn get_hw_version(&mut self) -> Result<u8, errors::ErrorKind> {
+        self.get_param(param::RO::HwVer)
+    }
+
+    /// Read SCK period setting. Larger is slower, see [`sck_frequency`].
+    pub fn get_sck_duration(&mut self) -> Result<u8, errors::ErrorKind> {
+        self.get_param(param::RW::SckDuration)
+    }
+
+    pub fn set_sck_duration(&mut self, duration: u8) -> Result<(), errors::ErrorKind> {
+        self.set_param(param::RW::SckDuration, duration)
+    }
+
+    /// Start STK500 clock output at about `frequency` Hz, e.g. to clock a target fused for
+    /// external clock. 0 stops it. Returns frequency which was set.
+    pub fn set_oscillator(&mut self, frequency: u32) -> Result<u32, errors::ErrorKind> {
+        let (pscale, cmatch) = match frequency {
+            0 => (0, 0),
+            _ => oscillator_setting(frequency).ok_or_else(|| {
+                errors::ErrorKind::Unsupported(format!(
+                    "clock output of {} Hz. Use 15 Hz to 3686400 Hz",
+                    frequency
+                ))
+            })?,
+        };
+        self.set_param(param::RW::OscCmatch, cmatch)?;
+        self.set_param(param::RW::OScPscale, pscale)?;
+        Ok(oscillator_frequency(pscale, cmatch))
     }
 }
 
-impl TryInto<IspMode> for STK500v2 {
-    type Error = errors::ErrorKind;
-    fn try_into(mut self) -> Result<IspMode, Self::Error> {
-        let bytes = vec![
+impl STK500v2 {
+    /// Hold target in reset by entering programming mode.
+    ///
+    /// Reset stays active, also after port is closed, until [`STK500v2::release_reset`] is called.
+    /// Fails if target does not answer.
+    pub fn hold_reset(&mut self) -> Result<(), errors::ErrorKind> {
+        let bytes = [
             command::Normal::EnterIspMode.into(),
             self.specs.timeout,
             self.specs.stab_delay,
@@ -401,25 +1237,505 @@ impl TryInto<IspMode> for STK500v2 {
             isp_command::PROGRAMMING_ENABLE.3,
         ];
         self.set_param(param::RW::ResetPolarity, self.specs.reset_polarity.into())?;
-        self.command(bytes)?;
-        Ok(IspMode::new(self))
+        self.command(&bytes)?;
+        Ok(())
     }
-}
-
-pub struct IspMode {
-    prog: STK500v2,
-}
 
-impl IspMode {
-    fn new(prog: STK500v2) -> IspMode {
-        IspMode { prog }
+    /// Release target reset by leaving programming mode. Target starts running.
+    pub fn release_reset(&mut self) -> Result<(), errors::ErrorKind> {
+        let bytes = [
+            command::Normal::LeaveIspMode.into(),
+            self.specs.pre_delay,
+            self.specs.post_delay,
+        ];
+        self.command(&bytes)?;
+        Ok(())
     }
 
-    fn load_address(&mut self, address: usize) -> Result<(), errors::ErrorKind> {
-        let mut dst_addr = vec![command::Normal::LoadAddress.into()];
-        dst_addr.extend((address as u32).to_be_bytes().to_vec());
-        self.prog.command(dst_addr)?;
-        Ok(())
+    /// Restart target.
+    pub fn reset(&mut self) -> Result<(), errors::ErrorKind> {
+        self.hold_reset()?;
+        self.release_reset()
+    }
+}
+
+/// XPROG commands, used for PDI and TPI programming by AVRISP mkII and STK600.
+///
+/// Described in AVR069 application note.
+pub mod xprog {
+    pub enum Mode {
+        Pdi = 0,
+        Jtag = 1,
+        Tpi = 2,
+    }
+
+    #[allow(dead_code)]
+    pub(super) enum Command {
+        EnterProgmode = 0x01,
+        LeaveProgmode = 0x02,
+        Erase = 0x03,
+        WriteMem = 0x04,
+        ReadMem = 0x05,
+        Crc = 0x06,
+        SetParam = 0x07,
+    }
+
+    #[derive(Clone, Copy)]
+    pub enum Memory {
+        Application = 1,
+        Boot = 2,
+        Eeprom = 3,
+        Fuse = 4,
+        LockBits = 5,
+        UserSignature = 6,
+        FactoryCalibration = 7,
+    }
+
+    pub enum Erase {
+        Chip = 1,
+        Application = 2,
+        Boot = 3,
+        Eeprom = 4,
+        ApplicationPage = 5,
+        BootPage = 6,
+        EepromPage = 7,
+        UserSignature = 8,
+        /// TPI configuration section, i.e. fuses.
+        Config = 9,
+    }
+
+    /// Parameter with its value.
+    pub enum Param {
+        /// Base address of NVM controller (PDI).
+        NvmBase(u32),
+        EepromPageSize(u16),
+        /// NVMCMD register address (TPI).
+        NvmCmdRegister(u8),
+        /// NVMCSR register address (TPI).
+        NvmCsrRegister(u8),
+    }
+
+    impl Param {
+        pub(super) fn to_bytes(&self) -> Vec<u8> {
+            match self {
+                Param::NvmBase(value) => [&[1][..], &value.to_be_bytes()].concat(),
+                Param::EepromPageSize(value) => [&[2][..], &value.to_be_bytes()].concat(),
+                Param::NvmCmdRegister(value) => vec![3, *value],
+                Param::NvmCsrRegister(value) => vec![4, *value],
+            }
+        }
+    }
+
+    /// Bit 0 of write mode. Erase page before writing.
+    pub const PAGE_ERASE: u8 = 0x01;
+    /// Bit 1 of write mode. Write page after loading data.
+    pub const PAGE_WRITE: u8 = 0x02;
+}
+
+impl STK500v2 {
+    /// Select protocol used by following XPROG commands. Fails if programmer does not have it.
+    pub fn xprog_set_mode(&mut self, mode: xprog::Mode) -> Result<(), errors::ErrorKind> {
+        let variant = self.read_programmer_signature()?;
+        let capabilities = variant.capabilities();
+        let (supported, name) = match mode {
+            xprog::Mode::Pdi => (capabilities.pdi, "PDI"),
+            xprog::Mode::Tpi => (capabilities.tpi, "TPI"),
+            xprog::Mode::Jtag => (true, "JTAG"),
+        };
+        if !supported {
+            return Err(errors::ErrorKind::Unsupported(format!(
+                "{} programming with {}",
+                name, variant
+            )));
+        }
+        self.command(&[command::Normal::XprogSetMode.into(), mode as u8])?;
+        Ok(())
+    }
+
+    /// Send XPROG command. Answer is command, XPROG command, status and data.
+    fn xprog(&mut self, cmd: xprog::Command, args: &[u8]) -> Result<Vec<u8>, errors::ErrorKind> {
+        let end = 2 + args.len();
+        check_body_size(end)?;
+        let mut body = [0; Message::MAX_BODY_SIZE];
+        body[0] = command::Normal::Xprog.into();
+        body[1] = cmd as u8;
+        let cmd = body[1];
+        body[2..end].copy_from_slice(args);
+        let answer = self.answer(&body[..end])?;
+        // Command, XPROG command and status.
+        if answer.len() < 3 {
+            return Err(errors::ErrorKind::AnswerLength {
+                expected: 3,
+                found: answer.len(),
+            });
+        }
+        if answer[1] != cmd {
+            return Err(errors::ErrorKind::AnswerIdError);
+        }
+        if answer[2] != 0 {
+            return Err(errors::ErrorKind::StatusError);
+        }
+        Ok(answer[3..].to_vec())
+    }
+
+    pub fn xprog_enter(&mut self) -> Result<(), errors::ErrorKind> {
+        self.xprog(xprog::Command::EnterProgmode, &[])?;
+        Ok(())
+    }
+
+    pub fn xprog_leave(&mut self) -> Result<(), errors::ErrorKind> {
+        self.xprog(xprog::Command::LeaveProgmode, &[])?;
+        Ok(())
+    }
+
+    pub fn xprog_set_param(&mut self, param: xprog::Param) -> Result<(), errors::ErrorKind> {
+        self.xprog(xprog::Command::SetParam, &param.to_bytes())?;
+        Ok(())
+    }
+
+    /// Erase memory. `address` selects page or section, depending on erase type.
+    pub fn xprog_erase(
+        &mut self,
+        erase: xprog::Erase,
+        address: u32,
+    ) -> Result<(), errors::ErrorKind> {
+        let mut args = vec![erase as u8];
+        args.extend_from_slice(&address.to_be_bytes());
+        self.xprog(xprog::Command::Erase, &args)?;
+        Ok(())
+    }
+
+    /// Read `buffer.len()` bytes of memory starting at `address`.
+    pub fn xprog_read(
+        &mut self,
+        memory: xprog::Memory,
+        address: u32,
+        buffer: &mut [u8],
+    ) -> Result<(), errors::ErrorKind> {
+        let mut args = vec![memory as u8];
+        args.extend_from_slice(&address.to_be_bytes());
+        args.extend_from_slice(&(buffer.len() as u16).to_be_bytes());
+        let data = self.xprog(xprog::Command::ReadMem, &args)?;
+        if data.len() < buffer.len() {
+            return Err(errors::ErrorKind::AnswerLength {
+                expected: buffer.len(),
+                found: data.len(),
+            });
+        }
+        buffer.copy_from_slice(&data[..buffer.len()]);
+        Ok(())
+    }
+
+    /// Write `data` at `address`. `mode` is a combination of [`xprog::PAGE_ERASE`] and
+    /// [`xprog::PAGE_WRITE`].
+    pub fn xprog_write(
+        &mut self,
+        memory: xprog::Memory,
+        mode: u8,
+        address: u32,
+        data: &[u8],
+    ) -> Result<(), errors::ErrorKind> {
+        let mut args = vec![memory as u8, mode];
+        args.extend_from_slice(&address.to_be_bytes());
+        args.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        args.extend_from_slice(data);
+        self.xprog(xprog::Command::WriteMem, &args)?;
+        Ok(())
+    }
+}
+
+/// Signature reads done at one `SckDuration` by [`sweep_sck`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct SckTrial {
+    pub duration: u8,
+    pub reads: usize,
+    /// Reads which returned signature of the chip.
+    pub matched: usize,
+}
+
+impl SckTrial {
+    /// Every read returned signature of the chip.
+    pub fn reliable(&self) -> bool {
+        self.reads > 0 && self.matched == self.reads
+    }
+}
+
+/// Enter programming mode and read signature `reads` times at each of `durations`. SCK setting
+/// of the programmer is restored afterwards, also when a trial failed.
+///
+/// With `durations` ordered from fast to slow (e.g. [`SWEEP_SCK`]) the first reliable trial is
+/// the fastest SCK target can be programmed with.
+pub fn sweep_sck(
+    prog: STK500v2,
+    durations: &[u8],
+    reads: usize,
+) -> Result<Vec<SckTrial>, errors::ErrorKind> {
+    let mut isp = IspMode::new(prog);
+    let initial = isp.prog.get_sck_duration()?;
+    let trials = sck_trials(&mut isp, durations, reads);
+    // Error of the trials comes first, restoring likely failed because of it.
+    let restored = isp.prog.set_sck_duration(initial);
+    let trials = trials?;
+    restored?;
+    Ok(trials)
+}
+
+/// Trials of [`sweep_sck`]. SCK is left at the last duration.
+fn sck_trials(
+    isp: &mut IspMode,
+    durations: &[u8],
+    reads: usize,
+) -> Result<Vec<SckTrial>, errors::ErrorKind> {
+    let mut trials = Vec::new();
+    for &duration in durations {
+        isp.prog.set_sck_duration(duration)?;
+        let mut matched = 0;
+        for _ in 0..reads {
+            let signature = isp
+                .prog
+                .hold_reset()
+                .and_then(|_| programmer::MCUSignature::get_mcu_signature(isp));
+            match signature {
+                Ok(signature) if signature.matches(&isp.prog.specs) => matched += 1,
+                // Lost programmer, not a slow target.
+                Err(err @ errors::ErrorKind::Io(_))
+                | Err(err @ errors::ErrorKind::Timeout { .. }) => return Err(err),
+                _ => (),
+            }
+            isp.prog.release_reset()?;
+        }
+        trials.push(SckTrial {
+            duration,
+            reads,
+            matched,
+        });
+    }
+    Ok(trials)
+}
+
+fn describe_sck(duration: u8) -> String {
+    format!(
+        "SCK duration {} (about {} kHz on STK500)",
+        duration,
+        sck_frequency(duration) / 1000
+    )
+}
+
+/// Fails if `tenths` of volt measured on target are outside supply range of `specs`.
+fn check_supply(specs: &specs::Specs, tenths: u8) -> Result<(), errors::ErrorKind> {
+    let (min, max) = specs.supply;
+    if tenths.saturating_add(VTARGET_MARGIN) < min || tenths > max.saturating_add(VTARGET_MARGIN) {
+        return Err(errors::ErrorKind::TargetVoltage { tenths, min, max });
+    }
+    Ok(())
+}
+
+/// Enter programming mode at `duration` SCK, trying `attempts` times until target answers with a
+/// valid signature. For targets with slow or just started clock, e.g. one given by
+/// [`STK500v2::set_oscillator`].
+///
+/// Programming mode is returned also when target never answered, signature tells then.
+pub fn enter_slow(
+    prog: STK500v2,
+    duration: u8,
+    attempts: usize,
+) -> Result<IspMode, errors::ErrorKind> {
+    let mut isp = IspMode::new(prog);
+    isp.check_vtarget()?;
+    let initial = isp.prog.get_sck_duration()?;
+    isp.prog.set_sck_duration(duration)?;
+    isp.initial_sck = Some(initial);
+    let mut entered = isp.try_enter();
+    for _ in 1..attempts {
+        match entered {
+            Ok(ref signature) if answered(signature) => break,
+            // Lost programmer, not a slow target.
+            Err(errors::ErrorKind::Io(_)) | Err(errors::ErrorKind::Timeout { .. }) => break,
+            Ok(_) => isp.prog.release_reset()?,
+            Err(_) => (),
+        }
+        entered = isp.try_enter();
+    }
+    match entered {
+        Ok(_) => {
+            isp.entered();
+            Ok(isp)
+        }
+        Err(err) => {
+            // Keep reported error, restoring SCK is best effort.
+            let _ = isp.prog.set_sck_duration(initial);
+            Err(err)
+        }
+    }
+}
+
+/// Target answered programming enable. Without answer signature reads as all zeros or ones.
+fn answered(signature: &specs::Signature) -> bool {
+    signature.bytes != (0, 0, 0) && signature.bytes != (0xff, 0xff, 0xff)
+}
+
+impl TryInto<IspMode> for STK500v2 {
+    type Error = errors::ErrorKind;
+    fn try_into(self) -> Result<IspMode, Self::Error> {
+        let mut isp = IspMode::new(self);
+        isp.enter()?;
+        Ok(isp)
+    }
+}
+
+pub struct IspMode {
+    prog: STK500v2,
+    /// `SckDuration` set before it was slowed down or adapted to target clock. Restored on close.
+    initial_sck: Option<u8>,
+    /// Bytes read with one ReadEeprom command. Page size once firmware refused more.
+    eeprom_block: usize,
+}
+
+impl IspMode {
+    fn new(prog: STK500v2) -> IspMode {
+        let eeprom_block = read_block(prog.specs.eeprom.page_size);
+        IspMode {
+            prog,
+            initial_sck: None,
+            eeprom_block,
+        }
+    }
+
+    /// Access programmer. Parameters can be read and written in ISP mode.
+    pub fn programmer(&mut self) -> &mut STK500v2 {
+        &mut self.prog
+    }
+
+    /// Leave programming mode and hand back the programmer. Slowed down SCK is restored.
+    ///
+    /// Parameters can be read then, or programming mode entered again, e.g. for another target.
+    pub fn leave(mut self) -> Result<STK500v2, errors::ErrorKind> {
+        self.prog.release_reset()?;
+        if let Some(duration) = self.initial_sck {
+            self.prog.set_sck_duration(duration)?;
+        }
+        Ok(self.prog)
+    }
+
+    /// `SckDuration` target answered at, if SCK had to be slowed down.
+    pub fn slowed_sck(&mut self) -> Result<Option<u8>, errors::ErrorKind> {
+        match self.initial_sck {
+            Some(_) => Ok(Some(self.prog.get_sck_duration()?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Set SCK to the fastest below a quarter of target clock given by fuses. Returns duration
+    /// set, None if clock is not known (crystal or external clock) or programmer is not STK500.
+    /// Other programmers have their own `SckDuration` tables.
+    pub fn adapt_sck(&mut self) -> Result<Option<u8>, errors::ErrorKind> {
+        if self.prog.read_programmer_signature()? != programmer::Variant::STK500_V2 {
+            return Ok(None);
+        }
+        let fuses = programmer::AVRFuseGet::get_fuses(self)?;
+        let clock = match fuses.clock(&self.prog.specs) {
+            Some(clock) => clock,
+            None => return Ok(None),
+        };
+        let duration = sck_below(clock, 4);
+        let current = self.prog.get_sck_duration()?;
+        if duration != current {
+            self.prog.set_sck_duration(duration)?;
+            self.initial_sck.get_or_insert(current);
+        }
+        Ok(Some(duration))
+    }
+
+    /// Enter programming mode. Chips running from slow clock, e.g. factory fused 1 MHz, do not
+    /// answer or read signature as all zeros or ones. SCK is slowed down step by step then.
+    ///
+    /// Gives up with [`errors::ErrorKind::SignatureMismatch`] if target does not answer even at
+    /// the slowest SCK. SCK is restored then.
+    fn enter(&mut self) -> Result<(), errors::ErrorKind> {
+        span!("enter_isp", chip = self.prog.specs.name);
+        self.check_vtarget()?;
+        let entered = self.try_enter();
+        let entered = match entered {
+            Ok(ref signature) if answered(signature) => entered,
+            // Not every programmer can change SCK.
+            _ => match self.prog.get_sck_duration() {
+                Ok(initial) => {
+                    let slowed = self.slow_down(initial, entered);
+                    if !matches!(slowed, Ok(ref signature) if answered(signature)) {
+                        // Keep reported error, restoring SCK is best effort.
+                        let _ = self.prog.set_sck_duration(initial);
+                        self.initial_sck = None;
+                    }
+                    slowed
+                }
+                Err(_) => entered,
+            },
+        };
+        let signature = entered?;
+        if !answered(&signature) {
+            return Err(errors::ErrorKind::SignatureMismatch {
+                expected: self.prog.specs.signature.clone(),
+                found: signature,
+            });
+        }
+        self.entered();
+        Ok(())
+    }
+
+    /// Retry entering programming mode at SCK slower than `initial`, until target answers.
+    /// Returns result of the last attempt.
+    fn slow_down(
+        &mut self,
+        initial: u8,
+        mut entered: Result<specs::Signature, errors::ErrorKind>,
+    ) -> Result<specs::Signature, errors::ErrorKind> {
+        for duration in SLOWER_SCK.iter().filter(|duration| **duration > initial) {
+            if entered.is_ok() {
+                self.prog.release_reset()?;
+            }
+            self.prog.set_sck_duration(*duration)?;
+            self.initial_sck = Some(initial);
+            self.prog.counters.retries += 1;
+            span!(
+                "retry",
+                attempt = self.prog.counters.retries,
+                sck_duration = *duration
+            );
+            let attempt = self.prog.counters.retries;
+            self.prog.emit(Event::Retry { attempt });
+            entered = self.try_enter();
+            if matches!(entered, Ok(ref signature) if answered(signature)) {
+                break;
+            }
+        }
+        entered
+    }
+
+    fn entered(&mut self) {
+        let chip = self.prog.specs.name;
+        self.prog.emit(Event::IspEntered { chip });
+    }
+
+    /// Unpowered target would only fail with timeouts, so tell it before programming mode is
+    /// entered. Programmers which do not measure Vtarget are not checked.
+    fn check_vtarget(&mut self) -> Result<(), errors::ErrorKind> {
+        match self.prog.optional_param(param::RW::Vtarget)? {
+            Some(tenths) => check_supply(&self.prog.specs, tenths),
+            None => Ok(()),
+        }
+    }
+
+    /// Hold reset and read signature.
+    fn try_enter(&mut self) -> Result<specs::Signature, errors::ErrorKind> {
+        self.prog.hold_reset()?;
+        programmer::MCUSignature::get_mcu_signature(self)
+    }
+
+    fn load_address(&mut self, address: usize) -> Result<(), errors::ErrorKind> {
+        let [a3, a2, a1, a0] = (address as u32).to_be_bytes();
+        self.prog
+            .command(&[command::Normal::LoadAddress.into(), a3, a2, a1, a0])?;
+        Ok(())
     }
 
     fn read_flash_command(
@@ -428,15 +1744,17 @@ impl IspMode {
         buffer: &mut [u8],
     ) -> Result<(), errors::ErrorKind> {
         let size_bytes = (size as u16).to_be_bytes();
-        let msg = self.prog.command(vec![
-            command::Isp::ReadFlash.into(),
-            size_bytes[0],
-            size_bytes[1],
-            // Stk500v2 firmware handles selecting low/high byte when reading.
-            isp_command::READ_FLASH_LOW.0,
-        ])?;
-        let data_offset = 2;
-        buffer.copy_from_slice(&msg.body_slice()[data_offset..(size + data_offset)]);
+        let data = self.prog.read_command(
+            &[
+                command::Isp::ReadFlash.into(),
+                size_bytes[0],
+                size_bytes[1],
+                // Stk500v2 firmware handles selecting low/high byte when reading.
+                isp_command::READ_FLASH_LOW.0,
+            ],
+            size,
+        )?;
+        buffer.copy_from_slice(data);
         Ok(())
     }
 
@@ -446,72 +1764,276 @@ impl IspMode {
         buffer: &mut [u8],
     ) -> Result<(), errors::ErrorKind> {
         let size_bytes = (size as u16).to_be_bytes();
-        let msg = self.prog.command(vec![
-            command::Isp::ReadEeprom.into(),
-            size_bytes[0],
-            size_bytes[1],
-            isp_command::READ_EEPROM.0,
-        ])?;
-        let data_offset = 2;
-        buffer.copy_from_slice(&msg.body_slice()[data_offset..(size + data_offset)]);
+        let data = self.prog.read_command(
+            &[
+                command::Isp::ReadEeprom.into(),
+                size_bytes[0],
+                size_bytes[1],
+                isp_command::READ_EEPROM.0,
+            ],
+            size,
+        )?;
+        buffer.copy_from_slice(data);
         Ok(())
     }
 
-    fn read_fuse(&mut self, cmd: isp_command::IspCommand) -> Result<u8, errors::ErrorKind> {
-        let msg = self.prog.command(vec![
-            command::Isp::ReadFuse.into(),
-            self.prog.specs.fuse_poll_index,
-            cmd.0,
-            cmd.1,
-            cmd.2,
-            cmd.3,
-        ])?;
-        Ok(msg.body_slice()[2])
-    }
-}
-
-impl programmer::FlashRead for IspMode {
-    fn read(&mut self, buffer: &mut [u8]) -> Result<(), errors::ErrorKind> {
-        let size = self.prog.specs.flash.page_size;
+    /// Convert flash byte address into address expected by LoadAddress command.
+    fn flash_address(&self, address: usize) -> usize {
         // If device has more than 64K flash, set bit 31.
         //
         // From AVR068:
         // If bit 31 is set, this indicates that the following read/write operation will be performed
         // on a memory that is larger than 64KBytes. This is an indication to STK500 that a load
         // extended address must be executed.
-        let start = if self.prog.specs.flash.size > 65536 {
+        let extended = if self.prog.specs.flash.size > 65536 {
             1 << 31
         } else {
             0
         };
+        // Flash is addressed in words.
+        extended | (address / 2)
+    }
+
+    /// Send ProgramFlash or ProgramEeprom command. Returns its status, see
+    /// [`check_page_written`].
+    ///
+    /// `cmds` are ISP instructions used by firmware: load (or write in word mode), write page
+    /// and read (used for polling). Firmware polls the read instruction until it answers other
+    /// than `memory.poll` values. Unlike read commands, program commands take no poll index.
+    fn program_command(
+        &mut self,
+        cmd: command::Isp,
+        memory: &specs::Memory,
+        cmds: (u8, u8, u8),
+        data: &[u8],
+    ) -> Result<u8, errors::ErrorKind> {
+        let size_bytes = (data.len() as u16).to_be_bytes();
+        let mut mode = memory.mode as u8;
+        // In page mode, bit 7 tells firmware to write page after loading it.
+        if mode & PAGE_MODE != 0 {
+            mode |= WRITE_PAGE;
+        }
+        let header = [
+            cmd.into(),
+            size_bytes[0],
+            size_bytes[1],
+            mode,
+            memory.delay as u8,
+            cmds.0,
+            cmds.1,
+            cmds.2,
+            memory.poll.0,
+            memory.poll.1,
+        ];
+        let end = header.len() + data.len();
+        check_body_size(end)?;
+        let mut body = [0; Message::MAX_BODY_SIZE];
+        body[..header.len()].copy_from_slice(&header);
+        body[header.len()..end].copy_from_slice(data);
+        Ok(self.prog.answer(&body[..end])?[1])
+    }
+
+    fn read_fuse(&mut self, cmd: isp_command::IspCommand) -> Result<u8, errors::ErrorKind> {
+        Ok(self.prog.read_command(
+            &[
+                command::Isp::ReadFuse.into(),
+                self.prog.specs.fuse_poll_index,
+                cmd.0,
+                cmd.1,
+                cmd.2,
+                cmd.3,
+            ],
+            1,
+        )?[0])
+    }
+}
+
+impl IspMode {
+    /// Read whole flash pages starting at page aligned byte `address`.
+    fn read_flash_pages(
+        &mut self,
+        address: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), errors::ErrorKind> {
+        span!("read_pages", memory = "flash", address, size = buffer.len());
+        // Several pages are read at once, which saves a round trip per page.
+        let size = read_block(self.prog.specs.flash.page_size);
         // Stk500v2 firmware handles incrementing address on its own.
         // Reduces reading time since no load address command needs to be send.
-        self.load_address(start)?;
-        for addr in (0..buffer.len()).step_by(size) {
-            self.read_flash_command(size, &mut buffer[addr..(addr + size)])?;
+        self.load_address(self.flash_address(address))?;
+        let page_size = self.prog.specs.flash.page_size;
+        for (index, block) in buffer.chunks_mut(size).enumerate() {
+            let block_address = address + index * size;
+            self.read_flash_command(block.len(), block)
+                .map_err(|err| page_error(err, MemoryType::Flash, block_address))?;
+            self.prog
+                .pages_read(MemoryType::Flash, page_size, block_address, block.len());
         }
         Ok(())
     }
-}
 
-impl programmer::EEPROMRead for IspMode {
-    fn read(&mut self, bytes: &mut [u8]) -> Result<(), errors::ErrorKind> {
+    /// Calibration byte at `address`. Chips with several oscillators or frequencies have one
+    /// byte for each.
+    fn read_osccal(&mut self, address: usize) -> Result<u8, errors::ErrorKind> {
+        Ok(self.prog.read_command(
+            &[
+                command::Isp::ReadOsccal.into(),
+                self.prog.specs.osccal_poll_index,
+                isp_command::READ_OSCCAL.0,
+                isp_command::READ_OSCCAL.1,
+                address as u8,
+                isp_command::READ_OSCCAL.3,
+            ],
+            1,
+        )?[0])
+    }
+
+    /// Read whole EEPROM pages starting at page aligned `address`.
+    fn read_eeprom_pages(
+        &mut self,
+        address: usize,
+        bytes: &mut [u8],
+    ) -> Result<(), errors::ErrorKind> {
         // According to AVR068 PDF, LoadAddress command needs to be executed once.
         // Firmware will increment address on its own. At least in byte mode.
         //
         // Tested on stk500v2 programmer, which reduced whole reading time by half.
-        let size = self.prog.specs.eeprom.page_size;
-        self.load_address(0)?;
-        for addr in (0..bytes.len()).step_by(size) {
-            self.read_eeprom_command(size, &mut bytes[addr..(addr + size)])?;
+        span!("read_pages", memory = "eeprom", address, size = bytes.len());
+        let page_size = self.prog.specs.eeprom.page_size;
+        self.load_address(address)?;
+        let mut addr = 0;
+        while addr < bytes.len() {
+            let size = self.eeprom_block.min(bytes.len() - addr);
+            match self.read_eeprom_command(size, &mut bytes[addr..(addr + size)]) {
+                // Some firmwares read only one page at a time.
+                Err(errors::ErrorKind::StatusError | errors::ErrorKind::AnswerLength { .. })
+                    if size > page_size =>
+                {
+                    self.eeprom_block = page_size;
+                    self.load_address(address + addr)?;
+                }
+                result => {
+                    result.map_err(|err| page_error(err, MemoryType::Eeprom, address + addr))?;
+                    self.prog
+                        .pages_read(MemoryType::Eeprom, page_size, address + addr, size);
+                    addr += size;
+                }
+            }
         }
         Ok(())
     }
+
+    /// Write flash pages starting at page aligned byte `address`.
+    fn write_flash_pages(&mut self, address: usize, bytes: &[u8]) -> Result<(), errors::ErrorKind> {
+        let flash = self.prog.specs.flash.clone();
+        for (page, chunk) in bytes.chunks(flash.page_size).enumerate() {
+            // Only whole pages can be written. Fill the rest with erased value.
+            let mut data = chunk.to_vec();
+            data.resize(flash.page_size, 0xff);
+            // Flash is erased already. Sparse images are written much faster.
+            if programmer::is_erased(&data) {
+                continue;
+            }
+            let page_address = address + page * flash.page_size;
+            span!("write_page", memory = "flash", address = page_address);
+            self.load_address(self.flash_address(page_address))?;
+            let status = self
+                .program_command(
+                    command::Isp::ProgramFlash,
+                    &flash,
+                    (
+                        isp_command::LOAD_FLASH_PAGE_LOW.0,
+                        isp_command::WRITE_FLASH.0,
+                        isp_command::READ_FLASH_LOW.0,
+                    ),
+                    &data,
+                )
+                .map_err(|err| page_error(err, MemoryType::Flash, page_address))?;
+            check_page_written(status, MemoryType::Flash, page_address, flash.page_size)?;
+            self.prog.emit(Event::PageWritten {
+                memory: MemoryType::Flash,
+                address: page_address,
+            });
+        }
+        Ok(())
+    }
+
+    /// Write EEPROM in chunks of page size starting at `address`. Page write programs only
+    /// loaded bytes, so chunk may be a part of page.
+    fn write_eeprom_pages(
+        &mut self,
+        address: usize,
+        bytes: &[u8],
+    ) -> Result<(), errors::ErrorKind> {
+        let eeprom = self.prog.specs.eeprom.clone();
+        let cmds = if eeprom.mode as u8 & PAGE_MODE != 0 {
+            (
+                isp_command::LOAD_EEPROM_PAGE.0,
+                isp_command::WRITE_EEPROM_PAGE.0,
+                isp_command::READ_EEPROM.0,
+            )
+        } else {
+            // In word mode firmware writes each byte on its own.
+            (isp_command::WRITE_EEPROM.0, 0, isp_command::READ_EEPROM.0)
+        };
+        for (page, chunk) in bytes.chunks(eeprom.page_size).enumerate() {
+            let page_address = address + page * eeprom.page_size;
+            span!("write_page", memory = "eeprom", address = page_address);
+            self.load_address(page_address)?;
+            let status = self
+                .program_command(command::Isp::ProgramEeprom, &eeprom, cmds, chunk)
+                .map_err(|err| page_error(err, MemoryType::Eeprom, page_address))?;
+            check_page_written(status, MemoryType::Eeprom, page_address, eeprom.page_size)?;
+            self.prog.emit(Event::PageWritten {
+                memory: MemoryType::Eeprom,
+                address: page_address,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl programmer::MemoryOps for IspMode {
+    fn layout(&self, memory: MemoryType) -> Result<specs::Memory, errors::ErrorKind> {
+        programmer::layout(&self.prog.specs, memory).cloned()
+    }
+
+    fn read_at(
+        &mut self,
+        memory: MemoryType,
+        address: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), errors::ErrorKind> {
+        match memory {
+            MemoryType::Flash => self.read_flash_pages(address, buffer),
+            MemoryType::Eeprom => self.read_eeprom_pages(address, buffer),
+            MemoryType::Calibration => {
+                for (offset, byte) in buffer.iter_mut().enumerate() {
+                    *byte = self.read_osccal(address + offset)?;
+                }
+                Ok(())
+            }
+            _ => Err(programmer::unsupported(memory)),
+        }
+    }
+
+    fn write_at(
+        &mut self,
+        memory: MemoryType,
+        address: usize,
+        bytes: &[u8],
+    ) -> Result<(), errors::ErrorKind> {
+        match memory {
+            MemoryType::Flash => self.write_flash_pages(address, bytes),
+            MemoryType::Eeprom => self.write_eeprom_pages(address, bytes),
+            _ => Err(programmer::unsupported(memory)),
+        }
+    }
 }
 
 impl programmer::Erase for IspMode {
     fn erase(&mut self) -> Result<(), errors::ErrorKind> {
-        self.prog.command(vec![
+        self.prog.command(&[
             command::Isp::ChipErase.into(),
             self.prog.specs.erase_delay,
             self.prog.specs.erase_poll_method,
@@ -525,28 +2047,30 @@ impl programmer::Erase for IspMode {
 }
 
 impl programmer::Programmer for IspMode {
-    fn close(mut self) -> Result<(), errors::ErrorKind> {
-        let bytes = vec![
-            command::Normal::LeaveIspMode.into(),
-            self.prog.specs.pre_delay,
-            self.prog.specs.post_delay,
-        ];
-        self.prog.command(bytes)?;
-        Ok(())
+    fn close(self) -> Result<(), errors::ErrorKind> {
+        self.leave().map(|_| ())
     }
 }
 
 impl programmer::AVRLockByteGet for IspMode {
     fn get_lock_byte(&mut self) -> Result<u8, errors::ErrorKind> {
-        let msg = self.prog.command(vec![
-            command::Isp::ReadLock.into(),
-            self.prog.specs.lock_poll_index,
-            isp_command::READ_LOCK.0,
-            isp_command::READ_LOCK.1,
-            isp_command::READ_LOCK.2,
-            isp_command::READ_LOCK.3,
-        ])?;
-        Ok(msg.body_slice()[2])
+        Ok(self.prog.read_command(
+            &[
+                command::Isp::ReadLock.into(),
+                self.prog.specs.lock_poll_index,
+                isp_command::READ_LOCK.0,
+                isp_command::READ_LOCK.1,
+                isp_command::READ_LOCK.2,
+                isp_command::READ_LOCK.3,
+            ],
+            1,
+        )?[0])
+    }
+}
+
+impl programmer::AVROsccalGet for IspMode {
+    fn get_osccal(&mut self) -> Result<u8, errors::ErrorKind> {
+        self.read_osccal(0)
     }
 }
 
@@ -560,28 +2084,166 @@ impl programmer::AVRFuseGet for IspMode {
     }
 }
 
+impl programmer::AVRFuseSet for IspMode {
+    fn set_fuses(
+        &mut self,
+        fuses: &programmer::AVRFuse,
+    ) -> Result<programmer::AVRFuse, errors::ErrorKind> {
+        for fuse in self.prog.specs.fuses() {
+            let cmd = match fuse {
+                specs::Fuse::Low => isp_command::WRITE_LOW_FUSE,
+                specs::Fuse::High => isp_command::WRITE_HIGH_FUSE,
+                specs::Fuse::Extended => isp_command::WRITE_EXTENDED_FUSE,
+            };
+            self.prog.command(&[
+                command::Isp::ProgramFuse.into(),
+                cmd.0,
+                cmd.1,
+                cmd.2,
+                fuses.get(fuse),
+            ])?;
+        }
+        let read = programmer::AVRFuseGet::get_fuses(self)?;
+        programmer::check_fuses(self.prog.specs.fuse_bits, fuses, read)
+    }
+}
+
+impl programmer::AVRLockByteSet for IspMode {
+    fn set_lock_byte(&mut self, byte: u8) -> Result<u8, errors::ErrorKind> {
+        programmer::write_lock(self, programmer::LOCK_BITS, byte, |isp, byte| {
+            isp.prog.command(&[
+                command::Isp::ProgramLock.into(),
+                isp_command::WRITE_LOCK.0,
+                isp_command::WRITE_LOCK.1,
+                isp_command::WRITE_LOCK.2,
+                byte,
+            ])?;
+            Ok(())
+        })
+    }
+}
+
 impl programmer::MCUSignature for IspMode {
     fn get_mcu_signature(&mut self) -> Result<specs::Signature, errors::ErrorKind> {
         let mut signature: [u8; 3] = [0; 3];
-        for addr in 0..signature.len() {
-            let msg = self.prog.command(vec![
-                command::Isp::ReadSignature.into(),
-                self.prog.specs.signature_poll_index,
-                isp_command::READ_SIGNATURE.0,
-                isp_command::READ_SIGNATURE.1,
-                addr as u8,
-                isp_command::READ_SIGNATURE.3,
-            ])?;
-            signature[addr] = msg.body_slice()[2];
+        for (addr, byte) in signature.iter_mut().enumerate() {
+            *byte = self.prog.read_command(
+                &[
+                    command::Isp::ReadSignature.into(),
+                    self.prog.specs.signature_poll_index,
+                    isp_command::READ_SIGNATURE.0,
+                    isp_command::READ_SIGNATURE.1,
+                    addr as u8,
+                    isp_command::READ_SIGNATURE.3,
+                ],
+                1,
+            )?[0];
         }
         Ok(specs::Signature::from(signature))
     }
 }
 
+impl programmer::Session for IspMode {
+    fn identify(&mut self) -> Result<programmer::Identity, errors::ErrorKind> {
+        Ok(programmer::Identity {
+            name: self.prog.read_programmer_signature()?.to_string(),
+            hardware: Some(self.prog.get_hw_version()?),
+            firmware: Some(self.prog.get_sw_version()?),
+        })
+    }
+
+    fn slowed_clock(&mut self) -> Result<Option<String>, errors::ErrorKind> {
+        Ok(self.slowed_sck()?.map(describe_sck))
+    }
+
+    fn adapt_clock(&mut self) -> Result<Option<String>, errors::ErrorKind> {
+        Ok(self.adapt_sck()?.map(describe_sck))
+    }
+
+    fn counters(&self) -> programmer::Counters {
+        programmer::Counters {
+            mismatches: self.prog.link.mismatches(),
+            ..self.prog.counters
+        }
+    }
+
+    fn set_listener(&mut self, listener: Box<dyn Listener + Send>) {
+        self.prog.set_listener(listener)
+    }
+
+    fn emit(&mut self, event: Event) {
+        self.prog.emit(event)
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), errors::ErrorKind> {
+        programmer::Programmer::close(*self)
+    }
+}
+
+/// Internals exercised by benchmarks in `benches/`. Not a stable API.
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench {
+    use super::*;
+
+    /// Frame `body` into a message. Returns message size.
+    pub fn frame(seq: u8, body: &[u8]) -> usize {
+        Message::new(seq, body).map_or(0, |msg| msg.as_slice().len())
+    }
+
+    pub fn checksum(bytes: &[u8]) -> u8 {
+        Message::calc_checksum(bytes)
+    }
+
+    /// Programming mode over a programmer which answers every command with success. Read
+    /// memory is all zeros.
+    pub fn mock(specs: specs::Specs) -> IspMode {
+        let link = Mock {
+            sequencer: SequenceGenerator::new(),
+            message: Message::empty(),
+        };
+        IspMode::new(STK500v2::new(Box::new(link), specs))
+    }
+
+    /// Frames sent and received messages like [`Link`] does, without a port.
+    struct Mock {
+        sequencer: SequenceGenerator,
+        message: Message,
+    }
+
+    impl Transport for Mock {
+        fn transfer(&mut self, body: &[u8]) -> Result<&[u8], errors::ErrorKind> {
+            let seq = self.sequencer.next().unwrap();
+            self.message.set(seq, body)?;
+            // Command id, status, data and second status.
+            let data_size = if body[0] == command::Isp::ReadFlash.into()
+                || body[0] == command::Isp::ReadEeprom.into()
+            {
+                u16::from_be_bytes([body[1], body[2]]) as usize
+            } else {
+                0
+            };
+            let mut answer = [0; Message::MAX_BODY_SIZE];
+            answer[0] = body[0];
+            answer[1] = Status::CmdOk.into();
+            answer[2 + data_size] = Status::CmdOk.into();
+            self.message.set(seq, &answer[..data_size + 3])?;
+            self.message.check()?;
+            Ok(self.message.body_slice())
+        }
+
+        fn sign_on(&mut self) -> Result<programmer::Variant, errors::ErrorKind> {
+            Ok(programmer::Variant::STK500_V2)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::protocol::MessageBuffer;
     use super::*;
     use claim::*;
+    use std::convert::TryFrom;
 
     mod sequence_generator {
 
@@ -604,6 +2266,508 @@ mod tests {
             let mut gen = SequenceGenerator::new().skip(1);
             assert_eq!(gen.next(), Some(1));
         }
+
+        #[test]
+        fn tolerant_policy_accepts_mismatch() {
+            assert!(SequencePolicy::Strict.check(3, 3).is_ok());
+            assert!(SequencePolicy::Strict.check(3, 2).is_err());
+            assert!(SequencePolicy::Warn.check(3, 2).is_ok());
+            assert!(SequencePolicy::Ignore.check(3, 0).is_ok());
+        }
+    }
+
+    mod xprog_param {
+        use super::*;
+
+        #[test]
+        fn encoded_big_endian() {
+            assert_eq!(
+                xprog::Param::NvmBase(0x0100_01c0).to_bytes(),
+                vec![1, 0x01, 0x00, 0x01, 0xc0]
+            );
+            assert_eq!(xprog::Param::NvmCsrRegister(0x32).to_bytes(), vec![4, 0x32]);
+        }
+    }
+
+    mod sck {
+        use super::*;
+
+        #[test]
+        fn slowest_retry_below_32_khz() {
+            assert_eq!(sck_frequency(1), 460_800);
+            assert!(sck_frequency(SLOWER_SCK[SLOWER_SCK.len() - 1]) < 32_000);
+        }
+
+        #[test]
+        fn slow_enough_for_clock() {
+            assert_eq!(sck_below(16_000_000, 8), 0);
+            assert!(sck_frequency(sck_below(1_000_000, 8)) < 125_000);
+            assert_eq!(sck_below(1_000, 8), u8::MAX);
+        }
+
+        #[test]
+        fn quarter_of_clock() {
+            assert_eq!(sck_below(8_000_000, 4), 0);
+            assert_eq!(sck_below(1_000_000, 4), 2);
+            assert!(sck_frequency(sck_below(128_000, 4)) < 32_000);
+        }
+
+        #[test]
+        fn oscillator() {
+            assert_eq!(oscillator_setting(1_000_000), Some((1, 2)));
+            assert_eq!(oscillator_frequency(1, 2), 1_228_800);
+            let (pscale, cmatch) = oscillator_setting(1_000).unwrap();
+            assert_eq!(oscillator_frequency(pscale, cmatch), 1_001);
+            assert_eq!(oscillator_setting(15), Some((7, 239)));
+            assert_eq!(oscillator_setting(14), None);
+            assert_eq!(oscillator_setting(4_000_000), None);
+            assert_eq!(oscillator_frequency(0, 0), 0);
+        }
+    }
+
+    #[test]
+    fn short_answer_is_error() {
+        assert_eq!(answer_data(&[0x14, 0x00, 1, 2, 0x00], 2).unwrap(), &[1, 2]);
+        match answer_data(&[0x14, 0x00, 1], 2) {
+            Err(errors::ErrorKind::AnswerLength { expected, found }) => {
+                assert_eq!((expected, found), (4, 3))
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
+    #[test]
+    fn short_xprog_read_is_error() {
+        /// Answers ReadMem with one data byte only.
+        struct Short([u8; 4]);
+
+        impl Transport for Short {
+            fn transfer(&mut self, body: &[u8]) -> Result<&[u8], errors::ErrorKind> {
+                self.0 = [body[0], body[1], 0, 0xaa];
+                Ok(&self.0)
+            }
+
+            fn sign_on(&mut self) -> Result<programmer::Variant, errors::ErrorKind> {
+                Ok(programmer::Variant::STK500_V2)
+            }
+        }
+
+        let mut prog = STK500v2::new(Box::new(Short([0; 4])), specs::atmega::ATMEGA_32);
+        let mut buffer = [0; 4];
+        match prog.xprog_read(xprog::Memory::Application, 0, &mut buffer) {
+            Err(errors::ErrorKind::AnswerLength { expected, found }) => {
+                assert_eq!((expected, found), (4, 1))
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
+    #[test]
+    fn xprog_timeout_names_command() {
+        struct Silent;
+
+        impl Transport for Silent {
+            fn transfer(&mut self, _body: &[u8]) -> Result<&[u8], errors::ErrorKind> {
+                Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into())
+            }
+
+            fn sign_on(&mut self) -> Result<programmer::Variant, errors::ErrorKind> {
+                Ok(programmer::Variant::STK500_V2)
+            }
+        }
+
+        let mut prog = STK500v2::new(Box::new(Silent), specs::atmega::ATMEGA_32);
+        match prog.xprog_enter() {
+            Err(errors::ErrorKind::Timeout { operation, .. }) => {
+                assert_eq!(operation, "command 0x50")
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
+    #[test]
+    fn parameters_summary() {
+        let params = Parameters {
+            hardware: 2,
+            firmware: SwVersion {
+                major: 2,
+                minor: 10,
+            },
+            build: None,
+            vtarget: Some(50),
+            vadjust: None,
+            oscillator: Some((1, 0)),
+            sck_duration: 1,
+            topcard: Some(0xff),
+            status: Some(0),
+            reset_polarity: Some(1),
+        };
+        let text = params.to_string();
+        assert!(text.contains("firmware version: 2.10\n"));
+        assert!(text.contains("build number: n/a\n"));
+        assert!(text.contains("target voltage: 5.0 V\n"));
+        assert!(text.contains("oscillator: prescaler 1, compare match 0\n"));
+        assert!(text.contains("top card: 0xff\n"));
+    }
+
+    #[test]
+    fn page_write_failure_has_address() {
+        let err = check_page_written(0x81, MemoryType::Flash, 0x80, 0x80).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Writing flash page 1 at 0x0080 failed. Target did not confirm it in time"
+        );
+        assert!(check_page_written(0x80, MemoryType::Flash, 0x80, 0x80).is_err());
+        // Other failures are not poll timeouts.
+        assert!(matches!(
+            check_page_written(0xc0, MemoryType::Eeprom, 0, 4),
+            Err(errors::ErrorKind::StatusError)
+        ));
+        assert!(check_page_written(0, MemoryType::Eeprom, 0, 4).is_ok());
+    }
+
+    #[test]
+    fn timeout_names_pending_page() {
+        struct Silent;
+
+        impl Transport for Silent {
+            fn transfer(&mut self, _body: &[u8]) -> Result<&[u8], errors::ErrorKind> {
+                Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into())
+            }
+
+            fn sign_on(&mut self) -> Result<programmer::Variant, errors::ErrorKind> {
+                Ok(programmer::Variant::STK500_V2)
+            }
+        }
+
+        let err = command(&mut Silent, &[command::Isp::ProgramFlash.into()]).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Programmer stopped responding at command 0x13"));
+        let err = page_error(err, MemoryType::Flash, 0x1c00);
+        assert!(err
+            .to_string()
+            .starts_with("Programmer stopped responding at flash page at 0x1c00 after 0.0 s"));
+    }
+
+    #[test]
+    fn leave_keeps_session() {
+        /// Answers every command with success and 0x1e data byte.
+        struct Echo {
+            sent: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+            answer: [u8; 4],
+        }
+
+        impl Transport for Echo {
+            fn transfer(&mut self, body: &[u8]) -> Result<&[u8], errors::ErrorKind> {
+                self.sent.lock().unwrap().push(body[0]);
+                self.answer = [body[0], Status::CmdOk.into(), 0x1e, Status::CmdOk.into()];
+                Ok(&self.answer)
+            }
+
+            fn sign_on(&mut self) -> Result<programmer::Variant, errors::ErrorKind> {
+                Ok(programmer::Variant::STK500_V2)
+            }
+        }
+
+        let sent = std::sync::Arc::default();
+        let link = Echo {
+            sent: std::sync::Arc::clone(&sent),
+            answer: [0; 4],
+        };
+        let prog = STK500v2::new(Box::new(link), specs::atmega::ATMEGA_32);
+        let isp: IspMode = prog.try_into().unwrap();
+        let prog = isp.leave().unwrap();
+        let leave: u8 = command::Normal::LeaveIspMode.into();
+        assert_eq!(sent.lock().unwrap().last(), Some(&leave));
+        let isp: Result<IspMode, _> = prog.try_into();
+        assert!(isp.is_ok());
+    }
+
+    #[test]
+    fn sweep_restores_sck_after_failure() {
+        /// Programmer with SCK duration 7 whose target connection breaks on signature read.
+        struct Broken {
+            sck: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+            answer: [u8; 4],
+        }
+
+        impl Transport for Broken {
+            fn transfer(&mut self, body: &[u8]) -> Result<&[u8], errors::ErrorKind> {
+                let ok = Status::CmdOk.into();
+                match body[0] {
+                    id if id == command::Isp::ReadSignature.into() => {
+                        return Err(std::io::Error::from(std::io::ErrorKind::BrokenPipe).into());
+                    }
+                    id if id == command::Normal::SetParameter.into()
+                        && body[1] == param::RW::SckDuration.into() =>
+                    {
+                        self.sck.lock().unwrap().push(body[2]);
+                    }
+                    _ => (),
+                }
+                self.answer = [body[0], ok, 7, ok];
+                Ok(&self.answer)
+            }
+
+            fn sign_on(&mut self) -> Result<programmer::Variant, errors::ErrorKind> {
+                Ok(programmer::Variant::STK500_V2)
+            }
+        }
+
+        let sck = std::sync::Arc::default();
+        let link = Broken {
+            sck: std::sync::Arc::clone(&sck),
+            answer: [0; 4],
+        };
+        let prog = STK500v2::new(Box::new(link), specs::atmega::ATMEGA_32);
+        assert!(matches!(
+            sweep_sck(prog, &[1, 2], 3),
+            Err(errors::ErrorKind::Io(_))
+        ));
+        assert_eq!(*sck.lock().unwrap(), [1, 7]);
+    }
+
+    #[test]
+    fn enter_gives_up_on_blank_signature() {
+        /// Programmer with SCK duration 0 whose target never answers, signature reads 0xff.
+        struct Absent {
+            sck: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+            answer: [u8; 4],
+        }
+
+        impl Transport for Absent {
+            fn transfer(&mut self, body: &[u8]) -> Result<&[u8], errors::ErrorKind> {
+                let ok = Status::CmdOk.into();
+                let data = match body[0] {
+                    id if id == command::Isp::ReadSignature.into() => 0xff,
+                    id if id == command::Normal::SetParameter.into()
+                        && body[1] == param::RW::SckDuration.into() =>
+                    {
+                        self.sck.lock().unwrap().push(body[2]);
+                        0
+                    }
+                    // Vtarget is 5.0 V.
+                    id if id == command::Normal::GetParameter.into()
+                        && body[1] == param::RW::Vtarget.into() =>
+                    {
+                        50
+                    }
+                    _ => 0,
+                };
+                self.answer = [body[0], ok, data, ok];
+                Ok(&self.answer)
+            }
+
+            fn sign_on(&mut self) -> Result<programmer::Variant, errors::ErrorKind> {
+                Ok(programmer::Variant::STK500_V2)
+            }
+        }
+
+        let sck = std::sync::Arc::default();
+        let link = Absent {
+            sck: std::sync::Arc::clone(&sck),
+            answer: [0; 4],
+        };
+        let mut prog = STK500v2::new(Box::new(link), specs::atmega::ATMEGA_32);
+        let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = std::sync::Arc::clone(&events);
+        prog.set_listener(Box::new(move |event: &Event| {
+            recorded.lock().unwrap().push(event.clone())
+        }));
+        let isp: Result<IspMode, _> = prog.try_into();
+        assert!(matches!(
+            isp,
+            Err(errors::ErrorKind::SignatureMismatch { .. })
+        ));
+        let mut expected = SLOWER_SCK.to_vec();
+        expected.push(0);
+        assert_eq!(*sck.lock().unwrap(), expected);
+        assert!(!events
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|event| matches!(event, Event::IspEntered { .. })));
+    }
+
+    #[test]
+    fn eeprom_write_sends_changed_bytes() {
+        /// EEPROM behind firmware, recording address and size of each `ProgramEeprom`.
+        struct Eeprom {
+            memory: Vec<u8>,
+            address: usize,
+            writes: std::sync::Arc<std::sync::Mutex<Vec<(usize, usize)>>>,
+            answer: Vec<u8>,
+        }
+
+        impl Transport for Eeprom {
+            fn transfer(&mut self, body: &[u8]) -> Result<&[u8], errors::ErrorKind> {
+                let ok = Status::CmdOk.into();
+                self.answer = vec![body[0], ok];
+                match body[0] {
+                    id if id == command::Normal::LoadAddress.into() => {
+                        self.address = u16::from_be_bytes([body[3], body[4]]) as usize;
+                    }
+                    id if id == command::Isp::ReadEeprom.into() => {
+                        let size = u16::from_be_bytes([body[1], body[2]]) as usize;
+                        let data = &self.memory[self.address..self.address + size];
+                        self.answer.extend_from_slice(data);
+                        self.answer.push(ok);
+                        self.address += size;
+                    }
+                    id if id == command::Isp::ProgramEeprom.into() => {
+                        let data = &body[10..];
+                        let end = self.address + data.len();
+                        self.memory[self.address..end].copy_from_slice(data);
+                        self.writes.lock().unwrap().push((self.address, data.len()));
+                        self.address = end;
+                    }
+                    // Parameters read, e.g. VTarget, are 5.0 V.
+                    _ => self.answer.extend_from_slice(&[50, ok]),
+                }
+                Ok(&self.answer)
+            }
+
+            fn sign_on(&mut self) -> Result<programmer::Variant, errors::ErrorKind> {
+                Ok(programmer::Variant::STK500_V2)
+            }
+        }
+
+        let writes = std::sync::Arc::default();
+        let link = Eeprom {
+            memory: vec![0xff; 16],
+            address: 0,
+            writes: std::sync::Arc::clone(&writes),
+            answer: Vec::new(),
+        };
+        let prog = STK500v2::new(Box::new(link), specs::atmega::ATMEGA_32);
+        let mut isp: IspMode = prog.try_into().unwrap();
+        let mut bytes = [0xff; 16];
+        bytes[1] = 0;
+        bytes[3..7].copy_from_slice(&[1, 2, 3, 4]);
+        programmer::MemoryOps::write(&mut isp, MemoryType::Eeprom, &bytes).unwrap();
+        // Page size is 4, unchanged bytes and pages are not sent.
+        assert_eq!(*writes.lock().unwrap(), vec![(1, 1), (3, 1), (4, 3)]);
+        writes.lock().unwrap().clear();
+        programmer::MemoryOps::write(&mut isp, MemoryType::Eeprom, &bytes).unwrap();
+        assert!(writes.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn vtarget_in_supply_range() {
+        let chip = &specs::atmega::ATMEGA_32;
+        assert!(check_supply(chip, 50).is_ok());
+        assert!(check_supply(chip, 26).is_ok());
+        let err = check_supply(chip, 0).unwrap_err();
+        assert!(err.to_string().contains("not powered"), "{}", err);
+        let err = check_supply(chip, 18).unwrap_err();
+        assert!(err.to_string().contains("2.7 V to 5.5 V"), "{}", err);
+        assert!(check_supply(chip, 60).is_err());
+    }
+
+    #[test]
+    fn sweep_finds_fastest_reliable_sck() {
+        /// Target answers only with `SckDuration` 2 or slower.
+        struct Slow {
+            sck: u8,
+            answer: [u8; 4],
+        }
+
+        impl Transport for Slow {
+            fn transfer(&mut self, body: &[u8]) -> Result<&[u8], errors::ErrorKind> {
+                let data = match body[0] {
+                    id if id == command::Normal::SetParameter.into() => {
+                        if body[1] == param::RW::SckDuration.into() {
+                            self.sck = body[2];
+                        }
+                        0
+                    }
+                    id if id == command::Normal::GetParameter.into() => self.sck,
+                    id if id == command::Isp::ReadSignature.into() && self.sck >= 2 => {
+                        [0x1e, 0x95, 0x02][body[4] as usize]
+                    }
+                    _ => 0xff,
+                };
+                self.answer = [body[0], Status::CmdOk.into(), data, Status::CmdOk.into()];
+                Ok(&self.answer)
+            }
+
+            fn sign_on(&mut self) -> Result<programmer::Variant, errors::ErrorKind> {
+                Ok(programmer::Variant::STK500_V2)
+            }
+        }
+
+        let link = Slow {
+            sck: 1,
+            answer: [0; 4],
+        };
+        let prog = STK500v2::new(Box::new(link), specs::atmega::ATMEGA_32);
+        let trials = sweep_sck(prog, &SWEEP_SCK[..4], 2).unwrap();
+        let fastest = trials.iter().find(|trial| trial.reliable()).unwrap();
+        assert_eq!(fastest.duration, 2);
+        assert_eq!(trials[0].matched, 0);
+    }
+
+    #[test]
+    fn resume_follows_firmware_address() {
+        let mut resume = Resume::default();
+        resume.track(&[command::Normal::SetParameter.into(), 0x98, 2]);
+        resume.track(&[command::Normal::SetParameter.into(), 0x98, 3]);
+        resume.track(&[command::Normal::EnterIspMode.into(), 200]);
+        resume.track(&[command::Normal::LoadAddress.into(), 0x80, 0, 0x10, 0]);
+        resume.track(&[command::Isp::ReadFlash.into(), 1, 0, 0x20]);
+        assert_eq!(
+            resume.commands(),
+            vec![
+                vec![command::Normal::SetParameter.into(), 0x98, 3],
+                vec![command::Normal::EnterIspMode.into(), 200],
+                vec![command::Normal::LoadAddress.into(), 0x80, 0, 0x10, 0x80],
+            ]
+        );
+        resume.track(&[command::Normal::LeaveIspMode.into(), 1, 1]);
+        assert_eq!(resume.isp, None);
+        // Short bodies are refused by firmware, they must not panic here.
+        resume.track(&[command::Normal::LoadAddress.into(), 0x80]);
+        resume.track(&[command::Isp::ReadFlash.into(), 1]);
+        resume.track(&[command::Normal::SetParameter.into()]);
+        resume.track(&[]);
+        assert_eq!(resume.address, Some(0x8000_1080));
+    }
+
+    #[test]
+    fn reconnect_only_after_link_loss() {
+        use std::io;
+        let timeout = errors::ErrorKind::Io(io::Error::new(io::ErrorKind::TimedOut, "timeout"));
+        assert!(!link_lost(&timeout));
+        let unplugged = errors::ErrorKind::Io(io::Error::new(io::ErrorKind::BrokenPipe, "gone"));
+        assert!(link_lost(&unplugged));
+        assert!(!link_lost(&errors::ErrorKind::ChecksumError));
+    }
+
+    #[test]
+    fn sign_on_answer() {
+        let answer = [
+            0x01, 0x00, 8, b'S', b'T', b'K', b'5', b'0', b'0', b'_', b'2',
+        ];
+        assert_eq!(signature(&answer).unwrap(), programmer::Variant::STK500_V2);
+        // Announced length longer than name.
+        let answer = [0x01, 0x00, 10, b'C', b'L', b'O', b'N', b'E'];
+        assert_eq!(
+            signature(&answer).unwrap(),
+            programmer::Variant::Unknown(String::from("CLONE"))
+        );
+        assert!(signature(&[0x01, 0x00]).is_err());
+    }
+
+    #[test]
+    fn read_block_holds_whole_pages() {
+        assert_eq!(read_block(64), 256);
+        assert_eq!(read_block(96), 192);
+    }
+
+    #[test]
+    fn large_pages_read_in_parts() {
+        assert_eq!(read_block(512), 256);
     }
 
     mod message {
@@ -614,6 +2778,96 @@ mod tests {
             assert_eq!(Message::calc_checksum(&[2, 55, 22, 78]), 109);
         }
 
+        #[test]
+        fn set_reuses_buffer() {
+            let mut msg = Message::new(0, &[1, 2, 3, 4, 5]).unwrap();
+            assert_ok!(msg.set(1, &[89, 100, 78, 109]));
+            assert_eq!(msg.as_slice(), &[0x1b, 1, 0, 4, 0x0e, 89, 100, 78, 109, 14]);
+            assert_ok!(msg.check());
+        }
+
+        #[test]
+        fn body_too_large() {
+            assert_ok!(Message::new(0, &[0; Message::MAX_BODY_SIZE]));
+            match Message::new(0, &[0; Message::MAX_BODY_SIZE + 1]) {
+                Err(errors::ErrorKind::BodyTooLarge {
+                    size: 276,
+                    max: 275,
+                }) => (),
+                result => panic!("unexpected {:?}", result),
+            }
+        }
+
+        #[test]
+        fn oversized_body_is_error() {
+            let mut buffer: MessageBuffer = [0; Message::MAX_SIZE];
+            buffer[0] = Message::MESSAGE_START;
+            buffer[2] = 0xff;
+            buffer[3] = 0xff;
+            buffer[4] = Message::TOKEN;
+            match Message::try_from(buffer) {
+                Err(errors::ErrorKind::MalformedFrame) => (),
+                result => panic!("unexpected {:?}", result),
+            }
+        }
+
+        /// Decode `stream` as a message.
+        fn decode(stream: &[u8]) -> Result<Message, errors::ErrorKind> {
+            let mut msg = Message::empty();
+            msg.read_from(&mut &stream[..])?;
+            Ok(msg)
+        }
+
+        #[test]
+        fn decoding_arbitrary_bytes_does_not_panic() {
+            fn property(stream: Vec<u8>) -> bool {
+                let _ = decode(&stream);
+                true
+            }
+            quickcheck::quickcheck(property as fn(Vec<u8>) -> bool);
+        }
+
+        #[test]
+        fn decoding_arbitrary_size_does_not_panic() {
+            fn property(seq: u8, size: u16, rest: Vec<u8>) -> bool {
+                let [size0, size1] = size.to_be_bytes();
+                let mut stream = vec![Message::MESSAGE_START, seq, size0, size1, Message::TOKEN];
+                stream.extend(rest);
+                let _ = decode(&stream);
+                true
+            }
+            quickcheck::quickcheck(property as fn(u8, u16, Vec<u8>) -> bool);
+        }
+
+        #[test]
+        fn round_trip() {
+            fn property(seq: u8, mut body: Vec<u8>) -> bool {
+                body.truncate(Message::MAX_BODY_SIZE);
+                let sent = Message::new(seq, &body).unwrap();
+                match decode(sent.as_slice()) {
+                    Ok(msg) => msg.get_sequence() == seq && msg.body_slice() == &body[..],
+                    Err(_) => false,
+                }
+            }
+            quickcheck::quickcheck(property as fn(u8, Vec<u8>) -> bool);
+        }
+
+        #[test]
+        fn corrupted_body_is_detected() {
+            fn property(body: Vec<u8>, index: usize, flip: u8) -> bool {
+                let mut body = body;
+                body.truncate(Message::MAX_BODY_SIZE);
+                let msg = Message::new(0, &body).unwrap();
+                let mut stream = msg.as_slice().to_vec();
+                // Body and checksum.
+                let index = Message::BODY_START_POSITION
+                    + index % (stream.len() - Message::BODY_START_POSITION);
+                stream[index] ^= flip.max(1);
+                decode(&stream).is_err()
+            }
+            quickcheck::quickcheck(property as fn(Vec<u8>, usize, u8) -> bool);
+        }
+
         #[test]
         fn try_from_array_is_ok() {
             let mut buffer: MessageBuffer = [0; Message::MAX_SIZE];
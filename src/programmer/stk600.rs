@@ -0,0 +1,136 @@
+//! STK600 development board.
+//!
+//! Speaks STK500v2 commands over USB. Target chip sits in a socket card connected through a
+//! routing card matching the chip, so cards are checked before entering programming mode.
+//! Which cards fit which chip is not part of [`specs`], so only cards named by
+//! [`STK600::expect_cards`] are compared. Otherwise any routing card is accepted.
+//! PDI and TPI chips are programmed with XPROG commands of [`STK500v2`].
+use super::stk500v2::{param, IspMode, STK500v2, UsbLink};
+use crate::errors;
+use crate::specs;
+use std::convert::TryInto;
+
+pub const VID: u16 = 0x03eb;
+pub const PID: u16 = 0x2106;
+const ENDPOINT_IN: u8 = 0x83;
+const ENDPOINT_OUT: u8 = 0x02;
+/// Card id read when card is not mounted.
+const NO_CARD: u8 = 0xff;
+/// SCK of STK600 is 8 MHz divided by `SckDuration` + 1.
+const SCK_CLOCK: u32 = 8_000_000;
+/// Largest `SckDuration` accepted by firmware.
+const MAX_SCK_DURATION: u16 = 4095;
+
+/// Cards mounted on STK600. Ids are printed on the cards.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cards {
+    pub routing: u8,
+    pub socket: u8,
+}
+
+pub struct STK600 {
+    prog: STK500v2,
+    expected: Option<Cards>,
+}
+
+impl STK600 {
+    /// Open first STK600 found on USB.
+    pub fn open(specs: specs::Specs) -> Result<STK600, errors::ErrorKind> {
+        let link = UsbLink::open(VID, PID, ENDPOINT_IN, ENDPOINT_OUT)?;
+        Ok(STK600 {
+            prog: STK500v2::new(Box::new(link), specs),
+            expected: None,
+        })
+    }
+
+    /// Refuse to program unless `cards` are mounted, e.g. ones listed for the chip in STK600
+    /// user guide.
+    pub fn expect_cards(mut self, cards: Cards) -> STK600 {
+        self.expected = Some(cards);
+        self
+    }
+
+    /// Access STK500v2 commands, e.g. XPROG.
+    pub fn programmer(&mut self) -> &mut STK500v2 {
+        &mut self.prog
+    }
+
+    /// Use as plain STK500v2 programmer, e.g. for TPI.
+    pub fn into_programmer(self) -> STK500v2 {
+        self.prog
+    }
+
+    /// Read ids of mounted cards.
+    pub fn cards(&mut self) -> Result<Cards, errors::ErrorKind> {
+        Ok(Cards {
+            routing: self.prog.get_param(param::RO::RoutingCardId)?,
+            socket: self.prog.get_param(param::RO::SocketCardId)?,
+        })
+    }
+
+    /// Make sure target can be reached. Without routing card, target is not connected to
+    /// programmer at all. Wrong card connects chip pins to wrong signals.
+    pub fn check_cards(&mut self) -> Result<Cards, errors::ErrorKind> {
+        let cards = self.cards()?;
+        if cards.routing == NO_CARD {
+            return Err(errors::ErrorKind::Unsupported(String::from(
+                "STK600 has no routing card mounted",
+            )));
+        }
+        match &self.expected {
+            Some(expected) if *expected != cards => Err(errors::ErrorKind::Unsupported(format!(
+                "STK600 with routing card {:#04x} and socket card {:#04x}, expected \
+                 {:#04x} and {:#04x}",
+                cards.routing, cards.socket, expected.routing, expected.socket
+            ))),
+            _ => Ok(cards),
+        }
+    }
+
+    /// Set ISP clock to the fastest not above `frequency` Hz. Returns frequency which was set.
+    /// STK600 has 16 bit `SckDuration` of its own, instead of the one of STK500.
+    pub fn set_sck_frequency(&mut self, frequency: u32) -> Result<u32, errors::ErrorKind> {
+        let duration = sck_duration(frequency);
+        self.prog.set_param2(param::RW2::SckDuration, duration)?;
+        Ok(sck_frequency(duration))
+    }
+
+    /// Read ISP clock frequency in Hz.
+    pub fn get_sck_frequency(&mut self) -> Result<u32, errors::ErrorKind> {
+        Ok(sck_frequency(
+            self.prog.get_param2(param::RW2::SckDuration)?,
+        ))
+    }
+}
+
+/// `SckDuration` of the fastest SCK not above `frequency`.
+fn sck_duration(frequency: u32) -> u16 {
+    let duration = SCK_CLOCK.div_ceil(frequency.max(1)).saturating_sub(1);
+    duration.min(u32::from(MAX_SCK_DURATION)) as u16
+}
+
+fn sck_frequency(duration: u16) -> u32 {
+    SCK_CLOCK / (u32::from(duration) + 1)
+}
+
+impl TryInto<IspMode> for STK600 {
+    type Error = errors::ErrorKind;
+    fn try_into(mut self) -> Result<IspMode, Self::Error> {
+        self.check_cards()?;
+        self.prog.try_into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sck_not_above_requested() {
+        assert_eq!(sck_duration(8_000_000), 0);
+        assert_eq!(sck_duration(1_000_000), 7);
+        assert_eq!(sck_frequency(sck_duration(3_000_000)), 2_666_666);
+        assert_eq!(sck_duration(1), MAX_SCK_DURATION);
+        assert_eq!(sck_duration(0), MAX_SCK_DURATION);
+    }
+}
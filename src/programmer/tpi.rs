@@ -0,0 +1,236 @@
+//! TPI programming of ATtiny4/5/9/10/20/40 with XPROG capable STK500v2 programmer, e.g. STK600
+//! or AVRISP mkII.
+//!
+//! Programmer does TPI framing, NVM commands are sent as XPROG commands. Chip is described by
+//! [`TpiSpecs`]. Its single configuration byte is exposed as low fuse.
+use super::stk500v2::{xprog, STK500v2};
+use crate::errors;
+use crate::programmer::{self, MemoryType};
+use crate::specs::tpi::{self, TpiSpecs};
+use crate::specs::{Memory, Signature};
+use std::convert::TryInto;
+
+/// Value of fuse byte not present in chip.
+const NO_FUSE: u8 = 0xff;
+
+/// Non-volatile lock bits. Other bits of lock byte are unused.
+const NVLB: u8 = 0x03;
+
+/// Check if configuration byte turns reset pin into IO. Bits are active low.
+fn reset_disabled(specs: &TpiSpecs, config: u8) -> bool {
+    specs
+        .fuse_bits
+        .iter()
+        .any(|bits| bits.name == "RSTDISBL" && bits.value(config) == 0)
+}
+
+pub struct Tpi {
+    prog: STK500v2,
+    specs: TpiSpecs,
+}
+
+impl Tpi {
+    /// Use STK500v2 programmer for TPI. ISP specs it was opened with are not used.
+    pub fn new(prog: STK500v2, specs: TpiSpecs) -> Tpi {
+        Tpi { prog, specs }
+    }
+}
+
+impl TryInto<ProgMode> for Tpi {
+    type Error = errors::ErrorKind;
+    fn try_into(mut self) -> Result<ProgMode, Self::Error> {
+        self.prog.xprog_set_mode(xprog::Mode::Tpi)?;
+        self.prog
+            .xprog_set_param(xprog::Param::NvmCmdRegister(tpi::NVMCMD))?;
+        self.prog
+            .xprog_set_param(xprog::Param::NvmCsrRegister(tpi::NVMCSR))?;
+        self.prog.xprog_enter()?;
+        Ok(ProgMode { prog: self })
+    }
+}
+
+pub struct ProgMode {
+    prog: Tpi,
+}
+
+impl ProgMode {
+    fn read_pages(&mut self, address: usize, buffer: &mut [u8]) -> Result<(), errors::ErrorKind> {
+        let size = self.prog.specs.flash.page_size;
+        for (page, chunk) in buffer.chunks_mut(size).enumerate() {
+            let start = (address + page * size) as u32;
+            self.prog
+                .prog
+                .xprog_read(xprog::Memory::Application, start, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Write flash pages. Flash is written in words, so odd length is padded with 0xFF.
+    fn write_flash_pages(&mut self, address: usize, bytes: &[u8]) -> Result<(), errors::ErrorKind> {
+        let flash = self.prog.specs.flash.clone();
+        for (page, chunk) in bytes.chunks(flash.page_size).enumerate() {
+            // Flash is erased already.
+            if programmer::is_erased(chunk) {
+                continue;
+            }
+            let mut data = chunk.to_vec();
+            if data.len() % 2 == 1 {
+                data.push(0xff);
+            }
+            let start = (flash.start + address + page * flash.page_size) as u32;
+            self.prog.prog.xprog_write(
+                xprog::Memory::Application,
+                xprog::PAGE_WRITE,
+                start,
+                &data,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn read_byte(
+        &mut self,
+        memory: xprog::Memory,
+        address: usize,
+    ) -> Result<u8, errors::ErrorKind> {
+        let mut byte = [0];
+        self.prog
+            .prog
+            .xprog_read(memory, address as u32, &mut byte)?;
+        Ok(byte[0])
+    }
+
+    /// Write single byte. NVM writes whole words, high byte is left unprogrammed.
+    fn write_byte(
+        &mut self,
+        memory: xprog::Memory,
+        address: usize,
+        byte: u8,
+    ) -> Result<(), errors::ErrorKind> {
+        self.prog
+            .prog
+            .xprog_write(memory, xprog::PAGE_WRITE, address as u32, &[byte, 0xff])
+    }
+}
+
+/// Only flash is accessible. Chips have no EEPROM.
+impl programmer::MemoryOps for ProgMode {
+    fn layout(&self, memory: MemoryType) -> Result<Memory, errors::ErrorKind> {
+        match memory {
+            MemoryType::Flash => Ok(self.prog.specs.flash.clone()),
+            _ => Err(programmer::unsupported(memory)),
+        }
+    }
+
+    fn read_at(
+        &mut self,
+        memory: MemoryType,
+        address: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), errors::ErrorKind> {
+        if memory != MemoryType::Flash {
+            return Err(programmer::unsupported(memory));
+        }
+        let start = self.prog.specs.flash.start;
+        self.read_pages(start + address, buffer)
+    }
+
+    fn write_at(
+        &mut self,
+        memory: MemoryType,
+        address: usize,
+        bytes: &[u8],
+    ) -> Result<(), errors::ErrorKind> {
+        if memory != MemoryType::Flash {
+            return Err(programmer::unsupported(memory));
+        }
+        self.write_flash_pages(address, bytes)
+    }
+}
+
+impl programmer::Erase for ProgMode {
+    fn erase(&mut self) -> Result<(), errors::ErrorKind> {
+        // Chip erase is started by write to high byte of any flash word.
+        let address = (self.prog.specs.flash.start + 1) as u32;
+        self.prog.prog.xprog_erase(xprog::Erase::Chip, address)
+    }
+}
+
+impl programmer::Programmer for ProgMode {
+    fn close(mut self) -> Result<(), errors::ErrorKind> {
+        self.prog.prog.xprog_leave()
+    }
+}
+
+impl programmer::MCUSignature for ProgMode {
+    fn get_mcu_signature(&mut self) -> Result<Signature, errors::ErrorKind> {
+        let mut signature = [0; 3];
+        self.prog.prog.xprog_read(
+            xprog::Memory::Application,
+            tpi::SIGNATURE as u32,
+            &mut signature,
+        )?;
+        Ok(Signature::from(signature))
+    }
+}
+
+impl programmer::AVRFuseGet for ProgMode {
+    /// Configuration byte is returned as low fuse.
+    fn get_fuses(&mut self) -> Result<programmer::AVRFuse, errors::ErrorKind> {
+        let config = self.read_byte(xprog::Memory::Fuse, tpi::CONFIGURATION)?;
+        Ok(programmer::AVRFuse::new(config, NO_FUSE, NO_FUSE))
+    }
+}
+
+impl programmer::AVRFuseSet for ProgMode {
+    /// Write low fuse to configuration byte. Disabled reset pin would leave chip reachable only
+    /// with 12V programming, so it is refused.
+    fn set_fuses(
+        &mut self,
+        fuses: &programmer::AVRFuse,
+    ) -> Result<programmer::AVRFuse, errors::ErrorKind> {
+        let config = fuses.get(crate::specs::Fuse::Low);
+        if reset_disabled(&self.prog.specs, config) {
+            return Err(errors::ErrorKind::UnsafeFuses);
+        }
+        self.prog
+            .prog
+            .xprog_erase(xprog::Erase::Config, tpi::CONFIGURATION as u32)?;
+        self.write_byte(xprog::Memory::Fuse, tpi::CONFIGURATION, config)?;
+        let read = programmer::AVRFuseGet::get_fuses(self)?;
+        programmer::check_fuses(self.prog.specs.fuse_bits, fuses, read)
+    }
+}
+
+impl programmer::AVRLockByteGet for ProgMode {
+    fn get_lock_byte(&mut self) -> Result<u8, errors::ErrorKind> {
+        self.read_byte(xprog::Memory::LockBits, tpi::LOCK_BITS)
+    }
+}
+
+impl programmer::AVRLockByteSet for ProgMode {
+    /// Only chip erase sets lock bits back to 1.
+    fn set_lock_byte(&mut self, byte: u8) -> Result<u8, errors::ErrorKind> {
+        programmer::write_lock(self, NVLB, byte, |mode, byte| {
+            mode.write_byte(xprog::Memory::LockBits, tpi::LOCK_BITS, byte)
+        })
+    }
+}
+
+impl programmer::AVROsccalGet for ProgMode {
+    fn get_osccal(&mut self) -> Result<u8, errors::ErrorKind> {
+        self.read_byte(xprog::Memory::FactoryCalibration, tpi::CALIBRATION)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reset_disabled_config() {
+        assert!(!reset_disabled(&tpi::ATTINY_10, 0xff));
+        assert!(!reset_disabled(&tpi::ATTINY_10, 0xfb));
+        assert!(reset_disabled(&tpi::ATTINY_10, 0xfe));
+    }
+}
@@ -0,0 +1,708 @@
+//! UPDI programming over plain USB-serial adapter (serialupdi).
+//!
+//! TX and RX are joined through a resistor, so each sent byte is echoed back. Every instruction
+//! starts with `SYNC` byte. Memories, NVM controller and fuses are accessed through data space.
+//! Chip is described by [`UpdiSpecs`], not by ISP [`Specs`].
+//!
+//! [`Specs`]: crate::specs::Specs
+use crate::errors;
+use crate::programmer::{self, MemoryType};
+use crate::specs::updi::{NvmVersion, UpdiSpecs};
+use crate::specs::{Memory, Signature};
+use serial::core::{PortSettings, SerialPort, SerialPortSettings};
+use std::convert::TryInto;
+use std::io::prelude::*;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod instruction {
+    pub const LDS: u8 = 0x00;
+    pub const STS: u8 = 0x40;
+    pub const LD: u8 = 0x20;
+    pub const ST: u8 = 0x60;
+    pub const LDCS: u8 = 0x80;
+    pub const STCS: u8 = 0xc0;
+    pub const REPEAT: u8 = 0xa0;
+    pub const KEY: u8 = 0xe0;
+
+    pub const PTR_INC: u8 = 0x04;
+    pub const PTR_ADDRESS: u8 = 0x08;
+
+    pub const ADDRESS_16: u8 = 0x04;
+    pub const ADDRESS_24: u8 = 0x08;
+
+    pub const DATA_8: u8 = 0x00;
+    pub const DATA_16: u8 = 0x01;
+    pub const DATA_24: u8 = 0x02;
+}
+
+/// Control and status registers.
+mod cs {
+    pub const STATUSA: u8 = 0x00;
+    pub const CTRLA: u8 = 0x02;
+    pub const CTRLB: u8 = 0x03;
+    pub const ASI_KEY_STATUS: u8 = 0x07;
+    pub const ASI_RESET_REQ: u8 = 0x08;
+    pub const ASI_SYS_STATUS: u8 = 0x0b;
+
+    /// Inter-byte delay. Gives slow adapters time to turn around.
+    pub const CTRLA_IBDLY: u8 = 1 << 7;
+    /// Collision detection disable.
+    pub const CTRLB_CCDETDIS: u8 = 1 << 3;
+    pub const CTRLB_UPDIDIS: u8 = 1 << 2;
+
+    pub const KEY_STATUS_CHIPERASE: u8 = 1 << 3;
+    pub const KEY_STATUS_NVMPROG: u8 = 1 << 4;
+
+    pub const SYS_STATUS_NVMPROG: u8 = 1 << 3;
+    pub const SYS_STATUS_LOCKSTATUS: u8 = 1 << 0;
+
+    pub const RESET_REQ: u8 = 0x59;
+}
+
+mod key {
+    pub const NVMPROG: &[u8; 8] = b"NVMProg ";
+    pub const CHIPERASE: &[u8; 8] = b"NVMErase";
+}
+
+/// NVM controller registers and commands.
+mod nvm {
+    pub const BASE: usize = 0x1000;
+    pub const CTRLA: usize = BASE;
+    pub const STATUS: usize = BASE + 0x02;
+    pub const DATA: usize = BASE + 0x06;
+    pub const ADDR: usize = BASE + 0x08;
+
+    pub const STATUS_FBUSY: u8 = 1 << 0;
+    pub const STATUS_EEBUSY: u8 = 1 << 1;
+    /// Write error bit of version 0.
+    pub const V0_WRERROR: u8 = 1 << 2;
+    /// Error code bits of version 2.
+    pub const V2_ERROR: u8 = 0x70;
+
+    pub mod v0 {
+        pub const WRITE_PAGE: u8 = 0x01;
+        pub const ERASE_PAGE: u8 = 0x02;
+        pub const ERASE_WRITE_PAGE: u8 = 0x03;
+        pub const PAGE_BUFFER_CLEAR: u8 = 0x04;
+        pub const WRITE_FUSE: u8 = 0x07;
+    }
+
+    pub mod v2 {
+        pub const NOCMD: u8 = 0x00;
+        pub const FLASH_WRITE: u8 = 0x02;
+        pub const FLASH_PAGE_ERASE: u8 = 0x08;
+        pub const EEPROM_ERASE_WRITE: u8 = 0x13;
+    }
+}
+
+const SYNC: u8 = 0x55;
+const ACK: u8 = 0x40;
+/// Signature row address.
+const SIGROW: usize = 0x1100;
+/// Part of signature row holding factory data of both NVM versions.
+const SIGROW_SIZE: usize = 0x30;
+/// Longest block transferred with single `REPEAT`.
+const MAX_REPEAT: usize = 256;
+const NVM_TIMEOUT: Duration = Duration::from_secs(1);
+const PROGMODE_TIMEOUT: Duration = Duration::from_millis(100);
+
+const SERIAL_SETTINGS: PortSettings = PortSettings {
+    baud_rate: serial::Baud115200,
+    char_size: serial::Bits8,
+    parity: serial::ParityEven,
+    stop_bits: serial::Stop2,
+    flow_control: serial::FlowNone,
+};
+
+/// Instruction loading or storing single value at `address`.
+fn direct(opcode: u8, address: usize, address_24: bool) -> Vec<u8> {
+    let bytes = (address as u32).to_le_bytes();
+    if address_24 {
+        vec![
+            SYNC,
+            opcode | instruction::ADDRESS_24 | instruction::DATA_8,
+            bytes[0],
+            bytes[1],
+            bytes[2],
+        ]
+    } else {
+        vec![
+            SYNC,
+            opcode | instruction::ADDRESS_16 | instruction::DATA_8,
+            bytes[0],
+            bytes[1],
+        ]
+    }
+}
+
+/// Key instruction. Key is sent starting with its last byte.
+fn key_instruction(key: &[u8; 8]) -> Vec<u8> {
+    let mut bytes = vec![SYNC, instruction::KEY];
+    bytes.extend(key.iter().rev());
+    bytes
+}
+
+/// UPDI data link over serial port.
+struct Link {
+    port: serial::SystemPort,
+    address_24: bool,
+}
+
+impl Link {
+    fn open(port: &str, address_24: bool) -> Result<Link, errors::ErrorKind> {
+        let mut port = serial::open(port).map_err(std::io::Error::from)?;
+        port.configure(&SERIAL_SETTINGS)
+            .map_err(std::io::Error::from)?;
+        port.set_timeout(Duration::from_millis(100))
+            .map_err(std::io::Error::from)?;
+        let mut link = Link { port, address_24 };
+        if link.init().is_err() {
+            // UPDI may be in unknown state. Reset it with break.
+            link.double_break()?;
+            link.init()?;
+        }
+        Ok(link)
+    }
+
+    /// Send bytes and consume their echo.
+    fn send(&mut self, bytes: &[u8]) -> Result<(), errors::ErrorKind> {
+        self.port.write_all(bytes)?;
+        self.port.flush()?;
+        let mut echo = vec![0; bytes.len()];
+        self.port.read_exact(&mut echo)?;
+        if echo != bytes {
+            return Err(errors::ErrorKind::AnswerIdError);
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, size: usize) -> Result<Vec<u8>, errors::ErrorKind> {
+        let mut bytes = vec![0; size];
+        self.port.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn ack(&mut self) -> Result<(), errors::ErrorKind> {
+        if self.receive(1)?[0] != ACK {
+            return Err(errors::ErrorKind::StatusError);
+        }
+        Ok(())
+    }
+
+    /// Two breaks (long low level) reset UPDI. Sent as zero byte at low baud rate.
+    fn double_break(&mut self) -> Result<(), errors::ErrorKind> {
+        let baud = |rate| move |settings: &mut dyn SerialPortSettings| settings.set_baud_rate(rate);
+        self.port
+            .reconfigure(&baud(serial::Baud300))
+            .map_err(std::io::Error::from)?;
+        for _ in 0..2 {
+            self.port.write_all(&[0])?;
+            // Echo of break is not a valid byte.
+            let _ = self.receive(1);
+        }
+        self.port
+            .reconfigure(&baud(SERIAL_SETTINGS.baud_rate))
+            .map_err(std::io::Error::from)?;
+        Ok(())
+    }
+
+    /// Configure UPDI and check that it answers.
+    fn init(&mut self) -> Result<(), errors::ErrorKind> {
+        self.stcs(cs::CTRLB, cs::CTRLB_CCDETDIS)?;
+        self.stcs(cs::CTRLA, cs::CTRLA_IBDLY)?;
+        if self.ldcs(cs::STATUSA)? == 0 {
+            return Err(errors::ErrorKind::StatusError);
+        }
+        Ok(())
+    }
+
+    fn ldcs(&mut self, register: u8) -> Result<u8, errors::ErrorKind> {
+        self.send(&[SYNC, instruction::LDCS | register])?;
+        Ok(self.receive(1)?[0])
+    }
+
+    fn stcs(&mut self, register: u8, value: u8) -> Result<(), errors::ErrorKind> {
+        self.send(&[SYNC, instruction::STCS | register, value])
+    }
+
+    fn lds(&mut self, address: usize) -> Result<u8, errors::ErrorKind> {
+        self.send(&direct(instruction::LDS, address, self.address_24))?;
+        Ok(self.receive(1)?[0])
+    }
+
+    fn sts(&mut self, address: usize, value: u8) -> Result<(), errors::ErrorKind> {
+        self.send(&direct(instruction::STS, address, self.address_24))?;
+        self.ack()?;
+        self.send(&[value])?;
+        self.ack()
+    }
+
+    fn key(&mut self, key: &[u8; 8]) -> Result<(), errors::ErrorKind> {
+        self.send(&key_instruction(key))
+    }
+
+    fn set_pointer(&mut self, address: usize) -> Result<(), errors::ErrorKind> {
+        let bytes = (address as u32).to_le_bytes();
+        if self.address_24 {
+            self.send(&[
+                SYNC,
+                instruction::ST | instruction::PTR_ADDRESS | instruction::DATA_24,
+                bytes[0],
+                bytes[1],
+                bytes[2],
+            ])?;
+        } else {
+            self.send(&[
+                SYNC,
+                instruction::ST | instruction::PTR_ADDRESS | instruction::DATA_16,
+                bytes[0],
+                bytes[1],
+            ])?;
+        }
+        self.ack()
+    }
+
+    fn repeat(&mut self, count: usize) -> Result<(), errors::ErrorKind> {
+        self.send(&[SYNC, instruction::REPEAT, (count - 1) as u8])
+    }
+
+    /// Read block starting at `address`.
+    fn read(&mut self, address: usize, buffer: &mut [u8]) -> Result<(), errors::ErrorKind> {
+        for (i, chunk) in buffer.chunks_mut(MAX_REPEAT).enumerate() {
+            self.set_pointer(address + i * MAX_REPEAT)?;
+            if chunk.len() > 1 {
+                self.repeat(chunk.len())?;
+            }
+            self.send(&[
+                SYNC,
+                instruction::LD | instruction::PTR_INC | instruction::DATA_8,
+            ])?;
+            chunk.copy_from_slice(&self.receive(chunk.len())?);
+        }
+        Ok(())
+    }
+
+    /// Write block starting at `address`, in bytes or in words.
+    fn write(&mut self, address: usize, data: &[u8], words: bool) -> Result<(), errors::ErrorKind> {
+        let (size, data_size) = if words {
+            (2, instruction::DATA_16)
+        } else {
+            (1, instruction::DATA_8)
+        };
+        for (i, chunk) in data.chunks(MAX_REPEAT * size).enumerate() {
+            self.set_pointer(address + i * MAX_REPEAT * size)?;
+            let count = chunk.len() / size;
+            if count > 1 {
+                self.repeat(count)?;
+            }
+            self.send(&[SYNC, instruction::ST | instruction::PTR_INC | data_size])?;
+            for value in chunk.chunks(size) {
+                self.send(value)?;
+                self.ack()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reset target. Left in reset if `release` is false.
+    fn reset(&mut self, release: bool) -> Result<(), errors::ErrorKind> {
+        self.stcs(cs::ASI_RESET_REQ, cs::RESET_REQ)?;
+        if release {
+            self.stcs(cs::ASI_RESET_REQ, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Wait until `mask` bits of system status equal `value`.
+    fn wait_sys_status(&mut self, mask: u8, value: u8) -> Result<(), errors::ErrorKind> {
+        let deadline = Instant::now() + PROGMODE_TIMEOUT;
+        while self.ldcs(cs::ASI_SYS_STATUS)? & mask != value {
+            if Instant::now() > deadline {
+                return Err(errors::ErrorKind::Io(std::io::ErrorKind::TimedOut.into()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Programmer made of USB-serial adapter connected to UPDI pin.
+pub struct Updi {
+    link: Link,
+    specs: UpdiSpecs,
+}
+
+impl Updi {
+    pub fn open(port: &str, specs: UpdiSpecs) -> Result<Updi, errors::ErrorKind> {
+        Ok(Updi {
+            link: Link::open(port, specs.address_24())?,
+            specs,
+        })
+    }
+
+    /// Erase whole chip with erase key. Works also on locked chips, so it can be done before
+    /// entering programming mode.
+    pub fn erase(&mut self) -> Result<(), errors::ErrorKind> {
+        self.link.key(key::CHIPERASE)?;
+        if self.link.ldcs(cs::ASI_KEY_STATUS)? & cs::KEY_STATUS_CHIPERASE == 0 {
+            return Err(errors::ErrorKind::StatusError);
+        }
+        self.link.reset(true)?;
+        self.link.wait_sys_status(cs::SYS_STATUS_LOCKSTATUS, 0)
+    }
+
+    fn enter_progmode(&mut self) -> Result<(), errors::ErrorKind> {
+        if self.link.ldcs(cs::ASI_SYS_STATUS)? & cs::SYS_STATUS_NVMPROG != 0 {
+            return Ok(());
+        }
+        self.link.key(key::NVMPROG)?;
+        if self.link.ldcs(cs::ASI_KEY_STATUS)? & cs::KEY_STATUS_NVMPROG == 0 {
+            return Err(errors::ErrorKind::StatusError);
+        }
+        self.link.reset(true)?;
+        self.link
+            .wait_sys_status(cs::SYS_STATUS_NVMPROG, cs::SYS_STATUS_NVMPROG)
+    }
+}
+
+impl TryInto<ProgMode> for Updi {
+    type Error = errors::ErrorKind;
+    fn try_into(mut self) -> Result<ProgMode, Self::Error> {
+        self.enter_progmode()?;
+        Ok(ProgMode { prog: self })
+    }
+}
+
+pub struct ProgMode {
+    prog: Updi,
+}
+
+impl ProgMode {
+    /// Access programmer.
+    pub fn programmer(&mut self) -> &mut Updi {
+        &mut self.prog
+    }
+
+    fn nvm_wait(&mut self) -> Result<(), errors::ErrorKind> {
+        let error = match self.prog.specs.nvm {
+            NvmVersion::V0 => nvm::V0_WRERROR,
+            NvmVersion::V2 => nvm::V2_ERROR,
+        };
+        let deadline = Instant::now() + NVM_TIMEOUT;
+        loop {
+            let status = self.prog.link.lds(nvm::STATUS)?;
+            if status & error != 0 {
+                return Err(errors::ErrorKind::StatusError);
+            }
+            if status & (nvm::STATUS_FBUSY | nvm::STATUS_EEBUSY) == 0 {
+                return Ok(());
+            }
+            if Instant::now() > deadline {
+                return Err(errors::ErrorKind::Io(std::io::ErrorKind::TimedOut.into()));
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn nvm_command(&mut self, command: u8) -> Result<(), errors::ErrorKind> {
+        self.prog.link.sts(nvm::CTRLA, command)
+    }
+
+    /// Write pages of memory mapped at data space `address`. Pages are erased first if `erase`.
+    fn write_pages(
+        &mut self,
+        address: usize,
+        page_size: usize,
+        data: &[u8],
+        flash: bool,
+        erase: bool,
+    ) -> Result<(), errors::ErrorKind> {
+        for (page, chunk) in data.chunks(page_size).enumerate() {
+            // Erased flash page is left as it is, unless it has to be erased.
+            if flash && !erase && programmer::is_erased(chunk) {
+                continue;
+            }
+            let start = address + page * page_size;
+            let mut chunk = chunk.to_vec();
+            // Flash is written in words.
+            if flash && chunk.len() % 2 == 1 {
+                chunk.push(0xff);
+            }
+            self.nvm_wait()?;
+            match self.prog.specs.nvm {
+                NvmVersion::V0 => {
+                    self.nvm_command(nvm::v0::PAGE_BUFFER_CLEAR)?;
+                    self.nvm_wait()?;
+                    self.prog.link.write(start, &chunk, flash)?;
+                    let command = if erase || !flash {
+                        nvm::v0::ERASE_WRITE_PAGE
+                    } else {
+                        nvm::v0::WRITE_PAGE
+                    };
+                    self.nvm_command(command)?;
+                }
+                NvmVersion::V2 if flash => {
+                    if erase {
+                        self.nvm_command(nvm::v2::FLASH_PAGE_ERASE)?;
+                        // Write to any address in page starts erase.
+                        self.prog.link.sts(start, 0xff)?;
+                        self.nvm_wait()?;
+                    }
+                    self.nvm_command(nvm::v2::FLASH_WRITE)?;
+                    self.prog.link.write(start, &chunk, true)?;
+                }
+                NvmVersion::V2 => {
+                    self.nvm_command(nvm::v2::EEPROM_ERASE_WRITE)?;
+                    self.prog.link.write(start, &chunk, false)?;
+                }
+            }
+            self.nvm_wait()?;
+            if self.prog.specs.nvm == NvmVersion::V2 {
+                self.nvm_command(nvm::v2::NOCMD)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_flash(
+        &mut self,
+        address: usize,
+        bytes: &[u8],
+        erase: bool,
+    ) -> Result<(), errors::ErrorKind> {
+        let flash = self.prog.specs.flash.clone();
+        self.write_pages(flash.start + address, flash.page_size, bytes, true, erase)
+    }
+
+    /// User row is EEPROM on NVM version 0 and flash on version 2. Both are erased first.
+    fn write_usersig(&mut self, address: usize, bytes: &[u8]) -> Result<(), errors::ErrorKind> {
+        let usersig = self.prog.specs.usersig.clone();
+        let flash = self.prog.specs.nvm == NvmVersion::V2;
+        self.write_pages(
+            usersig.start + address,
+            usersig.page_size,
+            bytes,
+            flash,
+            true,
+        )
+    }
+
+    /// Erase whole user row. Chip erase leaves it intact.
+    pub fn erase_usersig(&mut self) -> Result<(), errors::ErrorKind> {
+        let usersig = self.prog.specs.usersig.clone();
+        for start in (usersig.start..usersig.start + usersig.size).step_by(usersig.page_size) {
+            self.nvm_wait()?;
+            match self.prog.specs.nvm {
+                NvmVersion::V0 => {
+                    self.nvm_command(nvm::v0::PAGE_BUFFER_CLEAR)?;
+                    self.nvm_wait()?;
+                    // Page buffer write selects the page.
+                    self.prog.link.sts(start, 0xff)?;
+                    self.nvm_command(nvm::v0::ERASE_PAGE)?;
+                }
+                NvmVersion::V2 => {
+                    self.nvm_command(nvm::v2::FLASH_PAGE_ERASE)?;
+                    // Write to any address in page starts erase.
+                    self.prog.link.sts(start, 0xff)?;
+                }
+            }
+            self.nvm_wait()?;
+            if self.prog.specs.nvm == NvmVersion::V2 {
+                self.nvm_command(nvm::v2::NOCMD)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write EEPROM bytes. Page erase-write cycles only loaded bytes.
+    fn write_eeprom(&mut self, address: usize, bytes: &[u8]) -> Result<(), errors::ErrorKind> {
+        let eeprom = self.prog.specs.eeprom.clone();
+        self.write_pages(eeprom.start + address, eeprom.page_size, bytes, false, true)
+    }
+
+    /// Read all fuse bytes.
+    pub fn read_fuses(&mut self) -> Result<Vec<u8>, errors::ErrorKind> {
+        let mut fuses = vec![0; self.prog.specs.fuse_count];
+        let address = self.prog.specs.fuses;
+        self.prog.link.read(address, &mut fuses)?;
+        Ok(fuses)
+    }
+
+    /// Write fuse at `index`. Returns value read back.
+    pub fn write_fuse(&mut self, index: usize, value: u8) -> Result<u8, errors::ErrorKind> {
+        if index >= self.prog.specs.fuse_count {
+            return Err(errors::ErrorKind::AddressOutOfRange {
+                address: index,
+                size: self.prog.specs.fuse_count,
+            });
+        }
+        let address = self.prog.specs.fuses + index;
+        match self.prog.specs.nvm {
+            NvmVersion::V0 => {
+                self.nvm_wait()?;
+                let bytes = (address as u16).to_le_bytes();
+                self.prog.link.sts(nvm::ADDR, bytes[0])?;
+                self.prog.link.sts(nvm::ADDR + 1, bytes[1])?;
+                self.prog.link.sts(nvm::DATA, value)?;
+                self.nvm_command(nvm::v0::WRITE_FUSE)?;
+                self.nvm_wait()?;
+            }
+            // Fuses are written as EEPROM.
+            NvmVersion::V2 => self.write_pages(address, 1, &[value], false, true)?,
+        }
+        self.prog.link.lds(address)
+    }
+
+    /// Read serial number and calibration values of the chip.
+    pub fn read_production_signature(&mut self) -> Result<ProductionSignature, errors::ErrorKind> {
+        let mut row = [0; SIGROW_SIZE];
+        self.prog.link.read(SIGROW, &mut row)?;
+        Ok(ProductionSignature::parse(self.prog.specs.nvm, &row))
+    }
+}
+
+impl programmer::MemoryOps for ProgMode {
+    /// Layout of flash, EEPROM or user row.
+    fn layout(&self, memory: MemoryType) -> Result<Memory, errors::ErrorKind> {
+        match memory {
+            MemoryType::Flash => Ok(self.prog.specs.flash.clone()),
+            MemoryType::Eeprom => Ok(self.prog.specs.eeprom.clone()),
+            MemoryType::UserSig => Ok(self.prog.specs.usersig.clone()),
+            _ => Err(programmer::unsupported(memory)),
+        }
+    }
+
+    fn read_at(
+        &mut self,
+        memory: MemoryType,
+        address: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), errors::ErrorKind> {
+        let start = self.layout(memory)?.start;
+        self.prog.link.read(start + address, buffer)
+    }
+
+    fn write_at(
+        &mut self,
+        memory: MemoryType,
+        address: usize,
+        bytes: &[u8],
+    ) -> Result<(), errors::ErrorKind> {
+        match memory {
+            MemoryType::Flash => self.write_flash(address, bytes, false),
+            MemoryType::Eeprom => self.write_eeprom(address, bytes),
+            MemoryType::UserSig => self.write_usersig(address, bytes),
+            _ => Err(programmer::unsupported(memory)),
+        }
+    }
+
+    /// NVM controller erases single pages, so any value can be written.
+    fn patch_at(
+        &mut self,
+        memory: MemoryType,
+        address: usize,
+        _current: &[u8],
+        bytes: &[u8],
+    ) -> Result<(), errors::ErrorKind> {
+        match memory {
+            MemoryType::Flash => self.write_flash(address, bytes, true),
+            _ => self.write_at(memory, address, bytes),
+        }
+    }
+}
+
+impl programmer::Erase for ProgMode {
+    fn erase(&mut self) -> Result<(), errors::ErrorKind> {
+        self.prog.erase()?;
+        // Reset after erase leaves programming mode.
+        self.prog.enter_progmode()
+    }
+}
+
+impl programmer::Programmer for ProgMode {
+    /// Leave programming mode and start application.
+    fn close(mut self) -> Result<(), errors::ErrorKind> {
+        self.prog.link.reset(true)?;
+        self.prog
+            .link
+            .stcs(cs::CTRLB, cs::CTRLB_UPDIDIS | cs::CTRLB_CCDETDIS)
+    }
+}
+
+impl programmer::MCUSignature for ProgMode {
+    fn get_mcu_signature(&mut self) -> Result<Signature, errors::ErrorKind> {
+        let mut signature = [0; 3];
+        self.prog.link.read(SIGROW, &mut signature)?;
+        Ok(Signature::from(signature))
+    }
+}
+
+/// Production signature row, written at factory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProductionSignature {
+    pub device_id: Signature,
+    /// Unique per chip. 10 bytes on NVM version 0, 16 bytes on version 2.
+    pub serial_number: Vec<u8>,
+    /// Temperature sensor calibration, `TEMPSENSE0` and `TEMPSENSE1`. Single bytes on NVM
+    /// version 0 (gain and signed offset), 16 bit words on version 2.
+    pub temp_sense: [u16; 2],
+}
+
+impl ProductionSignature {
+    /// Decode signature row read from its start.
+    fn parse(nvm: NvmVersion, row: &[u8]) -> ProductionSignature {
+        let device_id = Signature::from((row[0], row[1], row[2]));
+        match nvm {
+            NvmVersion::V0 => ProductionSignature {
+                device_id,
+                serial_number: row[0x03..0x0d].to_vec(),
+                temp_sense: [row[0x20] as u16, row[0x21] as u16],
+            },
+            NvmVersion::V2 => ProductionSignature {
+                device_id,
+                serial_number: row[0x10..0x20].to_vec(),
+                temp_sense: [
+                    u16::from_le_bytes([row[0x04], row[0x05]]),
+                    u16::from_le_bytes([row[0x06], row[0x07]]),
+                ],
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn production_signature_layout() {
+        let row: Vec<u8> = (0..SIGROW_SIZE as u8).collect();
+        let v0 = ProductionSignature::parse(NvmVersion::V0, &row);
+        assert_eq!(v0.device_id, Signature::from((0, 1, 2)));
+        assert_eq!(v0.serial_number, (3..13).collect::<Vec<u8>>());
+        assert_eq!(v0.temp_sense, [0x20, 0x21]);
+        let v2 = ProductionSignature::parse(NvmVersion::V2, &row);
+        assert_eq!(v2.serial_number, (16..32).collect::<Vec<u8>>());
+        assert_eq!(v2.temp_sense, [0x0504, 0x0706]);
+    }
+
+    #[test]
+    fn direct_address_size() {
+        assert_eq!(
+            direct(instruction::LDS, 0x1102, false),
+            vec![0x55, 0x04, 0x02, 0x11]
+        );
+        assert_eq!(
+            direct(instruction::STS, 0x80_0100, true),
+            vec![0x55, 0x48, 0x00, 0x01, 0x80]
+        );
+    }
+
+    #[test]
+    fn key_is_reversed() {
+        assert_eq!(
+            key_instruction(key::NVMPROG),
+            vec![0x55, 0xe0, b' ', b'g', b'o', b'r', b'P', b'M', b'V', b'N']
+        );
+    }
+}
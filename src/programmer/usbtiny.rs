@@ -0,0 +1,385 @@
+//! USBtinyISP programmer (e.g. Adafruit USBtinyISP).
+//!
+//! There is no message framing. Each operation is a USB vendor control request. Memory is
+//! transferred in chunks, ISP instructions are sent one by one with `SPI` request.
+use crate::command as isp_command;
+use crate::errors;
+use crate::programmer::{self, MemoryType};
+use crate::specs;
+use rusb::{DeviceHandle, GlobalContext};
+use std::cell::Cell;
+use std::convert::TryInto;
+use std::thread;
+use std::time::Duration;
+
+pub const VID: u16 = 0x1781;
+pub const PID: u16 = 0x0c9f;
+
+#[allow(dead_code)]
+mod request {
+    pub const ECHO: u8 = 0;
+    pub const POWER_UP: u8 = 5;
+    pub const POWER_DOWN: u8 = 6;
+    pub const SPI: u8 = 7;
+    pub const POLL_BYTES: u8 = 8;
+    pub const FLASH_READ: u8 = 9;
+    pub const FLASH_WRITE: u8 = 10;
+    pub const EEPROM_READ: u8 = 11;
+    pub const EEPROM_WRITE: u8 = 12;
+}
+
+/// Index of `POWER_UP` request. Reset is active low.
+const RESET_LOW: u16 = 0;
+const RESET_HIGH: u16 = 1;
+/// Half period of SCK in microseconds. Slow enough for chips running at 1 MHz.
+const SCK_PERIOD: u16 = 10;
+/// Bytes transferred with single request.
+const CHUNK_SIZE: usize = 128;
+const TIMEOUT: Duration = Duration::from_millis(500);
+/// Time for target to start after reset.
+const RESET_DELAY: Duration = Duration::from_millis(50);
+/// Fuse and lock writes are not polled. Maximum write time from datasheets.
+const WRITE_DELAY: Duration = Duration::from_millis(5);
+
+/// Value and index of `SPI` request carrying ISP instruction.
+fn spi_setup(cmd: isp_command::IspCommand) -> (u16, u16) {
+    (
+        u16::from_le_bytes([cmd.0, cmd.1]),
+        u16::from_le_bytes([cmd.2, cmd.3]),
+    )
+}
+
+pub struct USBtiny {
+    handle: DeviceHandle<GlobalContext>,
+    specs: specs::Specs,
+    /// Control transfers made. USBtiny has no retries.
+    frames: Cell<usize>,
+}
+
+impl USBtiny {
+    /// Open first USBtiny found on USB.
+    pub fn open(specs: specs::Specs) -> Result<USBtiny, errors::ErrorKind> {
+        let handle = rusb::open_device_with_vid_pid(VID, PID)
+            .ok_or(errors::ErrorKind::Io(std::io::ErrorKind::NotFound.into()))?;
+        Ok(USBtiny {
+            handle,
+            specs,
+            frames: Cell::new(0),
+        })
+    }
+
+    fn control_in(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        buffer: &mut [u8],
+    ) -> Result<(), errors::ErrorKind> {
+        let request_type = rusb::request_type(
+            rusb::Direction::In,
+            rusb::RequestType::Vendor,
+            rusb::Recipient::Device,
+        );
+        self.frames.set(self.frames.get() + 1);
+        let size =
+            self.handle
+                .read_control(request_type, request, value, index, buffer, TIMEOUT)?;
+        if size != buffer.len() {
+            return Err(errors::ErrorKind::StatusError);
+        }
+        Ok(())
+    }
+
+    fn control_out(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> Result<(), errors::ErrorKind> {
+        let request_type = rusb::request_type(
+            rusb::Direction::Out,
+            rusb::RequestType::Vendor,
+            rusb::Recipient::Device,
+        );
+        self.frames.set(self.frames.get() + 1);
+        self.handle
+            .write_control(request_type, request, value, index, data, TIMEOUT)?;
+        Ok(())
+    }
+
+    /// Send 4 byte ISP instruction to target. Returns bytes shifted out by target.
+    fn spi(&self, cmd: isp_command::IspCommand) -> Result<[u8; 4], errors::ErrorKind> {
+        let (value, index) = spi_setup(cmd);
+        let mut answer = [0; 4];
+        self.control_in(request::SPI, value, index, &mut answer)?;
+        Ok(answer)
+    }
+
+    /// Power target, hold reset and enable programming. Pulses reset once if target does not
+    /// answer, as datasheets advise.
+    fn enter_isp_mode(&self) -> Result<(), errors::ErrorKind> {
+        for reset in &[RESET_LOW, RESET_HIGH] {
+            self.control_in(request::POWER_UP, SCK_PERIOD, *reset, &mut [])?;
+            thread::sleep(RESET_DELAY);
+            if *reset == RESET_HIGH {
+                self.control_in(request::POWER_UP, SCK_PERIOD, RESET_LOW, &mut [])?;
+                thread::sleep(RESET_DELAY);
+            }
+            if self.spi(isp_command::PROGRAMMING_ENABLE)?[2] == isp_command::PROGRAMMING_ENABLE.1 {
+                return Ok(());
+            }
+        }
+        Err(errors::ErrorKind::StatusError)
+    }
+}
+
+impl TryInto<IspMode> for USBtiny {
+    type Error = errors::ErrorKind;
+    fn try_into(self) -> Result<IspMode, Self::Error> {
+        // Firmware takes 16 bit byte address.
+        if self.specs.flash.size > 0x10000 {
+            return Err(errors::ErrorKind::Unsupported(format!(
+                "USBtiny can not address flash of {}",
+                self.specs.name
+            )));
+        }
+        self.enter_isp_mode()?;
+        Ok(IspMode { prog: self })
+    }
+}
+
+pub struct IspMode {
+    prog: USBtiny,
+}
+
+impl IspMode {
+    /// Access programmer.
+    pub fn programmer(&mut self) -> &mut USBtiny {
+        &mut self.prog
+    }
+
+    fn read_chunks(
+        &mut self,
+        request: u8,
+        address: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), errors::ErrorKind> {
+        for (i, chunk) in buffer.chunks_mut(CHUNK_SIZE).enumerate() {
+            let index = (address + i * CHUNK_SIZE) as u16;
+            self.prog.control_in(request, 0, index, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn read_flash_pages(
+        &mut self,
+        address: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), errors::ErrorKind> {
+        self.read_chunks(request::FLASH_READ, address, buffer)
+    }
+
+    /// Calibration byte at `address`. Chips with several oscillators or frequencies have one
+    /// byte for each.
+    fn read_osccal(&self, address: usize) -> Result<u8, errors::ErrorKind> {
+        let (cmd, high, _, low) = isp_command::READ_OSCCAL;
+        Ok(self.prog.spi((cmd, high, address as u8, low))?[3])
+    }
+
+    fn read_eeprom_pages(
+        &mut self,
+        address: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), errors::ErrorKind> {
+        self.read_chunks(request::EEPROM_READ, address, buffer)
+    }
+
+    /// Load whole pages starting at page aligned byte `address` and write each of them.
+    fn write_flash_pages(&mut self, address: usize, bytes: &[u8]) -> Result<(), errors::ErrorKind> {
+        let flash = self.prog.specs.flash.clone();
+        for (page, chunk) in bytes.chunks(flash.page_size).enumerate() {
+            // Only whole pages can be written. Fill the rest with erased value.
+            let mut data = chunk.to_vec();
+            data.resize(flash.page_size, 0xff);
+            // Flash is erased already.
+            if programmer::is_erased(&data) {
+                continue;
+            }
+            let start = address + page * flash.page_size;
+            for (i, part) in data.chunks(CHUNK_SIZE).enumerate() {
+                let index = (start + i * CHUNK_SIZE) as u16;
+                // Zero delay, firmware only loads page buffer.
+                self.prog
+                    .control_out(request::FLASH_WRITE, 0, index, part)?;
+            }
+            let word = start / 2;
+            let (cmd, _, _, last) = isp_command::WRITE_FLASH;
+            self.prog.spi((cmd, (word >> 8) as u8, word as u8, last))?;
+            thread::sleep(Duration::from_millis(flash.delay as u64));
+        }
+        Ok(())
+    }
+
+    /// Write EEPROM byte by byte. Firmware waits given delay after each byte.
+    fn write_eeprom_pages(
+        &mut self,
+        address: usize,
+        bytes: &[u8],
+    ) -> Result<(), errors::ErrorKind> {
+        let delay = self.prog.specs.eeprom.delay as u16;
+        for (i, chunk) in bytes.chunks(CHUNK_SIZE).enumerate() {
+            let index = (address + i * CHUNK_SIZE) as u16;
+            self.prog
+                .control_out(request::EEPROM_WRITE, delay, index, chunk)?;
+        }
+        Ok(())
+    }
+}
+
+impl programmer::MemoryOps for IspMode {
+    fn layout(&self, memory: MemoryType) -> Result<specs::Memory, errors::ErrorKind> {
+        programmer::layout(&self.prog.specs, memory).cloned()
+    }
+
+    fn read_at(
+        &mut self,
+        memory: MemoryType,
+        address: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), errors::ErrorKind> {
+        match memory {
+            MemoryType::Flash => self.read_flash_pages(address, buffer),
+            MemoryType::Eeprom => self.read_eeprom_pages(address, buffer),
+            MemoryType::Calibration => {
+                for (offset, byte) in buffer.iter_mut().enumerate() {
+                    *byte = self.read_osccal(address + offset)?;
+                }
+                Ok(())
+            }
+            _ => Err(programmer::unsupported(memory)),
+        }
+    }
+
+    fn write_at(
+        &mut self,
+        memory: MemoryType,
+        address: usize,
+        bytes: &[u8],
+    ) -> Result<(), errors::ErrorKind> {
+        match memory {
+            MemoryType::Flash => self.write_flash_pages(address, bytes),
+            MemoryType::Eeprom => self.write_eeprom_pages(address, bytes),
+            _ => Err(programmer::unsupported(memory)),
+        }
+    }
+}
+
+impl programmer::Erase for IspMode {
+    fn erase(&mut self) -> Result<(), errors::ErrorKind> {
+        self.prog.spi(isp_command::CHIP_ERASE)?;
+        thread::sleep(Duration::from_millis(self.prog.specs.erase_delay.into()));
+        // Chip erase ends programming mode on some devices.
+        self.prog.enter_isp_mode()
+    }
+}
+
+impl programmer::Programmer for IspMode {
+    fn close(self) -> Result<(), errors::ErrorKind> {
+        self.prog.control_in(request::POWER_DOWN, 0, 0, &mut [])
+    }
+}
+
+impl programmer::AVRLockByteGet for IspMode {
+    fn get_lock_byte(&mut self) -> Result<u8, errors::ErrorKind> {
+        Ok(self.prog.spi(isp_command::READ_LOCK)?[3])
+    }
+}
+
+impl programmer::AVRLockByteSet for IspMode {
+    fn set_lock_byte(&mut self, byte: u8) -> Result<u8, errors::ErrorKind> {
+        programmer::write_lock(self, programmer::LOCK_BITS, byte, |isp, byte| {
+            let (a, b, c, _) = isp_command::WRITE_LOCK;
+            isp.prog.spi((a, b, c, byte))?;
+            thread::sleep(WRITE_DELAY);
+            Ok(())
+        })
+    }
+}
+
+impl programmer::AVROsccalGet for IspMode {
+    fn get_osccal(&mut self) -> Result<u8, errors::ErrorKind> {
+        self.read_osccal(0)
+    }
+}
+
+impl programmer::AVRFuseGet for IspMode {
+    fn get_fuses(&mut self) -> Result<programmer::AVRFuse, errors::ErrorKind> {
+        Ok(programmer::AVRFuse {
+            low: self.prog.spi(isp_command::READ_LOW_FUSE)?[3],
+            high: self.prog.spi(isp_command::READ_HIGH_FUSE)?[3],
+            extended: self.prog.spi(isp_command::READ_EXTENDED_FUSE)?[3],
+        })
+    }
+}
+
+impl programmer::AVRFuseSet for IspMode {
+    fn set_fuses(
+        &mut self,
+        fuses: &programmer::AVRFuse,
+    ) -> Result<programmer::AVRFuse, errors::ErrorKind> {
+        for fuse in self.prog.specs.fuses() {
+            let (a, b, c, _) = match fuse {
+                specs::Fuse::Low => isp_command::WRITE_LOW_FUSE,
+                specs::Fuse::High => isp_command::WRITE_HIGH_FUSE,
+                specs::Fuse::Extended => isp_command::WRITE_EXTENDED_FUSE,
+            };
+            self.prog.spi((a, b, c, fuses.get(fuse)))?;
+            thread::sleep(WRITE_DELAY);
+        }
+        let read = programmer::AVRFuseGet::get_fuses(self)?;
+        programmer::check_fuses(self.prog.specs.fuse_bits, fuses, read)
+    }
+}
+
+impl programmer::MCUSignature for IspMode {
+    fn get_mcu_signature(&mut self) -> Result<specs::Signature, errors::ErrorKind> {
+        let mut signature = [0; 3];
+        for (addr, byte) in signature.iter_mut().enumerate() {
+            let (a, b, _, d) = isp_command::READ_SIGNATURE;
+            *byte = self.prog.spi((a, b, addr as u8, d))?[3];
+        }
+        Ok(specs::Signature::from(signature))
+    }
+}
+
+impl programmer::Session for IspMode {
+    fn identify(&mut self) -> Result<programmer::Identity, errors::ErrorKind> {
+        Ok(programmer::Identity {
+            name: String::from("USBtinyISP"),
+            hardware: None,
+            firmware: None,
+        })
+    }
+
+    fn counters(&self) -> programmer::Counters {
+        programmer::Counters {
+            frames: self.prog.frames.get(),
+            ..programmer::Counters::default()
+        }
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), errors::ErrorKind> {
+        programmer::Programmer::close(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spi_setup_packs_instruction() {
+        assert_eq!(spi_setup((0xac, 0x53, 0x00, 0x01)), (0x53ac, 0x0100));
+    }
+}
@@ -1,6 +1,7 @@
 use crate::specs::*;
 
 pub const ATMEGA_32: Specs = Specs {
+    name: "ATmega32",
     timeout: 0xc8,
     stab_delay: 0x64,
     cmd_exe_delay: 0x19,
@@ -23,6 +24,7 @@ pub const ATMEGA_32: Specs = Specs {
         size: 32768,
         mode: 0x21,
         delay: 10,
+        poll: (0xff, 0xff),
     },
     eeprom: Memory {
         page_size: 4,
@@ -30,13 +32,85 @@ pub const ATMEGA_32: Specs = Specs {
         size: 1024,
         mode: 0x04,
         delay: 20,
+        poll: (0xff, 0xff),
+    },
+    calibration: Memory {
+        page_size: 1,
+        start: 0,
+        size: 4,
+        mode: 0,
+        delay: 0,
+        poll: (0, 0),
     },
     signature: Signature {
         bytes: (0x1e, 0x95, 0x02),
     },
+    boot_sizes: &[4096, 2048, 1024, 512],
+    fuse_bits: &[
+        FuseBits {
+            fuse: Fuse::Low,
+            name: "BODLEVEL",
+            mask: 0x80,
+        },
+        FuseBits {
+            fuse: Fuse::Low,
+            name: "BODEN",
+            mask: 0x40,
+        },
+        FuseBits {
+            fuse: Fuse::Low,
+            name: "SUT_CKSEL",
+            mask: 0x3f,
+        },
+        FuseBits {
+            fuse: Fuse::High,
+            name: "OCDEN",
+            mask: 0x80,
+        },
+        FuseBits {
+            fuse: Fuse::High,
+            name: "JTAGEN",
+            mask: 0x40,
+        },
+        FuseBits {
+            fuse: Fuse::High,
+            name: "SPIEN",
+            mask: 0x20,
+        },
+        FuseBits {
+            fuse: Fuse::High,
+            name: "CKOPT",
+            mask: 0x10,
+        },
+        FuseBits {
+            fuse: Fuse::High,
+            name: "EESAVE",
+            mask: 0x08,
+        },
+        FuseBits {
+            fuse: Fuse::High,
+            name: "BOOTSZ",
+            mask: 0x06,
+        },
+        FuseBits {
+            fuse: Fuse::High,
+            name: "BOOTRST",
+            mask: 0x01,
+        },
+    ],
+    fuse_defaults: (0xe1, 0x99, 0xff),
+    // ATmega32L from 2.7 V.
+    supply: (27, 55),
+    rc_clocks: &[
+        (1, 1_000_000),
+        (2, 2_000_000),
+        (3, 4_000_000),
+        (4, 8_000_000),
+    ],
 };
 
 pub const ATMEGA_2560: Specs = Specs {
+    name: "ATmega2560",
     timeout: 200,
     stab_delay: 100,
     cmd_exe_delay: 25,
@@ -59,6 +133,7 @@ pub const ATMEGA_2560: Specs = Specs {
         page_size: 256,
         mode: 0x21,
         delay: 10,
+        poll: (0x00, 0x00),
     },
     eeprom: Memory {
         page_size: 8,
@@ -66,8 +141,187 @@ pub const ATMEGA_2560: Specs = Specs {
         size: 4096,
         mode: 0x04,
         delay: 20,
+        poll: (0x00, 0x00),
+    },
+    calibration: Memory {
+        page_size: 1,
+        start: 0,
+        size: 1,
+        mode: 0,
+        delay: 0,
+        poll: (0, 0),
     },
     signature: Signature {
         bytes: (0x1e, 0x98, 0x01),
     },
+    boot_sizes: &[8192, 4096, 2048, 1024],
+    fuse_bits: &[
+        FuseBits {
+            fuse: Fuse::Low,
+            name: "CKDIV8",
+            mask: 0x80,
+        },
+        FuseBits {
+            fuse: Fuse::Low,
+            name: "CKOUT",
+            mask: 0x40,
+        },
+        FuseBits {
+            fuse: Fuse::Low,
+            name: "SUT_CKSEL",
+            mask: 0x3f,
+        },
+        FuseBits {
+            fuse: Fuse::High,
+            name: "OCDEN",
+            mask: 0x80,
+        },
+        FuseBits {
+            fuse: Fuse::High,
+            name: "JTAGEN",
+            mask: 0x40,
+        },
+        FuseBits {
+            fuse: Fuse::High,
+            name: "SPIEN",
+            mask: 0x20,
+        },
+        FuseBits {
+            fuse: Fuse::High,
+            name: "WDTON",
+            mask: 0x10,
+        },
+        FuseBits {
+            fuse: Fuse::High,
+            name: "EESAVE",
+            mask: 0x08,
+        },
+        FuseBits {
+            fuse: Fuse::High,
+            name: "BOOTSZ",
+            mask: 0x06,
+        },
+        FuseBits {
+            fuse: Fuse::High,
+            name: "BOOTRST",
+            mask: 0x01,
+        },
+        FuseBits {
+            fuse: Fuse::Extended,
+            name: "BODLEVEL",
+            mask: 0x07,
+        },
+    ],
+    fuse_defaults: (0x62, 0x99, 0xff),
+    // ATmega2560V from 1.8 V.
+    supply: (18, 55),
+    rc_clocks: &[(2, 8_000_000), (3, 128_000)],
+};
+
+pub const ATMEGA_328P: Specs = Specs {
+    name: "ATmega328P",
+    timeout: 200,
+    stab_delay: 100,
+    cmd_exe_delay: 25,
+    synch_loops: 32,
+    byte_delay: 0,
+    pool_value: 0x53,
+    pool_index: 3,
+    pre_delay: 1,
+    erase_poll_method: 1,
+    erase_delay: 9,
+    fuse_poll_index: 4,
+    lock_poll_index: 4,
+    signature_poll_index: 4,
+    osccal_poll_index: 4,
+    post_delay: 1,
+    reset_polarity: true,
+    flash: Memory {
+        page_size: 128,
+        start: 0,
+        size: 32768,
+        mode: 0x41,
+        delay: 6,
+        poll: (0xff, 0xff),
+    },
+    eeprom: Memory {
+        page_size: 4,
+        start: 0,
+        size: 1024,
+        mode: 0x41,
+        delay: 20,
+        poll: (0xff, 0xff),
+    },
+    calibration: Memory {
+        page_size: 1,
+        start: 0,
+        size: 1,
+        mode: 0,
+        delay: 0,
+        poll: (0, 0),
+    },
+    signature: Signature {
+        bytes: (0x1e, 0x95, 0x0f),
+    },
+    boot_sizes: &[4096, 2048, 1024, 512],
+    fuse_bits: &[
+        FuseBits {
+            fuse: Fuse::Low,
+            name: "CKDIV8",
+            mask: 0x80,
+        },
+        FuseBits {
+            fuse: Fuse::Low,
+            name: "CKOUT",
+            mask: 0x40,
+        },
+        FuseBits {
+            fuse: Fuse::Low,
+            name: "SUT_CKSEL",
+            mask: 0x3f,
+        },
+        FuseBits {
+            fuse: Fuse::High,
+            name: "RSTDISBL",
+            mask: 0x80,
+        },
+        FuseBits {
+            fuse: Fuse::High,
+            name: "DWEN",
+            mask: 0x40,
+        },
+        FuseBits {
+            fuse: Fuse::High,
+            name: "SPIEN",
+            mask: 0x20,
+        },
+        FuseBits {
+            fuse: Fuse::High,
+            name: "WDTON",
+            mask: 0x10,
+        },
+        FuseBits {
+            fuse: Fuse::High,
+            name: "EESAVE",
+            mask: 0x08,
+        },
+        FuseBits {
+            fuse: Fuse::High,
+            name: "BOOTSZ",
+            mask: 0x06,
+        },
+        FuseBits {
+            fuse: Fuse::High,
+            name: "BOOTRST",
+            mask: 0x01,
+        },
+        FuseBits {
+            fuse: Fuse::Extended,
+            name: "BODLEVEL",
+            mask: 0x07,
+        },
+    ],
+    fuse_defaults: (0x62, 0xd9, 0xff),
+    supply: (18, 55),
+    rc_clocks: &[(2, 8_000_000), (3, 128_000)],
 };
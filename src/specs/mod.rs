@@ -2,21 +2,37 @@
 /// Can be obtained [here](http://packs.download.atmel.com/). Those are
 /// ZIPs with xml files describing given MCU. Simmilar to SVD for ARM.
 pub mod atmega;
+pub mod tpi;
+pub mod updi;
 use std::fmt;
+use std::str::FromStr;
 
 /// MCU signature.
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
 pub struct Signature {
     pub bytes: (u8, u8, u8),
 }
 
+impl Signature {
+    /// Whether this is signature of chip described by `specs`.
+    pub fn matches(&self, specs: &Specs) -> bool {
+        *self == specs.signature
+    }
+}
+
+/// Bytes followed by name of known chip, e.g. `1E 95 0F (ATmega328P)`. Alternate form (`{:#}`)
+/// leaves the name out.
 impl fmt::Display for Signature {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{:#04x} {:#04x} {:#04x}",
-            self.bytes.0, self.bytes.1, self.bytes.2
-        )
+        let (sig0, sig1, sig2) = self.bytes;
+        write!(f, "{:02X} {:02X} {:02X}", sig0, sig1, sig2)?;
+        if f.alternate() {
+            return Ok(());
+        }
+        match find_by_signature(self) {
+            Some(specs) => write!(f, " ({})", specs.name),
+            None => write!(f, " (unknown)"),
+        }
     }
 }
 
@@ -35,6 +51,7 @@ impl From<(u8, u8, u8)> for Signature {
 }
 
 /// Memory segment. EEPROM or flash.
+#[derive(Debug, Clone)]
 pub struct Memory {
     /// Start address of a given memory section. Given in XML in `address-spaces` section
     pub start: usize,
@@ -50,12 +67,202 @@ pub struct Memory {
     /// * `ISP_INTERFACE/IspProgramFlash_delay` for flash.
     /// * `ISP_INTERFACE/IspProgramEeprom_delay` for eeprom.
     pub delay: usize,
+    /// Values read from a location which is still being written, used by value polling. In xml:
+    /// * `ISP_INTERFACE/IspProgramFlash_pollVal1` and `pollVal2` for flash.
+    /// * `ISP_INTERFACE/IspProgramEeprom_pollVal1` and `pollVal2` for eeprom.
+    pub poll: (u8, u8),
+}
+
+impl Memory {
+    /// Bit 0 of `mode`. Set for page mode, cleared for word mode.
+    const PAGE_MODE: usize = 0x01;
+
+    /// Position of poll method bits in `mode`, which differs for word and page mode.
+    fn poll_shift(&self) -> usize {
+        if self.mode & Self::PAGE_MODE != 0 {
+            4
+        } else {
+            1
+        }
+    }
+
+    /// How programmer waits for a write to finish, as encoded in `mode`.
+    pub fn poll_method(&self) -> PollMethod {
+        let bits = (self.mode >> self.poll_shift()) & 0x07;
+        if bits & PollMethod::RdyBsy as usize != 0 {
+            PollMethod::RdyBsy
+        } else if bits & PollMethod::Value as usize != 0 {
+            PollMethod::Value
+        } else {
+            PollMethod::Delay
+        }
+    }
+
+    pub fn set_poll_method(&mut self, method: PollMethod) {
+        let shift = self.poll_shift();
+        self.mode = (self.mode & !(0x07 << shift)) | (method as usize) << shift;
+    }
+}
+
+/// How programmer finds out that target finished a write. Bits of AVR068 mode byte.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PollMethod {
+    /// Wait for `delay` milliseconds. Slowest, but works with every chip.
+    Delay = 0x01,
+    /// Read written location back until it holds written value.
+    Value = 0x02,
+    /// Poll RDY/BSY instruction.
+    RdyBsy = 0x04,
+}
+
+impl FromStr for PollMethod {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<PollMethod, String> {
+        match value {
+            "delay" => Ok(PollMethod::Delay),
+            "value" => Ok(PollMethod::Value),
+            "rdy-bsy" => Ok(PollMethod::RdyBsy),
+            _ => Err(format!(
+                "Unknown poll method {}. Use delay, value or rdy-bsy",
+                value
+            )),
+        }
+    }
+}
+
+/// Kind of memory accessed by programmers.
+#[derive(PartialEq, Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum MemoryType {
+    Flash,
+    Eeprom,
+    /// User signature row (AVR Dx, XMEGA).
+    UserSig,
+    /// Factory calibration bytes.
+    Calibration,
+}
+
+impl fmt::Display for MemoryType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryType::Flash => write!(f, "flash"),
+            MemoryType::Eeprom => write!(f, "eeprom"),
+            MemoryType::UserSig => write!(f, "usersig"),
+            MemoryType::Calibration => write!(f, "calibration"),
+        }
+    }
+}
+
+/// How a memory can be changed.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Access {
+    /// Set in factory, can only be read.
+    ReadOnly,
+    /// Any value can be written.
+    ReadWrite,
+    /// Written bits can only be cleared. Chip erase sets them back to 1.
+    ClearOnly,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Access::ReadOnly => write!(f, "read-only"),
+            Access::ReadWrite => write!(f, "read-write"),
+            Access::ClearOnly => write!(f, "clear-only"),
+        }
+    }
+}
+
+/// Entry of chip memory map.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Region {
+    /// E.g. `flash`, `fuses` or `signature`.
+    pub name: &'static str,
+    pub size: usize,
+    /// 1 for memories written byte by byte.
+    pub page_size: usize,
+    pub access: Access,
+    /// Memory type to pass to `Session::read` and `Session::write`. None for memories with
+    /// their own methods (fuses, lock byte, signature).
+    pub memory_type: Option<MemoryType>,
+}
+
+impl Region {
+    fn memory(memory_type: MemoryType, memory: &Memory, access: Access) -> Region {
+        let name = match memory_type {
+            MemoryType::Flash => "flash",
+            MemoryType::Eeprom => "eeprom",
+            MemoryType::UserSig => "usersig",
+            MemoryType::Calibration => "calibration",
+        };
+        Region {
+            name,
+            size: memory.size,
+            page_size: memory.page_size,
+            access,
+            memory_type: Some(memory_type),
+        }
+    }
+
+    fn bytes(name: &'static str, size: usize, access: Access) -> Region {
+        Region {
+            name,
+            size,
+            page_size: 1,
+            access,
+            memory_type: None,
+        }
+    }
+}
+
+/// Fuse byte.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Fuse {
+    Low,
+    High,
+    Extended,
+}
+
+impl fmt::Display for Fuse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fuse::Low => write!(f, "low"),
+            Fuse::High => write!(f, "high"),
+            Fuse::Extended => write!(f, "extended"),
+        }
+    }
+}
+
+/// Named group of bits within fuse byte.
+///
+/// Given in XML in `register-group` named `FUSE` as `bitfield` elements.
+#[derive(Debug, Clone)]
+pub struct FuseBits {
+    pub fuse: Fuse,
+    pub name: &'static str,
+    pub mask: u8,
+}
+
+impl FuseBits {
+    /// Extract value of this bit group from fuse byte. Result is shifted to lowest bits.
+    pub fn value(&self, byte: u8) -> u8 {
+        (byte & self.mask) >> self.mask.trailing_zeros()
+    }
 }
 
 /// Parameters required by programmers.
 ///
 /// Can be found in xml (under `ISP_INTERFACE`) for given chip.
+#[derive(Debug, Clone)]
 pub struct Specs {
+    /// Chip name as given in `device` element.
+    pub name: &'static str,
     /// `IspEnterProgMode_timeout`
     pub timeout: u8,
     /// `IspEnterProgMode_stabDelay`
@@ -90,12 +297,144 @@ pub struct Specs {
     pub signature_poll_index: u8,
     pub flash: Memory,
     pub eeprom: Memory,
+    /// Internal oscillator calibration bytes, read with `Read Calibration Byte` instruction. One
+    /// byte for each calibrated frequency, page size is 1.
+    pub calibration: Memory,
+    /// Boot section size in bytes for each `BOOTSZ` value. Empty if chip has no boot section.
+    pub boot_sizes: &'static [usize],
+    /// Fuse bits. Only fuse bytes present in chip are listed.
+    pub fuse_bits: &'static [FuseBits],
+    /// Factory values of low, high and extended fuse. Given in XML as `initval` of fuse registers.
+    pub fuse_defaults: (u8, u8, u8),
+    /// Lowest and highest supply voltage in tenths of volt. Widest of chip variants, e.g. low
+    /// voltage L or V ones.
+    pub supply: (u8, u8),
+    /// Internal oscillator frequency in Hz for each `CKSEL` value selecting one. Crystal and
+    /// external clock frequencies are not known.
+    pub rc_clocks: &'static [(u8, u32)],
+}
+
+impl Specs {
+    /// Layout of given memory, if chip has it.
+    pub fn memory(&self, memory: MemoryType) -> Option<&Memory> {
+        match memory {
+            MemoryType::Flash => Some(&self.flash),
+            MemoryType::Eeprom => Some(&self.eeprom),
+            MemoryType::Calibration => Some(&self.calibration),
+            // No ISP instruction reaches it.
+            MemoryType::UserSig => None,
+        }
+    }
+
+    /// Flash addresses of boot section selected by `BOOTSZ` fuse bits value.
+    pub fn boot_section(&self, bootsz: u8) -> Option<std::ops::Range<usize>> {
+        let size = self.boot_sizes.get(bootsz as usize)?;
+        Some(self.flash.size - size..self.flash.size)
+    }
+
+    /// Flash addresses of boot section selected by `BOOTSZ` bits of `high` fuse. Chips with
+    /// `BOOTSZ` in another fuse use [`boot_section`](Self::boot_section).
+    ///
+    /// Range is reported whether `BOOTRST` is programmed or not.
+    pub fn active_boot_section(&self, high: u8) -> Option<std::ops::Range<usize>> {
+        let bootsz = self.fuse_field("BOOTSZ")?;
+        self.boot_section(bootsz.value(high))
+    }
+
+    /// Bit group called `name`, e.g. `SPIEN`.
+    pub fn fuse_field(&self, name: &str) -> Option<&FuseBits> {
+        self.fuse_bits.iter().find(|bits| bits.name == name)
+    }
+
+    /// Override poll method of flash, EEPROM and chip erase given by chip description.
+    ///
+    /// Chip erase has no value polling, it keeps its own method then.
+    pub fn set_poll_method(&mut self, method: PollMethod) {
+        self.flash.set_poll_method(method);
+        self.eeprom.set_poll_method(method);
+        match method {
+            PollMethod::Delay => self.erase_poll_method = 0,
+            PollMethod::RdyBsy => self.erase_poll_method = 1,
+            PollMethod::Value => (),
+        }
+    }
+
+    /// Bits of `fuse` which have a function. Others are reserved and must stay 1.
+    pub fn fuse_mask(&self, fuse: Fuse) -> u8 {
+        self.fuse_bits
+            .iter()
+            .filter(|bits| bits.fuse == fuse)
+            .fold(0, |mask, bits| mask | bits.mask)
+    }
+
+    /// Factory value of `fuse`.
+    pub fn fuse_default(&self, fuse: Fuse) -> u8 {
+        match fuse {
+            Fuse::Low => self.fuse_defaults.0,
+            Fuse::High => self.fuse_defaults.1,
+            Fuse::Extended => self.fuse_defaults.2,
+        }
+    }
+
+    /// Memory map: flash, EEPROM, fuses, lock byte, signature and OSCCAL calibration byte.
+    pub fn memories(&self) -> Vec<Region> {
+        vec![
+            Region::memory(MemoryType::Flash, &self.flash, Access::ClearOnly),
+            Region::memory(MemoryType::Eeprom, &self.eeprom, Access::ReadWrite),
+            Region::bytes("fuses", self.fuses().len(), Access::ReadWrite),
+            Region::bytes("lock", 1, Access::ClearOnly),
+            Region::bytes("signature", 3, Access::ReadOnly),
+            Region::memory(MemoryType::Calibration, &self.calibration, Access::ReadOnly),
+        ]
+    }
+
+    /// Fuse bytes present in chip.
+    pub fn fuses(&self) -> Vec<Fuse> {
+        let mut fuses = Vec::new();
+        for bits in self.fuse_bits {
+            if !fuses.contains(&bits.fuse) {
+                fuses.push(bits.fuse);
+            }
+        }
+        fuses
+    }
+}
+
+/// All built in chip specifications.
+pub const CHIPS: [&Specs; 3] = [
+    &atmega::ATMEGA_32,
+    &atmega::ATMEGA_2560,
+    &atmega::ATMEGA_328P,
+];
+
+/// Find chip specification by name. Case insensitive.
+pub fn find(name: &str) -> Option<&'static Specs> {
+    CHIPS
+        .iter()
+        .find(|specs| specs.name.eq_ignore_ascii_case(name))
+        .copied()
+}
+
+/// Find chip specification by signature.
+pub fn find_by_signature(signature: &Signature) -> Option<&'static Specs> {
+    CHIPS.iter().find(|specs| signature.matches(specs)).copied()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn poll_method_override() {
+        let mut specs = atmega::ATMEGA_32;
+        assert_eq!(specs.flash.poll_method(), PollMethod::Value);
+        assert_eq!(specs.eeprom.poll_method(), PollMethod::Value);
+        specs.set_poll_method(PollMethod::RdyBsy);
+        assert_eq!(specs.flash.mode, 0x41);
+        assert_eq!(specs.eeprom.mode, 0x08);
+        assert_eq!(specs.erase_poll_method, 1);
+    }
+
     #[test]
     fn signature_from_array_trait() {
         let sign = Signature::from([1, 2, 3]);
@@ -125,4 +464,92 @@ mod tests {
         let s2 = Signature { bytes: (1, 2, 2) };
         assert_ne!(s1, s2);
     }
+
+    #[test]
+    fn signature_display() {
+        assert_eq!(
+            atmega::ATMEGA_2560.signature.to_string(),
+            "1E 98 01 (ATmega2560)"
+        );
+        let unknown = Signature::from((0x1e, 0x00, 0x0f));
+        assert_eq!(unknown.to_string(), "1E 00 0F (unknown)");
+        assert_eq!(format!("{:#}", unknown), "1E 00 0F");
+        assert!(atmega::ATMEGA_32.signature.matches(&atmega::ATMEGA_32));
+        assert!(!unknown.matches(&atmega::ATMEGA_32));
+    }
+
+    #[test]
+    fn find_ignores_case() {
+        assert_eq!(find("atmega32").unwrap().name, "ATmega32");
+    }
+
+    #[test]
+    fn find_by_signature_known() {
+        let sign = Signature::from((0x1e, 0x98, 0x01));
+        assert_eq!(find_by_signature(&sign).unwrap().name, "ATmega2560");
+    }
+
+    #[test]
+    fn fuse_bits_value_is_shifted() {
+        let bits = FuseBits {
+            fuse: Fuse::High,
+            name: "BOOTSZ",
+            mask: 0x06,
+        };
+        assert_eq!(bits.value(0b1111_1011), 0b01);
+    }
+
+    #[test]
+    fn fuses_without_extended() {
+        assert_eq!(atmega::ATMEGA_32.fuses(), vec![Fuse::Low, Fuse::High]);
+    }
+
+    #[test]
+    fn boot_section_from_high_fuse() {
+        let chip = atmega::ATMEGA_2560;
+        assert_eq!(chip.active_boot_section(0xd8), Some(0x3e000..0x40000));
+        assert_eq!(chip.active_boot_section(0xde), Some(0x3fc00..0x40000));
+        assert_eq!(
+            atmega::ATMEGA_32.active_boot_section(0x99),
+            Some(0x7000..0x8000)
+        );
+    }
+
+    #[test]
+    fn fuse_mask_and_default() {
+        let chip = atmega::ATMEGA_2560;
+        assert_eq!(chip.fuse_mask(Fuse::Extended), 0x07);
+        assert_eq!(chip.fuse_mask(Fuse::High), 0xff);
+        assert_eq!(chip.fuse_default(Fuse::Low), 0x62);
+    }
+
+    #[test]
+    fn memory_map() {
+        let memories = atmega::ATMEGA_32.memories();
+        let names: Vec<&str> = memories.iter().map(|region| region.name).collect();
+        assert_eq!(
+            names,
+            [
+                "flash",
+                "eeprom",
+                "fuses",
+                "lock",
+                "signature",
+                "calibration"
+            ]
+        );
+        assert_eq!(memories[0].size, 0x8000);
+        assert_eq!(memories[0].page_size, 128);
+        assert_eq!(memories[0].memory_type, Some(MemoryType::Flash));
+        assert_eq!(memories[2].size, 2);
+        assert_eq!(memories[4].access, Access::ReadOnly);
+        // One byte for each of 1, 2, 4 and 8 MHz.
+        assert_eq!(memories[5].size, 4);
+        assert_eq!(memories[5].memory_type, Some(MemoryType::Calibration));
+    }
+
+    #[test]
+    fn find_unknown() {
+        assert!(find("attiny13").is_none());
+    }
 }
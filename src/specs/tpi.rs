@@ -0,0 +1,132 @@
+//! Chips programmed over TPI. All memories share one address space, flash is mapped at
+//! [`FLASH_START`]. There is no EEPROM and only one configuration (fuse) byte, given as low fuse.
+use crate::specs::{Access, Fuse, FuseBits, Memory, MemoryType, Region, Signature};
+
+pub const FLASH_START: usize = 0x4000;
+pub const LOCK_BITS: usize = 0x3f00;
+pub const CONFIGURATION: usize = 0x3f40;
+pub const CALIBRATION: usize = 0x3f80;
+pub const SIGNATURE: usize = 0x3fc0;
+/// NVM controller registers in I/O space.
+pub const NVMCMD: u8 = 0x33;
+pub const NVMCSR: u8 = 0x32;
+
+#[derive(Debug, Clone)]
+pub struct TpiSpecs {
+    pub name: &'static str,
+    pub signature: Signature,
+    /// Flash as mapped into data space.
+    pub flash: Memory,
+    /// Bits of configuration byte.
+    pub fuse_bits: &'static [FuseBits],
+}
+
+impl TpiSpecs {
+    /// Memory map: flash, configuration byte (as fuses), lock byte, signature and calibration.
+    pub fn memories(&self) -> Vec<Region> {
+        vec![
+            Region::memory(MemoryType::Flash, &self.flash, Access::ClearOnly),
+            Region::bytes("fuses", 1, Access::ClearOnly),
+            Region::bytes("lock", 1, Access::ClearOnly),
+            Region::bytes("signature", 3, Access::ReadOnly),
+            Region::bytes("calibration", 1, Access::ReadOnly),
+        ]
+    }
+}
+
+const fn flash(size: usize, page_size: usize) -> Memory {
+    Memory {
+        start: FLASH_START,
+        size,
+        page_size,
+        // Not used by TPI.
+        mode: 0,
+        delay: 0,
+        poll: (0, 0),
+    }
+}
+
+const FUSE_BITS: [FuseBits; 3] = [
+    FuseBits {
+        fuse: Fuse::Low,
+        name: "CKOUT",
+        mask: 0x04,
+    },
+    FuseBits {
+        fuse: Fuse::Low,
+        name: "WDTON",
+        mask: 0x02,
+    },
+    FuseBits {
+        fuse: Fuse::Low,
+        name: "RSTDISBL",
+        mask: 0x01,
+    },
+];
+
+pub const ATTINY_4: TpiSpecs = TpiSpecs {
+    name: "ATtiny4",
+    signature: Signature {
+        bytes: (0x1e, 0x8f, 0x0a),
+    },
+    flash: flash(512, 16),
+    fuse_bits: &FUSE_BITS,
+};
+
+pub const ATTINY_5: TpiSpecs = TpiSpecs {
+    name: "ATtiny5",
+    signature: Signature {
+        bytes: (0x1e, 0x8f, 0x09),
+    },
+    flash: flash(512, 16),
+    fuse_bits: &FUSE_BITS,
+};
+
+pub const ATTINY_9: TpiSpecs = TpiSpecs {
+    name: "ATtiny9",
+    signature: Signature {
+        bytes: (0x1e, 0x90, 0x08),
+    },
+    flash: flash(1024, 16),
+    fuse_bits: &FUSE_BITS,
+};
+
+pub const ATTINY_10: TpiSpecs = TpiSpecs {
+    name: "ATtiny10",
+    signature: Signature {
+        bytes: (0x1e, 0x90, 0x03),
+    },
+    flash: flash(1024, 16),
+    fuse_bits: &FUSE_BITS,
+};
+
+pub const ATTINY_20: TpiSpecs = TpiSpecs {
+    name: "ATtiny20",
+    signature: Signature {
+        bytes: (0x1e, 0x91, 0x0f),
+    },
+    flash: flash(2048, 32),
+    fuse_bits: &FUSE_BITS,
+};
+
+pub const ATTINY_40: TpiSpecs = TpiSpecs {
+    name: "ATtiny40",
+    signature: Signature {
+        bytes: (0x1e, 0x92, 0x0e),
+    },
+    flash: flash(4096, 64),
+    fuse_bits: &FUSE_BITS,
+};
+
+/// All built in TPI chip specifications.
+pub const CHIPS: [&TpiSpecs; 6] = [
+    &ATTINY_4, &ATTINY_5, &ATTINY_9, &ATTINY_10, &ATTINY_20, &ATTINY_40,
+];
+
+/// Find chip specification by name. Case insensitive.
+pub fn find(name: &str) -> Option<&'static TpiSpecs> {
+    CHIPS
+        .iter()
+        .find(|specs| specs.name.eq_ignore_ascii_case(name))
+        .copied()
+}
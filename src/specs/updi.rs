@@ -0,0 +1,144 @@
+//! Chips programmed over UPDI. They have no ISP interface, so parameters differ from [`Specs`].
+//!
+//! Memories are given as mapped into data space. Values come from atdf files.
+//!
+//! [`Specs`]: super::Specs
+use crate::specs::{Access, Memory, MemoryType, Region, Signature};
+
+/// NVM controller version. Decides how memories are written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NvmVersion {
+    /// tinyAVR 0/1/2, megaAVR 0.
+    V0,
+    /// AVR DA / DB / DD.
+    V2,
+}
+
+#[derive(Debug, Clone)]
+pub struct UpdiSpecs {
+    pub name: &'static str,
+    pub signature: Signature,
+    pub nvm: NvmVersion,
+    /// Flash as mapped into data space. `start` is the mapping address.
+    pub flash: Memory,
+    pub eeprom: Memory,
+    /// User row. Survives chip erase, so it holds per unit data like serial numbers.
+    pub usersig: Memory,
+    /// Address of first fuse.
+    pub fuses: usize,
+    /// Number of fuse bytes.
+    pub fuse_count: usize,
+}
+
+impl UpdiSpecs {
+    /// Addresses above 64K need 24 bit UPDI addressing.
+    pub fn address_24(&self) -> bool {
+        self.flash.start + self.flash.size > 0x10000
+    }
+
+    /// Memory map: flash, EEPROM, user row, fuses, lock byte and signature.
+    pub fn memories(&self) -> Vec<Region> {
+        vec![
+            Region::memory(MemoryType::Flash, &self.flash, Access::ClearOnly),
+            Region::memory(MemoryType::Eeprom, &self.eeprom, Access::ReadWrite),
+            Region::memory(MemoryType::UserSig, &self.usersig, Access::ClearOnly),
+            Region::bytes("fuses", self.fuse_count, Access::ReadWrite),
+            Region::bytes("lock", 1, Access::ReadWrite),
+            Region::bytes("signature", 3, Access::ReadOnly),
+        ]
+    }
+}
+
+const fn memory(start: usize, size: usize, page_size: usize) -> Memory {
+    Memory {
+        start,
+        size,
+        page_size,
+        // Not used by UPDI.
+        mode: 0,
+        delay: 0,
+        poll: (0, 0),
+    }
+}
+
+pub const ATTINY_416: UpdiSpecs = UpdiSpecs {
+    name: "ATtiny416",
+    signature: Signature {
+        bytes: (0x1e, 0x92, 0x21),
+    },
+    nvm: NvmVersion::V0,
+    flash: memory(0x8000, 4096, 64),
+    eeprom: memory(0x1400, 128, 32),
+    usersig: memory(0x1300, 32, 32),
+    fuses: 0x1280,
+    fuse_count: 11,
+};
+
+pub const ATTINY_1616: UpdiSpecs = UpdiSpecs {
+    name: "ATtiny1616",
+    signature: Signature {
+        bytes: (0x1e, 0x94, 0x21),
+    },
+    nvm: NvmVersion::V0,
+    flash: memory(0x8000, 16384, 64),
+    eeprom: memory(0x1400, 256, 32),
+    usersig: memory(0x1300, 32, 32),
+    fuses: 0x1280,
+    fuse_count: 11,
+};
+
+pub const ATTINY_3216: UpdiSpecs = UpdiSpecs {
+    name: "ATtiny3216",
+    signature: Signature {
+        bytes: (0x1e, 0x95, 0x21),
+    },
+    nvm: NvmVersion::V0,
+    flash: memory(0x8000, 32768, 128),
+    eeprom: memory(0x1400, 256, 64),
+    usersig: memory(0x1300, 32, 32),
+    fuses: 0x1280,
+    fuse_count: 11,
+};
+
+pub const ATMEGA_4809: UpdiSpecs = UpdiSpecs {
+    name: "ATmega4809",
+    signature: Signature {
+        bytes: (0x1e, 0x96, 0x51),
+    },
+    nvm: NvmVersion::V0,
+    flash: memory(0x4000, 49152, 128),
+    eeprom: memory(0x1400, 256, 64),
+    usersig: memory(0x1300, 64, 64),
+    fuses: 0x1280,
+    fuse_count: 11,
+};
+
+pub const AVR128DA48: UpdiSpecs = UpdiSpecs {
+    name: "AVR128DA48",
+    signature: Signature {
+        bytes: (0x1e, 0x97, 0x08),
+    },
+    nvm: NvmVersion::V2,
+    flash: memory(0x80_0000, 131072, 512),
+    eeprom: memory(0x1400, 512, 1),
+    usersig: memory(0x1080, 32, 32),
+    fuses: 0x1050,
+    fuse_count: 9,
+};
+
+/// All built in UPDI chip specifications.
+pub const CHIPS: [&UpdiSpecs; 5] = [
+    &ATTINY_416,
+    &ATTINY_1616,
+    &ATTINY_3216,
+    &ATMEGA_4809,
+    &AVR128DA48,
+];
+
+/// Find chip specification by name. Case insensitive.
+pub fn find(name: &str) -> Option<&'static UpdiSpecs> {
+    CHIPS
+        .iter()
+        .find(|specs| specs.name.eq_ignore_ascii_case(name))
+        .copied()
+}
@@ -0,0 +1,11 @@
+//! Optional [`tracing`](https://docs.rs/tracing) spans, enabled by `tracing` feature.
+//!
+//! Spans carry no timing. Subscribers measure it, e.g. `FmtSpan::CLOSE` of `tracing-subscriber`.
+
+/// Enter debug span for the rest of enclosing block. Expands to nothing without `tracing` feature.
+macro_rules! span {
+    ($name:literal $(, $($fields:tt)*)?) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!($name $(, $($fields)*)?).entered();
+    };
+}